@@ -186,6 +186,13 @@
               description = "hotplug devices to the running USB pass-through vfio-user executable";
             };
           };
+          usbpolicyd = {
+            type = "app";
+            program = "${usbvfiod}/bin/usbpolicyd";
+            meta = {
+              description = "attach USB devices to USB pass-through instances according to a policy";
+            };
+          };
         };
 
         devShells.default = craneLib.devShell {
@@ -12,125 +12,533 @@
 //! Command-line tool to attach/detach/list USB devices to/from usbvfiod at runtime.
 
 use std::{
-    os::unix::net::UnixStream,
     path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
 };
 
 use anyhow::{anyhow, Context, Result};
-use clap::{ArgAction, Parser};
-use nusb::MaybeFuture;
+use clap::{ArgAction, ArgGroup, Parser, ValueEnum};
+use serde_json::{json, Value};
 use usbvfiod::hotplug_protocol::{
-    command::Command, device_paths::resolve_path, response::Response,
+    attached_device::AttachedDevice,
+    client::{BlockingHotplugClient, ClientError, HotplugClient},
+    command::DetachSelector,
+    device_file::DeviceFile,
+    host_devices::{
+        find_host_device, list_host_devices, HostDevice, HostDeviceError, HostDeviceEvent,
+        HostDeviceSelector, HostDeviceWatcher,
+    },
+    response::Response,
+    rules::Rules,
+    usb_id::{UsbId, UsbIdParseError},
 };
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let args = Cli::parse();
+    let output = args.output;
 
-    if let Some(path) = args.attach {
-        attach(path.as_path(), args.socket.as_path())?;
-    } else if let Some(vec) = args.detach {
-        // Safety: clap ensures that vec.len() == 2.
-        let bus = vec[0];
-        let dev = vec[1];
-        detach(bus, dev, args.socket.as_path())?;
-    } else if args.list {
-        list_attached(args.socket.as_path())?;
+    match run(args) {
+        Ok(report) => {
+            report.print(output);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            let (status, response) = classify(&err);
+            print_error(&err, response, output);
+            ExitCode::from(status as u8)
+        }
     }
+}
+
+fn run(args: Cli) -> Result<Report> {
+    let output = args.output;
+    if let Some(rules_path) = args.watch {
+        let rules = Rules::load(&rules_path)?;
+        let runtime =
+            tokio::runtime::Runtime::new().context("Failed to start the tokio runtime")?;
+        return runtime.block_on(watch(rules, &args.socket, output));
+    }
+
+    let mut client =
+        BlockingHotplugClient::connect(&args.socket).context("Failed to open socket")?;
 
-    Ok(())
+    if let Some(device) = args.attach {
+        let path = match device {
+            DeviceSpec::Path(path) => path,
+            DeviceSpec::UsbId(usb_id) => {
+                select_host_device(HostDeviceSelector::UsbId(usb_id), output)?
+            }
+        };
+        attach(&mut client, &path, args.port, args.interfaces, output)
+    } else if let Some(port_path) = args.attach_port {
+        let path = select_host_device(HostDeviceSelector::Port(port_path), output)?;
+        attach(&mut client, &path, args.port, args.interfaces, output)
+    } else if let Some(values) = args.detach {
+        let (bus, dev) = detach_target(&values)?;
+        output.progress(format_args!(
+            "Requesting detach of device {bus:03}:{dev:03}"
+        ));
+        detach(&mut client, DetachSelector::Device { bus, device: dev })
+    } else if let Some(port) = args.detach_guest_port {
+        output.progress(format_args!(
+            "Requesting detach of the device on port {port}"
+        ));
+        detach(&mut client, DetachSelector::Port(port))
+    } else if let Some(usb_id) = args.detach_usb_id {
+        output.progress(format_args!(
+            "Requesting detach of devices matching {usb_id}"
+        ));
+        detach(&mut client, DetachSelector::UsbId(usb_id))
+    } else if let Some(path) = args.detach_host_port {
+        output.progress(format_args!(
+            "Requesting detach of the device on host port {path}"
+        ));
+        detach(&mut client, DetachSelector::HostPort(path))
+    } else if args.detach_all {
+        output.progress(format_args!("Requesting detach of all devices"));
+        detach(&mut client, DetachSelector::All)
+    } else if args.list {
+        list_attached(&mut client)
+    } else if args.list_host {
+        list_host(&mut client)
+    } else {
+        Ok(Report::Nothing)
+    }
 }
 
-fn attach(device_path: &Path, socket_path: &Path) -> Result<()> {
-    let (bus, dev, device_path) = resolve_path(device_path)
-        .with_context(|| format!("Failed to resolve device path {device_path:?}"))?;
+/// Attach host devices matching the rules as they appear and detach them
+/// when they disappear. Runs until interrupted.
+async fn watch(rules: Rules, socket: &Path, output: OutputFormat) -> Result<Report> {
+    let client = HotplugClient::connect(socket)
+        .await
+        .context("Failed to open socket")?;
+    let mut watcher = HostDeviceWatcher::new()?;
+    // Only detach devices that were attached by us.
+    let mut attached = Vec::new();
+
+    loop {
+        let event = tokio::select! {
+            event = watcher.next() => event.ok_or_else(|| anyhow!("Watching host devices stopped"))?,
+            _ = tokio::signal::ctrl_c() => return Ok(Report::Nothing),
+        };
 
-    println!("Requesting attachment of device {bus:03}:{dev:03}");
+        match event {
+            HostDeviceEvent::Arrived(device) => {
+                let Some(rule) = rules.find(&device) else {
+                    continue;
+                };
+                match client
+                    .attach_path(
+                        device.device_path(),
+                        rule.guest_port,
+                        rule.interfaces.clone(),
+                    )
+                    .await
+                {
+                    Ok(_) => {
+                        output.watch_event("attached", &device, Some(rule.line));
+                        attached.push((device.bus, device.device));
+                    }
+                    Err(err) => output.watch_error(&device, &err.into()),
+                }
+            }
+            HostDeviceEvent::Removed(device) => {
+                let id = (device.bus, device.device);
+                let Some(index) = attached.iter().position(|attached| *attached == id) else {
+                    continue;
+                };
+                attached.swap_remove(index);
+                let selector = DetachSelector::Device {
+                    bus: device.bus,
+                    device: device.device,
+                };
+                match client.detach(selector).await {
+                    Ok(_) => output.watch_event("detached", &device, None),
+                    Err(err) => output.watch_error(&device, &err.into()),
+                }
+            }
+        }
+    }
+}
 
-    let open_file = |err_msg: &str| {
-        std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&device_path)
-            .with_context(|| err_msg.to_string())
-    };
+/// Exit codes of `remote`. Exit code 2 is used by clap for invalid command
+/// lines.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Failure = 1,
+    NoFreePort = 3,
+    NoSuchDevice = 4,
+    CouldNotDetermineSpeed = 5,
+    SocketError = 6,
+}
 
-    let file = open_file("Failed to open USB device file")?;
-    let device = nusb::Device::from_fd(file.into())
-        .wait()
-        .context("Failed to open nusb device")?;
-    device.reset().wait().context("Failed to reset device")?;
+/// Determine the exit code of a failed command and the response of the
+/// server, if the server rejected the command.
+fn classify(err: &anyhow::Error) -> (Status, Option<Response>) {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<ClientError>() {
+            return match err {
+                ClientError::Rejected(response) => {
+                    let status = match response {
+                        Response::NoFreePort => Status::NoFreePort,
+                        Response::NoSuchDevice => Status::NoSuchDevice,
+                        Response::CouldNotDetermineSpeed => Status::CouldNotDetermineSpeed,
+                        _ => Status::Failure,
+                    };
+                    (status, Some(*response))
+                }
+                ClientError::Connect { .. } | ClientError::Send(_) | ClientError::Receive(_) => {
+                    (Status::SocketError, None)
+                }
+                _ => (Status::Failure, None),
+            };
+        }
+        if let Some(HostDeviceError::NotFound(_)) = cause.downcast_ref() {
+            return (Status::NoSuchDevice, None);
+        }
+    }
 
-    // After the reset, the device instance is no longer usable and we need
-    // to reopen.
-    let file = open_file("Failed to open USB device file after device reset")?;
+    (Status::Failure, None)
+}
 
-    // write to socket for hot-attach fds
-    let command = Command::Attach {
-        bus,
-        device: dev,
-        fd: file,
-    };
-    let mut socket = UnixStream::connect(socket_path).context("Failed to open socket")?;
-    command
-        .send_over_socket(&socket)
-        .context("Failed to send attach command over the socket")?;
+fn print_error(err: &anyhow::Error, response: Option<Response>, output: OutputFormat) {
+    let candidates = err.chain().find_map(|cause| match cause.downcast_ref() {
+        Some(HostDeviceError::Ambiguous(_, devices)) => Some(devices),
+        _ => None,
+    });
 
-    let response = Response::receive_from_socket(&mut socket)
-        .context("Failed to receive response over the socket")?;
-    println!("{response:?}");
+    match output {
+        OutputFormat::Text => {
+            if let Some(devices) = candidates {
+                eprintln!("Matching host devices:");
+                for device in devices {
+                    eprintln!("{}", format_host_device(device));
+                }
+            }
+            eprintln!("Error: {err:?}");
+        }
+        OutputFormat::Json => {
+            let mut object = json!({
+                "error": format!("{err:#}"),
+                "response": response.map(|response| format!("{response:?}")),
+            });
+            if let Some(devices) = candidates {
+                object["candidates"] = devices
+                    .iter()
+                    .map(|device| host_device_json(device, false))
+                    .collect();
+            }
+            println!("{object}");
+        }
+    }
+}
 
-    Ok(())
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable text.
+    Text,
+    /// A single JSON object on stdout.
+    Json,
 }
 
-fn detach(bus: u8, dev: u8, socket_path: &Path) -> Result<()> {
-    println!("Requesting detach of device {bus:03}:{dev:03}");
+impl OutputFormat {
+    /// Print progress information, which is only part of the text output.
+    fn progress(self, message: std::fmt::Arguments<'_>) {
+        if self == Self::Text {
+            println!("{message}");
+        }
+    }
 
-    let command = Command::Detach { bus, device: dev };
-    let mut socket = UnixStream::connect(socket_path).context("Failed to open socket")?;
-    command
-        .send_over_socket(&socket)
-        .context("Failed to send detach command over the socket")?;
+    /// Print an event of the watch mode. With json, every event is a JSON
+    /// object on its own line.
+    fn watch_event(self, event: &str, device: &HostDevice, rule_line: Option<usize>) {
+        match self {
+            Self::Text => match rule_line {
+                Some(line) => println!(
+                    "{event} {} (rule on line {line})",
+                    format_host_device(device)
+                ),
+                None => println!("{event} {}", format_host_device(device)),
+            },
+            Self::Json => println!(
+                "{}",
+                json!({
+                    "event": event,
+                    "device": host_device_json(device, event == "attached"),
+                    "rule_line": rule_line,
+                })
+            ),
+        }
+    }
 
-    let response = Response::receive_from_socket(&mut socket)
-        .context("Failed to receive response over the socket")?;
-    println!("{response:?}");
+    fn watch_error(self, device: &HostDevice, err: &anyhow::Error) {
+        match self {
+            Self::Text => eprintln!("Error: {}: {err:#}", format_host_device(device)),
+            Self::Json => println!(
+                "{}",
+                json!({
+                    "event": "error",
+                    "device": host_device_json(device, false),
+                    "error": format!("{err:#}"),
+                })
+            ),
+        }
+    }
+}
 
-    Ok(())
+/// The result of a successful command.
+#[derive(Debug)]
+enum Report {
+    Attached { bus: u8, device: u8 },
+    Detached(Vec<AttachedDevice>),
+    AttachedList(Vec<AttachedDevice>),
+    HostDevices(Vec<(HostDevice, bool)>),
+    Nothing,
 }
 
-fn list_attached(socket_path: &Path) -> Result<()> {
-    let mut socket = UnixStream::connect(socket_path).context("Failed to open socket")?;
-    Command::List
-        .send_over_socket(&socket)
-        .context("Failed to send list command over socket")?;
+impl Report {
+    fn print(self, output: OutputFormat) {
+        match output {
+            OutputFormat::Text => self.print_text(),
+            OutputFormat::Json => {
+                if let Some(json) = self.to_json() {
+                    println!("{json}");
+                }
+            }
+        }
+    }
+
+    fn print_text(self) {
+        match self {
+            Self::Attached { .. } => println!("{:?}", Response::SuccessfulOperation),
+            Self::Detached(devices) => {
+                for device in devices {
+                    print!(
+                        "Detached device {:03}:{:03} ({}) from port {}",
+                        device.bus, device.device, device.usb_id, device.port
+                    );
+                    match device.host_port_path {
+                        Some(path) => println!(", host port {path}"),
+                        None => println!(),
+                    }
+                }
+            }
+            Self::AttachedList(device_list) => {
+                match device_list.len() {
+                    0 => println!("No attached devices"),
+                    1 => println!("One attached device:"),
+                    count => println!("{count} attached devices:"),
+                }
+                for device in device_list {
+                    println!("{}", format_attached_device(&device));
+                }
+            }
+            Self::HostDevices(devices) => {
+                if devices.is_empty() {
+                    println!("No host devices");
+                }
+                for (device, attached) in devices {
+                    let marker = if attached { " (attached)" } else { "" };
+                    println!("{}{marker}", format_host_device(&device));
+                }
+            }
+            Self::Nothing => {}
+        }
+    }
 
-    let response = Response::receive_from_socket(&mut socket)
-        .context("Failed to receive response over the socket")?;
+    // Nothing has no JSON object, so that the output of, e.g., an
+    // interrupted watch mode only consists of its events.
+    fn to_json(&self) -> Option<Value> {
+        let json = match self {
+            Self::Attached { bus, device } => json!({
+                "attached": { "bus": bus, "device": device },
+            }),
+            Self::Detached(devices) => json!({
+                "detached": devices.iter().map(attached_device_json).collect::<Value>(),
+            }),
+            Self::AttachedList(devices) => json!({
+                "devices": devices.iter().map(attached_device_json).collect::<Value>(),
+            }),
+            Self::HostDevices(devices) => json!({
+                "host_devices": devices
+                    .iter()
+                    .map(|(device, attached)| host_device_json(device, *attached))
+                    .collect::<Value>(),
+            }),
+            Self::Nothing => return None,
+        };
 
-    if response != Response::ListFollowing {
-        return Err(anyhow!(
-            "Expected the response {:?} but got {:?}",
-            Response::ListFollowing,
-            response
-        ));
+        Some(json)
     }
+}
 
-    let device_list = response.receive_devices_list(&mut socket)?;
-    match device_list.len() {
-        0 => println!("No attached devices"),
-        1 => {
-            println!("One attached device:");
-            println!("{:03}:{:03}", device_list[0].0, device_list[0].1);
+fn attached_device_json(device: &AttachedDevice) -> Value {
+    json!({
+        "port": device.port,
+        "bus": device.bus,
+        "device": device.device,
+        "vendor_id": format!("{:04x}", device.usb_id.vendor_id),
+        "product_id": format!("{:04x}", device.usb_id.product_id),
+        "serial": device.usb_id.serial,
+        "host_port": device.host_port_path,
+    })
+}
+
+fn host_device_json(device: &HostDevice, attached: bool) -> Value {
+    json!({
+        "bus": device.bus,
+        "device": device.device,
+        "vendor_id": format!("{:04x}", device.usb_id.vendor_id),
+        "product_id": format!("{:04x}", device.usb_id.product_id),
+        "serial": device.usb_id.serial,
+        "class": format!("{:02x}", device.class),
+        "host_port": device.port_path,
+        "manufacturer": device.manufacturer,
+        "product": device.product,
+        "attached": attached,
+    })
+}
+
+/// What `--attach` accepts: a device file or the IDs of a host device.
+#[derive(Debug, Clone)]
+enum DeviceSpec {
+    Path(PathBuf),
+    UsbId(UsbId),
+}
+
+impl FromStr for DeviceSpec {
+    type Err = UsbIdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains('/') {
+            Ok(Self::Path(PathBuf::from(s)))
+        } else {
+            s.parse().map(Self::UsbId)
         }
-        count => {
-            println!("{count} attached devices:");
-            for (bus, dev) in device_list {
-                println!("{bus:03}:{dev:03}");
-            }
+    }
+}
+
+/// Resolve the selector to the device file of the single matching host
+/// device.
+fn select_host_device(selector: HostDeviceSelector, output: OutputFormat) -> Result<PathBuf> {
+    let device = find_host_device(&selector)?;
+    output.progress(format_args!(
+        "Selected device {:03}:{:03} ({}) for {selector}",
+        device.bus, device.device, device.usb_id
+    ));
+
+    Ok(device.device_path())
+}
+
+/// Interpret the values of `--detach`: either bus and device number or the
+/// IDs of a host device.
+fn detach_target(values: &[String]) -> Result<(u8, u8)> {
+    match values {
+        [bus, dev] => Ok((
+            bus.parse().context("Invalid bus number")?,
+            dev.parse().context("Invalid device number")?,
+        )),
+        [usb_id] => {
+            let usb_id = usb_id.parse::<UsbId>()?;
+            let device = find_host_device(&HostDeviceSelector::UsbId(usb_id))?;
+            Ok((device.bus, device.device))
         }
+        // clap ensures that one or two values are given.
+        _ => unreachable!(),
     }
+}
+
+fn attach(
+    client: &mut BlockingHotplugClient,
+    device_path: &Path,
+    port: Option<u8>,
+    interfaces: Option<Vec<u8>>,
+    output: OutputFormat,
+) -> Result<Report> {
+    let device = DeviceFile::open_and_reset(device_path)
+        .with_context(|| format!("Failed to open USB device {device_path:?}"))?;
+
+    let (bus, dev) = (device.bus, device.device);
+    match port {
+        Some(port) => output.progress(format_args!(
+            "Requesting attachment of device {bus:03}:{dev:03} to port {port}"
+        )),
+        None => output.progress(format_args!(
+            "Requesting attachment of device {bus:03}:{dev:03}"
+        )),
+    }
+
+    client
+        .attach(device, port, interfaces)
+        .context("Failed to attach the device")?;
+
+    Ok(Report::Attached { bus, device: dev })
+}
+
+fn detach(client: &mut BlockingHotplugClient, selector: DetachSelector) -> Result<Report> {
+    let detached = client
+        .detach(selector)
+        .context("Failed to detach the device")?;
+    if detached.is_empty() {
+        return Err(ClientError::Rejected(Response::NoSuchDevice))
+            .context("Failed to detach the device");
+    }
+
+    Ok(Report::Detached(detached))
+}
+
+fn list_attached(client: &mut BlockingHotplugClient) -> Result<Report> {
+    let device_list = client
+        .list_attached()
+        .context("Failed to list the attached devices")?;
 
-    Ok(())
+    Ok(Report::AttachedList(device_list))
+}
+
+fn format_attached_device(device: &AttachedDevice) -> String {
+    let host_port = device
+        .host_port_path
+        .as_ref()
+        .map(|path| format!(", host port {path}"))
+        .unwrap_or_default();
+    format!(
+        "{:03}:{:03} ({}) on port {}{host_port}",
+        device.bus, device.device, device.usb_id, device.port
+    )
+}
+
+fn format_host_device(device: &HostDevice) -> String {
+    let description = [device.manufacturer.as_deref(), device.product.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "{:03}:{:03} {} port {} {description}",
+        device.bus,
+        device.device,
+        device.usb_id,
+        device.port_path.as_deref().unwrap_or("-"),
+    )
+    .trim_end()
+    .to_string()
+}
+
+fn list_host(client: &mut BlockingHotplugClient) -> Result<Report> {
+    let attached = client
+        .list()
+        .context("Failed to list the attached devices")?;
+    let devices = list_host_devices()?
+        .into_iter()
+        .map(|device| {
+            let is_attached = attached.contains(&(device.bus, device.device));
+            (device, is_attached)
+        })
+        .collect();
+
+    Ok(Report::HostDevices(devices))
 }
 
 #[derive(Parser, Debug)]
@@ -141,31 +549,148 @@ fn list_attached(socket_path: &Path) -> Result<()> {
     about = env!("CARGO_PKG_DESCRIPTION"),
     long_about = None
 )]
+#[command(group(
+    ArgGroup::new("action")
+        .args([
+            "attach",
+            "attach_port",
+            "detach",
+            "detach_guest_port",
+            "detach_usb_id",
+            "detach_host_port",
+            "detach_all",
+            "list",
+            "list_host",
+            "watch",
+        ])
+        .multiple(false)
+))]
+#[command(group(ArgGroup::new("attach_target").args(["attach", "attach_port"])))]
 struct Cli {
     /// Path to the hot-attach socket that the usbvfiod instances exposes.
     #[arg(long, value_name = "PATH")]
     socket: PathBuf,
 
-    /// Attach the USB device to usbvfiod. The path must point to a device in: /dev/bus/usb.
-    /// This option is mutually exclusive with --detach and --list.
+    /// Output format. With json, the result or error of every command is
+    /// printed as a single JSON object on stdout.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Attach the USB device to usbvfiod. Either a path pointing to a device
+    /// in /dev/bus/usb, or the vendor and product ID (hexadecimal) and
+    /// optionally the serial number of a host device. The attachment is
+    /// refused if several host devices match.
+    ///
+    /// This option is mutually exclusive with the detach options and --list.
+    #[arg(long, value_name = "PATH|VID:PID[:SERIAL]")]
+    attach: Option<DeviceSpec>,
+
+    /// Attach the host device plugged into this host port, as named in
+    /// /sys/bus/usb/devices, e.g. 1-3.2.
+    #[arg(long, value_name = "PATH")]
+    attach_port: Option<String>,
+
+    /// Attach the device to this guest root-hub port instead of the first
+    /// free port. The attachment fails if the port is occupied or does not
+    /// match the USB version of the device.
+    #[arg(long, value_name = "PORT", requires = "attach_target", value_parser = clap::value_parser!(u8).range(1..))]
+    port: Option<u8>,
+
+    /// Pass only these interfaces of the device (comma-separated interface
+    /// numbers) through to the guest. The host keeps the other interfaces.
     #[arg(
         long,
-        value_name = "PATH",
-        conflicts_with = "detach",
-        conflicts_with = "list"
+        value_name = "N,...",
+        value_delimiter = ',',
+        requires = "attach_target"
     )]
-    attach: Option<PathBuf>,
+    interfaces: Option<Vec<u8>>,
 
     /// Detach the USB device from usbvfiod. Specify the device with the bus number
-    /// and the device number.
+    /// and the device number, or with the vendor and product ID
+    /// (hexadecimal) and optionally the serial number of a host device.
     ///
-    /// This option is mutually exclusive with --attach and --list.
-    #[arg(long, num_args = 2, conflicts_with = "attach", conflicts_with = "list")]
-    detach: Option<Vec<u8>>,
+    /// This option is mutually exclusive with --attach, --list and the other
+    /// detach options.
+    #[arg(long, num_args = 1..=2, value_names = ["BUS DEV|VID:PID[:SERIAL]"])]
+    detach: Option<Vec<String>>,
+
+    /// Detach the device attached to this guest root-hub port.
+    #[arg(long, value_name = "PORT")]
+    detach_guest_port: Option<u8>,
+
+    /// Detach all devices with this vendor and product ID (hexadecimal),
+    /// optionally restricted to a serial number.
+    #[arg(long, value_name = "VID:PID[:SERIAL]")]
+    detach_usb_id: Option<UsbId>,
+
+    /// Detach the device plugged into this host port, as named in
+    /// /sys/bus/usb/devices, e.g. 1-3.2.
+    #[arg(long, visible_alias = "detach-port", value_name = "PATH")]
+    detach_host_port: Option<String>,
+
+    /// Detach all devices.
+    #[arg(long, action = ArgAction::SetTrue)]
+    detach_all: bool,
 
     /// List the currently attached USB devices.
     ///
-    /// This option is mutually exclusive with --attach and --detach.
-    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "attach", conflicts_with = "detach")]
+    /// This option is mutually exclusive with --attach and the detach options.
+    #[arg(long, action = ArgAction::SetTrue)]
     list: bool,
+
+    /// List the USB devices of the host that can be attached, marking the
+    /// ones already attached.
+    #[arg(long, action = ArgAction::SetTrue)]
+    list_host: bool,
+
+    /// Watch the USB devices of the host. Devices matching the rules in
+    /// this file are reset and attached when they appear, and detached when
+    /// they disappear. Runs until interrupted.
+    #[arg(long, value_name = "RULES")]
+    watch: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_map_to_distinct_exit_codes() {
+        let rejected = |response| {
+            anyhow::Error::from(ClientError::Rejected(response)).context("Failed to attach")
+        };
+
+        assert_eq!(
+            classify(&rejected(Response::NoFreePort)),
+            (Status::NoFreePort, Some(Response::NoFreePort))
+        );
+        assert_eq!(
+            classify(&rejected(Response::NoSuchDevice)).0,
+            Status::NoSuchDevice
+        );
+        assert_eq!(
+            classify(&rejected(Response::CouldNotDetermineSpeed)).0,
+            Status::CouldNotDetermineSpeed
+        );
+        assert_eq!(
+            classify(&rejected(Response::PortOccupied)).0,
+            Status::Failure
+        );
+        assert_eq!(
+            classify(&anyhow::Error::from(ClientError::Connect {
+                path: PathBuf::from("/nonexistent"),
+                source: std::io::ErrorKind::NotFound.into(),
+            }))
+            .0,
+            Status::SocketError
+        );
+        assert_eq!(
+            classify(&anyhow::Error::from(HostDeviceError::NotFound(
+                HostDeviceSelector::Port("1-2".to_string())
+            )))
+            .0,
+            Status::NoSuchDevice
+        );
+    }
 }
@@ -0,0 +1,806 @@
+#![deny(
+    clippy::all,
+    clippy::cargo,
+    clippy::nursery,
+    clippy::must_use_candidate
+)]
+// now allow a few rules which are denied by the above's statement
+#![allow(clippy::multiple_crate_versions)]
+#![deny(missing_debug_implementations)]
+#![deny(rustdoc::all)]
+
+//! Policy daemon that opens USB devices and hands them to usbvfiod.
+//!
+//! `usbpolicyd` owns the access to /dev/bus/usb. It watches the USB devices
+//! of the host and attaches devices to the usbvfiod instance of the VM the
+//! policy assigns them to. This way, usbvfiod itself does not need any
+//! access to /dev.
+//!
+//! The policy file is line-based. Empty lines and lines starting with `#`
+//! are ignored. `vm` lines name a VM and the hotplug socket of its usbvfiod
+//! instance, `attach` lines assign devices to a VM with the conditions of
+//! [`usbvfiod::hotplug_protocol::rules`]:
+//!
+//! ```text
+//! vm office /run/usbvfiod/office-hotplug.sock
+//! vm kiosk /run/usbvfiod/kiosk-hotplug.sock
+//!
+//! attach office vid=1050 pid=0407
+//! attach kiosk class=03 port=1-3.2 guest-port=5
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Read,
+    os::unix::{fs::FileTypeExt, net::UnixStream as StdUnixStream},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{ArgAction, Parser};
+use serde_json::{json, Value};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+use tracing::{info, warn, Level};
+use tracing_subscriber::FmtSubscriber;
+use usbvfiod::hotplug_protocol::{
+    client::{ClientError, HotplugClient},
+    command::DetachSelector,
+    host_devices::{HostDevice, HostDeviceEvent, HostDeviceWatcher},
+    rules::Rule,
+};
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+
+    if args.status {
+        let socket = args
+            .status_socket
+            .expect("clap ensures that the status socket is given");
+        let mut status = String::new();
+        StdUnixStream::connect(&socket)
+            .with_context(|| format!("Failed to connect to the status socket {socket:?}"))?
+            .read_to_string(&mut status)
+            .context("Failed to read the status")?;
+        println!("{status}");
+        return Ok(());
+    }
+
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(match args.verbose {
+            0 => Level::INFO,
+            1 => Level::DEBUG,
+            _ => Level::TRACE,
+        })
+        .with_ansi(!args.no_color)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber)
+        .context("Failed to set global tracing subscriber")?;
+
+    let policy = Policy::load(&args.policy.expect("clap ensures that the policy is given"))?;
+    let runtime = tokio::runtime::Runtime::new().context("Failed to start the tokio runtime")?;
+
+    runtime.block_on(run(
+        policy,
+        args.status_socket.as_deref(),
+        Duration::from_secs(args.retry_interval),
+    ))
+}
+
+async fn run(policy: Policy, status_socket: Option<&Path>, retry: Duration) -> Result<()> {
+    let mut daemon = Daemon::new(policy);
+    let status_listener = status_socket.map(bind_status_socket).transpose()?;
+    let mut watcher = HostDeviceWatcher::new()?;
+    let mut retry = tokio::time::interval(retry);
+    let mut terminate = signal(SignalKind::terminate()).context("Failed to handle SIGTERM")?;
+
+    info!("Watching host devices");
+    loop {
+        tokio::select! {
+            event = watcher.next() => match event {
+                Some(HostDeviceEvent::Arrived(device)) => daemon.arrived(device),
+                Some(HostDeviceEvent::Removed(device)) => daemon.removed(&device),
+                None => break Err(anyhow!("Watching host devices stopped")),
+            },
+            Some(report) = daemon.reports.recv() => daemon.handle_report(report),
+            _ = retry.tick() => {
+                daemon.check_attached();
+                daemon.retry_pending();
+            }
+            accepted = accept(status_listener.as_ref()) => match accepted {
+                Ok(stream) => {
+                    tokio::spawn(send_status(stream, daemon.status()));
+                }
+                Err(err) => warn!("Failed to accept status connection: {err}"),
+            },
+            _ = tokio::signal::ctrl_c() => break Ok(()),
+            _ = terminate.recv() => break Ok(()),
+        }
+    }
+    .inspect(|()| {
+        if let Some(path) = status_socket {
+            let _ = fs::remove_file(path);
+        }
+    })
+}
+
+fn bind_status_socket(path: &Path) -> Result<UnixListener> {
+    // Remove the socket of a previous instance, but nothing else.
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale status socket {path:?}"))?;
+    }
+
+    UnixListener::bind(path).with_context(|| format!("Failed to bind status socket {path:?}"))
+}
+
+async fn accept(listener: Option<&UnixListener>) -> std::io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _)| stream),
+        None => std::future::pending().await,
+    }
+}
+
+async fn send_status(mut stream: UnixStream, status: Value) {
+    if let Err(err) = stream.write_all(status.to_string().as_bytes()).await {
+        warn!("Failed to send status: {err}");
+    }
+}
+
+/// A VM and the hotplug socket of its usbvfiod instance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Vm {
+    name: String,
+    socket: PathBuf,
+}
+
+/// Devices matching `rule` belong to the VM `vm`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Assignment {
+    vm: String,
+    rule: Rule,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Policy {
+    vms: Vec<Vm>,
+    assignments: Vec<Assignment>,
+}
+
+impl Policy {
+    fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read policy {}", path.display()))?;
+
+        Self::parse(&content).with_context(|| format!("Failed to parse policy {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let mut policy = Self::default();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words.as_slice() {
+                ["vm", name, socket] => {
+                    if policy.vm(name).is_some() {
+                        return Err(anyhow!("line {}: duplicate VM {name}", index + 1));
+                    }
+                    policy.vms.push(Vm {
+                        name: (*name).to_string(),
+                        socket: PathBuf::from(socket),
+                    });
+                }
+                ["attach", vm, conditions @ ..] => {
+                    if policy.vm(vm).is_none() {
+                        return Err(anyhow!("line {}: unknown VM {vm}", index + 1));
+                    }
+                    policy.assignments.push(Assignment {
+                        vm: (*vm).to_string(),
+                        rule: Rule::parse(index + 1, conditions.iter().copied())?,
+                    });
+                }
+                _ => return Err(anyhow!("line {}: unknown directive: {line}", index + 1)),
+            }
+        }
+
+        Ok(policy)
+    }
+
+    fn vm(&self, name: &str) -> Option<&Vm> {
+        self.vms.iter().find(|vm| vm.name == name)
+    }
+
+    /// Find the first assignment matching the device.
+    fn assignment(&self, device: &HostDevice) -> Option<&Assignment> {
+        self.assignments
+            .iter()
+            .find(|assignment| assignment.rule.matches(device))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DeviceState {
+    Attached,
+    /// The VM could not be reached. Attaching is retried periodically.
+    Pending(String),
+    /// The device could not be opened or usbvfiod refused it.
+    Failed(String),
+}
+
+/// A host device assigned to a VM.
+#[derive(Debug)]
+struct ManagedDevice {
+    device: HostDevice,
+    assignment: Assignment,
+    state: DeviceState,
+    /// Whether an attach request is queued for the VM.
+    attaching: bool,
+}
+
+/// A request to the worker of a VM.
+#[derive(Debug)]
+enum VmRequest {
+    Attach {
+        device: HostDevice,
+        port: Option<u8>,
+        interfaces: Option<Vec<u8>>,
+    },
+    Detach(HostDevice),
+    List,
+}
+
+/// The outcome of a request, reported back to the daemon by the worker of
+/// the VM.
+#[derive(Debug)]
+enum VmReport {
+    Attach {
+        vm: String,
+        device: (u8, u8),
+        result: Result<(), ClientError>,
+    },
+    List {
+        vm: String,
+        result: Result<Vec<(u8, u8)>, ClientError>,
+    },
+}
+
+/// Talks to the usbvfiod instance of a VM. Every VM has its own worker, so
+/// that a VM that does not respond only holds up its own requests.
+#[derive(Debug)]
+struct VmWorker {
+    vm: Vm,
+    client: Option<HotplugClient>,
+    requests: mpsc::UnboundedReceiver<VmRequest>,
+    reports: mpsc::UnboundedSender<VmReport>,
+}
+
+impl VmWorker {
+    fn spawn(vm: Vm, reports: mpsc::UnboundedSender<VmReport>) -> mpsc::UnboundedSender<VmRequest> {
+        let (sender, requests) = mpsc::unbounded_channel();
+        tokio::spawn(
+            Self {
+                vm,
+                client: None,
+                requests,
+                reports,
+            }
+            .run(),
+        );
+
+        sender
+    }
+
+    async fn run(mut self) {
+        while let Some(request) = self.requests.recv().await {
+            let vm = self.vm.name.clone();
+            let report = match request {
+                VmRequest::Attach {
+                    device,
+                    port,
+                    interfaces,
+                } => {
+                    let result = match self.client().await {
+                        Ok(client) => client
+                            .attach_path(device.device_path(), port, interfaces)
+                            .await
+                            .map(|_| ()),
+                        Err(err) => Err(err),
+                    };
+                    VmReport::Attach {
+                        vm,
+                        device: (device.bus, device.device),
+                        result,
+                    }
+                }
+                VmRequest::Detach(device) => {
+                    self.detach(&device).await;
+                    continue;
+                }
+                VmRequest::List => {
+                    let result = match self.client().await {
+                        Ok(client) => client.list().await,
+                        Err(err) => Err(err),
+                    };
+                    VmReport::List { vm, result }
+                }
+            };
+            if self.reports.send(report).is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn detach(&mut self, device: &HostDevice) {
+        let selector = DetachSelector::Device {
+            bus: device.bus,
+            device: device.device,
+        };
+        let result = match self.client().await {
+            Ok(client) => client.detach(selector).await,
+            Err(err) => Err(err),
+        };
+        let vm = &self.vm.name;
+        match result {
+            Ok(_) => info!("Detached {} from VM {vm}", describe(device)),
+            Err(err) => warn!(
+                "Failed to detach {} from VM {vm}: {:#}",
+                describe(device),
+                anyhow::Error::from(err)
+            ),
+        }
+    }
+
+    /// Get the client for the VM, connecting on first use. The usbvfiod
+    /// instance of the VM may not be running yet.
+    async fn client(&mut self) -> Result<HotplugClient, ClientError> {
+        if let Some(client) = &self.client {
+            return Ok(client.clone());
+        }
+
+        let client = HotplugClient::connect(&self.vm.socket).await?;
+        self.client = Some(client.clone());
+
+        Ok(client)
+    }
+}
+
+/// Keeps track of the assigned devices. Talking to the VMs is left to
+/// their workers, which report back through `reports`.
+#[derive(Debug)]
+struct Daemon {
+    policy: Policy,
+    vms: HashMap<String, mpsc::UnboundedSender<VmRequest>>,
+    reports: mpsc::UnboundedReceiver<VmReport>,
+    devices: Vec<ManagedDevice>,
+    /// VMs with a queued request for their attached devices.
+    listing: HashSet<String>,
+}
+
+impl Daemon {
+    fn new(policy: Policy) -> Self {
+        let (report_sender, reports) = mpsc::unbounded_channel();
+        let vms = policy
+            .vms
+            .iter()
+            .map(|vm| {
+                (
+                    vm.name.clone(),
+                    VmWorker::spawn(vm.clone(), report_sender.clone()),
+                )
+            })
+            .collect();
+
+        Self {
+            policy,
+            vms,
+            reports,
+            devices: vec![],
+            listing: HashSet::new(),
+        }
+    }
+
+    fn arrived(&mut self, device: HostDevice) {
+        let Some(assignment) = self.policy.assignment(&device).cloned() else {
+            return;
+        };
+
+        self.devices.push(ManagedDevice {
+            device,
+            assignment,
+            state: DeviceState::Pending(String::new()),
+            attaching: false,
+        });
+        self.attach(self.devices.len() - 1);
+    }
+
+    fn removed(&mut self, device: &HostDevice) {
+        let Some(index) = self.devices.iter().position(|managed| {
+            (managed.device.bus, managed.device.device) == (device.bus, device.device)
+        }) else {
+            return;
+        };
+        let managed = self.devices.swap_remove(index);
+        // A queued attach may still succeed, the detach is queued after it.
+        if managed.state == DeviceState::Attached || managed.attaching {
+            self.request(&managed.assignment.vm, VmRequest::Detach(device.clone()));
+        }
+    }
+
+    /// Attach devices again that their VM lost, e.g., because its usbvfiod
+    /// instance restarted and the client reconnected to the new one.
+    fn check_attached(&mut self) {
+        let vms = self
+            .devices
+            .iter()
+            .filter(|managed| managed.state == DeviceState::Attached)
+            .map(|managed| managed.assignment.vm.clone())
+            .collect::<HashSet<_>>();
+
+        for vm in vms {
+            if self.listing.insert(vm.clone()) {
+                self.request(&vm, VmRequest::List);
+            }
+        }
+    }
+
+    fn retry_pending(&mut self) {
+        for index in 0..self.devices.len() {
+            let managed = &self.devices[index];
+            if matches!(managed.state, DeviceState::Pending(_)) && !managed.attaching {
+                self.attach(index);
+            }
+        }
+    }
+
+    fn attach(&mut self, index: usize) {
+        let managed = &mut self.devices[index];
+        managed.attaching = true;
+        let request = VmRequest::Attach {
+            device: managed.device.clone(),
+            port: managed.assignment.rule.guest_port,
+            interfaces: managed.assignment.rule.interfaces.clone(),
+        };
+        let vm = managed.assignment.vm.clone();
+        self.request(&vm, request);
+    }
+
+    fn request(&self, vm: &str, request: VmRequest) {
+        let worker = self
+            .vms
+            .get(vm)
+            .expect("assignments only reference VMs of the policy");
+        if worker.send(request).is_err() {
+            warn!("The worker of VM {vm} stopped");
+        }
+    }
+
+    fn handle_report(&mut self, report: VmReport) {
+        match report {
+            VmReport::Attach { vm, device, result } => self.attached(&vm, device, result),
+            VmReport::List { vm, result } => self.listed(&vm, result),
+        }
+    }
+
+    fn attached(&mut self, vm: &str, device: (u8, u8), result: Result<(), ClientError>) {
+        // The device may have been removed in the meantime.
+        let Some(managed) = self.devices.iter_mut().find(|managed| {
+            managed.assignment.vm == vm && (managed.device.bus, managed.device.device) == device
+        }) else {
+            return;
+        };
+        managed.attaching = false;
+        let description = describe(&managed.device);
+
+        let state = match result {
+            Ok(()) => {
+                info!("Attached {description} to VM {vm}");
+                DeviceState::Attached
+            }
+            Err(err) => {
+                let unreachable = matches!(
+                    err,
+                    ClientError::Connect { .. } | ClientError::Send(_) | ClientError::Receive(_)
+                );
+                let message = format!("{:#}", anyhow::Error::from(err));
+                let state = if unreachable {
+                    DeviceState::Pending(message.clone())
+                } else {
+                    DeviceState::Failed(message.clone())
+                };
+                // Only log changes, as pending devices are retried periodically.
+                if managed.state != state {
+                    warn!("Failed to attach {description} to VM {vm}: {message}");
+                }
+                state
+            }
+        };
+        managed.state = state;
+    }
+
+    fn listed(&mut self, vm: &str, result: Result<Vec<(u8, u8)>, ClientError>) {
+        self.listing.remove(vm);
+        let attached = result.map_err(|err| format!("{:#}", anyhow::Error::from(err)));
+
+        for managed in self
+            .devices
+            .iter_mut()
+            .filter(|managed| managed.assignment.vm == vm && managed.state == DeviceState::Attached)
+        {
+            let identifier = (managed.device.bus, managed.device.device);
+            let message = match &attached {
+                Ok(attached) if attached.contains(&identifier) => continue,
+                Ok(_) => "the VM no longer has the device".to_string(),
+                Err(message) => message.clone(),
+            };
+            info!(
+                "Attaching {} to VM {vm} again: {message}",
+                describe(&managed.device)
+            );
+            managed.state = DeviceState::Pending(message);
+        }
+    }
+
+    fn status(&self) -> Value {
+        let vms = self
+            .policy
+            .vms
+            .iter()
+            .map(|vm| {
+                let devices = self
+                    .devices
+                    .iter()
+                    .filter(|managed| managed.assignment.vm == vm.name)
+                    .map(device_status)
+                    .collect::<Value>();
+                json!({
+                    "name": vm.name,
+                    "socket": vm.socket,
+                    "devices": devices,
+                })
+            })
+            .collect::<Value>();
+
+        json!({ "vms": vms })
+    }
+}
+
+fn device_status(managed: &ManagedDevice) -> Value {
+    let device = &managed.device;
+    let mut status = json!({
+        "bus": device.bus,
+        "device": device.device,
+        "vendor_id": format!("{:04x}", device.usb_id.vendor_id),
+        "product_id": format!("{:04x}", device.usb_id.product_id),
+        "serial": device.usb_id.serial,
+        "host_port": device.port_path,
+        "rule_line": managed.assignment.rule.line,
+    });
+    match &managed.state {
+        DeviceState::Attached => status["state"] = "attached".into(),
+        DeviceState::Pending(err) => {
+            status["state"] = "pending".into();
+            status["error"] = err.as_str().into();
+        }
+        DeviceState::Failed(err) => {
+            status["state"] = "failed".into();
+            status["error"] = err.as_str().into();
+        }
+    }
+
+    status
+}
+
+fn describe(device: &HostDevice) -> String {
+    format!(
+        "device {:03}:{:03} ({})",
+        device.bus, device.device, device.usb_id
+    )
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    version = env!("CARGO_PKG_VERSION"),
+    author = env!("CARGO_PKG_AUTHORS"),
+    about = "Policy daemon that assigns USB devices to usbvfiod instances.",
+    long_about = None
+)]
+struct Cli {
+    /// The policy file that assigns devices to VMs.
+    #[arg(long, value_name = "PATH", required_unless_present = "status")]
+    policy: Option<PathBuf>,
+
+    /// Serve the status of all assigned devices as JSON on this socket.
+    #[arg(long, value_name = "PATH")]
+    status_socket: Option<PathBuf>,
+
+    /// Print the status of a running instance, which is queried through
+    /// --status-socket.
+    #[arg(long, action = ArgAction::SetTrue, requires = "status_socket", conflicts_with = "policy")]
+    status: bool,
+
+    /// Interval in seconds to retry attaching devices whose VM could not be
+    /// reached.
+    #[arg(long, value_name = "SECONDS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    retry_interval: u64,
+
+    /// Enable verbose logging. Can be specified multiple times to
+    /// increase verbosity.
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Disable colored output.
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_color: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener as StdUnixListener, thread};
+
+    use usbvfiod::hotplug_protocol::{command::Command, response::Response};
+
+    use super::*;
+
+    fn host_device(usb_id: &str, interface_classes: Vec<u8>) -> HostDevice {
+        HostDevice {
+            bus: 1,
+            device: 2,
+            usb_id: usb_id.parse().unwrap(),
+            class: 0,
+            interface_classes,
+            port_path: Some("1-2".to_string()),
+            manufacturer: None,
+            product: None,
+        }
+    }
+
+    #[test]
+    fn parse_policy() {
+        let policy = Policy::parse(
+            "
+            # VMs
+            vm office /run/office.sock
+            vm kiosk /run/kiosk.sock
+
+            attach office vid=1050 pid=0407
+            attach kiosk class=03 guest-port=5
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(policy.vms.len(), 2);
+        assert_eq!(
+            policy.vm("kiosk").unwrap().socket,
+            PathBuf::from("/run/kiosk.sock")
+        );
+
+        let device = host_device;
+        assert_eq!(
+            policy.assignment(&device("1050:0407", vec![3])).unwrap().vm,
+            "office"
+        );
+        let keyboard = policy.assignment(&device("046d:c52b", vec![3])).unwrap();
+        assert_eq!(keyboard.vm, "kiosk");
+        assert_eq!(keyboard.rule.guest_port, Some(5));
+        assert_eq!(policy.assignment(&device("0781:5581", vec![8])), None);
+
+        Policy::parse("attach office vid=1050").unwrap_err();
+        Policy::parse("vm office /a.sock\nvm office /b.sock").unwrap_err();
+        Policy::parse("vm office /a.sock\nattach office").unwrap_err();
+        Policy::parse("vm office").unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn devices_lost_by_the_vm_are_attached_again() {
+        let socket = std::env::temp_dir().join(format!(
+            "usbpolicyd-test-{}-restarted.sock",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&socket);
+        // a freshly started usbvfiod without any devices
+        let listener = StdUnixListener::bind(&socket).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Ok(command) = Command::receive_from_socket(&stream) {
+                assert!(matches!(command, Command::List));
+                Response::ListFollowing
+                    .send_device_list(vec![], &mut stream)
+                    .unwrap();
+            }
+        });
+
+        let policy = Policy::parse(&format!(
+            "vm office {}\nattach office vid=1050",
+            socket.display()
+        ))
+        .unwrap();
+        let device = host_device("1050:0407", vec![3]);
+        let mut daemon = Daemon::new(policy.clone());
+        daemon.devices.push(ManagedDevice {
+            assignment: policy.assignment(&device).unwrap().clone(),
+            device,
+            state: DeviceState::Attached,
+            attaching: false,
+        });
+
+        daemon.check_attached();
+        let report = daemon.reports.recv().await.unwrap();
+        daemon.handle_report(report);
+        assert!(matches!(daemon.devices[0].state, DeviceState::Pending(_)));
+
+        fs::remove_file(&socket).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unresponsive_vms_do_not_hold_up_others() {
+        let socket = |name: &str| {
+            let path = std::env::temp_dir().join(format!(
+                "usbpolicyd-test-{}-{name}.sock",
+                std::process::id()
+            ));
+            let _ = fs::remove_file(&path);
+            path
+        };
+        let (hung_socket, office_socket) = (socket("hung"), socket("office"));
+        // accepts the connection, but never answers
+        let hung_listener = StdUnixListener::bind(&hung_socket).unwrap();
+        let office_listener = StdUnixListener::bind(&office_socket).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = office_listener.accept().unwrap();
+            while let Ok(command) = Command::receive_from_socket(&stream) {
+                assert!(matches!(command, Command::List));
+                Response::ListFollowing
+                    .send_device_list(vec![], &mut stream)
+                    .unwrap();
+            }
+        });
+
+        let policy = Policy::parse(&format!(
+            "vm hung {}\nvm office {}\nattach hung vid=046d\nattach office vid=1050",
+            hung_socket.display(),
+            office_socket.display()
+        ))
+        .unwrap();
+        let mut daemon = Daemon::new(policy.clone());
+        for (usb_id, device) in [("046d:c52b", 2), ("1050:0407", 3)] {
+            let device = HostDevice {
+                device,
+                ..host_device(usb_id, vec![3])
+            };
+            daemon.devices.push(ManagedDevice {
+                assignment: policy.assignment(&device).unwrap().clone(),
+                device,
+                state: DeviceState::Attached,
+                attaching: false,
+            });
+        }
+
+        daemon.check_attached();
+        let report = tokio::time::timeout(Duration::from_secs(1), daemon.reports.recv())
+            .await
+            .expect("the office VM should answer before the hung VM times out")
+            .unwrap();
+        daemon.handle_report(report);
+        assert_eq!(daemon.devices[0].state, DeviceState::Attached);
+        assert!(matches!(daemon.devices[1].state, DeviceState::Pending(_)));
+
+        // the hung VM is not asked again while its answer is outstanding
+        daemon.check_attached();
+        assert_eq!(daemon.listing, HashSet::from(["hung".to_string()]));
+
+        drop(hung_listener);
+        fs::remove_file(&hung_socket).unwrap();
+        fs::remove_file(&office_socket).unwrap();
+    }
+}
@@ -6,10 +6,13 @@
 use std::{
     os::fd::RawFd,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use clap::Parser;
 
+use crate::hotplug_server::HotplugServerConfig;
+
 #[derive(Parser, Debug)]
 #[command(
     name = env!("CARGO_PKG_NAME"),
@@ -41,15 +44,54 @@ pub struct Cli {
     /// specified multiple times to attach more devices. The path must
     /// point to a device in: /dev/bus/usb
     ///
+    /// Append @PORT to attach the device to a specific guest root-hub
+    /// port, e.g. /dev/bus/usb/001/004@5.
+    ///
     /// See the documentation for how to identify devices.
-    #[arg(long = "device", value_name = "PATH")]
-    pub devices: Vec<PathBuf>,
+    #[arg(long = "device", value_name = "PATH[@PORT]", value_parser = parse_device_arg)]
+    pub devices: Vec<DeviceArg>,
+
+    /// Path to a configuration file, e.g. containing port pinning rules.
+    ///
+    /// See the documentation for the file format.
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Attach devices again when they are unplugged from the host and
+    /// plugged in again, or when another device is plugged into a pinned
+    /// host port. Devices detached via the hotplug socket stay detached.
+    ///
+    /// usbvfiod needs access to /dev/bus/usb to open the devices.
+    #[arg(long)]
+    pub reattach: bool,
 
     /// The path where to create a listening Unix domain socket and listen
     /// for hotplug commands.
     #[arg(long, value_name = "PATH")]
     pub hotplug_socket_path: Option<PathBuf>,
 
+    /// File mode of the hotplug socket as octal number, e.g. 660.
+    #[arg(long, value_name = "MODE", value_parser = parse_octal_mode, requires = "hotplug_socket_path")]
+    pub hotplug_socket_mode: Option<u32>,
+
+    /// Numeric user ID that should own the hotplug socket.
+    #[arg(long, value_name = "UID", requires = "hotplug_socket_path")]
+    pub hotplug_socket_uid: Option<u32>,
+
+    /// Numeric group ID that should own the hotplug socket.
+    #[arg(long, value_name = "GID", requires = "hotplug_socket_path")]
+    pub hotplug_socket_gid: Option<u32>,
+
+    /// Maximum number of hotplug connections that are handled
+    /// concurrently. Further connections are closed immediately.
+    #[arg(long, value_name = "COUNT", default_value_t = 16, value_parser = clap::value_parser!(u16).range(1..))]
+    pub hotplug_max_connections: u16,
+
+    /// Timeout in seconds for receiving a hotplug command and sending
+    /// the response.
+    #[arg(long, value_name = "SECONDS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub hotplug_timeout: u64,
+
     /// Enable PCAP logging and write captured USB traffic to this file.
     /// The file will be created when the first packet is logged.
     #[arg(long, value_name = "PATH")]
@@ -71,7 +113,51 @@ pub enum ServerSocket<'a> {
     Path(&'a Path),
 }
 
+/// A device to attach at startup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceArg {
+    pub path: PathBuf,
+    /// The requested guest root-hub port.
+    pub port: Option<u8>,
+}
+
+// Device paths may contain '@' themselves, so only a numeric suffix is a
+// port.
+fn parse_device_arg(arg: &str) -> Result<DeviceArg, String> {
+    match arg
+        .rsplit_once('@')
+        .and_then(|(path, port)| Some((path, port.parse::<u8>().ok()?)))
+    {
+        Some((_, 0)) => Err("0 is not a valid root-hub port number".to_string()),
+        Some((path, port)) => Ok(DeviceArg {
+            path: path.into(),
+            port: Some(port),
+        }),
+        None => Ok(DeviceArg {
+            path: arg.into(),
+            port: None,
+        }),
+    }
+}
+
+fn parse_octal_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("{mode} is not a valid octal file mode"))
+}
+
 impl Cli {
+    pub fn hotplug_server_config(&self) -> HotplugServerConfig {
+        HotplugServerConfig {
+            mode: self.hotplug_socket_mode,
+            uid: self.hotplug_socket_uid,
+            gid: self.hotplug_socket_gid,
+            max_connections: self.hotplug_max_connections.into(),
+            io_timeout: Duration::from_secs(self.hotplug_timeout),
+        }
+    }
+
     pub fn server_socket(&self) -> ServerSocket<'_> {
         self.socket_path.as_ref().map_or_else(
             || unreachable!(),
@@ -79,3 +165,34 @@ impl Cli {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_args_only_split_off_port_numbers() {
+        let device = |path: &str, port| DeviceArg {
+            path: path.into(),
+            port,
+        };
+
+        assert_eq!(
+            parse_device_arg("/dev/bus/usb/001/004@5"),
+            Ok(device("/dev/bus/usb/001/004", Some(5)))
+        );
+        assert_eq!(
+            parse_device_arg("/dev/bus/usb/001/004"),
+            Ok(device("/dev/bus/usb/001/004", None))
+        );
+        assert_eq!(
+            parse_device_arg("/run/usb@vm1/device"),
+            Ok(device("/run/usb@vm1/device", None))
+        );
+        assert_eq!(
+            parse_device_arg("/run/usb@vm1/device@3"),
+            Ok(device("/run/usb@vm1/device", Some(3)))
+        );
+        parse_device_arg("/dev/bus/usb/001/004@0").unwrap_err();
+    }
+}
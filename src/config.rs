@@ -0,0 +1,379 @@
+//! Parse the usbvfiod configuration file.
+//!
+//! The configuration file is line-based. Empty lines and lines starting
+//! with `#` are ignored. Every other line is a directive:
+//!
+//! ```text
+//! # Always attach this keyboard to guest root-hub port 5.
+//! pin 046d:c52b 5
+//! # Pin only the storage device with this serial number.
+//! pin 0781:5581:4C530001 2
+//! # Attach whatever is plugged into host port 1-3.2 to guest port 3.
+//! pin port=1-3.2 3
+//! # Keep this modem attached while it re-enumerates, for up to 10 seconds.
+//! follow 12d1:1506 10
+//! # Leave this HID device without host driver after detaching it.
+//! reattach-drivers 1050:0407 no
+//! ```
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use usbvfiod::hotplug_protocol::usb_id::UsbId;
+
+use crate::device::xhci::real_device::HostDeviceInfo;
+
+/// The contents of the configuration file.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Config {
+    pub port_pins: Vec<PortPin>,
+    pub host_port_pins: Vec<HostPortPin>,
+    pub follow_rules: Vec<FollowRule>,
+    pub driver_rules: Vec<DriverRule>,
+}
+
+/// Attach devices matching `device` to the guest root-hub port `port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortPin {
+    pub device: UsbId,
+    pub port: u8,
+}
+
+/// Attach any device plugged into the host port `host_port` (e.g. `1-3.2`)
+/// to the guest root-hub port `port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPortPin {
+    pub host_port: String,
+    pub port: u8,
+}
+
+/// Devices a directive applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DevicePattern {
+    /// Devices with the vendor and product ID (and serial number, if given).
+    UsbId(UsbId),
+    /// Devices plugged into the host port, e.g. `1-3.2`.
+    HostPort(String),
+}
+
+impl DevicePattern {
+    fn parse(device: &str) -> Result<Self> {
+        if device.starts_with("port=") {
+            parse_host_port(device).map(Self::HostPort)
+        } else {
+            Ok(Self::UsbId(device.parse()?))
+        }
+    }
+
+    pub fn matches(&self, host_info: &HostDeviceInfo) -> bool {
+        match self {
+            Self::UsbId(usb_id) => usb_id.matches(
+                host_info.usb_id.vendor_id,
+                host_info.usb_id.product_id,
+                host_info.usb_id.serial.as_deref(),
+            ),
+            Self::HostPort(path) => host_info.port_path.as_ref() == Some(path),
+        }
+    }
+}
+
+/// Follow devices matching `device` when they re-enumerate: a device that
+/// appears on the same host port within `timeout` takes their place on the
+/// guest port, whatever its IDs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FollowRule {
+    pub device: DevicePattern,
+    pub timeout: Duration,
+}
+
+/// Whether to bind the host kernel drivers to the interfaces of devices
+/// matching `device` again when they are detached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriverRule {
+    pub device: DevicePattern,
+    pub reattach: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration {}", path.display()))?;
+
+        Self::parse(&content)
+            .with_context(|| format!("Failed to parse configuration {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let mut config = Self::default();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words.as_slice() {
+                ["pin", device, port] if device.starts_with("port=") => {
+                    config.host_port_pins.push(HostPortPin {
+                        host_port: parse_host_port(device)
+                            .with_context(|| format!("line {}", index + 1))?,
+                        port: parse_port(port).with_context(|| format!("line {}", index + 1))?,
+                    });
+                }
+                ["pin", device, port] => config.port_pins.push(PortPin {
+                    device: device
+                        .parse()
+                        .with_context(|| format!("line {}", index + 1))?,
+                    port: parse_port(port).with_context(|| format!("line {}", index + 1))?,
+                }),
+                ["follow", device, timeout] => config.follow_rules.push(FollowRule {
+                    device: DevicePattern::parse(device)
+                        .with_context(|| format!("line {}", index + 1))?,
+                    timeout: parse_timeout(timeout)
+                        .with_context(|| format!("line {}", index + 1))?,
+                }),
+                ["reattach-drivers", device, reattach] => config.driver_rules.push(DriverRule {
+                    device: DevicePattern::parse(device)
+                        .with_context(|| format!("line {}", index + 1))?,
+                    reattach: parse_yes_no(reattach)
+                        .with_context(|| format!("line {}", index + 1))?,
+                }),
+                _ => return Err(anyhow!("line {}: unknown directive: {line}", index + 1)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Look up the port the device with the given IDs is pinned to.
+    ///
+    /// Pins that name a serial number take precedence over pins that
+    /// match any device with the same vendor and product ID.
+    pub fn pinned_port(&self, id: &UsbId) -> Option<u8> {
+        let matching = |pin: &&PortPin| {
+            pin.device
+                .matches(id.vendor_id, id.product_id, id.serial.as_deref())
+        };
+
+        self.port_pins
+            .iter()
+            .filter(matching)
+            .find(|pin| pin.device.serial.is_some())
+            .or_else(|| self.port_pins.iter().find(matching))
+            .map(|pin| pin.port)
+    }
+
+    /// Look up the port the device is pinned to, either by its host port or
+    /// by its IDs. Host port pins take precedence.
+    pub fn pinned_port_of(&self, host_info: &HostDeviceInfo) -> Option<u8> {
+        host_info
+            .port_path
+            .as_deref()
+            .and_then(|path| self.host_port_pin(path))
+            .or_else(|| self.pinned_port(&host_info.usb_id))
+    }
+
+    /// Look up how long to wait for the device to come back after it
+    /// vanished, if it should be followed at all.
+    pub fn follow_timeout(&self, host_info: &HostDeviceInfo) -> Option<Duration> {
+        self.follow_rules
+            .iter()
+            .find(|rule| rule.device.matches(host_info))
+            .map(|rule| rule.timeout)
+    }
+
+    /// Look up whether the host kernel drivers of the device should be
+    /// reattached on detach. `None` leaves the choice to the default.
+    pub fn reattach_drivers(&self, host_info: &HostDeviceInfo) -> Option<bool> {
+        self.driver_rules
+            .iter()
+            .find(|rule| rule.device.matches(host_info))
+            .map(|rule| rule.reattach)
+    }
+
+    /// Look up the port devices on the host port `path` are pinned to.
+    pub fn host_port_pin(&self, path: &str) -> Option<u8> {
+        self.host_port_pins
+            .iter()
+            .find(|pin| pin.host_port == path)
+            .map(|pin| pin.port)
+    }
+}
+
+fn parse_yes_no(value: &str) -> Result<bool> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(anyhow!("expected yes or no, got {value}")),
+    }
+}
+
+fn parse_timeout(timeout: &str) -> Result<Duration> {
+    timeout
+        .parse::<u64>()
+        .ok()
+        .filter(|seconds| *seconds != 0)
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow!("{timeout} is not a valid timeout in seconds"))
+}
+
+fn parse_host_port(device: &str) -> Result<String> {
+    device
+        .strip_prefix("port=")
+        .filter(|path| !path.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("{device} does not name a host port"))
+}
+
+fn parse_port(port: &str) -> Result<u8> {
+    port.parse::<u8>()
+        .ok()
+        .filter(|port| *port != 0)
+        .ok_or_else(|| anyhow!("{port} is not a valid root-hub port number"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config_with_port_pins() {
+        let config = Config::parse(
+            "
+            # comment
+            pin 046d:c52b 5
+
+            pin 0781:5581:4C530001 2
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.port_pins,
+            vec![
+                PortPin {
+                    device: "046d:c52b".parse().unwrap(),
+                    port: 5
+                },
+                PortPin {
+                    device: "0781:5581:4C530001".parse().unwrap(),
+                    port: 2
+                },
+            ]
+        );
+
+        Config::parse("pin 046d:c52b 0").unwrap_err();
+        Config::parse("pin 046d 1").unwrap_err();
+        Config::parse("pin 046d:c52b").unwrap_err();
+        Config::parse("unknown 046d:c52b 1").unwrap_err();
+    }
+
+    #[test]
+    fn host_port_pins_take_precedence() {
+        let config = Config::parse(
+            "
+            pin 046d:c52b 5
+            pin port=1-3.2 3
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.host_port_pins,
+            vec![HostPortPin {
+                host_port: "1-3.2".to_string(),
+                port: 3
+            }]
+        );
+        let host_info = |port_path: Option<&str>| HostDeviceInfo {
+            usb_id: "046d:c52b".parse().unwrap(),
+            port_path: port_path.map(str::to_string),
+        };
+        assert_eq!(config.pinned_port_of(&host_info(Some("1-3.2"))), Some(3));
+        assert_eq!(config.pinned_port_of(&host_info(Some("1-4"))), Some(5));
+        assert_eq!(config.pinned_port_of(&host_info(None)), Some(5));
+
+        Config::parse("pin port= 3").unwrap_err();
+        Config::parse("pin port=1-3.2 0").unwrap_err();
+    }
+
+    #[test]
+    fn follow_rules_match_by_id_or_host_port() {
+        let config = Config::parse(
+            "
+            follow 12d1:1506 10
+            follow port=1-3.2 2
+            ",
+        )
+        .unwrap();
+
+        let host_info = |usb_id: &str, port_path: &str| HostDeviceInfo {
+            usb_id: usb_id.parse().unwrap(),
+            port_path: Some(port_path.to_string()),
+        };
+        assert_eq!(
+            config.follow_timeout(&host_info("12d1:1506", "1-3.2")),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            config.follow_timeout(&host_info("0483:df11", "1-3.2")),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(config.follow_timeout(&host_info("0483:df11", "1-4")), None);
+
+        Config::parse("follow 12d1:1506 0").unwrap_err();
+        Config::parse("follow 12d1:1506").unwrap_err();
+        Config::parse("follow port= 10").unwrap_err();
+    }
+
+    #[test]
+    fn driver_rules_override_the_default() {
+        let config = Config::parse(
+            "
+            reattach-drivers 1050:0407 no
+            reattach-drivers port=1-2 yes
+            ",
+        )
+        .unwrap();
+
+        let host_info = |usb_id: &str, port_path: &str| HostDeviceInfo {
+            usb_id: usb_id.parse().unwrap(),
+            port_path: Some(port_path.to_string()),
+        };
+        assert_eq!(
+            config.reattach_drivers(&host_info("1050:0407", "1-2")),
+            Some(false)
+        );
+        assert_eq!(
+            config.reattach_drivers(&host_info("0781:5581", "1-2")),
+            Some(true)
+        );
+        assert_eq!(
+            config.reattach_drivers(&host_info("0781:5581", "1-3")),
+            None
+        );
+
+        Config::parse("reattach-drivers 1050:0407 maybe").unwrap_err();
+    }
+
+    #[test]
+    fn pins_with_serial_take_precedence() {
+        let config = Config::parse(
+            "
+            pin 0781:5581 3
+            pin 0781:5581:AAAA 2
+            ",
+        )
+        .unwrap();
+
+        let with_serial = |serial: Option<&str>| UsbId {
+            vendor_id: 0x0781,
+            product_id: 0x5581,
+            serial: serial.map(str::to_string),
+        };
+        assert_eq!(config.pinned_port(&with_serial(Some("AAAA"))), Some(2));
+        assert_eq!(config.pinned_port(&with_serial(Some("BBBB"))), Some(3));
+        assert_eq!(config.pinned_port(&with_serial(None)), Some(3));
+        assert_eq!(config.pinned_port(&"046d:c52b".parse().unwrap()), None);
+    }
+}
@@ -732,10 +732,8 @@ pub mod testutils {
         }
 
         fn write(&self, req: Request, value: u64) {
-            if req.size != RequestSize::Size8 {
-                panic!("Only supporting 8-byte writes");
-            }
-            self.write_bulk(req.addr, &value.to_le_bytes());
+            let size = u8::from(req.size) as usize;
+            self.write_bulk(req.addr, &value.to_le_bytes()[..size]);
         }
 
         fn read_bulk(&self, offset: u64, data: &mut [u8]) {
@@ -1,12 +1,17 @@
 use super::packet::UsbTransferType;
-use crate::device::xhci::real_endpoint_handle::{
-    ControlRequestProcessingResult, InTrbProcessingStatus, OutTrbProcessingResult,
+use crate::device::xhci::{
+    real_endpoint_handle::{
+        ControlRequestProcessingResult, InTrbProcessingStatus, OutTrbProcessingResult,
+    },
+    trb::CompletionCode,
 };
 
+const LINUX_EXDEV: i32 = 18;
 const LINUX_ENODEV: i32 = 19;
 const LINUX_EINVAL: i32 = 22;
 const LINUX_EPIPE: i32 = 32;
 const LINUX_EPROTO: i32 = 71;
+const LINUX_EOVERFLOW: i32 = 75;
 
 pub const fn errno_status(errno: i32) -> i32 {
     -errno
@@ -49,6 +54,15 @@ impl EndpointPcapMeta {
             transfer_type: UsbTransferType::Interrupt,
         }
     }
+
+    pub const fn isochronous(bus: u16, dev: u8, ep: u8) -> Self {
+        Self {
+            bus_number: bus,
+            device_address: dev,
+            endpoint_id: ep,
+            transfer_type: UsbTransferType::Isochronous,
+        }
+    }
 }
 
 pub const fn control_error_status(error: &ControlRequestProcessingResult) -> i32 {
@@ -82,3 +96,14 @@ pub const fn out_error_status(error: &OutTrbProcessingResult) -> i32 {
         OutTrbProcessingResult::Success => 0,
     }
 }
+
+/// Status of an isochronous packet as Linux reports it. Packets that were
+/// not transferred yet report `EXDEV`.
+pub const fn isoch_packet_status(completion_code: Option<CompletionCode>) -> i32 {
+    match completion_code {
+        Some(CompletionCode::Success | CompletionCode::ShortPacket) => 0,
+        Some(CompletionCode::IsochBufferOverrun) => errno_status(LINUX_EOVERFLOW),
+        Some(CompletionCode::UsbTransactionError) => errno_status(LINUX_EPROTO),
+        Some(_) | None => errno_status(LINUX_EXDEV),
+    }
+}
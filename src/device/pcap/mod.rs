@@ -2,12 +2,13 @@ pub mod meta;
 pub mod packet;
 
 pub use meta::EndpointPcapMeta;
-pub use packet::{UsbDirection, UsbEventType, UsbPcapManager};
+pub use packet::{IsochPacketDescriptor, UsbDirection, UsbEventType, UsbPcapManager};
 
 use crate::device::xhci::{
     real_endpoint_handle::{
         ControlRequestProcessingResult, InTrbProcessingStatus, OutTrbProcessingResult,
     },
+    trb::CompletionCode,
     usbrequest::UsbRequest,
 };
 
@@ -117,6 +118,44 @@ pub fn out_error(
     );
 }
 
+pub fn isoch_submission(meta: EndpointPcapMeta, urb_id: u64, payload: &[u8], expected_len: u32) {
+    let descriptor = IsochPacketDescriptor {
+        status: meta::isoch_packet_status(None),
+        offset: 0,
+        length: expected_len,
+    };
+    packet::log_isoch(
+        meta,
+        urb_id,
+        UsbEventType::Submission,
+        expected_len,
+        &[descriptor],
+        payload,
+    );
+}
+
+pub fn isoch_completion(
+    meta: EndpointPcapMeta,
+    urb_id: u64,
+    completion_code: CompletionCode,
+    len: u32,
+    data: &[u8],
+) {
+    let descriptor = IsochPacketDescriptor {
+        status: meta::isoch_packet_status(Some(completion_code)),
+        offset: 0,
+        length: len,
+    };
+    packet::log_isoch(
+        meta,
+        urb_id,
+        UsbEventType::Completion,
+        len,
+        &[descriptor],
+        data,
+    );
+}
+
 pub fn trb_error(meta: EndpointPcapMeta, urb_id: u64) {
     packet::log_error(
         meta,
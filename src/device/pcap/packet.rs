@@ -34,8 +34,7 @@ impl UsbEventType {
 /// USB transfer type stored in the linktype header.
 #[derive(Clone, Copy, Debug)]
 pub enum UsbTransferType {
-    // TODO: implement isochronous transfer logging
-    // Isochronous,
+    Isochronous,
     Control,
     Bulk,
     Interrupt,
@@ -44,8 +43,7 @@ pub enum UsbTransferType {
 impl UsbTransferType {
     const fn code(self) -> u8 {
         match self {
-            // TODO: implement isochronous transfer logging
-            // Self::Isochronous => 0,
+            Self::Isochronous => 0,
             Self::Interrupt => 1,
             Self::Control => 2,
             Self::Bulk => 3,
@@ -80,6 +78,27 @@ const fn endpoint_address(
     }
 }
 
+/// Status and location of one packet of an isochronous transfer.
+///
+/// Isochronous records carry one descriptor per packet between the
+/// linktype header and the data.
+#[derive(Clone, Copy, Debug)]
+pub struct IsochPacketDescriptor {
+    pub status: i32,
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl IsochPacketDescriptor {
+    fn bytes(&self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[0..4].copy_from_slice(&self.status.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_le_bytes());
+        bytes
+    }
+}
+
 /// Linux USB per-packet header for PCAP linktype 189.
 ///
 /// `header_bytes` writes the fields in little-endian order.
@@ -368,6 +387,53 @@ pub fn log_completion(
     );
 }
 
+/// Emit a PCAP record for an isochronous transfer event.
+///
+/// In place of setup data, the header carries the number of packets with
+/// errors and the number of packet descriptors.
+pub fn log_isoch(
+    meta: super::meta::EndpointPcapMeta,
+    urb_id: u64,
+    event: UsbEventType,
+    data_length: u32,
+    descriptors: &[IsochPacketDescriptor],
+    payload: &[u8],
+) {
+    let direction = endpoint_direction(None, meta.endpoint_id);
+    let data_flag = data_flag_value(event, direction);
+    let error_count = descriptors
+        .iter()
+        .filter(|descriptor| descriptor.status != 0)
+        .count() as i32;
+    let mut iso = [0u8; 8];
+    iso[0..4].copy_from_slice(&error_count.to_le_bytes());
+    iso[4..8].copy_from_slice(&(descriptors.len() as i32).to_le_bytes());
+
+    let mut body = Vec::with_capacity(descriptors.len() * 16 + payload.len());
+    for descriptor in descriptors {
+        body.extend_from_slice(&descriptor.bytes());
+    }
+    body.extend_from_slice(packet_payload(data_flag, payload));
+
+    let header = UsbPacketLinktypeHeader {
+        id: urb_id,
+        event_type: event.code(),
+        transfer_type: UsbTransferType::Isochronous.code(),
+        endpoint_address: endpoint_address(meta.transfer_type, None, meta.endpoint_id),
+        device_address: meta.device_address,
+        bus_number: meta.bus_number,
+        setup_flag: b'-',
+        data_flag,
+        status: 0,
+        data_length,
+        delivered_data_length: body.len() as u32,
+        setup: iso,
+    };
+
+    let record = pcap_record_bytes(&header, &body);
+    UsbPcapManager::write_record(&record);
+}
+
 pub const fn build_setup_bytes(request: &UsbRequest) -> [u8; 8] {
     [
         request.request_type,
@@ -298,6 +298,18 @@ pub mod xhci {
         pub const HCSPARAMS2: u64 = super::MAX_ERST_SIZE_EXP << 4;
         pub const HCCPARAMS1: u64 = super::offset::SUPPORTED_PROTOCOLS << 14;
 
+        /// Streams support 2^(MAX_PSA_SIZE + 1) primary streams per endpoint.
+        pub const MAX_PSA_SIZE: u64 = 15;
+        /// MaxPSASize and NSS (no Secondary Stream ID support) in HCCPARAMS1.
+        ///
+        /// Only advertised if the real devices support streams, otherwise
+        /// MaxPSASize stays 0 and drivers do not attempt to use streams.
+        pub const HCCPARAMS1_STREAMS: u64 = (MAX_PSA_SIZE << 12) | (1 << 7);
+        /// Latency Tolerance Messaging Capability in HCCPARAMS1. We do not
+        /// advertise it, because the host kernel manages the link power of
+        /// the real devices.
+        pub const HCCPARAMS1_LTC: u64 = 1 << 6;
+
         pub const USB_STRING: u64 = 0x20425355;
 
         pub mod supported_protocols {
@@ -317,8 +329,10 @@ pub mod xhci {
             const MINOR: u64 = 0x00;
             const NEXT: u64 = 0;
             pub const CAP_INFO: u64 = ID | (MAJOR << 24) | (MINOR << 16) | (NEXT << 8);
+            /// Hardware LPM Capability (HLC) in the protocol defined field.
+            const HLC: u64 = 1 << 19;
             pub const CONFIG: u64 =
-                (super::super::NUM_USB3_PORTS + 1) | (super::super::NUM_USB2_PORTS << 8);
+                (super::super::NUM_USB3_PORTS + 1) | (super::super::NUM_USB2_PORTS << 8) | HLC;
         }
     }
 
@@ -337,6 +351,9 @@ pub mod xhci {
             pub const RS: u64 = 0x1;
             pub const HCRST: u64 = 0x2;
             pub const INTE: u64 = 0x4;
+            pub const CSS: u64 = 0x100;
+            pub const CRS: u64 = 0x200;
+            pub const EWE: u64 = 0x400;
         }
 
         /// See xhci specification chapter 5.4.2
@@ -383,10 +400,40 @@ pub mod xhci {
             /// the field is larger than one bit.
             pub mod value {
                 pub const PLS_U0: u64 = 0x0;
+                pub const PLS_U2: u64 = 0x40;
+                pub const PLS_U3: u64 = 0x60;
                 pub const PLS_RXDETECT: u64 = 0xa0;
                 pub const PLS_POLLING: u64 = 0xe0;
+                pub const PLS_RESUME: u64 = 0x1e0;
+            }
+        }
+
+        /// See xhci specification chapter 5.4.9
+        pub mod portpmsc {
+            // USB 3 ports
+            pub const U1_TIMEOUT: u32 = 0xff;
+            pub const U2_TIMEOUT: u32 = 0xff00;
+            pub const FLA: u32 = 0x10000;
+
+            // USB 2 ports
+            pub const L1S: u32 = 0x7;
+            pub const RWE: u32 = 0x8;
+            pub const HIRD: u32 = 0xf0;
+            pub const L1_DEVICE_SLOT: u32 = 0xff00;
+            pub const HLE: u32 = 0x10000;
+            pub const PORT_TEST_CONTROL: u32 = 0xf000_0000;
+
+            /// Values of the L1 Status field.
+            pub mod value {
+                pub const L1S_SUCCESS: u32 = 0x1;
+                pub const L1S_TIMEOUT_ERROR: u32 = 0x4;
             }
         }
+
+        /// See xhci specification chapter 5.4.10
+        pub mod portli {
+            pub const LINK_ERROR_COUNT: u32 = 0xffff;
+        }
     }
 
     /// Constants for the runtime registers.
@@ -438,6 +485,10 @@ pub mod xhci {
             pub const DEVICE_NOTIFICATION_EVENT: u8 = 38;
             pub const MFINDEX_WRAP_EVENT: u8 = 39;
         }
+        /// The notification types of Device Notification Events
+        pub mod device_notification_types {
+            pub const FUNCTION_WAKE: u8 = 1;
+        }
         /// Constants specific to the event rings
         pub mod event_ring {
             /// The offsets to fields in Event Ring Segment Table Entries (ERSTE)
@@ -465,5 +516,10 @@ pub mod xhci {
             pub const STOPPED: u8 = 3;
             pub const ERROR: u8 = 4;
         }
+        /// The stream context type encoded in stream contexts
+        pub mod stream_context_type {
+            pub const SECONDARY_TRANSFER_RING: u8 = 0;
+            pub const PRIMARY_TRANSFER_RING: u8 = 1;
+        }
     }
 }
@@ -11,14 +11,16 @@ use crate::device::{
     interrupt_line::InterruptLine,
     pci::{
         config_space::{ConfigSpace, ConfigSpaceBuilder},
-        constants::xhci::{offset, MAX_INTRS, RUN_BASE},
+        constants::xhci::{offset, operational::usbcmd, MAX_INTRS, RUN_BASE},
         traits::PciDevice,
     },
     xhci::{
         controller_reset::ResetCoordinator,
+        controller_state::StateCoordinator,
         endpoint_launcher::EndpointLauncher,
+        mfindex::MicroframeIndex,
         port::{get_portli_id, get_portpmsc_id, get_portsc_id, HotplugControl, PortArray},
-        real_device::CompleteRealDevice,
+        real_device::{CompleteRealDevice, RealDevice},
         registers::{UsbcmdRegister, UsbstsRegister},
         slot_manager::SlotManager,
     },
@@ -39,6 +41,7 @@ pub struct XhciController<CRD: CompleteRealDevice> {
     slot_manager: SlotManager,
     usbcmd: UsbcmdRegister,
     usbsts: UsbstsRegister,
+    mfindex: MicroframeIndex,
 }
 
 impl<CRD: CompleteRealDevice> XhciController<CRD> {
@@ -47,13 +50,21 @@ impl<CRD: CompleteRealDevice> XhciController<CRD> {
         let usbsts = UsbstsRegister::new(usbcmd.value_reference());
         let interrupter = Interrupter::new(dma_bus.clone(), &async_runtime);
         let port_array = PortArray::new(interrupter.create_event_sender(), async_runtime.clone());
+        let mfindex = MicroframeIndex::default();
+        mfindex.send_wrap_events(
+            usbcmd.value_reference(),
+            interrupter.create_event_sender(),
+            &async_runtime,
+        );
         let ep_launch_requester = EndpointLauncher::start(
             port_array.create_device_retriever(),
             async_runtime.clone(),
             dma_bus.clone(),
             interrupter.create_event_sender(),
+            mfindex.clone(),
         );
         let slot_manager = SlotManager::new(dma_bus.clone(), &async_runtime, ep_launch_requester);
+        port_array.connect_slots(slot_manager.create_slot_worker_handle());
         let command_ring = CommandRing::new(
             dma_bus.clone(),
             &async_runtime,
@@ -67,6 +78,18 @@ impl<CRD: CompleteRealDevice> XhciController<CRD> {
                 Box::new(command_ring.reset_sender()),
                 Box::new(interrupter.reset_sender()),
                 Box::new(slot_manager.reset_sender()),
+                Box::new(mfindex.clone()),
+                Box::new(usbsts.clone()),
+            ],
+            &async_runtime,
+        );
+        StateCoordinator::start(
+            usbcmd.clone(),
+            usbsts.clone(),
+            [
+                Box::new(command_ring.state_sender()),
+                Box::new(interrupter.state_sender()),
+                Box::new(port_array.state_sender()),
             ],
             &async_runtime,
         );
@@ -79,6 +102,7 @@ impl<CRD: CompleteRealDevice> XhciController<CRD> {
             slot_manager,
             usbcmd,
             usbsts,
+            mfindex,
         }
     }
 
@@ -124,7 +148,12 @@ impl<CRD: CompleteRealDevice> PciDevice for XhciController<CRD> {
 
         match req.addr {
             // xHC Operational Registers
-            offset::USBCMD => self.usbcmd.write(value),
+            offset::USBCMD => {
+                self.usbcmd.write(value);
+                // MFINDEX only advances while the controller runs
+                self.mfindex
+                    .set_running(self.usbcmd.read() & usbcmd::RS != 0);
+            }
             offset::DNCTL => assert_eq!(value, 2, "debug notifications not supported"),
             offset::CRCR => self
                 .command_ring
@@ -134,8 +163,7 @@ impl<CRD: CompleteRealDevice> PciDevice for XhciController<CRD> {
             offset::DCBAAP => self.slot_manager.dcbaap.write(value),
             offset::DCBAAP_HI => assert_eq!(value, 0, "no support for configuration above 4G"),
             offset::CONFIG => self.slot_manager.config_reg.write(value as u32), // guard.enable_slots(value),
-            // USBSTS writes occur but we can ignore them (to get a device enumerated)
-            offset::USBSTS => {}
+            offset::USBSTS => self.usbsts.write(value),
             // xHC Runtime Registers (moved up for performance)
             offset::IMAN => self.interrupter.registers.interrupt_management.write(value),
             offset::IMOD => self
@@ -160,8 +188,9 @@ impl<CRD: CompleteRealDevice> PciDevice for XhciController<CRD> {
                 .expect("command worker should be alive"),
             offset::DOORBELL_DEVICE..offset::DOORBELL_DEVICE_END => {
                 let slot_id = ((req.addr - offset::DOORBELL_CONTROLLER) / 4) as u8;
+                // DB Target in bits 7:0, DB Stream ID in bits 31:16
                 self.slot_manager
-                    .doorbell(slot_id, value as u8)
+                    .doorbell(slot_id, value as u8, (value >> 16) as u16)
                     .expect("slot worker should be alive");
             }
             addr if get_portsc_id(addr).is_some() => {
@@ -179,6 +208,13 @@ impl<CRD: CompleteRealDevice> PciDevice for XhciController<CRD> {
                 self.port_array.write_portpmsc(port_id, value as u32);
             }
 
+            addr if get_portli_id(addr).is_some() => {
+                // SAFETY: unwrap() is safe because we already checked is_some() in the match guard above
+                let port_id = get_portli_id(addr).unwrap();
+                // SAFETY: The PORTLI is defined as 32 bit
+                self.port_array.write_portli(port_id, value as u32);
+            }
+
             addr => {
                 todo!("unknown write {}", addr);
             }
@@ -196,6 +232,9 @@ impl<CRD: CompleteRealDevice> PciDevice for XhciController<CRD> {
             offset::HCSPARAMS1 => capability::HCSPARAMS1,
             offset::HCSPARAMS2 => capability::HCSPARAMS2,
             offset::HCSPARAMS3 => 0,
+            offset::HCCPARAMS1 if <CRD::RD as RealDevice>::SUPPORTS_STREAMS => {
+                capability::HCCPARAMS1 | capability::HCCPARAMS1_STREAMS
+            }
             offset::HCCPARAMS1 => capability::HCCPARAMS1,
             offset::DBOFF => offset::DOORBELL_CONTROLLER,
             offset::RTSOFF => RUN_BASE,
@@ -237,7 +276,7 @@ impl<CRD: CompleteRealDevice> PciDevice for XhciController<CRD> {
             offset::DOORBELL_CONTROLLER => 0, // kernel reads the doorbell after write
             // Device Doorbell Registers (DOORBELL_DEVICE)
             offset::DOORBELL_DEVICE..offset::DOORBELL_DEVICE_END => 0,
-            offset::MFINDEX => 0x0,
+            offset::MFINDEX => self.mfindex.mfindex().into(),
 
             // Port Status and Control Register (PORTSC)
             addr if get_portsc_id(addr).is_some() => {
@@ -254,7 +293,11 @@ impl<CRD: CompleteRealDevice> PciDevice for XhciController<CRD> {
             }
 
             // Port Link Info Register (PORTLI_USB3)
-            addr if get_portli_id(addr).is_some() => 0,
+            addr if get_portli_id(addr).is_some() => {
+                // SAFETY: unwrap() is safe because we already checked is_some() in the match guard above
+                let port_id = get_portli_id(addr).unwrap();
+                self.port_array.read_portli(port_id) as u64
+            }
 
             // Everything else is Reserved Zero
             addr => {
@@ -17,13 +17,21 @@ use tracing::{debug, info, trace, warn};
 
 use crate::device::{
     bus::BusDeviceRef,
-    pci::constants::xhci::operational::{crcr, usbcmd},
+    pci::constants::xhci::{
+        capability,
+        operational::{crcr, usbcmd},
+        MAX_PORTS, NUM_USB3_PORTS,
+    },
     xhci::{
         controller_reset::ResetSender,
+        controller_state::StateSender,
         interrupter::EventSender,
         linked_ring::LinkedRing,
         slot_manager::SlotWorkerHandle,
-        trb::{CommandTrb, CommandTrbVariant, CompletionCode, EventTrb},
+        trb::{
+            CommandTrb, CommandTrbVariant, CompletionCode, EventTrb, ForceHeaderCommandTrbData,
+            GetPortBandwidthCommandTrbData,
+        },
     },
 };
 
@@ -47,6 +55,27 @@ impl ResetSender for CommandRingResetSender {
     }
 }
 
+#[derive(Debug)]
+pub struct CommandRingStateSender {
+    sender_to_worker: mpsc::UnboundedSender<WorkerMessage>,
+}
+
+impl StateSender for CommandRingStateSender {
+    fn send_save(&self, completion_notifier: oneshot::Sender<()>) -> anyhow::Result<()> {
+        self.sender_to_worker
+            .send(WorkerMessage::SaveState(completion_notifier))?;
+
+        Ok(())
+    }
+
+    fn send_restore(&self, completion_notifier: oneshot::Sender<bool>) -> anyhow::Result<()> {
+        self.sender_to_worker
+            .send(WorkerMessage::RestoreState(completion_notifier))?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct CommandWorker {
     state: WorkerState,
@@ -55,7 +84,10 @@ struct CommandWorker {
     usbcmd: Arc<AtomicU32>,
     event_sender: EventSender,
     ring: LinkedRing,
+    // the dequeue pointer and cycle state captured by Save State
+    saved_state: Option<(u64, bool)>,
     slot_handle: SlotWorkerHandle,
+    dma_bus: BusDeviceRef,
 }
 
 #[derive(Debug)]
@@ -73,6 +105,8 @@ enum WorkerMessage {
     Doorbell,
     Stop,
     Reset(oneshot::Sender<()>),
+    SaveState(oneshot::Sender<()>),
+    RestoreState(oneshot::Sender<bool>),
 }
 
 impl CommandRing {
@@ -95,7 +129,7 @@ impl CommandRing {
         let (sender_to_worker, receiver) = mpsc::unbounded_channel();
         let running = Arc::new(AtomicBool::new(false));
 
-        let ring = LinkedRing::new(dma_bus, 0, false);
+        let ring = LinkedRing::new(dma_bus.clone(), 0, false);
         let worker = CommandWorker {
             state: WorkerState::Stopped,
             receiver,
@@ -103,7 +137,9 @@ impl CommandRing {
             usbcmd,
             event_sender,
             ring,
+            saved_state: None,
             slot_handle,
+            dma_bus,
         };
         async_runtime.spawn(worker.run());
 
@@ -169,6 +205,12 @@ impl CommandRing {
         }
     }
 
+    pub fn state_sender(&self) -> CommandRingStateSender {
+        CommandRingStateSender {
+            sender_to_worker: self.sender_to_worker.clone(),
+        }
+    }
+
     fn send_to_worker(&self, msg: WorkerMessage) -> anyhow::Result<()> {
         self.sender_to_worker.send(msg)?;
 
@@ -193,8 +235,16 @@ impl CommandWorker {
                 WorkerState::Stopped => match self.next_msg().await? {
                     WorkerMessage::Reset(completion) => {
                         self.commandring_running.store(false, Ordering::Relaxed);
+                        self.saved_state = None;
                         completion.send(()).ok();
                     }
+                    WorkerMessage::SaveState(completion) => {
+                        self.save_state();
+                        completion.send(()).ok();
+                    }
+                    WorkerMessage::RestoreState(completion) => {
+                        completion.send(self.restore_state()).ok();
+                    }
                     WorkerMessage::SetDequeuePointerAndCS(dp, cs) => {
                         debug!("Updating command ring parameters: dp={dp:#x}, cs={cs}");
                         self.ring.set_dequeue_pointer(dp, cs);
@@ -214,9 +264,17 @@ impl CommandWorker {
                 WorkerState::Idle => match self.next_msg().await? {
                     WorkerMessage::Reset(completion) => {
                         self.commandring_running.store(false, Ordering::Relaxed);
+                        self.saved_state = None;
                         self.state = WorkerState::Stopped;
                         completion.send(()).ok();
                     }
+                    WorkerMessage::SaveState(completion) => {
+                        self.save_state();
+                        completion.send(()).ok();
+                    }
+                    WorkerMessage::RestoreState(completion) => {
+                        completion.send(self.restore_state()).ok();
+                    }
                     WorkerMessage::Doorbell => {
                         self.state = WorkerState::LookingForNewCommand;
                     }
@@ -234,10 +292,22 @@ impl CommandWorker {
                         match msg {
                             WorkerMessage::Reset(completion) => {
                                 self.commandring_running.store(false, Ordering::Relaxed);
+                                self.saved_state = None;
                                 self.state = WorkerState::Stopped;
                                 completion.send(()).ok();
                                 break;
                             }
+                            WorkerMessage::SaveState(completion) => {
+                                self.save_state();
+                                completion.send(()).ok();
+                            }
+                            WorkerMessage::RestoreState(completion) => {
+                                if self.restore_state() {
+                                    completion.send(true).ok();
+                                    continue 'run_loop;
+                                }
+                                completion.send(false).ok();
+                            }
                             WorkerMessage::Doorbell => {
                                 // we are already active and running, silently consume
                             }
@@ -287,6 +357,25 @@ impl CommandWorker {
         }
     }
 
+    fn save_state(&mut self) {
+        self.saved_state = Some(self.ring.get_dequeue_pointer());
+        debug!("Saved command ring state: {:?}", self.saved_state);
+    }
+
+    // The restored command ring is stopped until the driver rings the
+    // doorbell again. Returns false if there is no saved state.
+    fn restore_state(&mut self) -> bool {
+        let Some((dequeue_pointer, cycle_state)) = self.saved_state else {
+            return false;
+        };
+        self.ring.set_dequeue_pointer(dequeue_pointer, cycle_state);
+        self.commandring_running.store(false, Ordering::Relaxed);
+        self.state = WorkerState::Stopped;
+        debug!("Restored command ring state: dp={dequeue_pointer:#x}, cs={cycle_state}");
+
+        true
+    }
+
     async fn next_msg(&mut self) -> anyhow::Result<WorkerMessage> {
         self.receiver
             .recv()
@@ -390,7 +479,49 @@ impl CommandWorker {
                     data.slot_id,
                 )
             }
-            CommandTrbVariant::ForceHeader => todo!(),
+            CommandTrbVariant::NegotiateBandwidth(data) => {
+                let completion_code = self.slot_handle.negotiate_bandwidth(data.slot_id).await?;
+                EventTrb::new_command_completion_event_trb(
+                    trb.address,
+                    0,
+                    completion_code,
+                    data.slot_id,
+                )
+            }
+            CommandTrbVariant::SetLatencyToleranceValue(data) => {
+                // The command is only supported if LTC is advertised.
+                let completion_code = if capability::HCCPARAMS1 & capability::HCCPARAMS1_LTC == 0 {
+                    debug!(
+                        "Rejecting Best Effort Latency Tolerance {:#x} without LTC",
+                        data.best_effort_latency_tolerance
+                    );
+                    CompletionCode::TrbError
+                } else {
+                    CompletionCode::Success
+                };
+                EventTrb::new_command_completion_event_trb(trb.address, 0, completion_code, 0)
+            }
+            CommandTrbVariant::GetPortBandwidth(data) => {
+                let completion_code = self.get_port_bandwidth(data);
+                EventTrb::new_command_completion_event_trb(trb.address, 0, completion_code, 0)
+            }
+            CommandTrbVariant::ForceHeader(data) => {
+                let completion_code = Self::force_header(data);
+                EventTrb::new_command_completion_event_trb(trb.address, 0, completion_code, 0)
+            }
+            CommandTrbVariant::GetExtendedProperty(data) => {
+                // We do not implement any Extended Capability with properties.
+                debug!(
+                    "Get Extended Property for unsupported ECI {:#x}",
+                    data.extended_capability_identifier
+                );
+                EventTrb::new_command_completion_event_trb(
+                    trb.address,
+                    0,
+                    CompletionCode::ParameterError,
+                    data.slot_id,
+                )
+            }
             CommandTrbVariant::NoOp => EventTrb::new_command_completion_event_trb(
                 trb.address,
                 0,
@@ -412,6 +543,55 @@ impl CommandWorker {
 
         Ok(())
     }
+
+    /// Write the Port Bandwidth Context (XHCI spec 6.2.6) for the root hub.
+    ///
+    /// We do not schedule any bus traffic ourselves, so every port that
+    /// supports the requested speed reports all of its bandwidth as available.
+    fn get_port_bandwidth(&self, data: &GetPortBandwidthCommandTrbData) -> CompletionCode {
+        if data.hub_slot_id != 0 {
+            // We do not track the TT bandwidth of external hubs.
+            return CompletionCode::ParameterError;
+        }
+        let usb3_speed = match data.dev_speed {
+            1..=3 => false,
+            4 => true,
+            _ => return CompletionCode::ParameterError,
+        };
+
+        let available: Vec<u8> = (1..=MAX_PORTS)
+            .map(|port| {
+                if (port <= NUM_USB3_PORTS) == usb3_speed {
+                    100
+                } else {
+                    0
+                }
+            })
+            .collect();
+        // the first byte of the context is reserved
+        self.dma_bus.write_bulk(
+            data.port_bandwidth_context_pointer.wrapping_add(1),
+            &available,
+        );
+
+        CompletionCode::Success
+    }
+
+    fn force_header(data: &ForceHeaderCommandTrbData) -> CompletionCode {
+        // headers only exist on USB 3 links
+        if data.root_hub_port == 0 || data.root_hub_port as u64 > NUM_USB3_PORTS {
+            return CompletionCode::ParameterError;
+        }
+        // The host kernel owns the link to the real device; we cannot inject
+        // packets on it. Fail the command instead of pretending that the
+        // header was sent.
+        warn!(
+            "Cannot force a header of packet type {:#x} on port {}",
+            data.packet_type, data.root_hub_port
+        );
+
+        CompletionCode::TrbError
+    }
 }
 
 #[cfg(test)]
@@ -443,6 +623,8 @@ mod tests {
     const SECOND_ADDRESS: u64 = FIRST_ADDRESS + 0x10;
     const THIRD_ADDRESS: u64 = SECOND_ADDRESS + 0x10;
     const FOURTH_ADDRESS: u64 = THIRD_ADDRESS + 0x10;
+    const FIFTH_ADDRESS: u64 = FOURTH_ADDRESS + 0x10;
+    const SIXTH_ADDRESS: u64 = FIFTH_ADDRESS + 0x10;
 
     const SLOT_ID: u8 = 0;
 
@@ -716,4 +898,159 @@ mod tests {
 
         assert!(interrupter.is_empty());
     }
+
+    #[tokio::test]
+    async fn process_optional_commands() {
+        let (command_ring, mut interrupter, mut receiver, dma_bus, usbcmd) = init_test();
+        let port_bandwidth_context = 0x1000;
+
+        let commands = [
+            RawTrbBuilder::new(FIRST_ADDRESS)
+                .with_trb_type(trb_types::NEGOTIATE_BANDWIDTH_COMMAND)
+                .with_byte(15, 3)
+                .build(),
+            RawTrbBuilder::new(SECOND_ADDRESS)
+                .with_data_pointer(port_bandwidth_context)
+                .with_trb_type(trb_types::GET_PORT_BANDWIDTH_COMMAND)
+                // High Speed
+                .with_byte(14, 3)
+                .build(),
+            RawTrbBuilder::new(THIRD_ADDRESS)
+                .with_trb_type(trb_types::FORCE_HEADER_COMMAND)
+                // a USB 2 port
+                .with_byte(15, MAX_PORTS as u8)
+                .build(),
+            RawTrbBuilder::new(FOURTH_ADDRESS)
+                .with_trb_type(trb_types::GET_EXTENDED_PROPERTY_COMMAND)
+                .build(),
+            RawTrbBuilder::new(FIFTH_ADDRESS)
+                .with_trb_type(trb_types::FORCE_HEADER_COMMAND)
+                // a USB 3 port
+                .with_byte(15, 1)
+                .build(),
+            RawTrbBuilder::new(SIXTH_ADDRESS)
+                .with_trb_type(trb_types::SET_LATENCY_TOLERANCE_VALUE_COMMAND)
+                .build(),
+        ];
+        for command in &commands {
+            dma_bus.write_bulk(command.address, &command.buffer);
+        }
+
+        usbcmd.write(usbcmd::RS);
+        command_ring.doorbell().unwrap();
+
+        match receiver.recv().await.unwrap() {
+            SlotMessage::NegotiateBandwidth(slot_id, sender) => {
+                assert_eq!(slot_id, 3);
+                sender.send(CompletionCode::Success).unwrap();
+            }
+            unexpected => {
+                panic!("unexpected SlotMessage {unexpected:?}");
+            }
+        }
+        let expected_events = [
+            EventTrb::new_command_completion_event_trb(
+                FIRST_ADDRESS,
+                0,
+                CompletionCode::Success,
+                3,
+            ),
+            EventTrb::new_command_completion_event_trb(
+                SECOND_ADDRESS,
+                0,
+                CompletionCode::Success,
+                0,
+            ),
+            EventTrb::new_command_completion_event_trb(
+                THIRD_ADDRESS,
+                0,
+                CompletionCode::ParameterError,
+                0,
+            ),
+            EventTrb::new_command_completion_event_trb(
+                FOURTH_ADDRESS,
+                0,
+                CompletionCode::ParameterError,
+                0,
+            ),
+            // we cannot send the header
+            EventTrb::new_command_completion_event_trb(
+                FIFTH_ADDRESS,
+                0,
+                CompletionCode::TrbError,
+                0,
+            ),
+            // LTC is not advertised
+            EventTrb::new_command_completion_event_trb(
+                SIXTH_ADDRESS,
+                0,
+                CompletionCode::TrbError,
+                0,
+            ),
+        ];
+        for expected_event in expected_events {
+            assert_eq!(interrupter.await_event().await.unwrap(), expected_event);
+        }
+
+        // the reserved first byte stays untouched, only USB 2 ports have
+        // High Speed bandwidth
+        let mut context = [0; 1 + MAX_PORTS as usize];
+        dma_bus.read_bulk(port_bandwidth_context, &mut context);
+        let mut expected = vec![99];
+        expected.extend((1..=MAX_PORTS).map(|port| if port > NUM_USB3_PORTS { 100 } else { 0 }));
+        assert_eq!(context.to_vec(), expected);
+
+        assert!(interrupter.is_empty());
+        assert!(receiver.is_empty());
+    }
+
+    #[tokio::test]
+    async fn restore_state_continues_at_the_saved_position() {
+        let (command_ring, mut interrupter, _receiver, dma_bus, usbcmd) = init_test();
+        let state_sender = command_ring.state_sender();
+
+        assert!(
+            !state_sender.restore().unwrap().await.unwrap(),
+            "there is no saved state yet"
+        );
+
+        for address in [FIRST_ADDRESS, SECOND_ADDRESS] {
+            let command = RawTrbBuilder::new(address)
+                .with_trb_type(trb_types::NO_OP_COMMAND)
+                .build();
+            dma_bus.write_bulk(command.address, &command.buffer);
+        }
+        // the cycle bit of the uninitialized third TRB does not match, so the
+        // ring idles after the first two commands
+        usbcmd.write(usbcmd::RS);
+        command_ring.doorbell().unwrap();
+        for address in [FIRST_ADDRESS, SECOND_ADDRESS] {
+            assert_eq!(
+                interrupter.await_event().await.unwrap(),
+                EventTrb::new_command_completion_event_trb(address, 0, CompletionCode::Success, 0)
+            );
+        }
+
+        // halt the controller and save its state
+        usbcmd.write(0);
+        state_sender.save().unwrap().await.unwrap();
+        assert!(state_sender.restore().unwrap().await.unwrap());
+        assert_eq!(command_ring.status(), 0, "the restored ring is stopped");
+
+        let command = RawTrbBuilder::new(THIRD_ADDRESS)
+            .with_trb_type(trb_types::NO_OP_COMMAND)
+            .build();
+        dma_bus.write_bulk(command.address, &command.buffer);
+        usbcmd.write(usbcmd::RS);
+        command_ring.doorbell().unwrap();
+        assert_eq!(
+            interrupter.await_event().await.unwrap(),
+            EventTrb::new_command_completion_event_trb(
+                THIRD_ADDRESS,
+                0,
+                CompletionCode::Success,
+                0
+            )
+        );
+    }
 }
@@ -24,13 +24,13 @@ pub trait ResetSender: Send + Sync {
 /// and clears `HCRST` after all reset completion signals were received.
 pub struct ResetCoordinator {
     usbcmd: UsbcmdRegister,
-    reset_senders: [Box<dyn ResetSender>; 3],
+    reset_senders: [Box<dyn ResetSender>; 5],
 }
 
 impl ResetCoordinator {
     pub fn start(
         usbcmd: UsbcmdRegister,
-        reset_senders: [Box<dyn ResetSender>; 3],
+        reset_senders: [Box<dyn ResetSender>; 5],
         async_runtime: &runtime::Handle,
     ) {
         let coordinator = Self {
@@ -93,6 +93,12 @@ mod tests {
         let coordinator = ResetCoordinator {
             usbcmd: usbcmd.clone(),
             reset_senders: [
+                Box::new(TestResetSender {
+                    completion_sender: completion_sender.clone(),
+                }),
+                Box::new(TestResetSender {
+                    completion_sender: completion_sender.clone(),
+                }),
                 Box::new(TestResetSender {
                     completion_sender: completion_sender.clone(),
                 }),
@@ -105,7 +111,7 @@ mod tests {
 
         let reset_task = tokio::spawn(async move { coordinator.reset().await });
 
-        for _ in 0..3 {
+        for _ in 0..5 {
             let completion = completion_receiver.recv().await.unwrap();
             assert_eq!(usbcmd.read() & usbcmd::HCRST, usbcmd::HCRST);
             completion.send(()).unwrap();
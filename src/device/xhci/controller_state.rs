@@ -0,0 +1,210 @@
+//! Controller Save and Restore State (XHCI spec 4.23.2).
+//!
+//! Drivers save the internal state of a halted controller with USBCMD.CSS
+//! before the system sleeps, and restore it with USBCMD.CRS after waking
+//! up. The state lives in the components that own it, so the devices
+//! attached to the ports stay attached in between.
+
+use tokio::{runtime, sync::oneshot};
+use tracing::{error, info, warn};
+
+use crate::device::{
+    pci::constants::xhci::operational::usbcmd,
+    xhci::registers::{UsbcmdRegister, UsbstsRegister},
+};
+
+/// Sends save and restore requests to a component and reports when the
+/// component finished.
+///
+/// The completion notifiers must be triggered by the worker after it saved or
+/// restored its state. A restore reports `false` if there is no saved state to
+/// restore.
+pub trait StateSender: Send + Sync {
+    fn send_save(&self, completion_notifier: oneshot::Sender<()>) -> anyhow::Result<()>;
+
+    fn send_restore(&self, completion_notifier: oneshot::Sender<bool>) -> anyhow::Result<()>;
+
+    fn save(&self) -> anyhow::Result<oneshot::Receiver<()>> {
+        let (send, recv) = oneshot::channel();
+        self.send_save(send)?;
+
+        Ok(recv)
+    }
+
+    fn restore(&self) -> anyhow::Result<oneshot::Receiver<bool>> {
+        let (send, recv) = oneshot::channel();
+        self.send_restore(send)?;
+
+        Ok(recv)
+    }
+}
+
+/// Coordinates saving and restoring the state of the xHCI components.
+///
+/// The coordinator waits for `USBCMD.CSS` or `USBCMD.CRS`, saves or restores
+/// the state of all registered components, and clears the command after all
+/// components completed, which clears `USBSTS.SSS` or `USBSTS.RSS`. A failed
+/// operation is reported with `USBSTS.SRE`.
+pub struct StateCoordinator {
+    usbcmd: UsbcmdRegister,
+    usbsts: UsbstsRegister,
+    state_senders: [Box<dyn StateSender>; 3],
+}
+
+impl StateCoordinator {
+    pub fn start(
+        usbcmd: UsbcmdRegister,
+        usbsts: UsbstsRegister,
+        state_senders: [Box<dyn StateSender>; 3],
+        async_runtime: &runtime::Handle,
+    ) {
+        let coordinator = Self {
+            usbcmd,
+            usbsts,
+            state_senders,
+        };
+
+        async_runtime.spawn(coordinator.run_loop());
+    }
+
+    async fn run_loop(self) {
+        loop {
+            self.usbcmd.state_command_notification().await;
+
+            let result = match self.usbcmd.state_command() {
+                usbcmd::CSS => self.save().await,
+                usbcmd::CRS => self.restore().await,
+                _ => continue,
+            };
+            if let Err(err) = result {
+                error!("failed to save or restore the controller state: {err}");
+                self.usbsts.set_save_restore_error();
+            }
+            self.usbcmd.clear_state_command();
+        }
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        for state_sender in &self.state_senders {
+            state_sender.save()?.await?;
+        }
+        info!("Saved the controller state");
+
+        Ok(())
+    }
+
+    async fn restore(&self) -> anyhow::Result<()> {
+        let mut restored = true;
+        for state_sender in &self.state_senders {
+            restored &= state_sender.restore()?.await?;
+        }
+
+        if restored {
+            info!("Restored the controller state");
+        } else {
+            warn!("Restored the controller without a complete saved state");
+            self.usbsts.set_save_restore_error();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use crate::device::pci::constants::xhci::operational::usbsts;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct TestStateSender {
+        saved: Arc<AtomicBool>,
+    }
+
+    impl StateSender for TestStateSender {
+        fn send_save(&self, completion_notifier: oneshot::Sender<()>) -> anyhow::Result<()> {
+            self.saved.store(true, Ordering::Relaxed);
+            completion_notifier.send(()).unwrap();
+
+            Ok(())
+        }
+
+        fn send_restore(&self, completion_notifier: oneshot::Sender<bool>) -> anyhow::Result<()> {
+            completion_notifier
+                .send(self.saved.load(Ordering::Relaxed))
+                .unwrap();
+
+            Ok(())
+        }
+    }
+
+    fn coordinator(saved: &[Arc<AtomicBool>; 3]) -> (UsbcmdRegister, UsbstsRegister) {
+        let usbcmd = UsbcmdRegister::new();
+        let usbsts = UsbstsRegister::new(usbcmd.value_reference());
+        StateCoordinator::start(
+            usbcmd.clone(),
+            usbsts.clone(),
+            saved
+                .clone()
+                .map(|saved| -> Box<dyn StateSender> { Box::new(TestStateSender { saved }) }),
+            &runtime::Handle::current(),
+        );
+
+        (usbcmd, usbsts)
+    }
+
+    async fn wait_for_completion(usbsts: &UsbstsRegister) {
+        while usbsts.read() & (usbsts::SSS | usbsts::RSS) != 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn save_and_restore_complete_without_error() {
+        let saved = [(); 3].map(|()| Arc::new(AtomicBool::default()));
+        let (usbcmd, usbsts) = coordinator(&saved);
+
+        usbcmd.write(usbcmd::CSS);
+        assert_eq!(usbcmd.read(), 0, "CSS always reads as 0");
+        wait_for_completion(&usbsts).await;
+        assert!(saved.iter().all(|saved| saved.load(Ordering::Relaxed)));
+
+        usbcmd.write(usbcmd::CRS);
+        wait_for_completion(&usbsts).await;
+        assert_eq!(usbsts.read() & usbsts::SRE, 0);
+    }
+
+    #[tokio::test]
+    async fn restore_without_saved_state_reports_an_error() {
+        let saved = [(); 3].map(|()| Arc::new(AtomicBool::default()));
+        let (usbcmd, usbsts) = coordinator(&saved);
+        saved[0].store(true, Ordering::Relaxed);
+
+        usbcmd.write(usbcmd::CRS);
+        wait_for_completion(&usbsts).await;
+        assert_eq!(usbsts.read() & usbsts::SRE, usbsts::SRE);
+
+        usbsts.write(usbsts::SRE);
+        assert_eq!(usbsts.read() & usbsts::SRE, 0, "SRE is RW1C");
+    }
+
+    #[tokio::test]
+    async fn state_commands_are_ignored_while_running() {
+        let usbcmd = UsbcmdRegister::new();
+        let usbsts = UsbstsRegister::new(usbcmd.value_reference());
+
+        usbcmd.write(usbcmd::RS);
+        usbcmd.write(usbcmd::RS | usbcmd::CSS);
+        assert_eq!(usbsts.read() & usbsts::SSS, 0);
+        assert_eq!(usbcmd.state_command(), 0);
+
+        usbcmd.write(0);
+        usbcmd.write(usbcmd::CSS);
+        assert_eq!(usbsts.read() & usbsts::SSS, usbsts::SSS);
+    }
+}
@@ -12,7 +12,7 @@ use crate::{
         xhci::{
             hotplug_endpoint_handle::HotplugEndpointHandle,
             hotplug_endpoint_handle::HotplugTrbProcessingResult, linked_ring::LinkedRing,
-            slot_manager::EndpointContext, trb::CompletionCode,
+            slot_manager::EndpointContext, streams::StreamContext, trb::CompletionCode,
         },
     },
     oneshot_anyhow::SendWithAnyhowError,
@@ -22,6 +22,11 @@ use crate::{
 pub struct EndpointWorker<EH: HotplugEndpointHandle> {
     state: WorkerState,
     context: EndpointContext,
+    /// The stream context of the transfer ring if the endpoint uses streams.
+    ///
+    /// The dequeue pointer of the transfer ring lives in the stream context
+    /// then, while the endpoint context still holds the endpoint state.
+    stream_context: Option<StreamContext>,
     transfer_ring: LinkedRing,
     recv: mpsc::UnboundedReceiver<EndpointMessage>,
     real_endpoint: EH,
@@ -43,22 +48,25 @@ enum WorkerState {
 
 #[derive(Debug)]
 pub enum EndpointMessage {
-    Doorbell,
+    // contains the stream ID
+    Doorbell(u16),
     Stop(oneshot::Sender<CompletionCode>),
     Reset(oneshot::Sender<CompletionCode>),
-    // contains the new pointer
-    SetTrDequeuePointer(u64, bool, oneshot::Sender<CompletionCode>),
+    // contains the stream ID, the new pointer + cycle state
+    SetTrDequeuePointer(u16, u64, bool, oneshot::Sender<CompletionCode>),
+    // contains the new max packet size, only sent to the control endpoint
+    SetMaxPacketSize(u16, oneshot::Sender<()>),
     Terminate(oneshot::Sender<()>),
 }
 
 impl EndpointMessage {
     fn try_get_completion_code_sender(self) -> Option<oneshot::Sender<CompletionCode>> {
         match self {
-            Self::Doorbell => None,
-            Self::SetTrDequeuePointer(_, _, sender) => Some(sender),
+            Self::Doorbell(_) => None,
+            Self::SetTrDequeuePointer(_, _, _, sender) => Some(sender),
             Self::Reset(sender) => Some(sender),
             Self::Stop(sender) => Some(sender),
-            Self::Terminate(_) => None,
+            Self::SetMaxPacketSize(_, _) | Self::Terminate(_) => None,
         }
     }
 }
@@ -69,23 +77,54 @@ impl<EH: HotplugEndpointHandle> EndpointWorker<EH> {
         dma_bus: BusDeviceRef,
         trb_consumer: EH,
         context: EndpointContext,
+    ) -> EndpointSender {
+        Self::spawn(async_runtime, dma_bus, trb_consumer, context, None)
+    }
+
+    /// Launch a worker for the transfer ring of a single stream.
+    pub fn launch_stream(
+        async_runtime: &runtime::Handle,
+        dma_bus: BusDeviceRef,
+        trb_consumer: EH,
+        context: EndpointContext,
+        stream_context: StreamContext,
+    ) -> EndpointSender {
+        Self::spawn(
+            async_runtime,
+            dma_bus,
+            trb_consumer,
+            context,
+            Some(stream_context),
+        )
+    }
+
+    fn spawn(
+        async_runtime: &runtime::Handle,
+        dma_bus: BusDeviceRef,
+        trb_consumer: EH,
+        context: EndpointContext,
+        stream_context: Option<StreamContext>,
     ) -> EndpointSender {
         let (sender, recv) = mpsc::unbounded_channel();
 
         context.set_state(endpoint_state::RUNNING);
-        let (dequeue_pointer, cycle_state) = context.get_dequeue_pointer_and_cycle_state();
+        let (dequeue_pointer, cycle_state) = stream_context.as_ref().map_or_else(
+            || context.get_dequeue_pointer_and_cycle_state(),
+            StreamContext::get_dequeue_pointer_and_cycle_state,
+        );
         let transfer_ring = LinkedRing::new(dma_bus, dequeue_pointer, cycle_state);
 
         let worker = Self {
             state: WorkerState::WaitForDoorbell,
             context,
+            stream_context,
             recv,
             real_endpoint: trb_consumer,
             transfer_ring,
         };
         async_runtime.spawn(worker.run());
 
-        EndpointSender { msg_sender: sender }
+        EndpointSender::new(sender)
     }
 
     async fn run(self) {
@@ -101,7 +140,7 @@ impl<EH: HotplugEndpointHandle> EndpointWorker<EH> {
         loop {
             match self.state {
                 WorkerState::WaitForDoorbell => match self.next_msg().await? {
-                    EndpointMessage::Doorbell => self.state = WorkerState::LookForTrb,
+                    EndpointMessage::Doorbell(_) => self.state = WorkerState::LookForTrb,
                     EndpointMessage::Stop(sender) => {
                         self.context.set_state(endpoint_state::STOPPED);
                         self.state = WorkerState::Stopped;
@@ -117,6 +156,7 @@ impl<EH: HotplugEndpointHandle> EndpointWorker<EH> {
                         self.real_endpoint.submit_trb(trb)?;
                         self.state = WorkerState::WaitForTrbCompletion;
                     } else {
+                        self.real_endpoint.ring_empty().await?;
                         self.state = WorkerState::WaitForDoorbell;
                     }
                 }
@@ -131,8 +171,7 @@ impl<EH: HotplugEndpointHandle> EndpointWorker<EH> {
                                 self.transfer_ring.set_dequeue_pointer(addr, cs);
                             }
                             self.context.set_state(endpoint_state::HALTED);
-                            let (dequeue_pointer, cycle_state) = self.transfer_ring.get_dequeue_pointer();
-                            self.context.set_dequeue_pointer_and_cycle_state(dequeue_pointer, cycle_state);
+                            self.write_dequeue_pointer();
                             self.state = WorkerState::Halted;
                         }
                         HotplugTrbProcessingResult::TransactionError(addr) => {
@@ -140,14 +179,12 @@ impl<EH: HotplugEndpointHandle> EndpointWorker<EH> {
                                 self.transfer_ring.set_dequeue_pointer(addr, cs);
                             }
                             self.context.set_state(endpoint_state::HALTED);
-                            let (dequeue_pointer, cycle_state) = self.transfer_ring.get_dequeue_pointer();
-                            self.context.set_dequeue_pointer_and_cycle_state(dequeue_pointer, cycle_state);
+                            self.write_dequeue_pointer();
                             self.state = WorkerState::Halted;
                         }
                         HotplugTrbProcessingResult::TrbError => {
                             self.context.set_state(endpoint_state::ERROR);
-                            let (dequeue_pointer, cycle_state) = self.transfer_ring.get_dequeue_pointer();
-                            self.context.set_dequeue_pointer_and_cycle_state(dequeue_pointer, cycle_state);
+                            self.write_dequeue_pointer();
                             self.state = WorkerState::Error;
                         }
                     },
@@ -158,7 +195,10 @@ impl<EH: HotplugEndpointHandle> EndpointWorker<EH> {
                             self.state = WorkerState::StoppedWithContinuableTrb;
                             completion.send_anyhow(CompletionCode::Success)?;
                         }
-                        EndpointMessage::Doorbell => {}
+                        EndpointMessage::Doorbell(_) => {}
+                        EndpointMessage::SetMaxPacketSize(max_packet_size, completion) => {
+                            self.set_max_packet_size(max_packet_size, completion)?;
+                        }
                         msg => self.context_state_error(msg)?,
                     }
                 },
@@ -182,17 +222,17 @@ impl<EH: HotplugEndpointHandle> EndpointWorker<EH> {
                     msg => self.context_state_error(msg)?,
                 },
                 WorkerState::Error => match self.next_msg().await? {
-                    EndpointMessage::SetTrDequeuePointer(ptr, cs, completion) => {
+                    EndpointMessage::SetTrDequeuePointer(_, ptr, cs, completion) => {
                         self.state = WorkerState::SettingTrDequeuePointer(ptr, cs, completion);
                     }
                     msg => self.context_state_error(msg)?,
                 },
                 WorkerState::StoppedWithContinuableTrb => match self.next_msg().await? {
-                    EndpointMessage::SetTrDequeuePointer(ptr, cs, completion) => {
+                    EndpointMessage::SetTrDequeuePointer(_, ptr, cs, completion) => {
                         self.real_endpoint.cancel().await?;
                         self.state = WorkerState::SettingTrDequeuePointer(ptr, cs, completion);
                     }
-                    EndpointMessage::Doorbell => {
+                    EndpointMessage::Doorbell(_) => {
                         self.context.set_state(endpoint_state::RUNNING);
                         self.state = WorkerState::WaitForTrbCompletion;
                     }
@@ -202,11 +242,11 @@ impl<EH: HotplugEndpointHandle> EndpointWorker<EH> {
                     msg => self.context_state_error(msg)?,
                 },
                 WorkerState::Stopped => match self.next_msg().await? {
-                    EndpointMessage::Doorbell => {
+                    EndpointMessage::Doorbell(_) => {
                         self.context.set_state(endpoint_state::RUNNING);
                         self.state = WorkerState::LookForTrb;
                     }
-                    EndpointMessage::SetTrDequeuePointer(ptr, cs, completion) => {
+                    EndpointMessage::SetTrDequeuePointer(_, ptr, cs, completion) => {
                         self.state = WorkerState::SettingTrDequeuePointer(ptr, cs, completion);
                     }
                     EndpointMessage::Terminate(sender) => {
@@ -221,6 +261,7 @@ impl<EH: HotplugEndpointHandle> EndpointWorker<EH> {
                     self.context.set_state(endpoint_state::STOPPED);
                     self.transfer_ring.set_dequeue_pointer(ptr, cs);
                     self.state = WorkerState::Stopped;
+                    self.write_dequeue_pointer();
                     completion.send_anyhow(CompletionCode::Success)?;
                 }
                 WorkerState::Terminating(sender) => {
@@ -234,16 +275,50 @@ impl<EH: HotplugEndpointHandle> EndpointWorker<EH> {
         Ok(())
     }
 
+    // Max packet size changes are possible in every state, so they are
+    // handled here instead of by the states.
     async fn next_msg(&mut self) -> anyhow::Result<EndpointMessage> {
-        let msg = self
-            .recv
-            .recv()
-            .await
-            .ok_or_else(|| anyhow!("endpoint channel closed"))?;
+        loop {
+            let msg = self
+                .recv
+                .recv()
+                .await
+                .ok_or_else(|| anyhow!("endpoint channel closed"))?;
+
+            trace!("endpoint received: {msg:?}");
 
-        trace!("endpoint received: {msg:?}");
+            match msg {
+                EndpointMessage::SetMaxPacketSize(max_packet_size, completion) => {
+                    self.set_max_packet_size(max_packet_size, completion)?;
+                }
+                msg => return Ok(msg),
+            }
+        }
+    }
 
-        Ok(msg)
+    /// Take over the max packet size the guest evaluated for the control
+    /// endpoint. The worker owns the endpoint context while it runs.
+    fn set_max_packet_size(
+        &self,
+        max_packet_size: u16,
+        completion: oneshot::Sender<()>,
+    ) -> anyhow::Result<()> {
+        self.context.set_max_packet_size(max_packet_size);
+        completion.send_anyhow(())
+    }
+
+    /// DMA write the dequeue pointer of the transfer ring to the endpoint or
+    /// stream context.
+    fn write_dequeue_pointer(&self) {
+        let (dequeue_pointer, cycle_state) = self.transfer_ring.get_dequeue_pointer();
+        match &self.stream_context {
+            Some(stream_context) => {
+                stream_context.set_dequeue_pointer_and_cycle_state(dequeue_pointer, cycle_state);
+            }
+            None => self
+                .context
+                .set_dequeue_pointer_and_cycle_state(dequeue_pointer, cycle_state),
+        }
     }
 
     fn context_state_error(&self, msg: EndpointMessage) -> anyhow::Result<()> {
@@ -261,8 +336,12 @@ pub struct EndpointSender {
 }
 
 impl EndpointSender {
-    pub fn doorbell(&self) -> anyhow::Result<()> {
-        self.msg_sender.send(EndpointMessage::Doorbell)?;
+    pub const fn new(msg_sender: mpsc::UnboundedSender<EndpointMessage>) -> Self {
+        Self { msg_sender }
+    }
+
+    pub fn doorbell(&self, stream_id: u16) -> anyhow::Result<()> {
+        self.msg_sender.send(EndpointMessage::Doorbell(stream_id))?;
 
         Ok(())
     }
@@ -281,11 +360,13 @@ impl EndpointSender {
 
     pub fn set_tr_dequeue_pointer(
         &self,
+        stream_id: u16,
         dequeue_pointer: u64,
         cycle_state: bool,
         completion: oneshot::Sender<CompletionCode>,
     ) -> anyhow::Result<()> {
         self.msg_sender.send(EndpointMessage::SetTrDequeuePointer(
+            stream_id,
             dequeue_pointer,
             cycle_state,
             completion,
@@ -294,6 +375,15 @@ impl EndpointSender {
         Ok(())
     }
 
+    pub async fn set_max_packet_size(&self, max_packet_size: u16) -> anyhow::Result<()> {
+        let (send, recv) = oneshot::channel();
+        self.msg_sender
+            .send(EndpointMessage::SetMaxPacketSize(max_packet_size, send))?;
+        recv.await?;
+
+        Ok(())
+    }
+
     pub async fn terminate(&self) -> anyhow::Result<()> {
         let (send, recv) = oneshot::channel();
         self.msg_sender.send(EndpointMessage::Terminate(send))?;
@@ -34,6 +34,10 @@ pub trait EndpointHandle: BaseEndpointHandle {
 
     fn submit_trb(&mut self, trb: RawTrb) -> anyhow::Result<()>;
     fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_>;
+
+    /// Called when the endpoint finds no more TRBs on its transfer ring.
+    /// Handles that keep several TDs in flight complete them here.
+    fn ring_empty(&mut self) -> Self::CompletionFuture<'_>;
 }
 
 /// Possible result cases for processing of a TRB.
@@ -65,6 +69,10 @@ impl EndpointHandle for DummyEndpointHandle {
     fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_> {
         panic!("should never call functions of dummy endpoint handle");
     }
+
+    fn ring_empty(&mut self) -> Self::CompletionFuture<'_> {
+        panic!("should never call functions of dummy endpoint handle");
+    }
 }
 
 impl BaseEndpointHandle for DummyEndpointHandle {
@@ -89,6 +97,9 @@ pub struct ControlEndpointHandle<RCEH: RealControlEndpointHandle> {
     dma_bus: BusDeviceRef,
     event_sender: EventSender,
     submission_state: ControlSubmissionState,
+    // Event Data TRBs of the current control transfer, reported once the
+    // transfer completes.
+    event_data_trbs: Vec<EventDataTrbData>,
 }
 
 impl<RCEH: RealControlEndpointHandle> ControlEndpointHandle<RCEH> {
@@ -109,6 +120,7 @@ impl<RCEH: RealControlEndpointHandle> ControlEndpointHandle<RCEH> {
             dma_bus,
             event_sender,
             submission_state: ControlSubmissionState::NoTrbSubmitted,
+            event_data_trbs: vec![],
         }
     }
 }
@@ -118,6 +130,8 @@ enum ControlSubmissionState {
     #[default]
     NoTrbSubmitted,
     ParserConsumedTrb,
+    // Event Data TRB following the Status Stage of a completed transfer.
+    EventData(EventDataTrbData),
     // store address of trb that failed to parse.
     // needs to be specified inside the transfer event indicating the error.
     ParserError(u64),
@@ -130,6 +144,16 @@ impl<RCEH: RealControlEndpointHandle> EndpointHandle for ControlEndpointHandle<R
         Pin<Box<dyn Future<Output = anyhow::Result<TrbProcessingResult>> + Send + 'a>>;
 
     fn submit_trb(&mut self, trb: RawTrb) -> anyhow::Result<()> {
+        if let TransferTrbVariant::EventData(event_data) = TransferTrbVariant::parse(trb.buffer) {
+            self.submission_state = if self.trb_parser.is_idle() {
+                ControlSubmissionState::EventData(event_data)
+            } else {
+                self.event_data_trbs.push(event_data);
+                ControlSubmissionState::ParserConsumedTrb
+            };
+            return Ok(());
+        }
+
         let trb_address = trb.address;
         if let ControlFlow::Break(res) = self.trb_parser.trb(trb) {
             match res {
@@ -161,6 +185,11 @@ impl<RCEH: RealControlEndpointHandle> EndpointHandle for ControlEndpointHandle<R
         Box::pin(async {
             let result = match self.submission_state {
                 ControlSubmissionState::ParserConsumedTrb => TrbProcessingResult::Ok,
+                ControlSubmissionState::EventData(event_data) => {
+                    // the Status Stage transfers no data
+                    self.send_event_data_events(&[event_data], 0)?;
+                    TrbProcessingResult::Ok
+                }
                 ControlSubmissionState::ParserError(trb_address) => {
                     pcap::trb_error(self.pcap_meta, trb_address);
                     let event = EventTrb::new_transfer_event_trb(
@@ -184,6 +213,8 @@ impl<RCEH: RealControlEndpointHandle> EndpointHandle for ControlEndpointHandle<R
                                 debug!("writing data to {data_pointer}");
                                 self.dma_bus.write_bulk(data_pointer, &data);
                             }
+                            let event_data_trbs = mem::take(&mut self.event_data_trbs);
+                            self.send_event_data_events(&event_data_trbs, data.len() as u32)?;
 
                             let event = EventTrb::new_transfer_event_trb(
                                 usb_request.address,
@@ -199,6 +230,7 @@ impl<RCEH: RealControlEndpointHandle> EndpointHandle for ControlEndpointHandle<R
                         }
                         ControlRequestProcessingResult::SuccessfulControlOut => unreachable!(),
                         processing_error => {
+                            self.event_data_trbs.clear();
                             pcap::control_in_error(self.pcap_meta, usb_request, &processing_error);
                             self.handle_processing_error(processing_error, usb_request.address)?
                         }
@@ -216,6 +248,11 @@ impl<RCEH: RealControlEndpointHandle> EndpointHandle for ControlEndpointHandle<R
                                 usb_request.address,
                                 u32::from(usb_request.length),
                             );
+                            let event_data_trbs = mem::take(&mut self.event_data_trbs);
+                            self.send_event_data_events(
+                                &event_data_trbs,
+                                u32::from(usb_request.length),
+                            )?;
                             let event = EventTrb::new_transfer_event_trb(
                                 usb_request.address,
                                 0,
@@ -229,6 +266,7 @@ impl<RCEH: RealControlEndpointHandle> EndpointHandle for ControlEndpointHandle<R
                             TrbProcessingResult::Ok
                         }
                         processing_error => {
+                            self.event_data_trbs.clear();
                             pcap::control_out_error(self.pcap_meta, usb_request, &processing_error);
                             self.handle_processing_error(processing_error, usb_request.address)?
                         }
@@ -241,12 +279,18 @@ impl<RCEH: RealControlEndpointHandle> EndpointHandle for ControlEndpointHandle<R
             Ok(result)
         })
     }
+
+    fn ring_empty(&mut self) -> Self::CompletionFuture<'_> {
+        // the TRBs complete one by one
+        Box::pin(async { Ok(()) })
+    }
 }
 
 impl<RCEH: RealControlEndpointHandle> BaseEndpointHandle for ControlEndpointHandle<RCEH> {
     type CompletionFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
 
     fn cancel(&mut self) -> Self::CompletionFuture<'_> {
+        self.event_data_trbs.clear();
         Box::pin(async { self.real_ep.cancel().await })
     }
 
@@ -256,6 +300,31 @@ impl<RCEH: RealControlEndpointHandle> BaseEndpointHandle for ControlEndpointHand
 }
 
 impl<RCEH: RealControlEndpointHandle> ControlEndpointHandle<RCEH> {
+    // XHCI spec 4.11.5.2: The first Event Data TRB reports the bytes of the
+    // Data Stage, the accumulator is zero for any further one.
+    fn send_event_data_events(
+        &self,
+        event_data_trbs: &[EventDataTrbData],
+        data_stage_length: u32,
+    ) -> anyhow::Result<()> {
+        let mut edtla = data_stage_length;
+        for event_data in event_data_trbs {
+            if event_data.interrupt_on_completion {
+                let event = EventTrb::new_event_data_transfer_event_trb(
+                    event_data.event_data,
+                    edtla,
+                    CompletionCode::Success,
+                    self.endpoint_id,
+                    self.slot_id,
+                );
+                self.event_sender.send(event)?;
+            }
+            edtla = 0;
+        }
+
+        Ok(())
+    }
+
     fn handle_processing_error(
         &self,
         error: ControlRequestProcessingResult,
@@ -336,6 +405,11 @@ enum ControlRequestParserState {
 }
 
 impl ControlRequestParser {
+    /// Whether the parser waits for the Setup Stage of the next transfer.
+    const fn is_idle(&self) -> bool {
+        matches!(self.state, ControlRequestParserState::Initial)
+    }
+
     fn trb(&mut self, trb: RawTrb) -> ControlFlow<Result<UsbRequest, ()>> {
         let transfer_trb = TransferTrbVariant::parse(trb.buffer);
 
@@ -410,6 +484,8 @@ pub struct OutEndpointHandle<ROEH: RealOutEndpointHandle> {
     dma_bus: BusDeviceRef,
     event_sender: EventSender,
     submission_state: NormalSubmissionState,
+    // Event Data Transfer Length Accumulator of the current TD
+    edtla: u32,
 }
 
 impl<ROEH: RealOutEndpointHandle> OutEndpointHandle<ROEH> {
@@ -429,6 +505,7 @@ impl<ROEH: RealOutEndpointHandle> OutEndpointHandle<ROEH> {
             dma_bus,
             event_sender,
             submission_state: NormalSubmissionState::NoTrbSubmitted,
+            edtla: 0,
         }
     }
 }
@@ -438,6 +515,7 @@ enum NormalSubmissionState {
     #[default]
     NoTrbSubmitted,
     UnsupportedTrbType(RawTrb),
+    EventData(EventDataTrbData),
     AwaitingRealTransfer(TransferTrb),
 }
 
@@ -478,6 +556,9 @@ impl<ROEH: RealOutEndpointHandle> EndpointHandle for OutEndpointHandle<ROEH> {
                     variant: transfer_trb,
                 });
             }
+            TransferTrbVariant::EventData(event_data) => {
+                self.submission_state = NormalSubmissionState::EventData(*event_data);
+            }
             _ => self.submission_state = NormalSubmissionState::UnsupportedTrbType(trb),
         }
 
@@ -505,6 +586,22 @@ impl<ROEH: RealOutEndpointHandle> EndpointHandle for OutEndpointHandle<ROEH> {
 
                     TrbProcessingResult::TrbError
                 }
+                NormalSubmissionState::EventData(event_data) => {
+                    // XHCI spec 4.11.5.2
+                    if event_data.interrupt_on_completion {
+                        let transfer_event = EventTrb::new_event_data_transfer_event_trb(
+                            event_data.event_data,
+                            self.edtla,
+                            CompletionCode::Success,
+                            self.endpoint_id,
+                            self.slot_id,
+                        );
+                        self.event_sender.send(transfer_event)?;
+                    }
+                    self.edtla = 0;
+
+                    TrbProcessingResult::Ok
+                }
                 NormalSubmissionState::AwaitingRealTransfer(ref transfer_trb) => {
                     let (completion_code, processing_result) =
                         match self.real_ep.next_completion().await? {
@@ -554,6 +651,13 @@ impl<ROEH: RealOutEndpointHandle> EndpointHandle for OutEndpointHandle<ROEH> {
                                             transfer_trb.address,
                                             normal_data.transfer_length,
                                         );
+                                        // the accumulator starts over with the next TD
+                                        self.edtla = match normal_data.chain {
+                                            true => {
+                                                self.edtla.wrapping_add(normal_data.transfer_length)
+                                            }
+                                            false => 0,
+                                        };
                                         match normal_data.interrupt_on_completion {
                                             true => Some(CompletionCode::Success),
                                             false => None,
@@ -564,6 +668,10 @@ impl<ROEH: RealOutEndpointHandle> EndpointHandle for OutEndpointHandle<ROEH> {
                                 (completion_code, TrbProcessingResult::Ok)
                             }
                         };
+                    if !matches!(processing_result, TrbProcessingResult::Ok) {
+                        // the guest skips the rest of a failed TD
+                        self.edtla = 0;
+                    }
 
                     if let Some(completion_code) = completion_code {
                         let transfer_event = EventTrb::new_transfer_event_trb(
@@ -586,6 +694,11 @@ impl<ROEH: RealOutEndpointHandle> EndpointHandle for OutEndpointHandle<ROEH> {
             Ok(result)
         })
     }
+
+    fn ring_empty(&mut self) -> Self::CompletionFuture<'_> {
+        // the TRBs complete one by one
+        Box::pin(async { Ok(()) })
+    }
 }
 
 impl<ROEH: RealOutEndpointHandle> BaseEndpointHandle for OutEndpointHandle<ROEH> {
@@ -766,6 +879,11 @@ impl<RIEH: RealInEndpointHandle> EndpointHandle for TdBasedInEndpointHandle<RIEH
             }
         })
     }
+
+    fn ring_empty(&mut self) -> Self::CompletionFuture<'_> {
+        // the TRBs complete one by one
+        Box::pin(async { Ok(()) })
+    }
 }
 
 fn process_real_transfer_response(
@@ -789,6 +907,7 @@ fn process_real_transfer_response(
         status: completion.status,
         state: TdProcessingState::Default,
         data: &completion.data,
+        edtla: 0,
         endpoint_id,
         slot_id,
     };
@@ -822,6 +941,8 @@ struct TdProcessingInfo<'a> {
     // updated every TRB
     state: TdProcessingState,
     data: &'a [u8],
+    // Event Data Transfer Length Accumulator
+    edtla: u32,
 }
 
 enum TdProcessingState {
@@ -835,13 +956,38 @@ impl<'a> TdProcessingInfo<'a> {
         &mut self,
         trb: SupportedInEndpointTrb,
     ) -> anyhow::Result<Option<TrbProcessingResult>> {
-        // assumption: Only normal TRBs
         match trb.variant {
             SupportedInEndpointTrbVariant::Normal(data) => {
                 self.process_normal_trb(trb.addr, trb.cycle_bit, data)
             }
-            SupportedInEndpointTrbVariant::EventData(_data) => todo!(),
+            SupportedInEndpointTrbVariant::EventData(data) => self.process_event_data_trb(data),
+        }
+    }
+
+    // XHCI spec 4.11.5.2: Event Data TRBs report the bytes transferred since
+    // the start of the TD or the previous Event Data TRB. After a short
+    // packet, they report the Short Packet completion code.
+    fn process_event_data_trb(
+        &mut self,
+        trb_data: EventDataTrbData,
+    ) -> anyhow::Result<Option<TrbProcessingResult>> {
+        if trb_data.interrupt_on_completion {
+            let completion_code = match self.state {
+                TdProcessingState::Default => CompletionCode::Success,
+                TdProcessingState::ShortTransfer => CompletionCode::ShortPacket,
+            };
+            let transfer_event = EventTrb::new_event_data_transfer_event_trb(
+                trb_data.event_data,
+                self.edtla,
+                completion_code,
+                self.endpoint_id,
+                self.slot_id,
+            );
+            self.event_sender.send(transfer_event)?;
         }
+        self.edtla = 0;
+
+        Ok(None)
     }
 
     fn process_normal_trb(
@@ -859,6 +1005,7 @@ impl<'a> TdProcessingInfo<'a> {
                 let dma_byte_count = bytes_requested.min(bytes_available);
                 let bytes = &self.data[..dma_byte_count];
                 self.data = &self.data[dma_byte_count..];
+                self.edtla = self.edtla.wrapping_add(dma_byte_count as u32);
 
                 debug!(
                     "copying {dma_byte_count} bytes to {:#x}",
@@ -937,8 +1084,8 @@ impl<'a> TdProcessingInfo<'a> {
                 Ok(None)
             }
             TdProcessingState::ShortTransfer => {
-                // Skip all Normal TRBs.
-                // We will need more handling here once we support EventData TRBs.
+                // Skip all Normal TRBs, only Event Data TRBs still generate
+                // events.
                 Ok(None)
             }
         }
@@ -959,9 +1106,137 @@ impl<RIEH: RealInEndpointHandle> BaseEndpointHandle for TdBasedInEndpointHandle<
 
 #[cfg(test)]
 pub mod tests {
+    use std::sync::Arc;
+
     use super::*;
+    use crate::device::{
+        bus::testutils::TestBusDevice,
+        pci::constants::xhci::rings::trb_types,
+        xhci::{interrupter::tests::testutils::MockInterrupter, trb::testutils::RawTrbBuilder},
+    };
+    use testutils::{MockRealInEndpoint, MockRealOutEndpoint};
+
+    fn normal_trb(address: u64, data_pointer: u64, length: u32) -> RawTrbBuilder {
+        RawTrbBuilder::new(address)
+            .with_data_pointer(data_pointer)
+            .with_trb_transfer_length(length)
+            .with_trb_type(trb_types::NORMAL)
+    }
+
+    fn event_data_trb(address: u64, event_data: u64) -> RawTrbBuilder {
+        RawTrbBuilder::new(address)
+            .with_data_pointer(event_data)
+            .with_interrupt_on_completion()
+            .with_trb_type(trb_types::EVENT_DATA)
+    }
+
+    #[tokio::test]
+    async fn in_event_data_trb_reports_transferred_bytes() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let mut handle = TdBasedInEndpointHandle::new(
+            1,
+            3,
+            EndpointPcapMeta::bulk(3, 1, 3),
+            MockRealInEndpoint::new(),
+            ram.clone(),
+            event_sender,
+        );
+
+        for trb in [
+            normal_trb(0x10, 0x80, 16).with_chain().build(),
+            event_data_trb(0x20, 0xcafe).with_chain().build(),
+            normal_trb(0x30, 0x90, 8).with_chain().build(),
+            event_data_trb(0x40, 0xbeef).build(),
+        ] {
+            handle.submit_trb(trb).unwrap();
+            assert!(matches!(
+                handle.next_completion().await.unwrap(),
+                TrbProcessingResult::Ok
+            ));
+        }
+
+        // the accumulator restarts after each Event Data TRB
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_event_data_transfer_event_trb(
+                0xcafe,
+                16,
+                CompletionCode::Success,
+                3,
+                1
+            ))
+        );
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_event_data_transfer_event_trb(
+                0xbeef,
+                8,
+                CompletionCode::Success,
+                3,
+                1
+            ))
+        );
+        assert!(interrupter.is_empty());
+        let mut data = [0; 24];
+        ram.read_bulk(0x80, &mut data);
+        assert_eq!(data, [42; 24]);
+    }
+
+    #[tokio::test]
+    async fn out_event_data_trb_reports_transferred_bytes() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let mut handle = OutEndpointHandle::new(
+            1,
+            4,
+            EndpointPcapMeta::bulk(3, 1, 4),
+            MockRealOutEndpoint::new(),
+            ram,
+            event_sender,
+        );
+
+        for trb in [
+            normal_trb(0x10, 0x80, 16).with_chain().build(),
+            normal_trb(0x20, 0x90, 8).with_chain().build(),
+            event_data_trb(0x30, 0xcafe).build(),
+            // the next TD starts with a fresh accumulator
+            normal_trb(0x40, 0x80, 4).with_chain().build(),
+            event_data_trb(0x50, 0xbeef).build(),
+        ] {
+            handle.submit_trb(trb).unwrap();
+            assert!(matches!(
+                handle.next_completion().await.unwrap(),
+                TrbProcessingResult::Ok
+            ));
+        }
+
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_event_data_transfer_event_trb(
+                0xcafe,
+                24,
+                CompletionCode::Success,
+                4,
+                1
+            ))
+        );
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_event_data_transfer_event_trb(
+                0xbeef,
+                4,
+                CompletionCode::Success,
+                4,
+                1
+            ))
+        );
+        assert!(interrupter.is_empty());
+    }
 
     pub mod testutils {
+        use std::collections::VecDeque;
+
         use super::*;
 
         // will return `vec![42; requested length]`
@@ -1024,14 +1299,16 @@ pub mod tests {
             }
         }
 
-        // will return `vec![42; requested length]`
+        // will return `vec![42; requested length]` for every submission
         #[derive(Debug)]
         pub struct MockRealInEndpoint {
-            data_length: usize,
+            data_lengths: VecDeque<usize>,
         }
         impl MockRealInEndpoint {
             pub fn new() -> Self {
-                Self { data_length: 0 }
+                Self {
+                    data_lengths: VecDeque::new(),
+                }
             }
         }
         impl RealInEndpointHandle for MockRealInEndpoint {
@@ -1039,13 +1316,13 @@ pub mod tests {
                 Pin<Box<dyn Future<Output = anyhow::Result<InTrbProcessingResult>> + Send + 'a>>;
 
             fn submit(&mut self, data: usize) -> anyhow::Result<()> {
-                self.data_length = data;
+                self.data_lengths.push_back(data);
                 Ok(())
             }
 
             fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_> {
                 Box::pin(async {
-                    let data = vec![42; self.data_length];
+                    let data = vec![42; self.data_lengths.pop_front().unwrap_or_default()];
                     let result = InTrbProcessingResult {
                         status: InTrbProcessingStatus::Success,
                         data,
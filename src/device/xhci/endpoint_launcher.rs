@@ -1,15 +1,18 @@
+use std::sync::Arc;
+
 use anyhow::anyhow;
 use tokio::{
     runtime,
     sync::{mpsc, oneshot},
 };
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use crate::{
     device::{
         bus::BusDeviceRef,
         pcap::EndpointPcapMeta,
+        pci::constants::xhci::capability,
         xhci::{
             endpoint::{EndpointSender, EndpointWorker},
             endpoint_handle::{
@@ -17,9 +20,12 @@ use crate::{
             },
             hotplug_endpoint_handle::HotplugEndpointHandleImpl,
             interrupter::EventSender,
+            isoch_endpoint_handle::{IsochInEndpointHandle, IsochOutEndpointHandle},
+            mfindex::MicroframeIndex,
             port::DeviceRetriever,
             real_device::{CompleteRealDevice, RealDevice},
             slot_manager::{EndpointContext, EndpointType},
+            streams::{StreamContext, StreamContextArray, StreamEndpoint},
         },
     },
     oneshot_anyhow::SendWithAnyhowError,
@@ -32,6 +38,7 @@ pub struct EndpointLauncher<CRD: CompleteRealDevice> {
     async_runtime: runtime::Handle,
     dma_bus: BusDeviceRef,
     event_sender: EventSender,
+    mfindex: MicroframeIndex,
 }
 
 #[derive(Debug)]
@@ -40,7 +47,8 @@ pub struct LaunchRequest {
     pub endpoint_id: u8,
     pub root_hub_port: u8,
     pub endpoint_context: EndpointContext,
-    pub responder: oneshot::Sender<EndpointSender>,
+    /// Receives `None` if the device refuses the endpoint.
+    pub responder: oneshot::Sender<Option<EndpointSender>>,
 }
 
 #[derive(Debug, Clone)]
@@ -48,12 +56,14 @@ pub struct LaunchRequester {
     msg_send: mpsc::UnboundedSender<LaunchRequest>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct LaunchArgs {
     slot_id: u8,
     endpoint_id: u8,
     endpoint_context: EndpointContext,
     detach_token: CancellationToken,
+    /// The transfer ring of a stream, if the endpoint uses streams.
+    stream_context: Option<StreamContext>,
 }
 
 impl LaunchRequester {
@@ -63,7 +73,7 @@ impl LaunchRequester {
         endpoint_id: u8,
         root_hub_port: u8,
         endpoint_context: EndpointContext,
-    ) -> anyhow::Result<EndpointSender> {
+    ) -> anyhow::Result<Option<EndpointSender>> {
         let (send, recv) = oneshot::channel();
         let launch_request = LaunchRequest {
             slot_id,
@@ -85,6 +95,7 @@ impl<CRD: CompleteRealDevice> EndpointLauncher<CRD> {
         async_runtime: runtime::Handle,
         dma_bus: BusDeviceRef,
         event_sender: EventSender,
+        mfindex: MicroframeIndex,
     ) -> LaunchRequester {
         let (send, recv) = mpsc::unbounded_channel();
         let launcher = Self {
@@ -93,6 +104,7 @@ impl<CRD: CompleteRealDevice> EndpointLauncher<CRD> {
             async_runtime: async_runtime.clone(),
             dma_bus,
             event_sender,
+            mfindex,
         };
         async_runtime.spawn(launcher.run());
 
@@ -123,8 +135,39 @@ impl<CRD: CompleteRealDevice> EndpointLauncher<CRD> {
                 .await?;
             let endpoint_type = request.endpoint_context.get_endpoint_type();
             debug!("endpoint context specifies endpoint type {endpoint_type:?}");
+            // MaxPStreams is only defined for bulk endpoints.
+            let uses_streams =
+                matches!(endpoint_type, EndpointType::BulkIn | EndpointType::BulkOut)
+                    && request.endpoint_context.get_max_primary_streams() > 0;
 
             let endpoint_sender = match device {
+                Some(_) if matches!(endpoint_type, EndpointType::Unsupported) => {
+                    debug!(
+                        "refusing endpoint {} of slot {} with invalid endpoint type",
+                        request.endpoint_id, request.slot_id
+                    );
+                    None
+                }
+                Some(device)
+                    if !matches!(endpoint_type, EndpointType::Control)
+                        && !device
+                            .realdevice_ref()
+                            .exposes_endpoint(request.endpoint_id) =>
+                {
+                    debug!(
+                        "refusing endpoint {} of slot {}, the device does not expose it",
+                        request.endpoint_id, request.slot_id
+                    );
+                    None
+                }
+                Some(_) if uses_streams && !Self::supports_streams(&request.endpoint_context) => {
+                    debug!(
+                        "refusing endpoint {} of slot {} with unsupported streams",
+                        request.endpoint_id, request.slot_id
+                    );
+                    None
+                }
+                Some(device) if uses_streams && !Self::allocates_streams(&device, &request) => None,
                 Some(device) => {
                     let pcap_usb_bus_number = match device.realdevice_ref().speed() {
                         Some(speed) if speed.is_usb2_speed() => 2,
@@ -136,91 +179,182 @@ impl<CRD: CompleteRealDevice> EndpointLauncher<CRD> {
                         endpoint_id: request.endpoint_id,
                         endpoint_context: request.endpoint_context,
                         detach_token: device.detach_token(),
+                        stream_context: None,
                     };
 
-                    match endpoint_type {
-                    EndpointType::Control => {
-                        let pcap_meta = EndpointPcapMeta::control(
-                            pcap_usb_bus_number,
-                            request.slot_id,
-                            request.endpoint_id,
-                        );
-                        let real_endpoint = device.realdevice_ref().control_endpoint_handle();
-                        self.launch_helper(
-                            ControlEndpointHandle::new,
-                            launch_args,
-                            pcap_meta,
-                            real_endpoint,
-                        )
-                    }
-                    EndpointType::BulkIn => {
-                        let pcap_meta = EndpointPcapMeta::bulk(
-                            pcap_usb_bus_number,
-                            request.slot_id,
-                            request.endpoint_id,
-                        );
-                        let real_endpoint = device
-                            .realdevice_ref()
-                            .bulk_in_endpoint_handle(request.endpoint_id);
-                        self.launch_helper(
-                            TdBasedInEndpointHandle::new,
-                            launch_args,
-                            pcap_meta,
-                            real_endpoint,
-                        )
-                    }
-                    EndpointType::BulkOut => {
-                        let pcap_meta = EndpointPcapMeta::bulk(
-                            pcap_usb_bus_number,
-                            request.slot_id,
-                            request.endpoint_id,
-                        );
-                        let real_endpoint = device
-                            .realdevice_ref()
-                            .bulk_out_endpoint_handle(request.endpoint_id);
-                        self.launch_helper(
-                            OutEndpointHandle::new,
-                            launch_args,
-                            pcap_meta,
-                            real_endpoint,
-                        )
-                    }
-                    EndpointType::InterruptIn => {
-                        let pcap_meta = EndpointPcapMeta::interrupt(
-                            pcap_usb_bus_number,
-                            request.slot_id,
-                            request.endpoint_id,
-                        );
-                        let real_endpoint = device
-                            .realdevice_ref()
-                            .interrupt_in_endpoint_handle(request.endpoint_id);
-                        self.launch_helper(
-                            TdBasedInEndpointHandle::new,
-                            launch_args,
-                            pcap_meta,
-                            real_endpoint,
-                        )
-                    }
-                    EndpointType::InterruptOut => {
-                        let pcap_meta = EndpointPcapMeta::interrupt(
-                            pcap_usb_bus_number,
-                            request.slot_id,
-                            request.endpoint_id,
-                        );
-                        let real_endpoint = device
-                            .realdevice_ref()
-                            .interrupt_out_endpoint_handle(request.endpoint_id);
-                        self.launch_helper(
-                            OutEndpointHandle::new,
-                            launch_args,
-                            pcap_meta,
-                            real_endpoint,
-                        )
-                    }
-                    EndpointType::Unsupported => unreachable!(
-                        "the slot should early-reject configure endpoint commands with unsupported endpoint types"
-                    ),
-                    }
+                    Some(match endpoint_type {
+                        EndpointType::Control => {
+                            let pcap_meta = EndpointPcapMeta::control(
+                                pcap_usb_bus_number,
+                                request.slot_id,
+                                request.endpoint_id,
+                            );
+                            let real_endpoint = device.realdevice_ref().control_endpoint_handle();
+                            self.launch_helper(
+                                ControlEndpointHandle::new,
+                                launch_args,
+                                pcap_meta,
+                                real_endpoint,
+                            )
+                        }
+                        EndpointType::BulkIn if uses_streams => {
+                            let pcap_meta = EndpointPcapMeta::bulk(
+                                pcap_usb_bus_number,
+                                request.slot_id,
+                                request.endpoint_id,
+                            );
+                            self.launch_streams_helper(
+                                TdBasedInEndpointHandle::new,
+                                launch_args,
+                                pcap_meta,
+                                &device,
+                                CRD::RD::bulk_in_stream_handle,
+                            )
+                        }
+                        EndpointType::BulkOut if uses_streams => {
+                            let pcap_meta = EndpointPcapMeta::bulk(
+                                pcap_usb_bus_number,
+                                request.slot_id,
+                                request.endpoint_id,
+                            );
+                            self.launch_streams_helper(
+                                OutEndpointHandle::new,
+                                launch_args,
+                                pcap_meta,
+                                &device,
+                                CRD::RD::bulk_out_stream_handle,
+                            )
+                        }
+                        EndpointType::BulkIn => {
+                            let pcap_meta = EndpointPcapMeta::bulk(
+                                pcap_usb_bus_number,
+                                request.slot_id,
+                                request.endpoint_id,
+                            );
+                            let real_endpoint = device
+                                .realdevice_ref()
+                                .bulk_in_endpoint_handle(request.endpoint_id);
+                            self.launch_helper(
+                                TdBasedInEndpointHandle::new,
+                                launch_args,
+                                pcap_meta,
+                                real_endpoint,
+                            )
+                        }
+                        EndpointType::BulkOut => {
+                            let pcap_meta = EndpointPcapMeta::bulk(
+                                pcap_usb_bus_number,
+                                request.slot_id,
+                                request.endpoint_id,
+                            );
+                            let real_endpoint = device
+                                .realdevice_ref()
+                                .bulk_out_endpoint_handle(request.endpoint_id);
+                            self.launch_helper(
+                                OutEndpointHandle::new,
+                                launch_args,
+                                pcap_meta,
+                                real_endpoint,
+                            )
+                        }
+                        EndpointType::InterruptIn => {
+                            let pcap_meta = EndpointPcapMeta::interrupt(
+                                pcap_usb_bus_number,
+                                request.slot_id,
+                                request.endpoint_id,
+                            );
+                            let real_endpoint = device
+                                .realdevice_ref()
+                                .interrupt_in_endpoint_handle(request.endpoint_id);
+                            self.launch_helper(
+                                TdBasedInEndpointHandle::new,
+                                launch_args,
+                                pcap_meta,
+                                real_endpoint,
+                            )
+                        }
+                        EndpointType::InterruptOut => {
+                            let pcap_meta = EndpointPcapMeta::interrupt(
+                                pcap_usb_bus_number,
+                                request.slot_id,
+                                request.endpoint_id,
+                            );
+                            let real_endpoint = device
+                                .realdevice_ref()
+                                .interrupt_out_endpoint_handle(request.endpoint_id);
+                            self.launch_helper(
+                                OutEndpointHandle::new,
+                                launch_args,
+                                pcap_meta,
+                                real_endpoint,
+                            )
+                        }
+                        EndpointType::IsochIn => {
+                            let pcap_meta = EndpointPcapMeta::isochronous(
+                                pcap_usb_bus_number,
+                                request.slot_id,
+                                request.endpoint_id,
+                            );
+                            let real_endpoint = device
+                                .realdevice_ref()
+                                .isoch_in_endpoint_handle(request.endpoint_id);
+                            let mfindex = self.mfindex.clone();
+                            self.launch_helper(
+                                |slot_id,
+                                 endpoint_id,
+                                 pcap_meta,
+                                 real_ep,
+                                 dma_bus,
+                                 event_sender| {
+                                    IsochInEndpointHandle::new(
+                                        slot_id,
+                                        endpoint_id,
+                                        pcap_meta,
+                                        real_ep,
+                                        dma_bus,
+                                        event_sender,
+                                        mfindex,
+                                    )
+                                },
+                                launch_args,
+                                pcap_meta,
+                                real_endpoint,
+                            )
+                        }
+                        EndpointType::IsochOut => {
+                            let pcap_meta = EndpointPcapMeta::isochronous(
+                                pcap_usb_bus_number,
+                                request.slot_id,
+                                request.endpoint_id,
+                            );
+                            let real_endpoint = device
+                                .realdevice_ref()
+                                .isoch_out_endpoint_handle(request.endpoint_id);
+                            let mfindex = self.mfindex.clone();
+                            self.launch_helper(
+                                |slot_id,
+                                 endpoint_id,
+                                 pcap_meta,
+                                 real_ep,
+                                 dma_bus,
+                                 event_sender| {
+                                    IsochOutEndpointHandle::new(
+                                        slot_id,
+                                        endpoint_id,
+                                        pcap_meta,
+                                        real_ep,
+                                        dma_bus,
+                                        event_sender,
+                                        mfindex,
+                                    )
+                                },
+                                launch_args,
+                                pcap_meta,
+                                real_endpoint,
+                            )
+                        }
+                        EndpointType::Unsupported => unreachable!("refused above"),
+                    })
                 }
                 // unlikely edge case: The device was very recently detached, we are now handling an address device/configure endpoint command
                 // the endpoint does not depend on the real device, so we need the address device/configure endpoint to succeed (while also not
@@ -233,12 +367,12 @@ impl<CRD: CompleteRealDevice> EndpointLauncher<CRD> {
                         self.event_sender.clone(),
                     );
 
-                    EndpointWorker::launch(
+                    Some(EndpointWorker::launch(
                         &self.async_runtime,
                         self.dma_bus.clone(),
                         hotplug_endpoint_handle,
                         request.endpoint_context,
-                    )
+                    ))
                 }
             };
 
@@ -253,6 +387,30 @@ impl<CRD: CompleteRealDevice> EndpointLauncher<CRD> {
             .ok_or_else(|| anyhow!("channel should never close"))
     }
 
+    /// Check whether we can run an endpoint with streams.
+    ///
+    /// The real device must support streams, and the Primary Stream Context
+    /// Array must match what we advertise in HCCPARAMS1.
+    fn supports_streams(endpoint_context: &EndpointContext) -> bool {
+        CRD::RD::SUPPORTS_STREAMS
+            && u64::from(endpoint_context.get_max_primary_streams()) <= capability::MAX_PSA_SIZE
+            && endpoint_context.has_linear_stream_array()
+    }
+
+    /// Let the real device allocate the streams of the endpoint.
+    fn allocates_streams(device: &CRD, request: &LaunchRequest) -> bool {
+        device
+            .realdevice_ref()
+            .alloc_streams(request.endpoint_id)
+            .inspect_err(|e| {
+                warn!(
+                    "refusing endpoint {} of slot {}, failed to allocate streams: {e}",
+                    request.endpoint_id, request.slot_id
+                );
+            })
+            .is_ok()
+    }
+
     fn launch_helper<RealEndpoint, Endpoint, EndpointConstructor>(
         &self,
         constructor: EndpointConstructor,
@@ -265,28 +423,254 @@ impl<CRD: CompleteRealDevice> EndpointLauncher<CRD> {
         EndpointConstructor:
             FnOnce(u8, u8, EndpointPcapMeta, RealEndpoint, BusDeviceRef, EventSender) -> Endpoint,
     {
-        let endpoint_handle = constructor(
-            launch_args.slot_id,
-            launch_args.endpoint_id,
-            pcap_meta,
-            real_endpoint,
+        launch_worker(
+            &self.async_runtime,
             self.dma_bus.clone(),
             self.event_sender.clone(),
-        );
-        let hotplug_endpoint_handle = HotplugEndpointHandleImpl::new(
-            launch_args.slot_id,
-            launch_args.endpoint_id,
-            endpoint_handle,
-            self.event_sender.clone(),
-            launch_args.detach_token,
-            &self.async_runtime,
-        );
+            constructor,
+            launch_args,
+            pcap_meta,
+            real_endpoint,
+        )
+    }
+
+    // Like launch_helper, but launches a worker per stream once the guest
+    // starts using the stream.
+    fn launch_streams_helper<RealEndpoint, Endpoint, EndpointConstructor, RealEndpointGetter>(
+        &self,
+        constructor: EndpointConstructor,
+        launch_args: LaunchArgs,
+        pcap_meta: EndpointPcapMeta,
+        device: &Arc<CRD>,
+        real_endpoint: RealEndpointGetter,
+    ) -> EndpointSender
+    where
+        Endpoint: EndpointHandle,
+        EndpointConstructor: Fn(u8, u8, EndpointPcapMeta, RealEndpoint, BusDeviceRef, EventSender) -> Endpoint
+            + Send
+            + 'static,
+        RealEndpointGetter: Fn(&CRD::RD, u8, u16) -> RealEndpoint + Send + 'static,
+    {
+        let stream_contexts =
+            StreamContextArray::new(&launch_args.endpoint_context, self.dma_bus.clone());
+        // do not keep the device alive after a detach
+        let device = Arc::downgrade(device);
+        let async_runtime = self.async_runtime.clone();
+        let dma_bus = self.dma_bus.clone();
+        let event_sender = self.event_sender.clone();
+        let endpoint_context = launch_args.endpoint_context.clone();
 
-        EndpointWorker::launch(
+        StreamEndpoint::launch(
             &self.async_runtime,
-            self.dma_bus.clone(),
+            endpoint_context,
+            stream_contexts,
+            move |stream_id, stream_context| match device.upgrade() {
+                Some(device) => launch_worker(
+                    &async_runtime,
+                    dma_bus.clone(),
+                    event_sender.clone(),
+                    &constructor,
+                    LaunchArgs {
+                        stream_context: Some(stream_context),
+                        ..launch_args.clone()
+                    },
+                    pcap_meta,
+                    real_endpoint(device.realdevice_ref(), launch_args.endpoint_id, stream_id),
+                ),
+                None => {
+                    debug!("Could not get real device, using dummy endpoint handle");
+                    let hotplug_endpoint_handle = HotplugEndpointHandleImpl::dummy(
+                        launch_args.slot_id,
+                        launch_args.endpoint_id,
+                        event_sender.clone(),
+                    );
+
+                    EndpointWorker::launch_stream(
+                        &async_runtime,
+                        dma_bus.clone(),
+                        hotplug_endpoint_handle,
+                        launch_args.endpoint_context.clone(),
+                        stream_context,
+                    )
+                }
+            },
+        )
+    }
+}
+
+fn launch_worker<RealEndpoint, Endpoint, EndpointConstructor>(
+    async_runtime: &runtime::Handle,
+    dma_bus: BusDeviceRef,
+    event_sender: EventSender,
+    constructor: EndpointConstructor,
+    launch_args: LaunchArgs,
+    pcap_meta: EndpointPcapMeta,
+    real_endpoint: RealEndpoint,
+) -> EndpointSender
+where
+    Endpoint: EndpointHandle,
+    EndpointConstructor:
+        FnOnce(u8, u8, EndpointPcapMeta, RealEndpoint, BusDeviceRef, EventSender) -> Endpoint,
+{
+    let endpoint_handle = constructor(
+        launch_args.slot_id,
+        launch_args.endpoint_id,
+        pcap_meta,
+        real_endpoint,
+        dma_bus.clone(),
+        event_sender.clone(),
+    );
+    let hotplug_endpoint_handle = HotplugEndpointHandleImpl::new(
+        launch_args.slot_id,
+        launch_args.endpoint_id,
+        endpoint_handle,
+        event_sender,
+        launch_args.detach_token,
+        async_runtime,
+    );
+
+    match launch_args.stream_context {
+        Some(stream_context) => EndpointWorker::launch_stream(
+            async_runtime,
+            dma_bus,
             hotplug_endpoint_handle,
             launch_args.endpoint_context,
-        )
+            stream_context,
+        ),
+        None => EndpointWorker::launch(
+            async_runtime,
+            dma_bus,
+            hotplug_endpoint_handle,
+            launch_args.endpoint_context,
+        ),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use tokio::runtime::Handle;
+
+    use crate::device::{
+        bus::testutils::TestBusDevice,
+        pci::constants::xhci::device_slots::endpoint_state,
+        xhci::{
+            interrupter::tests::testutils::MockInterrupter,
+            port::PortArray,
+            real_device::{
+                tests::testutils::MockRealDevice, CompleteRealDeviceImpl, HostDeviceInfo,
+            },
+        },
+    };
+
+    use super::*;
+
+    /// Request the launch of an endpoint of a device attached to a
+    /// controller. The endpoint context is at address 0 of the returned
+    /// memory.
+    async fn launch_endpoint(
+        real_device: MockRealDevice,
+        endpoint_id: u8,
+        write_context: impl FnOnce(&TestBusDevice),
+    ) -> (Option<EndpointSender>, Arc<TestBusDevice>) {
+        let async_runtime = Handle::current();
+        let (event_sender, _interrupter) = MockInterrupter::new();
+        let port_array = PortArray::new(event_sender.clone(), async_runtime.clone());
+        let host_info = HostDeviceInfo {
+            usb_id: "1234:5678".parse().unwrap(),
+            port_path: None,
+        };
+        port_array
+            .create_hotplug_control()
+            .attach(
+                CompleteRealDeviceImpl::new((1, 1), host_info, real_device),
+                None,
+            )
+            .await;
+        let port_id = port_array.create_hotplug_control().list_ports().await[0].port_id;
+
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        write_context(&ram);
+        let launch_requester = EndpointLauncher::start(
+            port_array.create_device_retriever(),
+            async_runtime,
+            ram.clone(),
+            event_sender,
+            MicroframeIndex::default(),
+        );
+
+        let endpoint_sender = launch_requester
+            .request_launch(
+                1,
+                endpoint_id,
+                port_id,
+                EndpointContext::new(0, ram.clone()),
+            )
+            .await
+            .unwrap();
+
+        (endpoint_sender, ram)
+    }
+
+    /// Request the launch of a bulk OUT endpoint with streams.
+    async fn launch_stream_endpoint(real_device: MockRealDevice) -> Option<EndpointSender> {
+        let (endpoint_sender, _) = launch_endpoint(real_device, 4, |ram| {
+            // MaxPStreams = 1, LSA = 1, Bulk OUT, Primary Stream Context Array at 0x100
+            ram.write_bulk(1, &[0x84]);
+            ram.write_bulk(4, &[2 << 3]);
+            ram.write_bulk(8, &0x100u64.to_le_bytes());
+        })
+        .await;
+
+        endpoint_sender
+    }
+
+    #[tokio::test]
+    async fn endpoints_with_streams_launch() {
+        assert!(launch_stream_endpoint(MockRealDevice::default())
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn control_endpoints_take_over_the_max_packet_size() {
+        let (endpoint_sender, ram) = launch_endpoint(MockRealDevice::default(), 1, |ram| {
+            // Control, MPS 8, transfer ring at 0x100
+            ram.write_bulk(4, &[4 << 3, 0, 8, 0]);
+            ram.write_bulk(8, &0x101u64.to_le_bytes());
+        })
+        .await;
+        let endpoint_sender = endpoint_sender.unwrap();
+
+        endpoint_sender.set_max_packet_size(64).await.unwrap();
+
+        // the worker keeps running on its transfer ring
+        let mut state = [0];
+        ram.read_bulk(0, &mut state);
+        assert_eq!(state[0], endpoint_state::RUNNING);
+        let context = EndpointContext::new(0, ram);
+        assert_eq!(context.get_max_packet_size(), 64);
+        assert_eq!(context.get_dequeue_pointer_and_cycle_state(), (0x100, true));
+        endpoint_sender.terminate().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoints_are_refused_if_streams_cannot_be_allocated() {
+        let real_device = MockRealDevice::default();
+        real_device.fail_stream_allocation();
+
+        assert!(launch_stream_endpoint(real_device).await.is_none());
+    }
+
+    pub mod testutils {
+        use super::*;
+
+        /// Create a `LaunchRequester` whose requests end up in the returned
+        /// receiver instead of at a running launcher.
+        pub fn mock_launch_requester() -> (LaunchRequester, mpsc::UnboundedReceiver<LaunchRequest>)
+        {
+            let (msg_send, msg_recv) = mpsc::unbounded_channel();
+
+            (LaunchRequester { msg_send }, msg_recv)
+        }
     }
 }
@@ -52,6 +52,15 @@ pub struct EventRing {
     cycle_state: bool,
 }
 
+/// The enqueue state of an Event Ring captured by Save State.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventRingState {
+    enqueue_pointer: u64,
+    trb_count: u32,
+    erst_count: u32,
+    cycle_state: bool,
+}
+
 impl EventRing {
     /// Create a new Event Ring.
     ///
@@ -76,6 +85,27 @@ impl EventRing {
         self.cycle_state = false;
     }
 
+    /// Capture the enqueue state for Save State.
+    pub const fn save(&self) -> EventRingState {
+        EventRingState {
+            enqueue_pointer: self.enqueue_pointer,
+            trb_count: self.trb_count,
+            erst_count: self.erst_count,
+            cycle_state: self.cycle_state,
+        }
+    }
+
+    /// Continue at the enqueue state captured by [`Self::save`].
+    ///
+    /// Unlike [`Self::configure`], the restored ring continues where it
+    /// stopped, so the driver's dequeue pointer stays valid.
+    pub const fn restore(&mut self, state: EventRingState) {
+        self.enqueue_pointer = state.enqueue_pointer;
+        self.trb_count = state.trb_count;
+        self.erst_count = state.erst_count;
+        self.cycle_state = state.cycle_state;
+    }
+
     /// Configure the Event Ring.
     ///
     /// Call this function when the driver writes to the ERSTBA register (as
@@ -426,6 +456,26 @@ mod tests {
         assert!(!ring.cycle_state);
     }
 
+    #[test]
+    fn event_ring_restore_continues_at_saved_enqueue_pointer() {
+        let (ram, mut ring, mut reg) = init_ram_and_ring_and_registers();
+
+        for _ in 0..3 {
+            ring.enqueue(&dummy_trb(), reg.erstba, reg.erstsz, reg.erdp);
+        }
+        reg.erdp = 0x30 + 32;
+        let saved = ring.save();
+
+        // configuring the ring again would start over at segment 0
+        ring.reset();
+        ring.configure(reg.erstba, reg.erstsz);
+        ring.restore(saved);
+        assert_eq!(ring.save(), saved);
+
+        ring.enqueue(&dummy_trb(), reg.erstba, reg.erstsz, reg.erdp);
+        assert_trb_written(&ram, 0x60, true);
+    }
+
     #[test]
     #[should_panic(expected = "ERSTSZ must be set before ERSTBA")]
     fn configure_requires_erstsz_first() {
@@ -51,6 +51,7 @@ pub trait HotplugEndpointHandle: BaseEndpointHandle {
 
     fn submit_trb(&mut self, trb: RawTrb) -> anyhow::Result<()>;
     fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_>;
+    fn ring_empty(&mut self) -> Self::CompletionFuture<'_>;
 }
 
 #[derive(Debug)]
@@ -201,6 +202,22 @@ impl<EH: EndpointHandle> HotplugEndpointHandle for HotplugEndpointHandleImpl<EH>
             Ok(result)
         })
     }
+
+    fn ring_empty(&mut self) -> Self::CompletionFuture<'_> {
+        Box::pin(async {
+            if let Ok(mut guard) = self.endpoint_handle.try_lock() {
+                if let Some(device) = guard.as_mut() {
+                    select! {
+                        result = device.ring_empty() => result?,
+                        // the detach handler waits for the lock
+                        _ = self.notify_detach.cancelled() => {}
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
 }
 
 impl<EH: EndpointHandle> BaseEndpointHandle for HotplugEndpointHandleImpl<EH> {
@@ -0,0 +1,250 @@
+//! Partial passthrough of composite devices.
+//!
+//! When only some interfaces of a device are passed through, the host keeps
+//! the others. The guest must not learn about them: configuration
+//! descriptors are rewritten to only contain the selected interfaces, and
+//! control requests to other interfaces are refused.
+
+use anyhow::{anyhow, Result};
+
+use crate::device::xhci::usbrequest::UsbRequest;
+
+const REQUEST_GET_DESCRIPTOR: u8 = 6;
+
+const DESCRIPTOR_TYPE_CONFIGURATION: u8 = 2;
+const DESCRIPTOR_TYPE_INTERFACE: u8 = 4;
+const DESCRIPTOR_TYPE_OTHER_SPEED_CONFIGURATION: u8 = 7;
+const DESCRIPTOR_TYPE_INTERFACE_ASSOCIATION: u8 = 11;
+
+/// Length of the configuration descriptor itself, without the interface
+/// and endpoint descriptors following it.
+const CONFIGURATION_DESCRIPTOR_LENGTH: usize = 9;
+
+/// The interfaces of a device that are passed through to the guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceFilter {
+    interfaces: Vec<u8>,
+}
+
+impl InterfaceFilter {
+    pub const fn new(interfaces: Vec<u8>) -> Self {
+        Self { interfaces }
+    }
+
+    pub fn allows(&self, interface_number: u8) -> bool {
+        self.interfaces.contains(&interface_number)
+    }
+
+    /// Check that the device has all interfaces to pass through.
+    pub fn check(&self, interface_numbers: &[u8]) -> Result<()> {
+        if self.interfaces.is_empty() {
+            return Err(anyhow!("No interfaces selected for passthrough"));
+        }
+        if let Some(missing) = self
+            .interfaces
+            .iter()
+            .find(|interface| !interface_numbers.contains(interface))
+        {
+            return Err(anyhow!("The device has no interface {missing}"));
+        }
+
+        Ok(())
+    }
+
+    /// Check whether the request reads a configuration descriptor, which
+    /// the guest must only get through [`Self::filter_configuration`].
+    pub const fn is_configuration_request(request: &UsbRequest) -> bool {
+        let descriptor_type = (request.value >> 8) as u8;
+
+        request.request_type == 0x80
+            && request.request == REQUEST_GET_DESCRIPTOR
+            && matches!(
+                descriptor_type,
+                DESCRIPTOR_TYPE_CONFIGURATION | DESCRIPTOR_TYPE_OTHER_SPEED_CONFIGURATION
+            )
+    }
+
+    /// The interface an interface-recipient request is addressed to.
+    pub const fn target_interface(request: &UsbRequest) -> Option<u8> {
+        match request.request_type & 0x1f {
+            1 => Some(request.index as u8),
+            _ => None,
+        }
+    }
+
+    /// The endpoint address an endpoint-recipient request is addressed to.
+    /// Requests to the default control endpoint yield `None`.
+    pub const fn target_endpoint(request: &UsbRequest) -> Option<u8> {
+        match (request.request_type & 0x1f, request.index as u8) {
+            (2, address) if address & 0x0f != 0 => Some(address),
+            _ => None,
+        }
+    }
+
+    /// Remove all interfaces that are not passed through from a
+    /// configuration descriptor and the descriptors following it.
+    ///
+    /// Class-specific and endpoint descriptors belong to the interface
+    /// preceding them. Interface associations are only kept if all their
+    /// interfaces are passed through. Truncated descriptors end the
+    /// configuration.
+    pub fn filter_configuration(&self, configuration: &[u8]) -> Vec<u8> {
+        if configuration.len() < CONFIGURATION_DESCRIPTOR_LENGTH {
+            return configuration.to_vec();
+        }
+
+        let mut filtered = configuration[..CONFIGURATION_DESCRIPTOR_LENGTH].to_vec();
+        let mut interfaces = Vec::new();
+        let mut keep = true;
+        let mut rest = &configuration[CONFIGURATION_DESCRIPTOR_LENGTH..];
+
+        while let [length, descriptor_type, ..] = *rest {
+            let length = length as usize;
+            if length < 2 || length > rest.len() {
+                break;
+            }
+            let (descriptor, remainder) = rest.split_at(length);
+            rest = remainder;
+
+            let include = match (descriptor_type, descriptor) {
+                (DESCRIPTOR_TYPE_INTERFACE, [_, _, interface_number, ..]) => {
+                    keep = self.allows(*interface_number);
+                    if keep && !interfaces.contains(interface_number) {
+                        interfaces.push(*interface_number);
+                    }
+                    keep
+                }
+                (DESCRIPTOR_TYPE_INTERFACE_ASSOCIATION, [_, _, first, count, ..]) => {
+                    (*first..first.saturating_add(*count)).all(|interface| self.allows(interface))
+                }
+                _ => keep,
+            };
+            if include {
+                filtered.extend_from_slice(descriptor);
+            }
+        }
+
+        let total_length = u16::try_from(filtered.len()).unwrap_or(u16::MAX);
+        filtered[2..4].copy_from_slice(&total_length.to_le_bytes());
+        filtered[4] = interfaces.len() as u8;
+
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(number: u8, alt_setting: u8) -> Vec<u8> {
+        vec![9, 4, number, alt_setting, 1, 3, 0, 0, 0]
+    }
+
+    fn endpoint(address: u8) -> Vec<u8> {
+        vec![7, 5, address, 3, 8, 0, 10]
+    }
+
+    fn configuration(descriptors: &[Vec<u8>], num_interfaces: u8) -> Vec<u8> {
+        let mut configuration = vec![9, 2, 0, 0, num_interfaces, 1, 0, 0x80, 50];
+        for descriptor in descriptors {
+            configuration.extend_from_slice(descriptor);
+        }
+        let total_length = configuration.len() as u16;
+        configuration[2..4].copy_from_slice(&total_length.to_le_bytes());
+        configuration
+    }
+
+    #[test]
+    fn filter_configuration_keeps_selected_interfaces() {
+        let association = vec![8, 11, 1, 2, 1, 0, 0, 0];
+        let audio_class = vec![5, 0x24, 1, 0, 1];
+        let full = configuration(
+            &[
+                interface(0, 0),
+                endpoint(0x81),
+                association.clone(),
+                interface(1, 0),
+                audio_class.clone(),
+                interface(2, 0),
+                interface(2, 1),
+                endpoint(0x02),
+            ],
+            3,
+        );
+
+        let filter = InterfaceFilter::new(vec![1, 2]);
+        assert_eq!(
+            filter.filter_configuration(&full),
+            configuration(
+                &[
+                    association,
+                    interface(1, 0),
+                    audio_class,
+                    interface(2, 0),
+                    interface(2, 1),
+                    endpoint(0x02),
+                ],
+                2,
+            )
+        );
+
+        // The association is incomplete without interface 2.
+        let filter = InterfaceFilter::new(vec![0, 1]);
+        assert_eq!(
+            filter.filter_configuration(&full),
+            configuration(
+                &[
+                    interface(0, 0),
+                    endpoint(0x81),
+                    interface(1, 0),
+                    vec![5, 0x24, 1, 0, 1],
+                ],
+                2,
+            )
+        );
+
+        // Truncated descriptors are dropped.
+        let filter = InterfaceFilter::new(vec![0]);
+        assert_eq!(
+            filter.filter_configuration(&full[..15]),
+            configuration(&[], 0)
+        );
+    }
+
+    #[test]
+    fn requests_are_classified() {
+        let request = |request_type, request, value, index| UsbRequest {
+            request_type,
+            request,
+            value,
+            index,
+            ..UsbRequest::default()
+        };
+
+        assert!(InterfaceFilter::is_configuration_request(&request(
+            0x80, 6, 0x0200, 0
+        )));
+        assert!(InterfaceFilter::is_configuration_request(&request(
+            0x80, 6, 0x0701, 0
+        )));
+        assert!(!InterfaceFilter::is_configuration_request(&request(
+            0x80, 6, 0x0100, 0
+        )));
+        assert_eq!(
+            InterfaceFilter::target_interface(&request(0x21, 0x0a, 0, 2)),
+            Some(2)
+        );
+        assert_eq!(
+            InterfaceFilter::target_interface(&request(0x80, 6, 0x0100, 0)),
+            None
+        );
+        assert_eq!(
+            InterfaceFilter::target_endpoint(&request(0x02, 1, 0, 0x81)),
+            Some(0x81)
+        );
+        assert_eq!(
+            InterfaceFilter::target_endpoint(&request(0x02, 1, 0, 0x80)),
+            None
+        );
+    }
+}
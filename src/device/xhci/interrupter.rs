@@ -7,7 +7,8 @@ use crate::device::bus::BusDeviceRef;
 use crate::device::interrupt_line::{DummyInterruptLine, InterruptLine};
 use crate::device::pci::constants::xhci::runtime::IMOD_DEFAULT;
 use crate::device::xhci::controller_reset::ResetSender;
-use crate::device::xhci::event_ring::EventRing;
+use crate::device::xhci::controller_state::StateSender;
+use crate::device::xhci::event_ring::{EventRing, EventRingState};
 use crate::device::xhci::registers::{ErstbaRegister, GenericRwRegister};
 use crate::device::xhci::trb::EventTrb;
 use crate::oneshot_anyhow::SendWithAnyhowError;
@@ -34,6 +35,27 @@ impl ResetSender for InterrupterResetSender {
     }
 }
 
+#[derive(Debug)]
+pub struct InterrupterStateSender {
+    msg_sender: mpsc::UnboundedSender<InterrupterMessage>,
+}
+
+impl StateSender for InterrupterStateSender {
+    fn send_save(&self, completion_notifier: oneshot::Sender<()>) -> anyhow::Result<()> {
+        self.msg_sender
+            .send(InterrupterMessage::SaveState(completion_notifier))?;
+
+        Ok(())
+    }
+
+    fn send_restore(&self, completion_notifier: oneshot::Sender<bool>) -> anyhow::Result<()> {
+        self.msg_sender
+            .send(InterrupterMessage::RestoreState(completion_notifier))?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct InterrupterRegisters {
     /// IMAN: Interrupt management register
@@ -68,6 +90,8 @@ struct EventWorker {
     msg_recv: mpsc::UnboundedReceiver<InterrupterMessage>,
     interrupt_line: Arc<dyn InterruptLine>,
     event_ring: EventRing,
+    // the enqueue state captured by Save State
+    saved_state: Option<EventRingState>,
 }
 
 #[derive(Debug)]
@@ -75,6 +99,17 @@ enum InterrupterMessage {
     SendEvent(EventTrb),
     UpdateInterruptLine(Arc<dyn InterruptLine>),
     Reset(oneshot::Sender<()>),
+    SaveState(oneshot::Sender<()>),
+    RestoreState(oneshot::Sender<bool>),
+}
+
+/// How the event ring left the unconfigured state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EventRingSetup {
+    /// The driver wrote ERSTBA.
+    Configure,
+    /// Restore State continued a saved event ring.
+    Restored,
 }
 
 #[derive(Debug, Clone)]
@@ -113,6 +148,7 @@ impl Interrupter {
             msg_recv,
             interrupt_line: Arc::new(DummyInterruptLine::default()),
             event_ring,
+            saved_state: None,
         };
 
         async_runtime.spawn(worker.run());
@@ -138,6 +174,12 @@ impl Interrupter {
             msg_sender: self.msg_sender.clone(),
         }
     }
+
+    pub fn state_sender(&self) -> InterrupterStateSender {
+        InterrupterStateSender {
+            msg_sender: self.msg_sender.clone(),
+        }
+    }
 }
 
 impl EventWorker {
@@ -161,11 +203,12 @@ impl EventWorker {
         // Each ERSTBA write configures a new event ring. A host controller reset
         // clears that configuration and sends us back to waiting for ERSTBA.
         loop {
-            self.wait_for_event_ring_configuration().await?;
-            self.event_ring.configure(
-                self.registers.erst_base_address.erstba(),
-                self.registers.erst_size.read() as u32,
-            );
+            if self.wait_for_event_ring_configuration().await? == EventRingSetup::Configure {
+                self.event_ring.configure(
+                    self.registers.erst_base_address.erstba(),
+                    self.registers.erst_size.read() as u32,
+                );
+            }
 
             self.run_configured().await?;
         }
@@ -173,10 +216,10 @@ impl EventWorker {
 
     // The first ERSTBA write starts the event ring. Drop events that happen
     // before configuration, but keep processing control messages.
-    async fn wait_for_event_ring_configuration(&mut self) -> anyhow::Result<()> {
+    async fn wait_for_event_ring_configuration(&mut self) -> anyhow::Result<EventRingSetup> {
         loop {
             select! {
-                _ = self.registers.erst_base_address.write_notification() => return Ok(()),
+                _ = self.registers.erst_base_address.write_notification() => return Ok(EventRingSetup::Configure),
                 // we cannot use self.next_msg() here because it borrows self mutable, clashing
                 // with the borrow of self.registers above
                 msg = self.msg_recv.recv() => match msg.ok_or_else(|| anyhow!("event channel closed"))? {
@@ -186,6 +229,18 @@ impl EventWorker {
                         self.reset();
                         completion.send_anyhow(())?;
                     }
+                    // there is no event ring to save
+                    InterrupterMessage::SaveState(completion) => {
+                        self.saved_state = None;
+                        completion.send_anyhow(())?;
+                    }
+                    InterrupterMessage::RestoreState(completion) => {
+                        let restored = self.restore_state();
+                        completion.send_anyhow(restored)?;
+                        if restored {
+                            return Ok(EventRingSetup::Restored);
+                        }
+                    }
                 },
             }
         }
@@ -215,12 +270,32 @@ impl EventWorker {
                     completion.send_anyhow(())?;
                     break;
                 }
+                InterrupterMessage::SaveState(completion) => {
+                    self.saved_state = Some(self.event_ring.save());
+                    debug!("Saved event ring state: {:?}", self.saved_state);
+                    completion.send_anyhow(())?;
+                }
+                InterrupterMessage::RestoreState(completion) => {
+                    completion.send_anyhow(self.restore_state())?;
+                }
             }
         }
 
         Ok(())
     }
 
+    // Continue the saved event ring, even if the driver rewrote ERSTBA
+    // meanwhile. Returns false if there is no saved state.
+    fn restore_state(&mut self) -> bool {
+        let Some(state) = self.saved_state else {
+            return false;
+        };
+        self.event_ring.restore(state);
+        debug!("Restored event ring state: {state:?}");
+
+        true
+    }
+
     fn reset(&mut self) {
         self.registers.interrupt_management.write(0);
         self.registers
@@ -230,6 +305,7 @@ impl EventWorker {
         self.registers.erst_size.write(0);
         self.registers.eventring_dequeue_pointer.write(0);
         self.event_ring.reset();
+        self.saved_state = None;
     }
 }
 
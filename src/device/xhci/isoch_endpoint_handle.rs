@@ -0,0 +1,1011 @@
+//! Endpoint handles for isochronous endpoints.
+//!
+//! An isochronous TD starts with an Isoch TRB, optionally followed by chained
+//! Normal and Event Data TRBs, and carries the data of one service interval. Unlike on other
+//! endpoints, errors do not halt the endpoint: the xHC reports the error for
+//! the TD and continues with the next one (XHCI spec 4.10.3, 4.11.2.5).
+//!
+//! TDs are executed in the frame given by their Frame ID, or right away if
+//! Start Isoch ASAP is set. TDs whose frame has passed are skipped with a
+//! Missed Service Error. When the transfer ring runs empty, the xHC reports
+//! a Ring Overrun (IN) or Ring Underrun (OUT).
+//!
+//! Several TDs are in flight on the real endpoint at a time, which executes
+//! them back to back. Their completions are reported in order when the
+//! endpoint runs out of room for more TDs, or when the ring runs empty.
+
+use std::{collections::VecDeque, fmt::Debug, future::Future, mem, pin::Pin, time::Duration};
+
+use anyhow::anyhow;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::device::{
+    bus::BusDeviceRef,
+    pcap::{self, EndpointPcapMeta},
+    xhci::{
+        endpoint_handle::{EndpointHandle, TrbProcessingResult},
+        hotplug_endpoint_handle::BaseEndpointHandle,
+        interrupter::EventSender,
+        mfindex::{MicroframeIndex, FRAME_NUMBER_MASK},
+        real_endpoint_handle::{
+            InTrbProcessingStatus, OutTrbProcessingResult, RealInEndpointHandle,
+            RealOutEndpointHandle,
+        },
+        trb::{
+            CompletionCode, EventDataTrbData, EventTrb, IsochTrbData, NormalTrbData, RawTrb,
+            TransferTrbVariant,
+        },
+    },
+};
+
+/// TDs may be scheduled at most this many frames ahead (XHCI spec 4.11.2.5).
+const MAX_FRAMES_AHEAD: u16 = 895;
+
+/// Maximum number of TDs in flight on the real endpoint.
+const MAX_TDS_IN_FLIGHT: usize = 16;
+
+/// TDs up to this many frames ahead are queued behind the TDs in flight.
+/// TDs further ahead wait until the TDs in flight completed and their frame
+/// starts, so that they are not executed early.
+const SCHEDULING_WINDOW: u16 = 32;
+
+/// The part of an Isoch TRB's immediate data that fits into the TRB.
+const MAX_IMMEDIATE_DATA: u32 = 8;
+
+/// When the xHC executes an isochronous TD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IsochSchedule {
+    Now,
+    InFrames(u16),
+    Missed,
+}
+
+impl IsochSchedule {
+    /// Schedule a TD for the frame `frame_id`, or right away without one.
+    ///
+    /// Frame IDs wrap around, so frames more than `MAX_FRAMES_AHEAD` ahead
+    /// are considered to be in the past.
+    const fn new(frame_id: Option<u16>, current_frame: u16) -> Self {
+        let Some(frame_id) = frame_id else {
+            return Self::Now;
+        };
+
+        match frame_id.wrapping_sub(current_frame) & FRAME_NUMBER_MASK {
+            0 => Self::Now,
+            frames @ 1..=MAX_FRAMES_AHEAD => Self::InFrames(frames),
+            _ => Self::Missed,
+        }
+    }
+}
+
+/// The part of an Isoch, Normal, or Event Data TRB that matters for a TD.
+#[derive(Debug)]
+struct IsochTdTrb {
+    address: u64,
+    data_pointer: u64,
+    transfer_length: u32,
+    immediate_data: bool,
+    interrupt_on_short: bool,
+    interrupt_on_completion: bool,
+    // the Event Data of Event Data TRBs, which transfer no data
+    event_data: Option<u64>,
+}
+
+impl IsochTdTrb {
+    const fn from_isoch(address: u64, data: &IsochTrbData) -> Self {
+        Self {
+            address,
+            data_pointer: data.data_pointer,
+            transfer_length: data.transfer_length,
+            immediate_data: data.immediate_data,
+            interrupt_on_short: data.interrupt_on_short,
+            interrupt_on_completion: data.interrupt_on_completion,
+            event_data: None,
+        }
+    }
+
+    const fn from_normal(address: u64, data: &NormalTrbData) -> Self {
+        Self {
+            address,
+            data_pointer: data.data_pointer,
+            transfer_length: data.transfer_length,
+            immediate_data: data.immediate_data,
+            interrupt_on_short: data.interrupt_on_short,
+            interrupt_on_completion: data.interrupt_on_completion,
+            event_data: None,
+        }
+    }
+
+    const fn from_event_data(address: u64, data: &EventDataTrbData) -> Self {
+        Self {
+            address,
+            data_pointer: 0,
+            transfer_length: 0,
+            immediate_data: false,
+            interrupt_on_short: false,
+            interrupt_on_completion: data.interrupt_on_completion,
+            event_data: Some(data.event_data),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct IsochTd {
+    trbs: Vec<IsochTdTrb>,
+    // `None` with Start Isoch ASAP
+    frame_id: Option<u16>,
+}
+
+impl IsochTd {
+    /// The address of the Isoch TRB, which identifies the TD.
+    fn address(&self) -> u64 {
+        self.trbs[0].address
+    }
+
+    fn length(&self) -> usize {
+        self.trbs
+            .iter()
+            .map(|trb| trb.transfer_length as usize)
+            .sum()
+    }
+}
+
+#[derive(Debug)]
+enum IsochSubmissionState {
+    CollectingTd(Option<IsochTd>),
+    TdComplete(IsochTd),
+    // store address of the TRB that does not belong on an isochronous ring.
+    InvalidTrb(u64),
+}
+
+impl Default for IsochSubmissionState {
+    fn default() -> Self {
+        Self::CollectingTd(None)
+    }
+}
+
+/// How an endpoint handle continues with the collected TRBs.
+#[derive(Debug)]
+enum IsochStep {
+    /// Report the result of the TRB to the endpoint.
+    Return(TrbProcessingResult),
+    /// Wait for the oldest TD in flight and report its completion.
+    CompleteOldest,
+    /// Wait for the frame of the TD.
+    Sleep(Duration),
+    /// Submit the TD to the real endpoint.
+    Submit(IsochTd),
+}
+
+/// TD handling shared by the IN and OUT directions.
+#[derive(Debug)]
+struct IsochTdProcessor {
+    slot_id: u8,
+    endpoint_id: u8,
+    pcap_meta: EndpointPcapMeta,
+    dma_bus: BusDeviceRef,
+    event_sender: EventSender,
+    mfindex: MicroframeIndex,
+    submission_state: IsochSubmissionState,
+    // submitted to the real endpoint, oldest first
+    in_flight: VecDeque<IsochTd>,
+    // whether a TD was executed since the ring last ran empty
+    serviced: bool,
+}
+
+impl IsochTdProcessor {
+    fn new(
+        slot_id: u8,
+        endpoint_id: u8,
+        pcap_meta: EndpointPcapMeta,
+        dma_bus: BusDeviceRef,
+        event_sender: EventSender,
+        mfindex: MicroframeIndex,
+    ) -> Self {
+        Self {
+            slot_id,
+            endpoint_id,
+            pcap_meta,
+            dma_bus,
+            event_sender,
+            mfindex,
+            submission_state: IsochSubmissionState::default(),
+            in_flight: VecDeque::new(),
+            serviced: false,
+        }
+    }
+
+    fn submit_trb(&mut self, trb: RawTrb) -> anyhow::Result<()> {
+        let IsochSubmissionState::CollectingTd(td) = &mut self.submission_state else {
+            return Err(anyhow!(
+                "Isochronous endpoint handle called while in state {:?}; there is a logic error somewhere",
+                self.submission_state
+            ));
+        };
+
+        let (td_trb, chain) = match (TransferTrbVariant::parse(trb.buffer), td.as_mut()) {
+            (TransferTrbVariant::Isoch(data), None) => {
+                *td = Some(IsochTd {
+                    trbs: vec![],
+                    frame_id: (!data.start_isoch_asap).then_some(data.frame_id),
+                });
+                (IsochTdTrb::from_isoch(trb.address, &data), data.chain)
+            }
+            (TransferTrbVariant::Normal(data), Some(_)) => {
+                (IsochTdTrb::from_normal(trb.address, &data), data.chain)
+            }
+            (TransferTrbVariant::EventData(data), Some(_)) => {
+                (IsochTdTrb::from_event_data(trb.address, &data), data.chain)
+            }
+            (variant, _) => {
+                warn!(
+                    "Encountered unsupported TRB on isochronous endpoint (slot {}, ep {}): {variant:?}",
+                    self.slot_id, self.endpoint_id
+                );
+                self.submission_state = IsochSubmissionState::InvalidTrb(trb.address);
+                return Ok(());
+            }
+        };
+        if td_trb.immediate_data && td_trb.transfer_length > MAX_IMMEDIATE_DATA {
+            warn!(
+                "Immediate data of {} bytes on isochronous endpoint (slot {}, ep {})",
+                td_trb.transfer_length, self.slot_id, self.endpoint_id
+            );
+            self.submission_state = IsochSubmissionState::InvalidTrb(trb.address);
+            return Ok(());
+        }
+
+        let Some(td) = td else {
+            unreachable!("the TD was started above");
+        };
+        td.trbs.push(td_trb);
+        if !chain {
+            let IsochSubmissionState::CollectingTd(Some(td)) =
+                mem::take(&mut self.submission_state)
+            else {
+                unreachable!("verified the state is CollectingTd at the start of the function");
+            };
+            self.submission_state = IsochSubmissionState::TdComplete(td);
+        }
+
+        Ok(())
+    }
+
+    /// Decide how to continue with the collected TRBs.
+    ///
+    /// The xHC completes TDs in order, so the TDs in flight complete before
+    /// a later TD is reported as missed or invalid.
+    fn next_step(&mut self) -> anyhow::Result<IsochStep> {
+        let in_flight = self.in_flight.len();
+        let schedule = match &self.submission_state {
+            IsochSubmissionState::CollectingTd(_) => {
+                return Ok(IsochStep::Return(TrbProcessingResult::Ok));
+            }
+            IsochSubmissionState::InvalidTrb(_) if in_flight > 0 => {
+                return Ok(IsochStep::CompleteOldest);
+            }
+            IsochSubmissionState::InvalidTrb(address) => {
+                let address = *address;
+                self.submission_state = IsochSubmissionState::default();
+                pcap::trb_error(self.pcap_meta, address);
+                self.send_event(address, 0, CompletionCode::TrbError)?;
+                return Ok(IsochStep::Return(TrbProcessingResult::TrbError));
+            }
+            IsochSubmissionState::TdComplete(td) => {
+                IsochSchedule::new(td.frame_id, self.mfindex.frame())
+            }
+        };
+
+        match schedule {
+            IsochSchedule::Missed if in_flight > 0 => return Ok(IsochStep::CompleteOldest),
+            IsochSchedule::InFrames(frames) if in_flight > 0 && frames > SCHEDULING_WINDOW => {
+                return Ok(IsochStep::CompleteOldest);
+            }
+            IsochSchedule::InFrames(frames) if in_flight == 0 => {
+                return Ok(IsochStep::Sleep(self.mfindex.until_frame(frames)));
+            }
+            _ if in_flight >= MAX_TDS_IN_FLIGHT => return Ok(IsochStep::CompleteOldest),
+            _ => {}
+        }
+
+        let IsochSubmissionState::TdComplete(td) = mem::take(&mut self.submission_state) else {
+            unreachable!("verified the state is TdComplete above");
+        };
+        self.serviced = true;
+        if schedule != IsochSchedule::Missed {
+            return Ok(IsochStep::Submit(td));
+        }
+
+        debug!(
+            "missed service interval of isochronous TD at {:#x}",
+            td.address()
+        );
+        pcap::isoch_completion(
+            self.pcap_meta,
+            td.address(),
+            CompletionCode::MissedServiceError,
+            0,
+            &[],
+        );
+        self.send_event(
+            td.address(),
+            td.length() as u32,
+            CompletionCode::MissedServiceError,
+        )?;
+
+        Ok(IsochStep::Return(TrbProcessingResult::Ok))
+    }
+
+    /// Take the oldest TD in flight once the real endpoint completed it.
+    fn completed_td(&mut self) -> anyhow::Result<IsochTd> {
+        self.in_flight
+            .pop_front()
+            .ok_or_else(|| anyhow!("Isochronous transfer completed without a TD in flight"))
+    }
+
+    /// Report a failed transfer of the TD. Isochronous endpoints do not
+    /// halt, so only a disconnect stops the endpoint.
+    fn transfer_failed(
+        &self,
+        td: &IsochTd,
+        disconnect: bool,
+    ) -> anyhow::Result<TrbProcessingResult> {
+        pcap::isoch_completion(
+            self.pcap_meta,
+            td.address(),
+            CompletionCode::UsbTransactionError,
+            0,
+            &[],
+        );
+        self.send_event(
+            td.address(),
+            td.length() as u32,
+            CompletionCode::UsbTransactionError,
+        )?;
+
+        Ok(match disconnect {
+            true => TrbProcessingResult::Disconnect,
+            false => TrbProcessingResult::Ok,
+        })
+    }
+
+    fn ring_empty(&mut self, completion_code: CompletionCode) -> anyhow::Result<()> {
+        if self.serviced {
+            self.serviced = false;
+            // The event does not refer to a TRB.
+            self.send_event(0, 0, completion_code)?;
+        }
+
+        Ok(())
+    }
+
+    /// Abandon a partially collected TD and the TDs in flight.
+    fn cancel(&mut self) {
+        self.submission_state = IsochSubmissionState::default();
+        self.in_flight.clear();
+    }
+
+    fn send_event(
+        &self,
+        trb_pointer: u64,
+        residual_length: u32,
+        completion_code: CompletionCode,
+    ) -> anyhow::Result<()> {
+        let event = EventTrb::new_transfer_event_trb(
+            trb_pointer,
+            residual_length,
+            completion_code,
+            false,
+            self.endpoint_id,
+            self.slot_id,
+        );
+        self.event_sender.send(event)
+    }
+
+    /// Report an Event Data TRB (XHCI spec 4.11.5.2).
+    fn send_event_data_event(
+        &self,
+        event_data: u64,
+        edtla: u32,
+        completion_code: CompletionCode,
+    ) -> anyhow::Result<()> {
+        let event = EventTrb::new_event_data_transfer_event_trb(
+            event_data,
+            edtla,
+            completion_code,
+            self.endpoint_id,
+            self.slot_id,
+        );
+        self.event_sender.send(event)
+    }
+}
+
+#[derive(Debug)]
+pub struct IsochInEndpointHandle<RIEH: RealInEndpointHandle> {
+    real_ep: RIEH,
+    processor: IsochTdProcessor,
+}
+
+impl<RIEH: RealInEndpointHandle> IsochInEndpointHandle<RIEH> {
+    pub fn new(
+        slot_id: u8,
+        endpoint_id: u8,
+        pcap_meta: EndpointPcapMeta,
+        real_ep: RIEH,
+        dma_bus: BusDeviceRef,
+        event_sender: EventSender,
+        mfindex: MicroframeIndex,
+    ) -> Self {
+        Self {
+            real_ep,
+            processor: IsochTdProcessor::new(
+                slot_id,
+                endpoint_id,
+                pcap_meta,
+                dma_bus,
+                event_sender,
+                mfindex,
+            ),
+        }
+    }
+
+    fn submit(&mut self, td: IsochTd) -> anyhow::Result<()> {
+        let td_length = td.length();
+        pcap::isoch_submission(
+            self.processor.pcap_meta,
+            td.address(),
+            &[],
+            td_length as u32,
+        );
+        self.real_ep.submit(td_length)?;
+        self.processor.in_flight.push_back(td);
+
+        Ok(())
+    }
+
+    async fn complete_oldest(&mut self) -> anyhow::Result<TrbProcessingResult> {
+        let completion = self.real_ep.next_completion().await?;
+        let td = self.processor.completed_td()?;
+        let processor = &self.processor;
+
+        match completion.status {
+            InTrbProcessingStatus::Success => {}
+            InTrbProcessingStatus::Disconnect => return processor.transfer_failed(&td, true),
+            InTrbProcessingStatus::Stall | InTrbProcessingStatus::TransactionError => {
+                return processor.transfer_failed(&td, false)
+            }
+        }
+
+        let mut data = &completion.data[..];
+        let mut completion_code = CompletionCode::Success;
+        // Event Data Transfer Length Accumulator
+        let mut edtla = 0u32;
+
+        for trb in &td.trbs {
+            if let Some(event_data) = trb.event_data {
+                if trb.interrupt_on_completion {
+                    processor.send_event_data_event(event_data, edtla, completion_code)?;
+                }
+                edtla = 0;
+                continue;
+            }
+            if completion_code == CompletionCode::ShortPacket {
+                // the remaining TRBs of the TD stay empty
+                continue;
+            }
+
+            let byte_count = (trb.transfer_length as usize).min(data.len());
+            processor
+                .dma_bus
+                .write_bulk(trb.data_pointer, &data[..byte_count]);
+            data = &data[byte_count..];
+            edtla = edtla.wrapping_add(byte_count as u32);
+
+            let residual_length = trb.transfer_length - byte_count as u32;
+            if residual_length > 0 {
+                completion_code = CompletionCode::ShortPacket;
+                if trb.interrupt_on_short || trb.interrupt_on_completion {
+                    processor.send_event(trb.address, residual_length, completion_code)?;
+                }
+            } else if trb.interrupt_on_completion {
+                processor.send_event(trb.address, 0, CompletionCode::Success)?;
+            }
+        }
+
+        pcap::isoch_completion(
+            processor.pcap_meta,
+            td.address(),
+            completion_code,
+            completion.data.len() as u32,
+            &completion.data,
+        );
+
+        Ok(TrbProcessingResult::Ok)
+    }
+}
+
+impl<RIEH: RealInEndpointHandle> EndpointHandle for IsochInEndpointHandle<RIEH> {
+    type TrbCompletionFuture<'a> =
+        Pin<Box<dyn Future<Output = anyhow::Result<TrbProcessingResult>> + Send + 'a>>;
+
+    fn submit_trb(&mut self, trb: RawTrb) -> anyhow::Result<()> {
+        self.processor.submit_trb(trb)
+    }
+
+    fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_> {
+        Box::pin(async {
+            loop {
+                match self.processor.next_step()? {
+                    IsochStep::Return(result) => return Ok(result),
+                    IsochStep::CompleteOldest => match self.complete_oldest().await? {
+                        TrbProcessingResult::Ok => {}
+                        result => return Ok(result),
+                    },
+                    IsochStep::Sleep(duration) => sleep(duration).await,
+                    IsochStep::Submit(td) => {
+                        self.submit(td)?;
+                        return Ok(TrbProcessingResult::Ok);
+                    }
+                }
+            }
+        })
+    }
+
+    fn ring_empty(&mut self) -> Self::CompletionFuture<'_> {
+        Box::pin(async {
+            while !self.processor.in_flight.is_empty() {
+                self.complete_oldest().await?;
+            }
+            self.processor.ring_empty(CompletionCode::RingOverrun)
+        })
+    }
+}
+
+impl<RIEH: RealInEndpointHandle> BaseEndpointHandle for IsochInEndpointHandle<RIEH> {
+    type CompletionFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+    fn cancel(&mut self) -> Self::CompletionFuture<'_> {
+        self.processor.cancel();
+        Box::pin(async { self.real_ep.cancel().await })
+    }
+
+    fn clear_halt(&mut self) -> Self::CompletionFuture<'_> {
+        Box::pin(async { self.real_ep.clear_halt().await })
+    }
+}
+
+#[derive(Debug)]
+pub struct IsochOutEndpointHandle<ROEH: RealOutEndpointHandle> {
+    real_ep: ROEH,
+    processor: IsochTdProcessor,
+}
+
+impl<ROEH: RealOutEndpointHandle> IsochOutEndpointHandle<ROEH> {
+    pub fn new(
+        slot_id: u8,
+        endpoint_id: u8,
+        pcap_meta: EndpointPcapMeta,
+        real_ep: ROEH,
+        dma_bus: BusDeviceRef,
+        event_sender: EventSender,
+        mfindex: MicroframeIndex,
+    ) -> Self {
+        Self {
+            real_ep,
+            processor: IsochTdProcessor::new(
+                slot_id,
+                endpoint_id,
+                pcap_meta,
+                dma_bus,
+                event_sender,
+                mfindex,
+            ),
+        }
+    }
+
+    fn submit(&mut self, td: IsochTd) -> anyhow::Result<()> {
+        let processor = &self.processor;
+        let mut data = Vec::with_capacity(td.length());
+        for trb in &td.trbs {
+            let length = trb.transfer_length as usize;
+            if trb.immediate_data {
+                // the length was checked when the TRB was submitted
+                data.extend_from_slice(&trb.data_pointer.to_le_bytes()[..length]);
+            } else {
+                let start = data.len();
+                data.resize(start + length, 0);
+                processor
+                    .dma_bus
+                    .read_bulk(trb.data_pointer, &mut data[start..]);
+            }
+        }
+
+        pcap::isoch_submission(processor.pcap_meta, td.address(), &data, data.len() as u32);
+        self.real_ep.submit(data)?;
+        self.processor.in_flight.push_back(td);
+
+        Ok(())
+    }
+
+    async fn complete_oldest(&mut self) -> anyhow::Result<TrbProcessingResult> {
+        let result = self.real_ep.next_completion().await?;
+        let td = self.processor.completed_td()?;
+        let processor = &self.processor;
+
+        match result {
+            OutTrbProcessingResult::Success => {}
+            OutTrbProcessingResult::Disconnect => return processor.transfer_failed(&td, true),
+            OutTrbProcessingResult::Stall | OutTrbProcessingResult::TransactionError => {
+                return processor.transfer_failed(&td, false)
+            }
+        }
+
+        pcap::isoch_completion(
+            processor.pcap_meta,
+            td.address(),
+            CompletionCode::Success,
+            td.length() as u32,
+            &[],
+        );
+        let mut edtla = 0u32;
+        for trb in &td.trbs {
+            match trb.event_data {
+                Some(event_data) => {
+                    if trb.interrupt_on_completion {
+                        processor.send_event_data_event(
+                            event_data,
+                            edtla,
+                            CompletionCode::Success,
+                        )?;
+                    }
+                    edtla = 0;
+                }
+                None => {
+                    edtla = edtla.wrapping_add(trb.transfer_length);
+                    if trb.interrupt_on_completion {
+                        processor.send_event(trb.address, 0, CompletionCode::Success)?;
+                    }
+                }
+            }
+        }
+
+        Ok(TrbProcessingResult::Ok)
+    }
+}
+
+impl<ROEH: RealOutEndpointHandle> EndpointHandle for IsochOutEndpointHandle<ROEH> {
+    type TrbCompletionFuture<'a> =
+        Pin<Box<dyn Future<Output = anyhow::Result<TrbProcessingResult>> + Send + 'a>>;
+
+    fn submit_trb(&mut self, trb: RawTrb) -> anyhow::Result<()> {
+        self.processor.submit_trb(trb)
+    }
+
+    fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_> {
+        Box::pin(async {
+            loop {
+                match self.processor.next_step()? {
+                    IsochStep::Return(result) => return Ok(result),
+                    IsochStep::CompleteOldest => match self.complete_oldest().await? {
+                        TrbProcessingResult::Ok => {}
+                        result => return Ok(result),
+                    },
+                    IsochStep::Sleep(duration) => sleep(duration).await,
+                    IsochStep::Submit(td) => {
+                        self.submit(td)?;
+                        return Ok(TrbProcessingResult::Ok);
+                    }
+                }
+            }
+        })
+    }
+
+    fn ring_empty(&mut self) -> Self::CompletionFuture<'_> {
+        Box::pin(async {
+            while !self.processor.in_flight.is_empty() {
+                self.complete_oldest().await?;
+            }
+            self.processor.ring_empty(CompletionCode::RingUnderrun)
+        })
+    }
+}
+
+impl<ROEH: RealOutEndpointHandle> BaseEndpointHandle for IsochOutEndpointHandle<ROEH> {
+    type CompletionFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+    fn cancel(&mut self) -> Self::CompletionFuture<'_> {
+        self.processor.cancel();
+        Box::pin(async { self.real_ep.cancel().await })
+    }
+
+    fn clear_halt(&mut self) -> Self::CompletionFuture<'_> {
+        Box::pin(async { self.real_ep.clear_halt().await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::device::{
+        bus::testutils::TestBusDevice,
+        pci::constants::xhci::rings::trb_types,
+        xhci::{
+            endpoint_handle::tests::testutils::{MockRealInEndpoint, MockRealOutEndpoint},
+            interrupter::tests::testutils::MockInterrupter,
+            trb::testutils::RawTrbBuilder,
+        },
+    };
+
+    #[test]
+    fn tds_are_scheduled_by_frame_id() {
+        // Start Isoch ASAP
+        assert_eq!(IsochSchedule::new(None, 100), IsochSchedule::Now);
+        assert_eq!(IsochSchedule::new(Some(100), 100), IsochSchedule::Now);
+        assert_eq!(
+            IsochSchedule::new(Some(108), 100),
+            IsochSchedule::InFrames(8)
+        );
+        // Frame IDs wrap around after 2048 frames.
+        assert_eq!(
+            IsochSchedule::new(Some(2), 2040),
+            IsochSchedule::InFrames(10)
+        );
+        assert_eq!(IsochSchedule::new(Some(99), 100), IsochSchedule::Missed);
+        assert_eq!(
+            IsochSchedule::new(Some(100 + MAX_FRAMES_AHEAD + 1), 100),
+            IsochSchedule::Missed
+        );
+    }
+
+    fn isoch_raw_trb(address: u64, data_pointer: u64, length: u32) -> RawTrb {
+        RawTrbBuilder::new(address)
+            .with_data_pointer(data_pointer)
+            .with_trb_transfer_length(length)
+            .with_interrupt_on_completion()
+            .with_trb_type(trb_types::ISOCH)
+            // Start Isoch ASAP
+            .with_byte(15, 0x80)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn isoch_in_td_completes_per_packet() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let mut handle = IsochInEndpointHandle::new(
+            1,
+            3,
+            EndpointPcapMeta::isochronous(3, 1, 3),
+            MockRealInEndpoint::new(),
+            ram.clone(),
+            event_sender,
+            MicroframeIndex::start(),
+        );
+
+        handle.submit_trb(isoch_raw_trb(0x40, 0x80, 16)).unwrap();
+        assert!(matches!(
+            handle.next_completion().await.unwrap(),
+            TrbProcessingResult::Ok
+        ));
+        // the TD is in flight until the ring runs empty
+        assert!(interrupter.is_empty());
+        handle.ring_empty().await.unwrap();
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_transfer_event_trb(
+                0x40,
+                0,
+                CompletionCode::Success,
+                false,
+                3,
+                1
+            ))
+        );
+        let mut data = [0; 16];
+        ram.read_bulk(0x80, &mut data);
+        assert_eq!(data, [42; 16]);
+
+        // the ring ran empty after the TD
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_transfer_event_trb(
+                0,
+                0,
+                CompletionCode::RingOverrun,
+                false,
+                3,
+                1
+            ))
+        );
+        handle.ring_empty().await.unwrap();
+        assert!(interrupter.is_empty());
+    }
+
+    #[tokio::test]
+    async fn isoch_in_tds_are_in_flight_together() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x400]));
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let mut handle = IsochInEndpointHandle::new(
+            1,
+            3,
+            EndpointPcapMeta::isochronous(3, 1, 3),
+            MockRealInEndpoint::new(),
+            ram,
+            event_sender,
+            MicroframeIndex::start(),
+        );
+        let td_address = |td: usize| 0x40 + 0x10 * td as u64;
+
+        for td in 0..MAX_TDS_IN_FLIGHT {
+            handle
+                .submit_trb(isoch_raw_trb(td_address(td), 0x200, 16))
+                .unwrap();
+            assert!(matches!(
+                handle.next_completion().await.unwrap(),
+                TrbProcessingResult::Ok
+            ));
+        }
+        assert!(interrupter.is_empty());
+
+        // Another TD has to wait for the oldest one.
+        handle
+            .submit_trb(isoch_raw_trb(td_address(MAX_TDS_IN_FLIGHT), 0x200, 16))
+            .unwrap();
+        assert!(matches!(
+            handle.next_completion().await.unwrap(),
+            TrbProcessingResult::Ok
+        ));
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_transfer_event_trb(
+                td_address(0),
+                0,
+                CompletionCode::Success,
+                false,
+                3,
+                1
+            ))
+        );
+        assert!(interrupter.is_empty());
+
+        // The others complete in order once the ring runs empty.
+        handle.ring_empty().await.unwrap();
+        for td in 1..=MAX_TDS_IN_FLIGHT {
+            assert_eq!(
+                interrupter.await_event().await,
+                Some(EventTrb::new_transfer_event_trb(
+                    td_address(td),
+                    0,
+                    CompletionCode::Success,
+                    false,
+                    3,
+                    1
+                ))
+            );
+        }
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_transfer_event_trb(
+                0,
+                0,
+                CompletionCode::RingOverrun,
+                false,
+                3,
+                1
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn isoch_out_td_misses_past_frame() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let mut handle = IsochOutEndpointHandle::new(
+            1,
+            2,
+            EndpointPcapMeta::isochronous(3, 1, 2),
+            MockRealOutEndpoint::new(),
+            ram,
+            event_sender,
+            MicroframeIndex::start(),
+        );
+
+        handle.submit_trb(isoch_raw_trb(0x30, 0x80, 16)).unwrap();
+        assert!(matches!(
+            handle.next_completion().await.unwrap(),
+            TrbProcessingResult::Ok
+        ));
+
+        // The counter starts at frame 0, so frame 2047 has just passed.
+        let trb = RawTrbBuilder::new(0x40)
+            .with_data_pointer(0x80)
+            .with_trb_transfer_length(16)
+            .with_interrupt_on_completion()
+            .with_trb_type(trb_types::ISOCH)
+            .with_byte(14, 0xf0)
+            .with_byte(15, 0x7f)
+            .build();
+        handle.submit_trb(trb).unwrap();
+        assert!(matches!(
+            handle.next_completion().await.unwrap(),
+            TrbProcessingResult::Ok
+        ));
+        // the TD in flight completes first
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_transfer_event_trb(
+                0x30,
+                0,
+                CompletionCode::Success,
+                false,
+                2,
+                1
+            ))
+        );
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_transfer_event_trb(
+                0x40,
+                16,
+                CompletionCode::MissedServiceError,
+                false,
+                2,
+                1
+            ))
+        );
+
+        // Normal TRBs cannot start an isochronous TD.
+        let trb = RawTrbBuilder::new(0x50)
+            .with_trb_type(trb_types::NORMAL)
+            .build();
+        handle.submit_trb(trb).unwrap();
+        assert!(matches!(
+            handle.next_completion().await.unwrap(),
+            TrbProcessingResult::TrbError
+        ));
+    }
+
+    #[tokio::test]
+    async fn isoch_out_td_with_too_much_immediate_data_fails() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x100]));
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let mut handle = IsochOutEndpointHandle::new(
+            1,
+            2,
+            EndpointPcapMeta::isochronous(3, 1, 2),
+            MockRealOutEndpoint::new(),
+            ram,
+            event_sender,
+            MicroframeIndex::start(),
+        );
+
+        let trb = RawTrbBuilder::new(0x40)
+            .with_trb_transfer_length(16)
+            .with_immediate_data()
+            .with_interrupt_on_completion()
+            .with_trb_type(trb_types::ISOCH)
+            // Start Isoch ASAP
+            .with_byte(15, 0x80)
+            .build();
+        handle.submit_trb(trb).unwrap();
+        assert!(matches!(
+            handle.next_completion().await.unwrap(),
+            TrbProcessingResult::TrbError
+        ));
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_transfer_event_trb(
+                0x40,
+                0,
+                CompletionCode::TrbError,
+                false,
+                2,
+                1
+            ))
+        );
+        assert!(interrupter.is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! The microframe index of the controller.
+//!
+//! Isochronous transfers are scheduled in USB frames (1 ms) that consist of
+//! eight microframes (125 us). The xHC counts microframes in MFINDEX, and
+//! drivers derive the Frame ID of isochronous TDs from it (XHCI spec 4.14.2).
+//!
+//! The index only advances while the controller runs (USBCMD.RS), and the
+//! 14-bit MFINDEX register wraps around every 2048 ms. Drivers learn about
+//! the wrap from MFINDEX Wrap events if they enable them with USBCMD.EWE.
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    runtime, select,
+    sync::{oneshot, Notify},
+    time::sleep,
+};
+use tracing::debug;
+
+use crate::device::{
+    pci::constants::xhci::operational::usbcmd,
+    xhci::{controller_reset::ResetSender, interrupter::EventSender, trb::EventTrb},
+};
+
+/// Duration of a single microframe.
+pub const MICROFRAME: Duration = Duration::from_micros(125);
+
+/// Number of microframes per frame.
+const MICROFRAMES_PER_FRAME: u64 = 8;
+
+/// Frame numbers, like the Frame ID field of Isoch TRBs, are 11-bit values.
+pub const FRAME_NUMBER_MASK: u16 = 0x7ff;
+
+/// The MFINDEX register counts microframes in 14 bits.
+const MFINDEX_MASK: u64 = 0x3fff;
+
+/// A microframe counter derived from a monotonic clock.
+///
+/// Clones share the counter, so the isochronous endpoints see the index the
+/// driver reads.
+#[derive(Debug, Clone, Default)]
+pub struct MicroframeIndex {
+    clock: Arc<Mutex<Clock>>,
+    // signaled when the counter is started, stopped or reset
+    changed: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct Clock {
+    // time counted before the counter was started the last time
+    counted: Duration,
+    // set while the counter runs
+    running_since: Option<Instant>,
+}
+
+impl Clock {
+    fn elapsed(&self) -> Duration {
+        self.counted
+            + self
+                .running_since
+                .map_or(Duration::ZERO, |since| since.elapsed())
+    }
+}
+
+impl MicroframeIndex {
+    /// Start counting microframes at 0.
+    #[cfg(test)]
+    pub fn start() -> Self {
+        let mfindex = Self::default();
+        mfindex.set_running(true);
+        mfindex
+    }
+
+    /// Start or stop counting, e.g., when the controller starts or stops
+    /// running.
+    pub fn set_running(&self, running: bool) {
+        let mut clock = self.clock.lock().unwrap();
+        match (clock.running_since, running) {
+            (None, true) => clock.running_since = Some(Instant::now()),
+            (Some(since), false) => {
+                clock.counted += since.elapsed();
+                clock.running_since = None;
+            }
+            _ => return,
+        }
+        drop(clock);
+
+        self.changed.notify_one();
+    }
+
+    /// Stop counting and start over at 0.
+    pub fn reset(&self) {
+        *self.clock.lock().unwrap() = Clock::default();
+        self.changed.notify_one();
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.clock.lock().unwrap().elapsed()
+    }
+
+    /// Number of microframes since the counter was started.
+    pub fn microframes(&self) -> u64 {
+        (self.elapsed().as_nanos() / MICROFRAME.as_nanos()) as u64
+    }
+
+    /// The value of the MFINDEX register.
+    pub fn mfindex(&self) -> u32 {
+        (self.microframes() & MFINDEX_MASK) as u32
+    }
+
+    /// The current frame number.
+    pub fn frame(&self) -> u16 {
+        (self.microframes() / MICROFRAMES_PER_FRAME) as u16 & FRAME_NUMBER_MASK
+    }
+
+    /// Time until the frame `frames` frames after the current one starts.
+    pub fn until_frame(&self, frames: u16) -> Duration {
+        let elapsed = self.elapsed().as_nanos();
+        let frame = MICROFRAME.as_nanos() * u128::from(MICROFRAMES_PER_FRAME);
+        let frame_start = (elapsed / frame + u128::from(frames)) * frame;
+
+        Duration::from_nanos(frame_start.saturating_sub(elapsed) as u64)
+    }
+
+    /// Time until MFINDEX wraps around, or `None` while the counter is
+    /// stopped.
+    fn until_wrap(&self) -> Option<Duration> {
+        let clock = self.clock.lock().unwrap();
+        clock.running_since?;
+        let elapsed = clock.elapsed().as_nanos();
+        drop(clock);
+
+        let period = MICROFRAME.as_nanos() * u128::from(MFINDEX_MASK + 1);
+        let wrap = (elapsed / period + 1) * period;
+        Some(Duration::from_nanos((wrap - elapsed) as u64))
+    }
+
+    /// Send an MFINDEX Wrap event whenever the index wraps around while
+    /// USBCMD.EWE is set.
+    pub fn send_wrap_events(
+        &self,
+        usbcmd: Arc<AtomicU32>,
+        event_sender: EventSender,
+        async_runtime: &runtime::Handle,
+    ) {
+        async_runtime.spawn(self.clone().wrap_event_loop(usbcmd, event_sender));
+    }
+
+    async fn wrap_event_loop(self, usbcmd: Arc<AtomicU32>, event_sender: EventSender) {
+        loop {
+            let Some(until_wrap) = self.until_wrap() else {
+                self.changed.notified().await;
+                continue;
+            };
+
+            select! {
+                _ = sleep(until_wrap) => {
+                    let enabled = u64::from(usbcmd.load(Ordering::Relaxed)) & usbcmd::EWE != 0;
+                    if enabled && event_sender.send(EventTrb::MfIndexWrap).is_err() {
+                        debug!("Stopped sending MFINDEX Wrap events");
+                        return;
+                    }
+                }
+                // the wrap moved
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+}
+
+impl ResetSender for MicroframeIndex {
+    fn send_reset(&self, completion_notifier: oneshot::Sender<()>) -> anyhow::Result<()> {
+        self.reset();
+        completion_notifier
+            .send(())
+            .map_err(|_| anyhow::anyhow!("reset completion receiver dropped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::xhci::interrupter::tests::testutils::MockInterrupter;
+
+    use super::*;
+
+    // Let the counter continue shortly before MFINDEX wraps.
+    fn skip_to_wrap(mfindex: &MicroframeIndex) {
+        let mut clock = mfindex.clock.lock().unwrap();
+        clock.counted = MICROFRAME * (MFINDEX_MASK as u32 - 8);
+        clock.running_since = Some(Instant::now());
+        drop(clock);
+        mfindex.changed.notify_one();
+    }
+
+    #[tokio::test]
+    async fn mfindex_only_advances_while_running() {
+        let mfindex = MicroframeIndex::default();
+        sleep(MICROFRAME * 16).await;
+        assert_eq!(mfindex.mfindex(), 0);
+
+        mfindex.set_running(true);
+        sleep(MICROFRAME * 16).await;
+        mfindex.set_running(false);
+        let stopped = mfindex.mfindex();
+        assert!(stopped >= 16);
+
+        sleep(MICROFRAME * 16).await;
+        assert_eq!(mfindex.mfindex(), stopped);
+
+        mfindex.reset();
+        assert_eq!(mfindex.mfindex(), 0);
+    }
+
+    #[tokio::test]
+    async fn mfindex_wraps_and_reports_wraps_if_enabled() {
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let usbcmd = Arc::new(AtomicU32::new(usbcmd::RS as u32));
+        let mfindex = MicroframeIndex::default();
+        mfindex.send_wrap_events(usbcmd.clone(), event_sender, &runtime::Handle::current());
+
+        skip_to_wrap(&mfindex);
+        sleep(MICROFRAME * 16).await;
+        assert!(mfindex.mfindex() < 0x100, "MFINDEX should have wrapped");
+        assert!(interrupter.is_empty(), "EWE is not set");
+
+        usbcmd.store((usbcmd::RS | usbcmd::EWE) as u32, Ordering::Relaxed);
+        skip_to_wrap(&mfindex);
+        assert_eq!(interrupter.await_event().await, Some(EventTrb::MfIndexWrap));
+        assert!(mfindex.mfindex() < 0x100, "MFINDEX should have wrapped");
+    }
+}
@@ -1,17 +1,23 @@
 pub mod command_ring;
 pub mod controller_reset;
+pub mod controller_state;
 pub mod endpoint;
 pub mod endpoint_handle;
 pub mod endpoint_launcher;
 pub mod event_ring;
 pub mod hotplug_endpoint_handle;
+pub mod interface_filter;
 pub mod interrupter;
+pub mod isoch_endpoint_handle;
 pub mod linked_ring;
+pub mod mfindex;
 pub mod nusb;
 pub mod port;
 pub mod real_device;
 pub mod real_endpoint_handle;
 pub mod registers;
 pub mod slot_manager;
+pub mod streams;
 pub mod trb;
+pub mod usbfs;
 pub mod usbrequest;
@@ -1,32 +1,108 @@
-use std::{fmt::Debug, future::Future, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    fs::{self, File},
+    future::Future,
+    mem,
+    os::{fd::OwnedFd, unix::fs::MetadataExt},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use anyhow::{anyhow, Error};
+use arc_swap::ArcSwap;
 use nusb::{
+    descriptors::{
+        language_id::US_ENGLISH, EndpointDescriptor, InterfaceDescriptors, TransferType,
+    },
     transfer::{
         Buffer, Bulk, BulkOrInterrupt, Completion, ControlIn, ControlOut, ControlType,
         EndpointDirection, EndpointType, In, Interrupt, Out, Recipient, TransferError,
     },
     Endpoint, Interface, MaybeFuture,
 };
-use tokio::{runtime, select, sync::mpsc};
+use tokio::{
+    runtime, select,
+    sync::{mpsc, oneshot},
+};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
+use usbvfiod::hotplug_protocol::usb_id::UsbId;
 
 use crate::device::xhci::{
     hotplug_endpoint_handle::BaseEndpointHandle,
-    real_device::{RealDevice, Speed},
+    interface_filter::InterfaceFilter,
+    real_device::{CompleteRealDeviceImpl, DeviceIdentity, HostDeviceInfo, RealDevice, Speed},
     real_endpoint_handle::{
         ControlRequestProcessingResult, InTrbProcessingResult, InTrbProcessingStatus,
         RealControlEndpointHandle, RealInEndpointHandle, RealOutEndpointHandle,
     },
+    usbfs::{usbdevfs, usbfs_ioctl, Transfer, UrbFile, UrbId, UrbKind},
     usbrequest::UsbRequest,
 };
 
 use super::real_endpoint_handle::OutTrbProcessingResult;
 
+/// A nusb device identified by its host port and USB IDs.
+pub type NusbCompleteRealDevice = CompleteRealDeviceImpl<NusbRealDevice, DeviceIdentity>;
+
+/// USB class code of human interface devices.
+const HID_CLASS: u8 = 0x03;
+
+const CONTROL_TRANSFER_TIMEOUT: Duration = Duration::from_millis(2000);
+
+/// Maximum length of control transfers through usbfs, enough for any
+/// configuration descriptor.
+const MAX_CONTROL_TRANSFER_LENGTH: u16 = 4096;
+
+const REQUEST_SET_CONFIGURATION: u8 = 9;
+const REQUEST_SET_INTERFACE: u8 = 11;
+
+const DESCRIPTOR_TYPE_SS_ENDPOINT_COMPANION: u8 = 0x30;
+
 struct NusbDeviceWrapper {
-    device: nusb::Device,
-    interfaces: Vec<Interface>,
+    // replaced when the device is reopened after a reset
+    device: ArcSwap<nusb::Device>,
+    // the claimed interfaces of the active configuration
+    interfaces: Mutex<Vec<Interface>>,
+    // only set if not all interfaces are passed through
+    filter: Option<InterfaceFilter>,
+    // claims the interfaces nusb cannot do the transfers of
+    urb_file: Option<Arc<UrbFile>>,
+    reattach_drivers: bool,
+    // incremented whenever the configuration or an alternate setting
+    // changes, which invalidates opened endpoints
+    generation: AtomicU64,
+    // cancelled once the interfaces are released
+    released: CancellationToken,
+}
+
+impl Drop for NusbDeviceWrapper {
+    fn drop(&mut self) {
+        // The device is no longer used, so the host kernel drivers may have
+        // the released interfaces back.
+        let released: Vec<_> = self
+            .interfaces
+            .get_mut()
+            .map(|interfaces| interfaces.drain(..).map(|i| i.interface_number()).collect())
+            .unwrap_or_default();
+        if self.reattach_drivers {
+            let device = self.device();
+            for interface_number in released {
+                if let Err(e) = device.attach_kernel_driver(interface_number) {
+                    debug!("Failed to reattach the driver of interface {interface_number}: {e}");
+                }
+            }
+        }
+        if let Some(urb_file) = &self.urb_file {
+            urb_file.release_all(self.reattach_drivers);
+        }
+        self.released.cancel();
+    }
 }
 
 impl Debug for NusbDeviceWrapper {
@@ -34,37 +110,261 @@ impl Debug for NusbDeviceWrapper {
         // The active configuration is either cached or not available
         // for unconfigured devices. There is no I/O for this.
         f.debug_struct("NusbDeviceWrapper")
-            .field("device", &self.device.active_configuration())
+            .field("device", &self.device().active_configuration())
             .finish()
     }
 }
 
-impl TryFrom<nusb::Device> for NusbDeviceWrapper {
-    type Error = Error;
-
-    fn try_from(device: nusb::Device) -> Result<Self, Error> {
-        // Claim all interfaces
-        let mut interfaces = vec![];
+impl NusbDeviceWrapper {
+    /// Claim the interfaces of the device listed in `allowed_interfaces`,
+    /// or all interfaces.
+    ///
+    /// `reattach_drivers` decides whether the host kernel drivers are bound
+    /// to the interfaces again once they are released. By default, this is
+    /// the case for HID devices, so that keyboards and mice keep working on
+    /// the host after the VM is done with them.
+    ///
+    /// Without `urb_file`, the interfaces with isochronous endpoints or
+    /// streams are claimed by nusb, but their isochronous endpoints are not
+    /// available and their streams cannot be allocated.
+    fn claim(
+        device: nusb::Device,
+        allowed_interfaces: Option<Vec<u8>>,
+        reattach_drivers: Option<bool>,
+        urb_file: Option<UrbFile>,
+    ) -> Result<Self, Error> {
         let desc = device.active_configuration()?;
+        let filter = allowed_interfaces.map(InterfaceFilter::new);
+        if let Some(filter) = &filter {
+            let interface_numbers = desc
+                .interfaces()
+                .map(|interface| interface.interface_number())
+                .collect::<Vec<_>>();
+            filter.check(&interface_numbers)?;
+        }
+        let reattach_drivers = reattach_drivers.unwrap_or_else(|| {
+            desc.interfaces()
+                .filter(|interface| {
+                    filter
+                        .as_ref()
+                        .is_none_or(|filter| filter.allows(interface.interface_number()))
+                })
+                .any(|interface| {
+                    interface
+                        .alt_settings()
+                        .any(|alt_setting| alt_setting.class() == HID_CLASS)
+                })
+        });
+
+        let wrapper = Self {
+            device: ArcSwap::from_pointee(device),
+            interfaces: Mutex::new(vec![]),
+            filter,
+            urb_file: urb_file.map(Arc::new),
+            reattach_drivers,
+            generation: AtomicU64::new(0),
+            released: CancellationToken::new(),
+        };
+        wrapper.claim_interfaces()?;
+
+        Ok(wrapper)
+    }
+
+    /// Claim the interfaces of the active configuration that are passed
+    /// through. An unconfigured device has no interfaces to claim.
+    fn claim_interfaces(&self) -> Result<(), Error> {
+        let device = self.device();
+        let Ok(desc) = device.active_configuration() else {
+            return Ok(());
+        };
+
+        let mut claimed = vec![];
         for interface in desc.interfaces() {
             let interface_number = interface.interface_number();
+            if !self
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.allows(interface_number))
+            {
+                continue;
+            }
+            if let Some(urb_file) = self
+                .urb_file
+                .as_ref()
+                .filter(|_| needs_urb_file(&interface))
+            {
+                debug!("Claiming interface {interface_number} for URBs");
+                urb_file.claim(interface_number)?;
+                continue;
+            }
             debug!("Claiming interface {}", interface_number);
-            interfaces.push(device.detach_and_claim_interface(interface_number).wait()?);
+            // Fails if no driver is bound, which is fine. The drivers are
+            // only reattached once the device is released for good, see
+            // `drop`, not whenever the guest selects a configuration.
+            let _ = device.detach_kernel_driver(interface_number);
+            claimed.push(device.claim_interface(interface_number).wait()?);
         }
+        self.interfaces.lock().unwrap().extend(claimed);
 
-        Ok(Self { device, interfaces })
+        Ok(())
     }
-}
 
-impl NusbDeviceWrapper {
-    fn get_interface_number_containing_endpoint(&self, endpoint_id: u8) -> Option<usize> {
-        self.interfaces.iter().position(|interface| {
-            interface
-                .descriptor()
-                .unwrap()
-                .endpoints()
-                .any(|ep| ep.address() == endpoint_id)
-        })
+    /// Select another configuration on behalf of the guest.
+    ///
+    /// The interfaces have to be released for that and are claimed again
+    /// afterwards, even if selecting the configuration failed. Endpoints
+    /// opened before are invalid afterwards.
+    fn set_configuration(&self, configuration: u8) -> Result<(), Error> {
+        debug!("Selecting configuration {configuration}");
+        self.release_interfaces();
+        self.generation.fetch_add(1, Ordering::Relaxed);
+
+        let result = self.device().set_configuration(configuration).wait();
+        self.claim_interfaces()?;
+
+        Ok(result?)
+    }
+
+    /// Release the claimed interfaces to claim them again. The host kernel
+    /// drivers stay detached.
+    fn release_interfaces(&self) {
+        self.interfaces.lock().unwrap().clear();
+        if let Some(urb_file) = &self.urb_file {
+            urb_file.release_all(false);
+        }
+    }
+
+    /// Select an alternate setting of a claimed interface on behalf of the
+    /// guest. Endpoints opened before are invalid afterwards.
+    fn set_alt_setting(&self, interface_number: u8, alt_setting: u8) -> Result<(), Error> {
+        debug!("Selecting alternate setting {alt_setting} of interface {interface_number}");
+        if let Some(urb_file) = self
+            .urb_file
+            .as_ref()
+            .filter(|urb_file| urb_file.claims(interface_number))
+        {
+            return Ok(urb_file.set_interface(interface_number, alt_setting)?);
+        }
+        let interface = self
+            .interfaces
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|interface| interface.interface_number() == interface_number)
+            .cloned()
+            .ok_or_else(|| anyhow!("Interface {interface_number} is not claimed"))?;
+        self.generation.fetch_add(1, Ordering::Relaxed);
+
+        Ok(interface.set_alt_setting(alt_setting).wait()?)
+    }
+
+    /// Reset the device on behalf of the guest and reopen it from `usbfs`.
+    ///
+    /// The interfaces are released for the reset and claimed again on the
+    /// reopened device. The host kernel drivers do not get them in between,
+    /// see [`Self::release_interfaces`]. Endpoints opened before are invalid
+    /// afterwards.
+    fn reset(&self, usbfs: &OwnedFd) -> Result<(), Error> {
+        debug!("Resetting the device");
+        self.release_interfaces();
+        self.generation.fetch_add(1, Ordering::Relaxed);
+
+        self.device().reset().wait()?;
+        // The usbfs file stays open across the reset. Reopening fails if the
+        // device re-enumerated, e.g., because its descriptors changed.
+        let device = nusb::Device::from_fd(usbfs.try_clone()?).wait()?;
+        self.device.store(Arc::new(device));
+        self.claim_interfaces()
+    }
+
+    fn device(&self) -> Arc<nusb::Device> {
+        self.device.load_full()
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Check whether the guest may use the endpoint. Only endpoints of
+    /// interfaces passed through are available.
+    ///
+    /// The guest configures the endpoints before it selects another
+    /// configuration, so all configurations are considered.
+    ///
+    /// nusb offers no isochronous transfers, so isochronous endpoints are
+    /// only available with a [`UrbFile`].
+    fn exposes_endpoint(&self, endpoint_id: u8) -> bool {
+        let endpoint_address = endpoint_id_to_address(endpoint_id);
+        let transfer_types: Vec<_> = self
+            .device()
+            .configurations()
+            .flat_map(|configuration| configuration.interface_alt_settings())
+            .filter(|alt_setting| {
+                self.filter
+                    .as_ref()
+                    .is_none_or(|filter| filter.allows(alt_setting.interface_number()))
+            })
+            .flat_map(|alt_setting| alt_setting.endpoints())
+            .filter(|ep| ep.address() == endpoint_address)
+            .map(|ep| ep.transfer_type())
+            .collect();
+
+        if transfer_types.contains(&TransferType::Isochronous) && self.urb_file.is_none() {
+            warn!("Isochronous endpoint {endpoint_address:#04x} is not available without URB file");
+            return false;
+        }
+
+        self.filter.is_none() || !transfer_types.is_empty()
+    }
+
+    /// The URB file, if it claimed the interface of the endpoint.
+    fn urb_file_of(&self, endpoint_id: u8) -> Option<Arc<UrbFile>> {
+        let interface = interface_of_endpoint(&self.device(), endpoint_id_to_address(endpoint_id))?;
+        self.urb_file
+            .clone()
+            .filter(|urb_file| urb_file.claims(interface))
+    }
+
+    /// Allocate the streams of a bulk endpoint on the URB file, as many as
+    /// its SuperSpeed endpoint companion descriptor allows.
+    fn alloc_streams(&self, endpoint_id: u8) -> Result<(), Error> {
+        let endpoint_address = endpoint_id_to_address(endpoint_id);
+        let urb_file = self.urb_file_of(endpoint_id).ok_or_else(|| {
+            anyhow!("Endpoint {endpoint_address:#04x} has no URB file for streams")
+        })?;
+        let device = self.device();
+        let interface = interface_of_endpoint(&device, endpoint_address).ok_or_else(|| {
+            anyhow!("Endpoint {endpoint_address:#04x} is not part of an interface")
+        })?;
+        let num_streams = device
+            .active_configuration()
+            .into_iter()
+            .flat_map(|configuration| configuration.interface_alt_settings())
+            .flat_map(|alt_setting| alt_setting.endpoints())
+            .filter(|ep| ep.address() == endpoint_address)
+            .map(|ep| max_streams(&ep))
+            .max()
+            .unwrap_or(0);
+        if num_streams == 0 {
+            return Err(anyhow!("Endpoint {endpoint_address:#04x} has no streams"));
+        }
+
+        Ok(urb_file.alloc_streams(interface, endpoint_address, num_streams)?)
+    }
+
+    /// The largest max packet size of the endpoint in any alternate setting
+    /// of the active configuration.
+    fn max_packet_size(&self, endpoint_id: u8) -> usize {
+        let endpoint_address = endpoint_id_to_address(endpoint_id);
+        self.device()
+            .active_configuration()
+            .into_iter()
+            .flat_map(|configuration| configuration.interface_alt_settings())
+            .flat_map(|alt_setting| alt_setting.endpoints())
+            .filter(|ep| ep.address() == endpoint_address)
+            .map(|ep| ep.max_packet_size())
+            .max()
+            .unwrap_or(1)
     }
 
     fn open_endpoint<EpType: EndpointType, Dir: EndpointDirection>(
@@ -72,10 +372,20 @@ impl NusbDeviceWrapper {
         endpoint_id: u8,
     ) -> Result<Endpoint<EpType, Dir>, Error> {
         let endpoint_address = endpoint_id_to_address(endpoint_id);
-        let interface_num = self
-            .get_interface_number_containing_endpoint(endpoint_address)
+        let interfaces = self.interfaces.lock().unwrap();
+        // Only the endpoints of the current alternate setting are available.
+        let interface = interfaces
+            .iter()
+            .find(|interface| {
+                interface.descriptor().is_some_and(|descriptor| {
+                    descriptor
+                        .endpoints()
+                        .any(|ep| ep.address() == endpoint_address)
+                })
+            })
             .ok_or_else(|| anyhow!("Endpoint with id {endpoint_id} is not part of an interface"))?;
-        let endpoint = self.interfaces[interface_num].endpoint(endpoint_address)?;
+        let endpoint = interface.endpoint(endpoint_address)?;
+        drop(interfaces);
 
         Ok(endpoint)
     }
@@ -90,52 +400,283 @@ const fn endpoint_id_to_address(endpoint_id: u8) -> u8 {
     endpoint_id.rotate_right(1)
 }
 
+/// The number of streams a bulk endpoint supports, according to its
+/// SuperSpeed endpoint companion descriptor.
+fn max_streams(endpoint: &EndpointDescriptor) -> u32 {
+    if endpoint.transfer_type() != TransferType::Bulk {
+        return 0;
+    }
+    endpoint
+        .descriptors()
+        .find(|desc| desc.descriptor_type() == DESCRIPTOR_TYPE_SS_ENDPOINT_COMPANION)
+        .and_then(|desc| desc.get(3).copied())
+        .map_or(0, |attributes| match attributes & 0x1f {
+            0 => 0,
+            max_streams => 1 << max_streams,
+        })
+}
+
+/// Whether nusb cannot do the transfers of the interface, because it has
+/// isochronous endpoints or endpoints with streams.
+fn needs_urb_file(interface: &InterfaceDescriptors) -> bool {
+    interface
+        .alt_settings()
+        .flat_map(|alt_setting| alt_setting.endpoints())
+        .any(|ep| ep.transfer_type() == TransferType::Isochronous || max_streams(&ep) > 0)
+}
+
+/// Look up the interface of the active configuration with an endpoint at
+/// `address` in any of its alternate settings.
+fn interface_of_endpoint(device: &nusb::Device, address: u8) -> Option<u8> {
+    device
+        .active_configuration()
+        .ok()?
+        .interface_alt_settings()
+        .find(|alt_setting| alt_setting.endpoints().any(|ep| ep.address() == address))
+        .map(|alt_setting| alt_setting.interface_number())
+}
+
+/// Read vendor ID, product ID and serial number of the device.
+///
+/// Devices without (readable) serial number string yield an ID without
+/// serial.
+fn read_usb_id(device: &nusb::Device) -> UsbId {
+    let descriptor = device.device_descriptor();
+    let serial = descriptor.serial_number_string_index().and_then(|index| {
+        device
+            .get_string_descriptor(index, US_ENGLISH, Duration::from_millis(500))
+            .wait()
+            .inspect_err(|e| debug!("Failed to read serial number: {e}"))
+            .ok()
+    });
+
+    UsbId {
+        vendor_id: descriptor.vendor_id(),
+        product_id: descriptor.product_id(),
+        serial,
+    }
+}
+
+/// Collect what the host knows about the device with the given bus and
+/// device number.
+pub fn read_host_device_info(device: &nusb::Device, bus: u8, dev: u8) -> HostDeviceInfo {
+    HostDeviceInfo {
+        usb_id: read_usb_id(device),
+        port_path: find_host_port_path(bus, dev),
+    }
+}
+
+/// Look up the host port path (e.g. `1-3.2`) of the device with the given
+/// bus and device number.
+fn find_host_port_path(bus: u8, dev: u8) -> Option<String> {
+    let port_path = nusb::list_devices()
+        .wait()
+        .inspect_err(|e| debug!("Failed to enumerate USB devices: {e}"))
+        .ok()?
+        .find(|info| info.busnum() == bus && info.device_address() == dev)?
+        .sysfs_path()
+        .file_name()?
+        .to_str()
+        .map(str::to_string);
+
+    if port_path.is_none() {
+        debug!("Could not determine host port path of device {bus:03}:{dev:03}");
+    }
+    port_path
+}
+
 #[derive(Debug)]
 pub struct NusbRealDevice {
     device_wrapper: Arc<NusbDeviceWrapper>,
+    // another file descriptor of the usbfs device file for the requests
+    // that nusb does not offer
+    usbfs: OwnedFd,
     async_runtime: runtime::Handle,
 }
 
 impl NusbRealDevice {
-    pub fn try_new(device: nusb::Device, async_runtime: runtime::Handle) -> Result<Self, Error> {
-        let device_wrapper = NusbDeviceWrapper::try_from(device)?;
+    /// Claim the device for passthrough. See [`NusbDeviceWrapper::claim`]
+    /// for `interfaces` and `reattach_drivers`.
+    ///
+    /// `usbfs` has to refer to the same open usbfs file as `device`, e.g.,
+    /// a duplicate of the file descriptor `device` was created from.
+    /// `urb_file` is a second usbfs file of the device for isochronous
+    /// transfers and bulk streams. Without it, the device is opened again
+    /// if needed.
+    pub fn try_new(
+        device: nusb::Device,
+        usbfs: OwnedFd,
+        urb_file: Option<OwnedFd>,
+        interfaces: Option<Vec<u8>>,
+        reattach_drivers: Option<bool>,
+        async_runtime: runtime::Handle,
+    ) -> Result<Self, Error> {
+        let passed_through: Vec<_> = device
+            .configurations()
+            .flat_map(|configuration| configuration.interfaces().collect::<Vec<_>>())
+            .filter(|interface| {
+                interfaces
+                    .as_ref()
+                    .is_none_or(|allowed| allowed.contains(&interface.interface_number()))
+            })
+            .collect();
+        let urb_file = if passed_through.iter().any(needs_urb_file) {
+            // The guest can still use the other endpoints.
+            urb_file
+                .map_or_else(
+                    || UrbFile::open(&usbfs, &async_runtime),
+                    |file| UrbFile::new(file, &async_runtime),
+                )
+                .inspect_err(|e| {
+                    warn!("Isochronous endpoints and bulk streams are not available: {e}");
+                })
+                .ok()
+        } else {
+            None
+        };
+        let device_wrapper =
+            NusbDeviceWrapper::claim(device, interfaces, reattach_drivers, urb_file)?;
 
         Ok(Self {
             device_wrapper: Arc::new(device_wrapper),
+            usbfs,
             async_runtime,
         })
     }
+
+    /// A token that is cancelled once the device is no longer used and its
+    /// interfaces are released.
+    pub fn released(&self) -> CancellationToken {
+        self.device_wrapper.released.clone()
+    }
+
+    fn normal_endpoint_handle<EpType: BulkOrInterrupt, Dir: EndpointDirection>(
+        &self,
+        endpoint_id: u8,
+        kind: UrbKind,
+    ) -> BulkOrInterruptHandle<EpType, Dir> {
+        match self.device_wrapper.urb_file_of(endpoint_id) {
+            Some(_) => BulkOrInterruptHandle::Urb(self.urb_endpoint_handle(endpoint_id, kind)),
+            None => BulkOrInterruptHandle::Nusb(NormalEndpointHandle::new(
+                endpoint_id,
+                self.device_wrapper.clone(),
+            )),
+        }
+    }
+
+    fn stream_handle<EpType: BulkOrInterrupt, Dir: EndpointDirection>(
+        &self,
+        endpoint_id: u8,
+        stream_id: u16,
+    ) -> BulkOrInterruptHandle<EpType, Dir> {
+        BulkOrInterruptHandle::Urb(
+            self.urb_endpoint_handle(endpoint_id, UrbKind::Bulk { stream_id }),
+        )
+    }
+
+    fn urb_endpoint_handle(&self, endpoint_id: u8, kind: UrbKind) -> UrbEndpointHandle {
+        UrbEndpointHandle {
+            urb_file: self.device_wrapper.urb_file_of(endpoint_id),
+            endpoint_address: endpoint_id_to_address(endpoint_id),
+            kind,
+            max_packet_size: self.device_wrapper.max_packet_size(endpoint_id),
+            pending: VecDeque::new(),
+        }
+    }
 }
 
 impl RealDevice for NusbRealDevice {
     type RCEH = ControlEndpointHandle;
-    type RBIEH = NormalEndpointHandle<Bulk, In>;
-    type RBOEH = NormalEndpointHandle<Bulk, Out>;
-    type RIIEH = NormalEndpointHandle<Interrupt, In>;
-    type RIOEH = NormalEndpointHandle<Interrupt, Out>;
+    type RBIEH = BulkOrInterruptHandle<Bulk, In>;
+    type RBOEH = BulkOrInterruptHandle<Bulk, Out>;
+    type RIIEH = BulkOrInterruptHandle<Interrupt, In>;
+    type RIOEH = BulkOrInterruptHandle<Interrupt, Out>;
+    type RISIEH = UrbEndpointHandle;
+    type RISOEH = UrbEndpointHandle;
+
+    // Streams are allocated and used on the URB file, so only endpoints of
+    // interfaces it claimed get them, see `alloc_streams`.
+    const SUPPORTS_STREAMS: bool = true;
 
     fn speed(&self) -> Option<super::real_device::Speed> {
-        self.device_wrapper.device.speed().map(|speed| speed.into())
+        self.device_wrapper
+            .device()
+            .speed()
+            .map(|speed| speed.into())
+    }
+
+    // The host suspends the device only if its autosuspend is enabled
+    // (power/control is "auto" in sysfs).
+    fn suspend(&self) -> anyhow::Result<()> {
+        Ok(usbfs_ioctl(&self.usbfs, usbdevfs::ALLOW_SUSPEND)?)
+    }
+
+    fn resume(&self) -> anyhow::Result<()> {
+        Ok(usbfs_ioctl(&self.usbfs, usbdevfs::FORBID_SUSPEND)?)
+    }
+
+    // usbfs offers no request for the runtime power state, but sysfs does.
+    fn host_suspended(&self) -> anyhow::Result<bool> {
+        let device_number = File::from(self.usbfs.try_clone()?).metadata()?.rdev();
+        let runtime_status = fs::read_to_string(format!(
+            "/sys/dev/char/{}:{}/power/runtime_status",
+            libc::major(device_number),
+            libc::minor(device_number)
+        ))?;
+
+        Ok(runtime_status.trim() == "suspended")
+    }
+
+    fn wait_for_resume(&self) -> anyhow::Result<()> {
+        Ok(usbfs_ioctl(&self.usbfs, usbdevfs::WAIT_FOR_RESUME)?)
+    }
+
+    fn reset(&self) -> anyhow::Result<()> {
+        self.device_wrapper.reset(&self.usbfs)
+    }
+
+    fn exposes_endpoint(&self, endpoint_id: u8) -> bool {
+        self.device_wrapper.exposes_endpoint(endpoint_id)
     }
 
     fn control_endpoint_handle(&self) -> Self::RCEH {
-        ControlEndpointHandle::new(self.device_wrapper.device.clone(), &self.async_runtime)
+        ControlEndpointHandle::new(self.device_wrapper.clone(), &self.async_runtime)
     }
 
     fn bulk_in_endpoint_handle(&self, endpoint_id: u8) -> Self::RBIEH {
-        NormalEndpointHandle::new(endpoint_id, self.device_wrapper.clone())
+        self.normal_endpoint_handle(endpoint_id, UrbKind::Bulk { stream_id: 0 })
     }
 
     fn bulk_out_endpoint_handle(&self, endpoint_id: u8) -> Self::RBOEH {
-        NormalEndpointHandle::new(endpoint_id, self.device_wrapper.clone())
+        self.normal_endpoint_handle(endpoint_id, UrbKind::Bulk { stream_id: 0 })
+    }
+
+    fn alloc_streams(&self, endpoint_id: u8) -> anyhow::Result<()> {
+        self.device_wrapper.alloc_streams(endpoint_id)
+    }
+
+    fn bulk_in_stream_handle(&self, endpoint_id: u8, stream_id: u16) -> Self::RBIEH {
+        self.stream_handle(endpoint_id, stream_id)
+    }
+
+    fn bulk_out_stream_handle(&self, endpoint_id: u8, stream_id: u16) -> Self::RBOEH {
+        self.stream_handle(endpoint_id, stream_id)
     }
 
     fn interrupt_in_endpoint_handle(&self, endpoint_id: u8) -> Self::RIIEH {
-        NormalEndpointHandle::new(endpoint_id, self.device_wrapper.clone())
+        self.normal_endpoint_handle(endpoint_id, UrbKind::Interrupt)
     }
 
     fn interrupt_out_endpoint_handle(&self, endpoint_id: u8) -> Self::RIOEH {
-        NormalEndpointHandle::new(endpoint_id, self.device_wrapper.clone())
+        self.normal_endpoint_handle(endpoint_id, UrbKind::Interrupt)
+    }
+
+    fn isoch_in_endpoint_handle(&self, endpoint_id: u8) -> Self::RISIEH {
+        self.urb_endpoint_handle(endpoint_id, UrbKind::Isochronous)
+    }
+
+    fn isoch_out_endpoint_handle(&self, endpoint_id: u8) -> Self::RISOEH {
+        self.urb_endpoint_handle(endpoint_id, UrbKind::Isochronous)
     }
 }
 
@@ -190,13 +731,13 @@ impl BaseEndpointHandle for ControlEndpointHandle {
 }
 
 impl ControlEndpointHandle {
-    fn new(device: nusb::Device, async_runtime: &runtime::Handle) -> Self {
+    fn new(device_wrapper: Arc<NusbDeviceWrapper>, async_runtime: &runtime::Handle) -> Self {
         let (request_submitter, request_receiver) = mpsc::unbounded_channel();
         let (response_submitter, response_receiver) = mpsc::unbounded_channel();
         let cancel = CancellationToken::new();
 
         async_runtime.spawn(cancellable_control_endpoint_worker(
-            device,
+            device_wrapper,
             request_receiver,
             response_submitter,
             cancel.clone(),
@@ -211,64 +752,28 @@ impl ControlEndpointHandle {
 }
 
 async fn cancellable_control_endpoint_worker(
-    device: nusb::Device,
+    device_wrapper: Arc<NusbDeviceWrapper>,
     request_receiver: mpsc::UnboundedReceiver<UsbRequest>,
     response_submitter: mpsc::UnboundedSender<ControlRequestProcessingResult>,
     cancel: CancellationToken,
 ) {
     select! {
-        _ = control_endpoint_worker(device, request_receiver, response_submitter) => {},
+        _ = control_endpoint_worker(device_wrapper, request_receiver, response_submitter) => {},
         _ = cancel.cancelled() => {},
     }
 }
 
 // this function can only return with an error, but ! cannot be used in Result
 async fn control_endpoint_worker(
-    device: nusb::Device,
+    device_wrapper: Arc<NusbDeviceWrapper>,
     mut request_receiver: mpsc::UnboundedReceiver<UsbRequest>,
     response_submitter: mpsc::UnboundedSender<ControlRequestProcessingResult>,
 ) -> anyhow::Result<()> {
     loop {
         if let Some(request) = request_receiver.recv().await {
-            let (recipient, control_type) = extract_recipient_and_type(request.request_type);
-            let is_out_request = request.request_type & 0x80 == 0;
-
-            let processing_result = match is_out_request {
-                true => {
-                    let data = request.data.unwrap_or(Vec::new());
-                    let control = ControlOut {
-                        control_type,
-                        recipient,
-                        request: request.request,
-                        value: request.value,
-                        index: request.index,
-                        data: &data,
-                    };
-                    match device
-                        .control_out(control, Duration::from_millis(2000))
-                        .await
-                    {
-                        Ok(_) => ControlRequestProcessingResult::SuccessfulControlOut,
-                        Err(err) => map_error(err),
-                    }
-                }
-                false => {
-                    let control = ControlIn {
-                        control_type,
-                        recipient,
-                        request: request.request,
-                        value: request.value,
-                        index: request.index,
-                        length: request.length,
-                    };
-                    match device
-                        .control_in(control, Duration::from_millis(2000))
-                        .await
-                    {
-                        Ok(data) => ControlRequestProcessingResult::SuccessfulControlIn(data),
-                        Err(err) => map_error(err),
-                    }
-                }
+            let processing_result = match &device_wrapper.filter {
+                Some(filter) => filtered_control_request(&device_wrapper, filter, request).await,
+                None => control_request(&device_wrapper, request).await,
             };
 
             response_submitter.send(processing_result)?;
@@ -276,6 +781,131 @@ async fn control_endpoint_worker(
     }
 }
 
+async fn control_request(
+    device_wrapper: &Arc<NusbDeviceWrapper>,
+    request: UsbRequest,
+) -> ControlRequestProcessingResult {
+    // The claimed interfaces have to follow the configuration and alternate
+    // settings, so these requests cannot be forwarded verbatim.
+    match (request.request_type, request.request) {
+        (0x00, REQUEST_SET_CONFIGURATION) => {
+            let configuration = request.value as u8;
+            return change_interfaces(device_wrapper, move |device_wrapper| {
+                device_wrapper.set_configuration(configuration)
+            })
+            .await;
+        }
+        (0x01, REQUEST_SET_INTERFACE) => {
+            let (interface_number, alt_setting) = (request.index as u8, request.value as u8);
+            return change_interfaces(device_wrapper, move |device_wrapper| {
+                device_wrapper.set_alt_setting(interface_number, alt_setting)
+            })
+            .await;
+        }
+        _ => {}
+    }
+
+    let device = device_wrapper.device();
+    let (recipient, control_type) = extract_recipient_and_type(request.request_type);
+    let is_out_request = request.request_type & 0x80 == 0;
+
+    match is_out_request {
+        true => {
+            let data = request.data.unwrap_or_default();
+            let control = ControlOut {
+                control_type,
+                recipient,
+                request: request.request,
+                value: request.value,
+                index: request.index,
+                data: &data,
+            };
+            match device.control_out(control, CONTROL_TRANSFER_TIMEOUT).await {
+                Ok(_) => ControlRequestProcessingResult::SuccessfulControlOut,
+                Err(err) => map_error(err),
+            }
+        }
+        false => {
+            let control = ControlIn {
+                control_type,
+                recipient,
+                request: request.request,
+                value: request.value,
+                index: request.index,
+                length: request.length,
+            };
+            match device.control_in(control, CONTROL_TRANSFER_TIMEOUT).await {
+                Ok(data) => ControlRequestProcessingResult::SuccessfulControlIn(data),
+                Err(err) => map_error(err),
+            }
+        }
+    }
+}
+
+/// Run a blocking change of the claimed interfaces.
+async fn change_interfaces<F>(
+    device_wrapper: &Arc<NusbDeviceWrapper>,
+    change: F,
+) -> ControlRequestProcessingResult
+where
+    F: FnOnce(&NusbDeviceWrapper) -> Result<(), Error> + Send + 'static,
+{
+    let device_wrapper = device_wrapper.clone();
+    match tokio::task::spawn_blocking(move || change(&device_wrapper)).await {
+        Ok(Ok(())) => ControlRequestProcessingResult::SuccessfulControlOut,
+        Ok(Err(err)) => {
+            warn!("Failed to change the interfaces of the device: {err:#}");
+            ControlRequestProcessingResult::Stall
+        }
+        Err(err) => {
+            warn!("Failed to change the interfaces of the device: {err}");
+            ControlRequestProcessingResult::TransactionError
+        }
+    }
+}
+
+// Hide the interfaces not passed through from the guest.
+async fn filtered_control_request(
+    device_wrapper: &Arc<NusbDeviceWrapper>,
+    filter: &InterfaceFilter,
+    request: UsbRequest,
+) -> ControlRequestProcessingResult {
+    let device = device_wrapper.device();
+    if InterfaceFilter::is_configuration_request(&request) {
+        // The guest may only read the beginning of the descriptor, but the
+        // filter needs all of it.
+        let control = ControlIn {
+            control_type: ControlType::Standard,
+            recipient: Recipient::Device,
+            request: request.request,
+            value: request.value,
+            index: request.index,
+            length: MAX_CONTROL_TRANSFER_LENGTH,
+        };
+        return match device.control_in(control, CONTROL_TRANSFER_TIMEOUT).await {
+            Ok(configuration) => {
+                let mut configuration = filter.filter_configuration(&configuration);
+                configuration.truncate(request.length.into());
+                ControlRequestProcessingResult::SuccessfulControlIn(configuration)
+            }
+            Err(err) => map_error(err),
+        };
+    }
+
+    let allowed = InterfaceFilter::target_interface(&request)
+        .is_none_or(|interface_number| filter.allows(interface_number))
+        && InterfaceFilter::target_endpoint(&request).is_none_or(|address| {
+            interface_of_endpoint(&device, address)
+                .is_some_and(|interface_number| filter.allows(interface_number))
+        });
+    if !allowed {
+        debug!("Refusing control request to an interface not passed through: {request:?}");
+        return ControlRequestProcessingResult::Stall;
+    }
+
+    control_request(device_wrapper, request).await
+}
+
 const fn map_error(error: TransferError) -> ControlRequestProcessingResult {
     match error {
         TransferError::Cancelled => ControlRequestProcessingResult::TransactionError,
@@ -306,7 +936,10 @@ fn extract_recipient_and_type(request_type: u8) -> (Recipient, ControlType) {
 pub struct NormalEndpointHandle<EpType: EndpointType + 'static, Dir: EndpointDirection + 'static> {
     id: u8,
     device_wrapper: Arc<NusbDeviceWrapper>,
-    endpoint: Option<Endpoint<EpType, Dir>>,
+    // the opened endpoint with the generation of the interfaces it belongs to
+    endpoint: Option<(u64, Endpoint<EpType, Dir>)>,
+    // submissions that failed because the endpoint is not available
+    failed_submissions: usize,
 }
 
 impl<EpType: EndpointType, Dir: EndpointDirection> NormalEndpointHandle<EpType, Dir> {
@@ -315,6 +948,7 @@ impl<EpType: EndpointType, Dir: EndpointDirection> NormalEndpointHandle<EpType,
             id,
             device_wrapper,
             endpoint: None,
+            failed_submissions: 0,
         }
     }
 }
@@ -328,15 +962,51 @@ impl<EpType: EndpointType, Dir: EndpointDirection> Debug for NormalEndpointHandl
 }
 
 impl<EpType: EndpointType, Dir: EndpointDirection> NormalEndpointHandle<EpType, Dir> {
-    fn endpoint(&mut self) -> &mut Endpoint<EpType, Dir> {
-        match self.endpoint {
-            Some(ref mut endpoint) => endpoint,
-            None => {
-                let ep: Endpoint<EpType, Dir> = self.device_wrapper.open_endpoint(self.id).expect("Failed to open endpoint on nusb device. We could handle this error and always return transaction errors. Panic is fine for now.");
-                self.endpoint = Some(ep);
-                self.endpoint.as_mut().unwrap()
+    /// Open the endpoint, or reopen it if the configuration or alternate
+    /// setting changed since. Returns `None` if the endpoint is not part of
+    /// the current alternate setting of a claimed interface.
+    fn endpoint(&mut self) -> Option<&mut Endpoint<EpType, Dir>> {
+        let generation = self.device_wrapper.generation();
+        if self
+            .endpoint
+            .as_ref()
+            .is_some_and(|(opened, _)| *opened != generation)
+        {
+            debug!(
+                "Reopening endpoint {} after the interfaces changed",
+                self.id
+            );
+            self.endpoint = None;
+        }
+
+        if self.endpoint.is_none() {
+            match self.device_wrapper.open_endpoint(self.id) {
+                Ok(endpoint) => self.endpoint = Some((generation, endpoint)),
+                Err(err) => {
+                    warn!("Failed to open endpoint {} on nusb device: {err}", self.id);
+                    return None;
+                }
             }
         }
+
+        self.endpoint.as_mut().map(|(_, endpoint)| endpoint)
+    }
+
+    /// The endpoint with pending transfers, if any.
+    fn pending_endpoint(&mut self) -> Option<&mut Endpoint<EpType, Dir>> {
+        self.endpoint
+            .as_mut()
+            .map(|(_, endpoint)| endpoint)
+            .filter(|endpoint| endpoint.pending() > 0)
+    }
+
+    /// Take a failed submission to complete, if any.
+    const fn take_failed_submission(&mut self) -> bool {
+        let failed = self.failed_submissions > 0;
+        if failed {
+            self.failed_submissions -= 1;
+        }
+        failed
     }
 }
 
@@ -345,15 +1015,24 @@ impl<EpType: BulkOrInterrupt> RealOutEndpointHandle for NormalEndpointHandle<EpT
         Pin<Box<dyn Future<Output = anyhow::Result<OutTrbProcessingResult>> + Send + 'a>>;
 
     fn submit(&mut self, data: Vec<u8>) -> anyhow::Result<()> {
-        let buf = Buffer::from(data);
-        self.endpoint().submit(buf);
+        match self.endpoint() {
+            Some(endpoint) => endpoint.submit(Buffer::from(data)),
+            None => self.failed_submissions += 1,
+        }
 
         Ok(())
     }
 
     fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_> {
         Box::pin(async {
-            let completion = self.endpoint().next_complete().await;
+            if self.take_failed_submission() {
+                return Ok(OutTrbProcessingResult::TransactionError);
+            }
+            // Transfers pending on an endpoint that was reopened since are lost.
+            let Some(endpoint) = self.pending_endpoint() else {
+                return Ok(OutTrbProcessingResult::TransactionError);
+            };
+            let completion = endpoint.next_complete().await;
             let result = match completion.status {
                 Err(err) => match err {
                     TransferError::Cancelled => OutTrbProcessingResult::TransactionError,
@@ -376,21 +1055,35 @@ impl<EpType: BulkOrInterrupt> RealInEndpointHandle for NormalEndpointHandle<EpTy
         Pin<Box<dyn Future<Output = anyhow::Result<InTrbProcessingResult>> + Send + 'a>>;
 
     fn submit(&mut self, len: usize) -> anyhow::Result<()> {
-        let endpoint = self.endpoint();
-        let request_len = determine_buffer_size(len, endpoint.max_packet_size());
-        let buf = Buffer::new(request_len);
-        endpoint.submit(buf);
+        match self.endpoint() {
+            Some(endpoint) => {
+                let request_len = determine_buffer_size(len, endpoint.max_packet_size());
+                endpoint.submit(Buffer::new(request_len));
+            }
+            None => self.failed_submissions += 1,
+        }
 
         Ok(())
     }
 
     fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_> {
         Box::pin(async {
+            let failed = InTrbProcessingResult {
+                status: InTrbProcessingStatus::TransactionError,
+                data: vec![],
+            };
+            if self.take_failed_submission() {
+                return Ok(failed);
+            }
+            // Transfers pending on an endpoint that was reopened since are lost.
+            let Some(endpoint) = self.pending_endpoint() else {
+                return Ok(failed);
+            };
             let Completion {
                 buffer: data,
                 actual_len: _,
                 status,
-            } = self.endpoint().next_complete().await;
+            } = endpoint.next_complete().await;
             let data = data.into_vec();
             let status = map_status(status);
 
@@ -420,7 +1113,10 @@ impl<EpType: BulkOrInterrupt, Dir: EndpointDirection> BaseEndpointHandle
 
     fn cancel(&mut self) -> Self::CompletionFuture<'_> {
         Box::pin(async {
-            let ep = self.endpoint();
+            self.failed_submissions = 0;
+            let Some((_, ep)) = self.endpoint.as_mut() else {
+                return Ok(());
+            };
             ep.cancel_all();
 
             // have to consume all cancelled TRBs (should be 0 or 1)
@@ -437,7 +1133,225 @@ impl<EpType: BulkOrInterrupt, Dir: EndpointDirection> BaseEndpointHandle
 
     fn clear_halt(&mut self) -> Self::CompletionFuture<'_> {
         Box::pin(async {
-            if let Err(error) = self.endpoint().clear_halt().await {
+            let Some(endpoint) = self.endpoint() else {
+                return Ok(());
+            };
+            if let Err(error) = endpoint.clear_halt().await {
+                warn!("clear_halt failed on non-control endpoint: {error}");
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Transfers on bulk and interrupt endpoints, by nusb or by the URB file if
+/// it claimed the interface of the endpoint.
+pub enum BulkOrInterruptHandle<EpType: BulkOrInterrupt + 'static, Dir: EndpointDirection + 'static>
+{
+    Nusb(NormalEndpointHandle<EpType, Dir>),
+    Urb(UrbEndpointHandle),
+}
+
+impl<EpType: BulkOrInterrupt + 'static, Dir: EndpointDirection + 'static> Debug
+    for BulkOrInterruptHandle<EpType, Dir>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nusb(handle) => f.debug_tuple("Nusb").field(handle).finish(),
+            Self::Urb(handle) => f.debug_tuple("Urb").field(handle).finish(),
+        }
+    }
+}
+
+impl<EpType: BulkOrInterrupt + 'static> RealInEndpointHandle for BulkOrInterruptHandle<EpType, In> {
+    type TrbCompletionFuture<'a> =
+        Pin<Box<dyn Future<Output = anyhow::Result<InTrbProcessingResult>> + Send + 'a>>;
+
+    fn submit(&mut self, len: usize) -> anyhow::Result<()> {
+        match self {
+            Self::Nusb(handle) => handle.submit(len),
+            Self::Urb(handle) => RealInEndpointHandle::submit(handle, len),
+        }
+    }
+
+    fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_> {
+        match self {
+            Self::Nusb(handle) => handle.next_completion(),
+            Self::Urb(handle) => RealInEndpointHandle::next_completion(handle),
+        }
+    }
+}
+
+impl<EpType: BulkOrInterrupt + 'static> RealOutEndpointHandle
+    for BulkOrInterruptHandle<EpType, Out>
+{
+    type TrbCompletionFuture<'a> =
+        Pin<Box<dyn Future<Output = anyhow::Result<OutTrbProcessingResult>> + Send + 'a>>;
+
+    fn submit(&mut self, data: Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            Self::Nusb(handle) => handle.submit(data),
+            Self::Urb(handle) => RealOutEndpointHandle::submit(handle, data),
+        }
+    }
+
+    fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_> {
+        match self {
+            Self::Nusb(handle) => handle.next_completion(),
+            Self::Urb(handle) => RealOutEndpointHandle::next_completion(handle),
+        }
+    }
+}
+
+impl<EpType: BulkOrInterrupt + 'static, Dir: EndpointDirection + 'static> BaseEndpointHandle
+    for BulkOrInterruptHandle<EpType, Dir>
+{
+    type CompletionFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+    fn cancel(&mut self) -> Self::CompletionFuture<'_> {
+        match self {
+            Self::Nusb(handle) => handle.cancel(),
+            Self::Urb(handle) => handle.cancel(),
+        }
+    }
+
+    fn clear_halt(&mut self) -> Self::CompletionFuture<'_> {
+        match self {
+            Self::Nusb(handle) => handle.clear_halt(),
+            Self::Urb(handle) => handle.clear_halt(),
+        }
+    }
+}
+
+/// Transfers on an endpoint of an interface claimed by the [`UrbFile`].
+#[derive(Debug)]
+pub struct UrbEndpointHandle {
+    // without URB file, every transfer fails
+    urb_file: Option<Arc<UrbFile>>,
+    endpoint_address: u8,
+    kind: UrbKind,
+    max_packet_size: usize,
+    // `None` for transfers that failed to submit
+    pending: VecDeque<Option<(UrbId, oneshot::Receiver<Box<Transfer>>)>>,
+}
+
+impl UrbEndpointHandle {
+    fn submit_transfer(&mut self, buffer: Vec<u8>) {
+        let transfer = Transfer::new(self.kind, self.endpoint_address, buffer);
+        let submitted = self.urb_file.as_ref().and_then(|urb_file| {
+            urb_file
+                .submit(transfer)
+                .inspect_err(|e| {
+                    debug!(
+                        "Failed to submit URB on endpoint {:#04x}: {e}",
+                        self.endpoint_address
+                    );
+                })
+                .ok()
+        });
+        self.pending.push_back(submitted);
+    }
+
+    /// Wait for the next transfer. A transfer that was not submitted fails
+    /// right away.
+    ///
+    /// The transfer stays pending until it completes, so waiting again
+    /// after the future was dropped returns the same transfer.
+    async fn next_transfer(&mut self) -> (Result<(), TransferError>, Vec<u8>) {
+        let Some(Some((_, completion))) = self.pending.front_mut() else {
+            self.pending.pop_front();
+            return (Err(TransferError::InvalidArgument), vec![]);
+        };
+
+        // Without completion, the device is gone.
+        let result = completion.await.map_or_else(
+            |_| (Err(TransferError::Disconnected), vec![]),
+            |transfer| transfer.into_completion(),
+        );
+        self.pending.pop_front();
+
+        result
+    }
+}
+
+impl RealInEndpointHandle for UrbEndpointHandle {
+    type TrbCompletionFuture<'a> =
+        Pin<Box<dyn Future<Output = anyhow::Result<InTrbProcessingResult>> + Send + 'a>>;
+
+    fn submit(&mut self, len: usize) -> anyhow::Result<()> {
+        // An isochronous transfer is a single packet of the TD's size.
+        let request_len = match self.kind {
+            UrbKind::Isochronous => len,
+            _ => determine_buffer_size(len, self.max_packet_size),
+        };
+        self.submit_transfer(vec![0; request_len]);
+
+        Ok(())
+    }
+
+    fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_> {
+        Box::pin(async {
+            let (status, data) = self.next_transfer().await;
+
+            Ok(InTrbProcessingResult {
+                status: map_status(status),
+                data,
+            })
+        })
+    }
+}
+
+impl RealOutEndpointHandle for UrbEndpointHandle {
+    type TrbCompletionFuture<'a> =
+        Pin<Box<dyn Future<Output = anyhow::Result<OutTrbProcessingResult>> + Send + 'a>>;
+
+    fn submit(&mut self, data: Vec<u8>) -> anyhow::Result<()> {
+        self.submit_transfer(data);
+
+        Ok(())
+    }
+
+    fn next_completion(&mut self) -> Self::TrbCompletionFuture<'_> {
+        Box::pin(async {
+            let (status, _) = self.next_transfer().await;
+
+            Ok(match map_status(status) {
+                InTrbProcessingStatus::Success => OutTrbProcessingResult::Success,
+                InTrbProcessingStatus::Stall => OutTrbProcessingResult::Stall,
+                InTrbProcessingStatus::Disconnect => OutTrbProcessingResult::Disconnect,
+                InTrbProcessingStatus::TransactionError => OutTrbProcessingResult::TransactionError,
+            })
+        })
+    }
+}
+
+impl BaseEndpointHandle for UrbEndpointHandle {
+    type CompletionFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+    fn cancel(&mut self) -> Self::CompletionFuture<'_> {
+        Box::pin(async {
+            let pending = mem::take(&mut self.pending);
+            let Some(urb_file) = &self.urb_file else {
+                return Ok(());
+            };
+            for (id, _) in pending.iter().flatten() {
+                urb_file.discard(*id);
+            }
+            // the discarded transfers complete as cancelled
+            for (_, completion) in pending.into_iter().flatten() {
+                let _ = completion.await;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn clear_halt(&mut self) -> Self::CompletionFuture<'_> {
+        Box::pin(async {
+            let Some(urb_file) = &self.urb_file else {
+                return Ok(());
+            };
+            if let Err(error) = urb_file.clear_halt(self.endpoint_address) {
                 warn!("clear_halt failed on non-control endpoint: {error}");
             }
             Ok(())
@@ -1,21 +1,38 @@
-use std::{array, mem, sync::Arc};
+use std::{
+    array, mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use tokio::{
     runtime,
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
+    task::AbortHandle,
+    time::sleep,
 };
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info};
-use usbvfiod::hotplug_protocol::response::Response;
+use tracing::{debug, info, warn};
+use usbvfiod::hotplug_protocol::{response::Response, usb_id::UsbId};
 
 use crate::{
     device::{
-        pci::constants::xhci::{offset, operational::portsc, MAX_PORTS, NUM_USB3_PORTS},
+        pci::constants::xhci::{
+            offset, operational::portsc, rings::device_notification_types, MAX_PORTS,
+            NUM_USB3_PORTS,
+        },
         xhci::{
+            controller_state::StateSender,
             interrupter::EventSender,
-            real_device::{CompleteRealDevice, RealDevice, Speed},
-            registers::{PortpmscRegister, PortscRegister},
+            real_device::{CompleteRealDevice, HostDeviceInfo, Identifier, RealDevice, Speed},
+            registers::{
+                LinkStateRequest, PortRegisterState, PortliRegister, PortpmscRegister,
+                PortscRegister,
+            },
+            slot_manager::SlotWorkerHandle,
             trb::EventTrb,
         },
     },
@@ -23,35 +40,60 @@ use crate::{
     oneshot_anyhow::SendWithAnyhowError,
 };
 
+/// How often a resume waiter checks whether the host suspended the device.
+const HOST_SUSPEND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 #[derive(Debug)]
 pub struct PortArray<CRD: CompleteRealDevice> {
     portsc: Arc<OneIndexed<PortscRegister, { MAX_PORTS as usize }>>,
     portpmsc: Arc<OneIndexed<PortpmscRegister, { MAX_PORTS as usize }>>,
+    portli: Arc<OneIndexed<PortliRegister, { MAX_PORTS as usize }>>,
     pub msg_sender: mpsc::UnboundedSender<PortMessage<CRD>>,
+    lost_devices: broadcast::Sender<LostDevice<CRD::ID>>,
+    slot_handle: Arc<OnceLock<SlotWorkerHandle>>,
 }
 
 impl<CRD: CompleteRealDevice> PortArray<CRD> {
     pub fn new(event_sender: EventSender, async_runtime: runtime::Handle) -> Self {
+        // SAFETY: port_id is capped at 255 according to spec
+        let port_version = |index: usize| Self::port_version(index as u8 + 1);
+
+        let portpmsc: Arc<OneIndexed<PortpmscRegister, { MAX_PORTS as usize }>> =
+            Arc::new(array::from_fn(|index| PortpmscRegister::new(port_version(index))).into());
+        let portli: Arc<OneIndexed<PortliRegister, { MAX_PORTS as usize }>> =
+            Arc::new(array::from_fn(|index| PortliRegister::new(port_version(index))).into());
+
         let portsc: Arc<OneIndexed<PortscRegister, { MAX_PORTS as usize }>> = Arc::new(
             array::from_fn(|index| {
-                // SAFETY: port_id is capped at 255 according to spec
                 let port_id = index as u8 + 1;
-                PortscRegister::new(event_sender.clone(), Self::port_version(port_id), port_id)
+                PortscRegister::new(
+                    event_sender.clone(),
+                    port_version(index),
+                    port_id,
+                    portpmsc[port_id as usize].clone(),
+                    portli[port_id as usize].clone(),
+                )
             })
             .into(),
         );
 
-        let portpmsc: Arc<OneIndexed<PortpmscRegister, { MAX_PORTS as usize }>> =
-            Arc::new(array::from_fn(|_| PortpmscRegister::default()).into());
-
         let (msg_sender, msg_recv) = mpsc::unbounded_channel();
+        let (lost_devices, _) = broadcast::channel(MAX_PORTS as usize);
+        let slot_handle = Arc::new(OnceLock::new());
 
         let worker = PortWorker {
             devices: [const { None }; MAX_PORTS as usize].into(),
+            power_states: [PowerState::Active; MAX_PORTS as usize].into(),
+            resume_waiters: [const { None }; MAX_PORTS as usize].into(),
+            resume_warnings: array::from_fn(|_| Arc::default()).into(),
+            suspend_count: 0,
+            saved_state: None,
             portsc: portsc.clone(),
             event_sender,
             msg_sender: msg_sender.clone(),
             msg_recv,
+            lost_devices: lost_devices.clone(),
+            slot_handle: slot_handle.clone(),
             async_runtime: async_runtime.clone(),
         };
 
@@ -60,12 +102,31 @@ impl<CRD: CompleteRealDevice> PortArray<CRD> {
         Self {
             portsc,
             portpmsc,
+            portli,
             msg_sender,
+            lost_devices,
+            slot_handle,
+        }
+    }
+
+    /// Give the ports access to the slots, which they need to address
+    /// Device Notifications.
+    ///
+    /// The slot manager is created after the ports, because it needs the
+    /// devices attached to them.
+    pub fn connect_slots(&self, slot_handle: SlotWorkerHandle) {
+        if self.slot_handle.set(slot_handle).is_err() {
+            warn!("ports are already connected to the slots");
         }
     }
 
     pub fn write_portsc(&self, port_id: usize, value: u64) -> anyhow::Result<()> {
-        self.portsc[port_id].write(value)
+        if let Some(request) = self.portsc[port_id].write(value)? {
+            self.msg_sender
+                .send(PortMessage::LinkState(port_id, request))?;
+        }
+
+        Ok(())
     }
 
     pub fn read_portsc(&self, port_id: usize) -> u64 {
@@ -80,9 +141,18 @@ impl<CRD: CompleteRealDevice> PortArray<CRD> {
         self.portpmsc[port_id].read()
     }
 
+    pub fn write_portli(&self, port_id: usize, value: u32) {
+        self.portli[port_id].write(value);
+    }
+
+    pub fn read_portli(&self, port_id: usize) -> u32 {
+        self.portli[port_id].read()
+    }
+
     pub fn create_hotplug_control(&self) -> HotplugControl<CRD> {
         HotplugControl {
             msg_send: self.msg_sender.clone(),
+            lost_devices: self.lost_devices.clone(),
         }
     }
 
@@ -92,6 +162,12 @@ impl<CRD: CompleteRealDevice> PortArray<CRD> {
         }
     }
 
+    pub fn state_sender(&self) -> PortStateSender<CRD> {
+        PortStateSender {
+            msg_sender: self.msg_sender.clone(),
+        }
+    }
+
     fn port_version(port_id: u8) -> UsbVersion {
         match port_id as u64 {
             1..=NUM_USB3_PORTS => UsbVersion::USB3,
@@ -101,24 +177,80 @@ impl<CRD: CompleteRealDevice> PortArray<CRD> {
     }
 }
 
+#[derive(Debug)]
+pub struct PortStateSender<CRD: CompleteRealDevice> {
+    msg_sender: mpsc::UnboundedSender<PortMessage<CRD>>,
+}
+
+impl<CRD: CompleteRealDevice> StateSender for PortStateSender<CRD> {
+    fn send_save(&self, completion_notifier: oneshot::Sender<()>) -> anyhow::Result<()> {
+        self.msg_sender
+            .send(PortMessage::SaveState(completion_notifier))?;
+
+        Ok(())
+    }
+
+    fn send_restore(&self, completion_notifier: oneshot::Sender<bool>) -> anyhow::Result<()> {
+        self.msg_sender
+            .send(PortMessage::RestoreState(completion_notifier))?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct PortWorker<CRD: CompleteRealDevice> {
     devices: OneIndexed<Option<Arc<CRD>>, { MAX_PORTS as usize }>,
+    power_states: OneIndexed<PowerState, { MAX_PORTS as usize }>,
+    // aborted once the port is no longer suspended
+    resume_waiters: OneIndexed<Option<AbortHandle>, { MAX_PORTS as usize }>,
+    // set once the resume waiter warned that it cannot observe the device
+    resume_warnings: OneIndexed<Arc<AtomicBool>, { MAX_PORTS as usize }>,
+    // distinguishes the resume waiters of consecutive suspends
+    suspend_count: u64,
+    // the port registers captured by Save State
+    saved_state: Option<Vec<PortRegisterState>>,
     portsc: Arc<OneIndexed<PortscRegister, { MAX_PORTS as usize }>>,
     event_sender: EventSender,
     // the worker does not use the sender itself but needs to pass clones of the sender to detach listeners
     msg_sender: mpsc::UnboundedSender<PortMessage<CRD>>,
     msg_recv: mpsc::UnboundedReceiver<PortMessage<CRD>>,
+    lost_devices: broadcast::Sender<LostDevice<CRD::ID>>,
+    slot_handle: Arc<OnceLock<SlotWorkerHandle>>,
     async_runtime: runtime::Handle,
 }
 
+/// The power state of the device on a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PowerState {
+    Active,
+    // The guest suspended the port. Contains the suspend count of the
+    // waiter that reports the resume of the device.
+    Suspended(u64),
+    // The device signaled remote wakeup, the guest has not resumed the
+    // port yet.
+    WokeUp,
+}
+
 #[derive(Debug)]
 pub enum PortMessage<CRD: CompleteRealDevice> {
-    Attach(CRD, oneshot::Sender<Response>),
+    // optional requested port id
+    Attach(CRD, Option<u8>, oneshot::Sender<Response>),
+    // sent by the detach listener once the detach token was cancelled
     Detach(CRD::ID, oneshot::Sender<Response>),
+    DetachMatching(DeviceSelector, oneshot::Sender<Vec<PortDevice<CRD>>>),
     ListAttached(oneshot::Sender<Vec<CRD::ID>>),
+    ListPorts(oneshot::Sender<Vec<PortDevice<CRD>>>),
     // port id
     GetDevice(usize, oneshot::Sender<Option<Arc<CRD>>>),
+    // port id
+    LinkState(usize, LinkStateRequest),
+    // sent by the resume waiter; port id, suspend count
+    Resumed(usize, u64),
+    // sent once the device was reset; port id, device, warm reset, result
+    ResetDone(usize, Arc<CRD>, bool, anyhow::Result<()>),
+    SaveState(oneshot::Sender<()>),
+    RestoreState(oneshot::Sender<bool>),
 }
 
 impl<CRD: CompleteRealDevice> PortWorker<CRD> {
@@ -133,15 +265,21 @@ impl<CRD: CompleteRealDevice> PortWorker<CRD> {
     async fn run_loop(&mut self) -> anyhow::Result<()> {
         loop {
             match self.next_msg().await? {
-                PortMessage::Attach(device, responder) => {
-                    responder.send_anyhow(self.attach(device)?)?;
+                PortMessage::Attach(device, port_id, responder) => {
+                    responder.send_anyhow(self.attach(device, port_id)?)?;
                 }
                 PortMessage::Detach(identifier, responder) => {
-                    responder.send_anyhow(self.detach(identifier)?)?;
+                    responder.send_anyhow(self.detach_cancelled(&identifier)?)?;
+                }
+                PortMessage::DetachMatching(selector, responder) => {
+                    responder.send_anyhow(self.detach_matching(&selector)?)?;
                 }
                 PortMessage::ListAttached(responder) => {
                     responder.send_anyhow(self.attached_devices())?;
                 }
+                PortMessage::ListPorts(responder) => {
+                    responder.send_anyhow(self.port_devices())?;
+                }
                 PortMessage::GetDevice(port_id, responder) => {
                     let device = self
                         .devices
@@ -149,6 +287,23 @@ impl<CRD: CompleteRealDevice> PortWorker<CRD> {
                         .and_then(|opt| opt.as_ref().map(|dev| dev.clone()));
                     responder.send_anyhow(device)?;
                 }
+                PortMessage::LinkState(port_id, request) => {
+                    self.change_link_state(port_id, request)?;
+                }
+                PortMessage::Resumed(port_id, suspend_count) => {
+                    self.resumed(port_id, suspend_count)?;
+                }
+                PortMessage::ResetDone(port_id, device, warm, result) => {
+                    self.reset_done(port_id, &device, warm, result)?;
+                }
+                PortMessage::SaveState(completion) => {
+                    self.saved_state = Some(self.portsc.iter().map(PortscRegister::save).collect());
+                    completion.send_anyhow(())?;
+                }
+                PortMessage::RestoreState(completion) => {
+                    let restored = self.restore_state()?;
+                    completion.send_anyhow(restored)?;
+                }
             };
         }
     }
@@ -160,12 +315,12 @@ impl<CRD: CompleteRealDevice> PortWorker<CRD> {
             .ok_or_else(|| anyhow!("port channel closed"))
     }
 
-    fn attach(&mut self, device: CRD) -> anyhow::Result<Response> {
+    fn attach(&mut self, device: CRD, requested_port: Option<u8>) -> anyhow::Result<Response> {
         if self.attached_devices().contains(&device.identifier()) {
             info!(
                 "A device with the same identifier is already attached and will be detached first"
             );
-            self.detach(device.identifier())?;
+            self.detach(&device.identifier(), |_| true)?;
         }
 
         let speed = match device.realdevice_ref().speed() {
@@ -174,7 +329,12 @@ impl<CRD: CompleteRealDevice> PortWorker<CRD> {
         };
         let version = UsbVersion::from_speed(speed);
 
-        let available_port_id = match (1..=MAX_PORTS as usize)
+        let available_port_id = match requested_port {
+            Some(port_id) => match self.check_requested_port(port_id as usize, version) {
+                Ok(port_id) => port_id,
+                Err(response) => return Ok(response),
+            },
+            None => match (1..=MAX_PORTS as usize)
                 .find(|&i| {
                     self.devices[i].is_none()
                         && self.portsc[i].usb_version() == version
@@ -182,16 +342,18 @@ impl<CRD: CompleteRealDevice> PortWorker<CRD> {
                 {
                     Some(port) => port,
                     None => return Ok(Response::NoFreePort),
-                };
+                },
+        };
 
         let identifier = device.identifier();
         self.async_runtime.spawn(detach_listener(
             device.detach_token(),
-            identifier,
+            identifier.clone(),
             self.msg_sender.clone(),
         ));
 
         self.devices[available_port_id] = Some(Arc::new(device));
+        self.resume_warnings[available_port_id] = Arc::default();
 
         let new_portsc = match version {
             UsbVersion::USB3 => {
@@ -223,6 +385,204 @@ impl<CRD: CompleteRealDevice> PortWorker<CRD> {
         Ok(Response::SuccessfulOperation)
     }
 
+    fn change_link_state(
+        &mut self,
+        port_id: usize,
+        request: LinkStateRequest,
+    ) -> anyhow::Result<()> {
+        let Some(device) = self.devices[port_id].clone() else {
+            debug!("Ignoring link state change {request:?} on empty port {port_id}");
+            if let LinkStateRequest::Reset { warm } = request {
+                // there is nothing to reset
+                self.portsc[port_id].complete_reset(warm)?;
+            }
+            return Ok(());
+        };
+        let power_state = self.power_states[port_id];
+
+        match request {
+            LinkStateRequest::Suspend => {
+                if let Err(err) = device.realdevice_ref().suspend() {
+                    // The port is suspended for the guest nonetheless.
+                    warn!("Failed to suspend the device on port {port_id}: {err}");
+                    return Ok(());
+                }
+                self.suspend_count += 1;
+                self.power_states[port_id] = PowerState::Suspended(self.suspend_count);
+                let waiter = self.async_runtime.spawn(resume_waiter(
+                    device,
+                    port_id,
+                    self.suspend_count,
+                    self.resume_warnings[port_id].clone(),
+                    self.msg_sender.clone(),
+                ));
+                if let Some(previous) = self.resume_waiters[port_id].replace(waiter.abort_handle())
+                {
+                    previous.abort();
+                }
+                debug!("Suspended the device on port {port_id}");
+            }
+            LinkStateRequest::Resume => self.resume_device(port_id, &device),
+            LinkStateRequest::EnterU0 => {
+                self.resume_device(port_id, &device);
+                self.portsc[port_id].enter_u0()?;
+
+                // A USB 3 device that woke up tells which function did so.
+                if power_state == PowerState::WokeUp
+                    && self.portsc[port_id].usb_version() == UsbVersion::USB3
+                {
+                    self.send_function_wake(port_id);
+                }
+            }
+            LinkStateRequest::Reset { warm } => {
+                // a reset also wakes up a suspended device
+                self.resume_device(port_id, &device);
+                self.async_runtime.spawn(reset_device(
+                    device,
+                    port_id,
+                    warm,
+                    self.msg_sender.clone(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Report the completed reset to the guest. A device that failed to reset
+    // is treated as lost.
+    fn reset_done(
+        &mut self,
+        port_id: usize,
+        device: &Arc<CRD>,
+        warm: bool,
+        result: anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if !self.devices[port_id]
+            .as_ref()
+            .is_some_and(|attached| Arc::ptr_eq(attached, device))
+        {
+            debug!("Device on port {port_id} was detached during the reset");
+            return Ok(());
+        }
+
+        match result {
+            Ok(()) => {
+                debug!("Reset the device on port {port_id}");
+                self.portsc[port_id].complete_reset(warm)
+            }
+            Err(err) => {
+                warn!("Failed to reset the device on port {port_id}: {err:#}");
+                let device = self.detach_port(port_id)?;
+                // Nobody listening is fine.
+                let _ = self.lost_devices.send(LostDevice {
+                    // SAFETY: port ids are capped at MAX_PORTS
+                    port_id: port_id as u8,
+                    identifier: device.identifier(),
+                    host_info: device.host_info().clone(),
+                });
+                Ok(())
+            }
+        }
+    }
+
+    // The devices stay attached while the driver sleeps, so only the port
+    // registers are restored. Ports that changed meanwhile report the change
+    // instead. Returns false if there is no saved state.
+    fn restore_state(&self) -> anyhow::Result<bool> {
+        let Some(saved_state) = &self.saved_state else {
+            return Ok(false);
+        };
+        for (portsc, saved) in self.portsc.iter().zip(saved_state) {
+            portsc.restore(*saved)?;
+        }
+
+        Ok(true)
+    }
+
+    fn resume_device(&mut self, port_id: usize, device: &CRD) {
+        if matches!(self.power_states[port_id], PowerState::Suspended(_)) {
+            if let Err(err) = device.realdevice_ref().resume() {
+                warn!("Failed to resume the device on port {port_id}: {err}");
+            }
+            debug!("Resumed the device on port {port_id}");
+        }
+        if let Some(waiter) = self.resume_waiters[port_id].take() {
+            waiter.abort();
+        }
+        self.power_states[port_id] = PowerState::Active;
+    }
+
+    // The resume waiter also returns when we resumed the device ourselves,
+    // so only a resume of a still suspended port is a remote wakeup.
+    fn resumed(&mut self, port_id: usize, suspend_count: u64) -> anyhow::Result<()> {
+        if self.power_states[port_id] != PowerState::Suspended(suspend_count) {
+            return Ok(());
+        }
+        let Some(device) = self.devices[port_id].clone() else {
+            return Ok(());
+        };
+
+        self.resume_waiters[port_id] = None;
+        info!("Device on port {port_id} signaled remote wakeup");
+        // Keep the host from suspending the device again.
+        if let Err(err) = device.realdevice_ref().resume() {
+            warn!("Failed to resume the device on port {port_id}: {err}");
+        }
+        self.power_states[port_id] = PowerState::WokeUp;
+        self.portsc[port_id].signal_remote_wakeup()
+    }
+
+    // Send a Function Wake Device Notification for the device on the port.
+    //
+    // We cannot observe the Function Wake notification of the real device,
+    // so it is attributed to the first interface.
+    fn send_function_wake(&self, port_id: usize) {
+        let Some(slot_handle) = self.slot_handle.get().cloned() else {
+            warn!("Cannot send a Function Wake notification without slots");
+            return;
+        };
+        let event_sender = self.event_sender.clone();
+        // The slot worker might wait for us to look up a device, so do not
+        // block the port worker on the answer.
+        self.async_runtime.spawn(async move {
+            // SAFETY: port ids are capped at MAX_PORTS
+            match slot_handle.slot_of_port(port_id as u8).await {
+                Ok(Some(slot_id)) => {
+                    let event = EventTrb::new_device_notification_event_trb(
+                        device_notification_types::FUNCTION_WAKE,
+                        0,
+                        slot_id,
+                    );
+                    if let Err(err) = event_sender.send(event) {
+                        warn!("Failed to send the Function Wake notification: {err}");
+                    }
+                }
+                Ok(None) => debug!("No slot for the device on port {port_id} that woke up"),
+                Err(err) => warn!("Failed to look up the slot of port {port_id}: {err}"),
+            }
+        });
+    }
+
+    // Returns the port id if a device of the given version can be attached to it,
+    // or the response explaining why not.
+    fn check_requested_port(&self, port_id: usize, version: UsbVersion) -> Result<usize, Response> {
+        if !(1..=MAX_PORTS as usize).contains(&port_id) {
+            debug!("Requested port {port_id} does not exist");
+            return Err(Response::NoSuchPort);
+        }
+        if self.portsc[port_id].usb_version() != version {
+            debug!("Requested port {port_id} is not a {version:?} port");
+            return Err(Response::PortVersionMismatch);
+        }
+        if self.devices[port_id].is_some() {
+            debug!("Requested port {port_id} is occupied");
+            return Err(Response::PortOccupied);
+        }
+
+        Ok(port_id)
+    }
+
     fn attached_devices(&self) -> Vec<CRD::ID> {
         self.devices
             .iter()
@@ -231,13 +591,48 @@ impl<CRD: CompleteRealDevice> PortWorker<CRD> {
             .collect()
     }
 
-    fn detach(&mut self, id: CRD::ID) -> anyhow::Result<Response> {
+    fn port_devices(&self) -> Vec<PortDevice<CRD>> {
+        self.devices
+            .enumerate()
+            .filter_map(|(i, port)| {
+                port.as_ref().map(|device| PortDevice {
+                    // SAFETY: port ids are capped at MAX_PORTS
+                    port_id: i as u8,
+                    device: device.clone(),
+                })
+            })
+            .collect()
+    }
+
+    // Detach the device with the identifier whose detach token was
+    // cancelled. A device that replaced it under the same identifier has a
+    // fresh token and stays attached.
+    fn detach_cancelled(&mut self, id: &CRD::ID) -> anyhow::Result<Response> {
+        let Some(lost) = self.detach(id, |device| device.detach_token().is_cancelled())? else {
+            return Ok(Response::NoSuchDevice);
+        };
+        // Explicit detaches cancel the token themselves and never get here,
+        // so the device was lost. Nobody listening is fine.
+        let _ = self.lost_devices.send(LostDevice {
+            port_id: lost.port_id,
+            identifier: lost.device.identifier(),
+            host_info: lost.device.host_info().clone(),
+        });
+
+        Ok(Response::SuccessfulOperation)
+    }
+
+    fn detach(
+        &mut self,
+        id: &CRD::ID,
+        filter: impl Fn(&CRD) -> bool,
+    ) -> anyhow::Result<Option<PortDevice<CRD>>> {
         // find out on which port the device is connected
         let port_id = match self
             .devices
             .enumerate()
-            .filter_map(|(i, port)| port.as_ref().map(|d| (i, d.identifier())))
-            .filter(|(_, dev_id)| *dev_id == id)
+            .filter_map(|(i, port)| port.as_ref().map(|d| (i, d)))
+            .filter(|(_, device)| device.identifier() == *id && filter(device))
             .map(|(i, _)| i)
             .next()
         {
@@ -255,19 +650,48 @@ impl<CRD: CompleteRealDevice> PortWorker<CRD> {
                 // However, this message will also be printed when detach command for unknown identifier
                 // is received.
                 debug!("Could not find the device to detach");
-                return Ok(Response::NoSuchDevice);
+                return Ok(None);
             }
         };
 
+        Ok(Some(PortDevice {
+            // SAFETY: port ids are capped at MAX_PORTS
+            port_id: port_id as u8,
+            device: self.detach_port(port_id)?,
+        }))
+    }
+
+    fn detach_matching(
+        &mut self,
+        selector: &DeviceSelector,
+    ) -> anyhow::Result<Vec<PortDevice<CRD>>> {
+        let mut detached = vec![];
+        for port_id in 1..=MAX_PORTS as usize {
+            let matches = self.devices[port_id]
+                .as_ref()
+                .is_some_and(|device| selector.matches(port_id, device.as_ref()));
+            if matches {
+                detached.push(PortDevice {
+                    // SAFETY: port ids are capped at MAX_PORTS
+                    port_id: port_id as u8,
+                    device: self.detach_port(port_id)?,
+                });
+            }
+        }
+
+        Ok(detached)
+    }
+
+    // Caller must make sure that a device is attached to the port.
+    fn detach_port(&mut self, port_id: usize) -> anyhow::Result<Arc<CRD>> {
         // inform everybody else (endpoint handles) about the detach, so that they can drop
         // their reference of the device, too. This operation also removes the device from
         // the devices array.
-        //
-        // Safety: just determined that this port_id refers to the device we want to detach
-        mem::take(&mut self.devices[port_id])
-            .unwrap()
-            .detach_token()
-            .cancel();
+        let device = mem::take(&mut self.devices[port_id])
+            .ok_or_else(|| anyhow!("no device attached to port {port_id}"))?;
+        // the resume waiter holds on to a suspended device
+        self.resume_device(port_id, &device);
+        device.detach_token().cancel();
 
         // update portsc register
         self.portsc[port_id].set(portsc::PP | portsc::CSC);
@@ -276,12 +700,72 @@ impl<CRD: CompleteRealDevice> PortWorker<CRD> {
         let event = EventTrb::new_port_status_change_event_trb(port_id as u8);
         self.event_sender.send(event)?;
 
-        info!("Detached device {id:?} from port {port_id}");
+        info!(
+            "Detached device {:?} from port {port_id}",
+            device.identifier()
+        );
 
-        Ok(Response::SuccessfulOperation)
+        Ok(device)
     }
 }
 
+// Report to the port worker once the suspended device resumed.
+//
+// Waiting for the resume blocks a thread that only a resume of the device
+// wakes up. Until the host actually suspended the device, the waiter polls
+// instead, so that the port worker can abort it. If the waiter cannot tell
+// whether the host suspended the device, it gives up, because it might
+// never be woken up otherwise. It warns about it once per device.
+async fn resume_waiter<CRD: CompleteRealDevice>(
+    device: Arc<CRD>,
+    port_id: usize,
+    suspend_count: u64,
+    warned: Arc<AtomicBool>,
+    msg_sender: mpsc::UnboundedSender<PortMessage<CRD>>,
+) {
+    loop {
+        match device.realdevice_ref().host_suspended() {
+            Ok(true) => break,
+            Ok(false) => sleep(HOST_SUSPEND_POLL_INTERVAL).await,
+            Err(err) if warned.swap(true, Ordering::Relaxed) => {
+                debug!("Cannot tell whether the device on port {port_id} is suspended: {err}");
+                return;
+            }
+            Err(err) => {
+                warn!(
+                    "Cannot tell whether the device on port {port_id} is suspended, \
+                     remote wakeup will not reach the guest: {err}"
+                );
+                return;
+            }
+        }
+    }
+
+    let waiting =
+        tokio::task::spawn_blocking(move || device.realdevice_ref().wait_for_resume()).await;
+    match waiting {
+        Ok(Ok(())) => {
+            let _ = msg_sender.send(PortMessage::Resumed(port_id, suspend_count));
+        }
+        Ok(Err(err)) => debug!("Stopped waiting for the device on port {port_id} to resume: {err}"),
+        Err(err) => warn!("Resume waiter of port {port_id} failed: {err}"),
+    }
+}
+
+// Reset the device without blocking the port worker.
+async fn reset_device<CRD: CompleteRealDevice>(
+    device: Arc<CRD>,
+    port_id: usize,
+    warm: bool,
+    msg_sender: mpsc::UnboundedSender<PortMessage<CRD>>,
+) {
+    let resetting = device.clone();
+    let result = tokio::task::spawn_blocking(move || resetting.realdevice_ref().reset())
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("reset task failed: {err}")));
+    let _ = msg_sender.send(PortMessage::ResetDone(port_id, device, warm, result));
+}
+
 async fn detach_listener<CRD: CompleteRealDevice>(
     cancel: CancellationToken,
     identifier: CRD::ID,
@@ -293,6 +777,45 @@ async fn detach_listener<CRD: CompleteRealDevice>(
     let _ = recv.await;
 }
 
+/// Selects attached devices, e.g. for detaching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// The device with the host bus and device number.
+    BusDevice(u8, u8),
+    /// The device on the root-hub port.
+    Port(u8),
+    /// All devices with the vendor and product ID (and serial number, if
+    /// given).
+    UsbId(UsbId),
+    /// The device on the host port, e.g. `1-3.2`.
+    HostPort(String),
+    All,
+}
+
+impl DeviceSelector {
+    fn matches<CRD: CompleteRealDevice>(&self, port_id: usize, device: &CRD) -> bool {
+        let host_info = device.host_info();
+        match self {
+            Self::BusDevice(bus, dev) => device.identifier().bus_device() == (*bus, *dev),
+            Self::Port(port) => *port as usize == port_id,
+            Self::UsbId(usb_id) => usb_id.matches(
+                host_info.usb_id.vendor_id,
+                host_info.usb_id.product_id,
+                host_info.usb_id.serial.as_deref(),
+            ),
+            Self::HostPort(path) => host_info.port_path.as_ref() == Some(path),
+            Self::All => true,
+        }
+    }
+}
+
+/// A device on a root-hub port, or removed from it.
+#[derive(Debug)]
+pub struct PortDevice<CRD: CompleteRealDevice> {
+    pub port_id: u8,
+    pub device: Arc<CRD>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UsbVersion {
     USB2,
@@ -340,24 +863,47 @@ pub const fn get_portli_id(addr: u64) -> Option<usize> {
     get_port_id_from_addr(addr, offset::PORTSC, MAX_PORTS, 0x8)
 }
 
+/// A device that was detached because its detach token was cancelled
+/// without a detach request, e.g. because it vanished from the host.
 #[derive(Debug, Clone)]
+pub struct LostDevice<ID: Identifier> {
+    pub port_id: u8,
+    pub identifier: ID,
+    pub host_info: HostDeviceInfo,
+}
+
+#[derive(Debug)]
 pub struct HotplugControl<CRD: CompleteRealDevice> {
     msg_send: mpsc::UnboundedSender<PortMessage<CRD>>,
+    lost_devices: broadcast::Sender<LostDevice<CRD::ID>>,
+}
+
+// derive(Clone) would require CRD: Clone, although only the senders are cloned.
+impl<CRD: CompleteRealDevice> Clone for HotplugControl<CRD> {
+    fn clone(&self) -> Self {
+        Self {
+            msg_send: self.msg_send.clone(),
+            lost_devices: self.lost_devices.clone(),
+        }
+    }
 }
 
 impl<CRD: CompleteRealDevice> HotplugControl<CRD> {
-    pub async fn attach(&self, device: CRD) -> Response {
+    /// Attach the device to `port_id`, or to the first free port of the
+    /// matching USB version if no port is given.
+    pub async fn attach(&self, device: CRD, port_id: Option<u8>) -> Response {
         let (responder, response_recv) = oneshot::channel();
-        let msg = PortMessage::Attach(device, responder);
+        let msg = PortMessage::Attach(device, port_id, responder);
         self.msg_send.send(msg).expect("channel should never close");
         response_recv
             .await
             .expect("oneshot channel should always provide a message")
     }
 
-    pub async fn detach(&self, identifier: CRD::ID) -> Response {
+    /// Detach all devices matching the selector and return them.
+    pub async fn detach(&self, selector: DeviceSelector) -> Vec<PortDevice<CRD>> {
         let (responder, response_recv) = oneshot::channel();
-        let msg = PortMessage::Detach(identifier, responder);
+        let msg = PortMessage::DetachMatching(selector, responder);
         self.msg_send.send(msg).expect("channel should never close");
         response_recv
             .await
@@ -372,6 +918,21 @@ impl<CRD: CompleteRealDevice> HotplugControl<CRD> {
             .await
             .expect("oneshot channel should always provide a message")
     }
+
+    /// Get notified about devices that are lost from now on.
+    pub fn subscribe_lost_devices(&self) -> broadcast::Receiver<LostDevice<CRD::ID>> {
+        self.lost_devices.subscribe()
+    }
+
+    /// List the attached devices together with their root-hub ports.
+    pub async fn list_ports(&self) -> Vec<PortDevice<CRD>> {
+        let (responder, response_recv) = oneshot::channel();
+        let msg = PortMessage::ListPorts(responder);
+        self.msg_send.send(msg).expect("channel should never close");
+        response_recv
+            .await
+            .expect("oneshot channel should always provide a message")
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -398,7 +959,8 @@ mod tests {
 
     use crate::device::xhci::{
         interrupter::tests::testutils::MockInterrupter,
-        real_device::{tests::testutils::MockRealDevice, CompleteRealDeviceImpl},
+        real_device::{tests::testutils::MockRealDevice, CompleteRealDeviceImpl, HostDeviceInfo},
+        slot_manager::{test::testutils::MockSlotManager, SlotMessage},
     };
 
     use super::*;
@@ -409,12 +971,24 @@ mod tests {
     const USB_DEV_NR: u8 = 1;
     const IDENTIFIER: (u8, u8) = (USB_BUS_NR, USB_DEV_NR);
 
+    fn mock_device(
+        identifier: (u8, u8),
+        usb_id: &str,
+        port_path: &str,
+    ) -> CompleteRealDeviceImpl<MockRealDevice, (u8, u8)> {
+        let host_info = HostDeviceInfo {
+            usb_id: usb_id.parse().unwrap(),
+            port_path: Some(port_path.to_string()),
+        };
+        CompleteRealDeviceImpl::new(identifier, host_info, MockRealDevice::default())
+    }
+
     #[tokio::test]
     async fn port_array_hotplug_control_can_attach_list_and_detach() {
         let async_runtime = Handle::current();
         let (event_sender, mut interrupter) = MockInterrupter::new();
 
-        let mock_real_device = CompleteRealDeviceImpl::new(IDENTIFIER, MockRealDevice::default());
+        let mock_real_device = mock_device(IDENTIFIER, "1234:5678", "1-1");
         let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
             PortArray::new(event_sender, async_runtime);
 
@@ -422,7 +996,7 @@ mod tests {
         let hotplug_control = port_array.create_hotplug_control();
         let response = timeout(
             Duration::from_secs(ASYNC_TIMEOUT_SECS),
-            hotplug_control.attach(mock_real_device),
+            hotplug_control.attach(mock_real_device, None),
         )
         .await
         .expect("local timeout on await");
@@ -447,13 +1021,15 @@ mod tests {
         // detach the device
         let response = timeout(
             Duration::from_secs(ASYNC_TIMEOUT_SECS),
-            hotplug_control.detach(IDENTIFIER),
+            hotplug_control.detach(DeviceSelector::BusDevice(IDENTIFIER.0, IDENTIFIER.1)),
         )
         .await
         .expect("local timeout on await");
 
-        // expect a successful response for the command and an event
-        assert_eq!(response, Response::SuccessfulOperation);
+        // expect the detached device in the response and an event
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].port_id, PORT_ID);
+        assert_eq!(response[0].device.identifier(), IDENTIFIER);
         assert_eq!(
             interrupter.await_event().await,
             Some(EventTrb::new_port_status_change_event_trb(PORT_ID))
@@ -470,4 +1046,515 @@ mod tests {
         assert_eq!(response, vec![]);
         assert!(interrupter.is_empty());
     }
+
+    #[tokio::test]
+    async fn port_array_hotplug_control_replaces_device_with_same_identifier() {
+        let async_runtime = Handle::current();
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+
+        let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
+            PortArray::new(event_sender, async_runtime);
+        let hotplug_control = port_array.create_hotplug_control();
+
+        let first = mock_device(IDENTIFIER, "1234:5678", "1-1");
+        let first_token = first.detach_token();
+        for device in [first, mock_device(IDENTIFIER, "1234:5678", "1-1")] {
+            let response = timeout(
+                Duration::from_secs(ASYNC_TIMEOUT_SECS),
+                hotplug_control.attach(device, None),
+            )
+            .await
+            .expect("local timeout on await");
+            assert_eq!(response, Response::SuccessfulOperation);
+        }
+        assert!(first_token.is_cancelled());
+        // attach, detach of the first device, attach of the second device
+        for _ in 0..3 {
+            assert_eq!(
+                interrupter.await_event().await,
+                Some(EventTrb::new_port_status_change_event_trb(PORT_ID))
+            );
+        }
+
+        // the detach listener of the first device must not detach the second
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let ports = timeout(
+            Duration::from_secs(ASYNC_TIMEOUT_SECS),
+            hotplug_control.list_ports(),
+        )
+        .await
+        .expect("local timeout on await");
+        assert_eq!(ports.len(), 1);
+        assert_eq!(ports[0].port_id, PORT_ID);
+        assert!(!ports[0].device.detach_token().is_cancelled());
+        assert!(interrupter.is_empty());
+    }
+
+    #[tokio::test]
+    async fn port_array_hotplug_control_reports_lost_devices() {
+        let async_runtime = Handle::current();
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+
+        let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
+            PortArray::new(event_sender, async_runtime);
+        let hotplug_control = port_array.create_hotplug_control();
+        let mut lost_devices = hotplug_control.subscribe_lost_devices();
+
+        let lost = mock_device(IDENTIFIER, "1234:5678", "1-1");
+        let lost_token = lost.detach_token();
+        for device in [lost, mock_device((1, 2), "1234:5678", "1-2")] {
+            let response = timeout(
+                Duration::from_secs(ASYNC_TIMEOUT_SECS),
+                hotplug_control.attach(device, None),
+            )
+            .await
+            .expect("local timeout on await");
+            assert_eq!(response, Response::SuccessfulOperation);
+        }
+
+        // detaching on request does not count as lost
+        hotplug_control.detach(DeviceSelector::Port(2)).await;
+        lost_token.cancel();
+        let lost = timeout(Duration::from_secs(ASYNC_TIMEOUT_SECS), lost_devices.recv())
+            .await
+            .expect("local timeout on await")
+            .unwrap();
+        assert_eq!(lost.port_id, PORT_ID);
+        assert_eq!(lost.identifier, IDENTIFIER);
+        assert_eq!(lost.host_info.port_path.as_deref(), Some("1-1"));
+        assert!(lost_devices.is_empty());
+
+        for port_id in [1, 2, 2, 1] {
+            assert_eq!(
+                interrupter.await_event().await,
+                Some(EventTrb::new_port_status_change_event_trb(port_id))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn port_array_hotplug_control_attaches_to_requested_port() {
+        const REQUESTED_PORT_ID: u8 = 3;
+        const OTHER_IDENTIFIER: (u8, u8) = (USB_BUS_NR, USB_DEV_NR + 1);
+
+        let async_runtime = Handle::current();
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+
+        let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
+            PortArray::new(event_sender, async_runtime);
+        let hotplug_control = port_array.create_hotplug_control();
+        let attach = |identifier, port_id| {
+            let device = mock_device(identifier, "1234:5678", "1-1");
+            timeout(
+                Duration::from_secs(ASYNC_TIMEOUT_SECS),
+                hotplug_control.attach(device, Some(port_id)),
+            )
+        };
+
+        // the mock device is a USB3 device, so port 3 fits
+        let response = attach(IDENTIFIER, REQUESTED_PORT_ID)
+            .await
+            .expect("local timeout on await");
+        assert_eq!(response, Response::SuccessfulOperation);
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(
+                REQUESTED_PORT_ID
+            ))
+        );
+
+        let response = attach(OTHER_IDENTIFIER, REQUESTED_PORT_ID)
+            .await
+            .expect("local timeout on await");
+        assert_eq!(response, Response::PortOccupied);
+
+        let first_usb2_port = NUM_USB3_PORTS as u8 + 1;
+        let response = attach(OTHER_IDENTIFIER, first_usb2_port)
+            .await
+            .expect("local timeout on await");
+        assert_eq!(response, Response::PortVersionMismatch);
+
+        let response = attach(OTHER_IDENTIFIER, MAX_PORTS as u8 + 1)
+            .await
+            .expect("local timeout on await");
+        assert_eq!(response, Response::NoSuchPort);
+
+        let response = attach(OTHER_IDENTIFIER, 0)
+            .await
+            .expect("local timeout on await");
+        assert_eq!(response, Response::NoSuchPort);
+
+        assert!(interrupter.is_empty());
+    }
+
+    #[tokio::test]
+    async fn port_array_hotplug_control_detaches_by_selector() {
+        let async_runtime = Handle::current();
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+
+        let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
+            PortArray::new(event_sender, async_runtime);
+        let hotplug_control = port_array.create_hotplug_control();
+
+        let devices = [
+            mock_device((1, 1), "1234:5678:AAAA", "1-1"),
+            mock_device((1, 2), "1234:5678:BBBB", "1-2"),
+            mock_device((1, 3), "1234:9999", "1-3.1"),
+            mock_device((1, 4), "4321:0001", "1-4"),
+        ];
+        for (index, device) in devices.into_iter().enumerate() {
+            let response = timeout(
+                Duration::from_secs(ASYNC_TIMEOUT_SECS),
+                hotplug_control.attach(device, None),
+            )
+            .await
+            .expect("local timeout on await");
+            assert_eq!(response, Response::SuccessfulOperation);
+            assert_eq!(
+                interrupter.await_event().await,
+                Some(EventTrb::new_port_status_change_event_trb(index as u8 + 1))
+            );
+        }
+
+        let detach = |selector| async {
+            let detached = timeout(
+                Duration::from_secs(ASYNC_TIMEOUT_SECS),
+                hotplug_control.detach(selector),
+            )
+            .await
+            .expect("local timeout on await");
+            detached
+                .iter()
+                .map(|detached| (detached.port_id, detached.device.identifier()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            detach(DeviceSelector::UsbId("1234:5678:BBBB".parse().unwrap())).await,
+            vec![(2, (1, 2))]
+        );
+        assert_eq!(
+            detach(DeviceSelector::HostPort("1-3.1".to_string())).await,
+            vec![(3, (1, 3))]
+        );
+        assert_eq!(detach(DeviceSelector::Port(4)).await, vec![(4, (1, 4))]);
+        assert_eq!(detach(DeviceSelector::Port(4)).await, vec![]);
+        assert_eq!(
+            detach(DeviceSelector::UsbId("1234:5678".parse().unwrap())).await,
+            vec![(1, (1, 1))]
+        );
+        for port_id in [2, 3, 4, 1] {
+            assert_eq!(
+                interrupter.await_event().await,
+                Some(EventTrb::new_port_status_change_event_trb(port_id))
+            );
+        }
+
+        // reattach two devices and detach everything
+        for device in [
+            mock_device((1, 1), "1234:5678:AAAA", "1-1"),
+            mock_device((1, 2), "1234:5678:BBBB", "1-2"),
+        ] {
+            timeout(
+                Duration::from_secs(ASYNC_TIMEOUT_SECS),
+                hotplug_control.attach(device, None),
+            )
+            .await
+            .expect("local timeout on await");
+            interrupter.await_event().await;
+        }
+        assert_eq!(
+            detach(DeviceSelector::All).await,
+            vec![(1, (1, 1)), (2, (1, 2))]
+        );
+        assert_eq!(detach(DeviceSelector::All).await, vec![]);
+    }
+
+    #[tokio::test]
+    async fn port_array_propagates_suspend_and_remote_wakeup() {
+        let async_runtime = Handle::current();
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let (slot_manager, mut slot_msgs) = MockSlotManager::new();
+
+        let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
+            PortArray::new(event_sender, async_runtime);
+        port_array.connect_slots(slot_manager.create_slot_worker_handle());
+        let hotplug_control = port_array.create_hotplug_control();
+
+        let response = timeout(
+            Duration::from_secs(ASYNC_TIMEOUT_SECS),
+            hotplug_control.attach(mock_device(IDENTIFIER, "1234:5678", "1-1"), None),
+        )
+        .await
+        .expect("local timeout on await");
+        assert_eq!(response, Response::SuccessfulOperation);
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(PORT_ID))
+        );
+        let device = port_array
+            .create_device_retriever()
+            .get_device(PORT_ID)
+            .await
+            .unwrap()
+            .expect("device should be attached");
+
+        // the guest suspends the port
+        let port_id = PORT_ID as usize;
+        port_array
+            .write_portsc(port_id, portsc::PED | portsc::LWS | portsc::value::PLS_U3)
+            .unwrap();
+        assert_eq!(
+            port_array.read_portsc(port_id) & portsc::PLS,
+            portsc::value::PLS_U3
+        );
+        // the worker handles messages in order, so listing the ports waits
+        // for the suspend
+        hotplug_control.list_ports().await;
+        assert!(device.realdevice_ref().is_suspended());
+
+        // the device wakes up
+        device.realdevice_ref().remote_wakeup();
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(PORT_ID))
+        );
+        let value = port_array.read_portsc(port_id);
+        assert_eq!(value & portsc::PLS, portsc::value::PLS_RESUME);
+        assert_ne!(value & portsc::PLC, 0);
+
+        // the guest finishes the resume and learns which function woke up
+        port_array
+            .write_portsc(
+                port_id,
+                portsc::PED | portsc::PLC | portsc::LWS | portsc::value::PLS_U0,
+            )
+            .unwrap();
+        match slot_msgs.recv().await {
+            Some(SlotMessage::SlotOfPort(PORT_ID, responder)) => responder.send(Some(3)).unwrap(),
+            msg => panic!("unexpected slot message {msg:?}"),
+        }
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(PORT_ID))
+        );
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_device_notification_event_trb(
+                device_notification_types::FUNCTION_WAKE,
+                0,
+                3
+            ))
+        );
+        assert_eq!(
+            port_array.read_portsc(port_id) & portsc::PLS,
+            portsc::value::PLS_U0
+        );
+        assert!(!device.realdevice_ref().is_suspended());
+        assert!(interrupter.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resume_waiter_of_a_device_that_never_suspends_is_aborted() {
+        let async_runtime = Handle::current();
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+
+        let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
+            PortArray::new(event_sender, async_runtime);
+        let hotplug_control = port_array.create_hotplug_control();
+        let mock_real_device = mock_device(IDENTIFIER, "1234:5678", "1-1");
+        // the host keeps the device active, e.g., without autosuspend
+        mock_real_device.realdevice_ref().never_suspend();
+
+        let response = timeout(
+            Duration::from_secs(ASYNC_TIMEOUT_SECS),
+            hotplug_control.attach(mock_real_device, None),
+        )
+        .await
+        .expect("local timeout on await");
+        assert_eq!(response, Response::SuccessfulOperation);
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(PORT_ID))
+        );
+        let device = port_array
+            .create_device_retriever()
+            .get_device(PORT_ID)
+            .await
+            .unwrap()
+            .expect("device should be attached");
+
+        // the guest suspends the port, but the device stays active
+        let port_id = PORT_ID as usize;
+        port_array
+            .write_portsc(port_id, portsc::PED | portsc::LWS | portsc::value::PLS_U3)
+            .unwrap();
+        hotplug_control.list_ports().await;
+
+        // detaching releases the device, the waiter does not keep it alive
+        let detached = hotplug_control.detach(DeviceSelector::All).await;
+        assert_eq!(detached.len(), 1);
+        drop(detached);
+        timeout(Duration::from_secs(ASYNC_TIMEOUT_SECS), async {
+            while Arc::strong_count(&device) > 1 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the resume waiter should release the device");
+    }
+
+    #[tokio::test]
+    async fn resume_waiter_gives_up_if_the_power_state_is_unknown() {
+        let async_runtime = Handle::current();
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
+            PortArray::new(event_sender, async_runtime);
+        let hotplug_control = port_array.create_hotplug_control();
+        let device = attach_for_reset(&port_array, &mut interrupter).await;
+        device.realdevice_ref().hide_power_state();
+
+        // the guest suspends the port, the waiter cannot observe the device
+        let port_id = PORT_ID as usize;
+        port_array
+            .write_portsc(port_id, portsc::PED | portsc::LWS | portsc::value::PLS_U3)
+            .unwrap();
+        hotplug_control.list_ports().await;
+        assert!(device.realdevice_ref().is_suspended());
+        timeout(Duration::from_secs(ASYNC_TIMEOUT_SECS), async {
+            while Arc::strong_count(&device) > 2 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the resume waiter should give up");
+
+        // the guest can still resume the port itself
+        port_array
+            .write_portsc(port_id, portsc::PED | portsc::LWS | portsc::value::PLS_U0)
+            .unwrap();
+        hotplug_control.list_ports().await;
+        assert!(!device.realdevice_ref().is_suspended());
+        assert_eq!(
+            port_array.read_portsc(port_id) & portsc::PLS,
+            portsc::value::PLS_U0
+        );
+    }
+
+    async fn attach_for_reset(
+        port_array: &PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>>,
+        interrupter: &mut MockInterrupter,
+    ) -> Arc<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> {
+        let response = timeout(
+            Duration::from_secs(ASYNC_TIMEOUT_SECS),
+            port_array
+                .create_hotplug_control()
+                .attach(mock_device(IDENTIFIER, "1234:5678", "1-1"), None),
+        )
+        .await
+        .expect("local timeout on await");
+        assert_eq!(response, Response::SuccessfulOperation);
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(PORT_ID))
+        );
+        // acknowledge the connection
+        port_array
+            .write_portsc(PORT_ID as usize, portsc::CSC | portsc::PEC | portsc::PRC)
+            .unwrap();
+
+        port_array
+            .create_device_retriever()
+            .get_device(PORT_ID)
+            .await
+            .unwrap()
+            .expect("device should be attached")
+    }
+
+    #[tokio::test]
+    async fn port_reset_resets_the_device_before_completing() {
+        let async_runtime = Handle::current();
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
+            PortArray::new(event_sender, async_runtime);
+        let device = attach_for_reset(&port_array, &mut interrupter).await;
+
+        let port_id = PORT_ID as usize;
+        port_array.write_portsc(port_id, portsc::PR).unwrap();
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(PORT_ID))
+        );
+
+        assert_eq!(device.realdevice_ref().resets(), 1);
+        let value = port_array.read_portsc(port_id);
+        assert_eq!(
+            value & (portsc::PR | portsc::PRC | portsc::PED),
+            portsc::PRC | portsc::PED
+        );
+        assert_eq!(value & portsc::CCS, portsc::CCS);
+        assert!(interrupter.is_empty());
+    }
+
+    #[tokio::test]
+    async fn port_reset_failure_disconnects_the_device() {
+        let async_runtime = Handle::current();
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
+            PortArray::new(event_sender, async_runtime);
+        let hotplug_control = port_array.create_hotplug_control();
+        let mut lost_devices = hotplug_control.subscribe_lost_devices();
+        let device = attach_for_reset(&port_array, &mut interrupter).await;
+        device.realdevice_ref().fail_resets();
+
+        let port_id = PORT_ID as usize;
+        port_array.write_portsc(port_id, portsc::PR).unwrap();
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(PORT_ID))
+        );
+
+        assert_eq!(port_array.read_portsc(port_id), portsc::PP | portsc::CSC);
+        let lost = lost_devices.recv().await.unwrap();
+        assert_eq!(lost.port_id, PORT_ID);
+        assert_eq!(lost.identifier, IDENTIFIER);
+        assert_eq!(hotplug_control.list_devices().await, vec![]);
+    }
+
+    #[tokio::test]
+    async fn restore_state_keeps_devices_and_reports_changed_ports() {
+        let async_runtime = Handle::current();
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let port_array: PortArray<CompleteRealDeviceImpl<MockRealDevice, (u8, u8)>> =
+            PortArray::new(event_sender, async_runtime);
+        let hotplug_control = port_array.create_hotplug_control();
+        let state_sender = port_array.state_sender();
+        attach_for_reset(&port_array, &mut interrupter).await;
+
+        let port_id = PORT_ID as usize;
+        let portsc = port_array.read_portsc(port_id);
+        port_array.write_portli(port_id, 3);
+        state_sender.save().unwrap().await.unwrap();
+
+        port_array.write_portli(port_id, 5);
+        assert!(state_sender.restore().unwrap().await.unwrap());
+        assert_eq!(port_array.read_portsc(port_id), portsc);
+        assert_eq!(port_array.read_portli(port_id), 3);
+        assert_eq!(hotplug_control.list_devices().await, vec![IDENTIFIER]);
+        assert!(interrupter.is_empty());
+
+        // a device that is detached while the state is saved stays detached
+        hotplug_control.detach(DeviceSelector::Port(PORT_ID)).await;
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(PORT_ID))
+        );
+        assert!(state_sender.restore().unwrap().await.unwrap());
+        assert_eq!(port_array.read_portsc(port_id), portsc::PP | portsc::CSC);
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(PORT_ID)),
+            "the port should report the change again"
+        );
+    }
 }
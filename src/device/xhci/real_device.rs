@@ -1,4 +1,5 @@
 use tokio_util::sync::CancellationToken;
+use usbvfiod::hotplug_protocol::usb_id::UsbId;
 
 use crate::device::xhci::real_endpoint_handle::{
     RealControlEndpointHandle, RealInEndpointHandle, RealOutEndpointHandle,
@@ -41,18 +42,120 @@ pub trait RealDevice: Debug + Send + Sync + 'static {
     type RBOEH: RealOutEndpointHandle;
     type RIIEH: RealInEndpointHandle;
     type RIOEH: RealOutEndpointHandle;
+    type RISIEH: RealInEndpointHandle;
+    type RISOEH: RealOutEndpointHandle;
+
+    /// Whether bulk endpoints of the device can use streams.
+    const SUPPORTS_STREAMS: bool;
 
     fn speed(&self) -> Option<Speed>;
+    /// Allow the host to suspend the device, because the guest suspended
+    /// the port.
+    fn suspend(&self) -> anyhow::Result<()>;
+    /// Resume the device and keep the host from suspending it again.
+    fn resume(&self) -> anyhow::Result<()>;
+    /// Whether the host actually suspended the device after `suspend`. The
+    /// host may keep the device active, e.g., if autosuspend is disabled.
+    fn host_suspended(&self) -> anyhow::Result<bool>;
+    /// Block until the device resumes after `suspend`, be it because of
+    /// `resume` or because the device signaled remote wakeup. May block
+    /// forever if the host did not suspend the device.
+    fn wait_for_resume(&self) -> anyhow::Result<()>;
+    /// Reset the device, because the guest reset the port. Blocks until the
+    /// device is usable again.
+    ///
+    /// Endpoint handles created before keep working on the reset device.
+    fn reset(&self) -> anyhow::Result<()>;
+    /// Check whether the guest may configure the (non-control) endpoint.
+    fn exposes_endpoint(&self, endpoint_id: u8) -> bool;
     fn control_endpoint_handle(&self) -> Self::RCEH;
     fn bulk_in_endpoint_handle(&self, endpoint_id: u8) -> Self::RBIEH;
     fn bulk_out_endpoint_handle(&self, endpoint_id: u8) -> Self::RBOEH;
+    /// Allocate the streams of a bulk endpoint before the guest uses them.
+    /// Only called if `SUPPORTS_STREAMS` is set. Fails if the device cannot
+    /// use streams on the endpoint, which refuses the endpoint.
+    fn alloc_streams(&self, endpoint_id: u8) -> anyhow::Result<()>;
+    /// Only called if `SUPPORTS_STREAMS` is set.
+    fn bulk_in_stream_handle(&self, endpoint_id: u8, stream_id: u16) -> Self::RBIEH;
+    /// Only called if `SUPPORTS_STREAMS` is set.
+    fn bulk_out_stream_handle(&self, endpoint_id: u8, stream_id: u16) -> Self::RBOEH;
     fn interrupt_in_endpoint_handle(&self, endpoint_id: u8) -> Self::RIIEH;
     fn interrupt_out_endpoint_handle(&self, endpoint_id: u8) -> Self::RIOEH;
+    fn isoch_in_endpoint_handle(&self, endpoint_id: u8) -> Self::RISIEH;
+    fn isoch_out_endpoint_handle(&self, endpoint_id: u8) -> Self::RISOEH;
+}
+
+pub trait Identifier: Debug + Clone + Eq + Send + Sync + 'static {
+    /// The host bus and device number of the device. They change whenever
+    /// the device re-enumerates, but remain available as an alias.
+    fn bus_device(&self) -> (u8, u8);
+}
+
+/// What the host knows about a real device apart from its identifier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostDeviceInfo {
+    pub usb_id: UsbId,
+    /// Host port path as found in /sys/bus/usb/devices, e.g. `1-3.2`.
+    pub port_path: Option<String>,
+}
+
+impl Identifier for (u8, u8) {
+    fn bus_device(&self) -> (u8, u8) {
+        *self
+    }
+}
+
+/// Identifies a real device by where it is plugged in and what it is.
+///
+/// Unlike the bus and device number, the host port path and the USB IDs
+/// survive re-enumeration, and two identical devices differ in their port
+/// path. Only if the port path is unknown, the bus and device number are
+/// used to tell devices apart.
+#[derive(Debug, Clone)]
+pub struct DeviceIdentity {
+    pub port_path: Option<String>,
+    pub usb_id: UsbId,
+    pub bus: u8,
+    pub device: u8,
 }
 
-pub trait Identifier: Debug + Copy + Eq + Send + Sync + 'static {}
+impl DeviceIdentity {
+    pub fn new((bus, device): (u8, u8), host_info: &HostDeviceInfo) -> Self {
+        Self {
+            port_path: host_info.port_path.clone(),
+            usb_id: host_info.usb_id.clone(),
+            bus,
+            device,
+        }
+    }
+}
+
+impl PartialEq for DeviceIdentity {
+    fn eq(&self, other: &Self) -> bool {
+        self.usb_id == other.usb_id
+            && match (&self.port_path, &other.port_path) {
+                (Some(path), Some(other_path)) => path == other_path,
+                _ => self.bus_device() == other.bus_device(),
+            }
+    }
+}
 
-impl Identifier for (u8, u8) {}
+impl Eq for DeviceIdentity {}
+
+impl Identifier for DeviceIdentity {
+    fn bus_device(&self) -> (u8, u8) {
+        (self.bus, self.device)
+    }
+}
+
+impl fmt::Display for DeviceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.port_path {
+            Some(path) => write!(f, "{} at host port {path}", self.usb_id),
+            None => write!(f, "{} at {:03}:{:03}", self.usb_id, self.bus, self.device),
+        }
+    }
+}
 
 // A RealDevice trait coupled with an identifier and cancellation token for detach notification.
 //
@@ -62,8 +165,8 @@ impl Identifier for (u8, u8) {}
 // the best bet for identification. However, with two identical devices, the
 // approach fails to uniquely identify the devices. Complete real device allows
 // distinction of devices by storing an externally-decided, unique identifier.
-// Host bus-/device-number is an option, but so are randomly chosen (but unique)
-// numbers or strings.
+// Host bus-/device-number is an option, but it changes on re-enumeration.
+// DeviceIdentity combines the host port with the USB IDs instead.
 //
 // A CompleteRealDevice must also provide a CancellationToken that can be used
 // by the potentially multiple references to the real device (endpoint handles
@@ -74,6 +177,7 @@ pub trait CompleteRealDevice: Debug + Send + Sync + 'static {
     type ID: Identifier;
 
     fn identifier(&self) -> Self::ID;
+    fn host_info(&self) -> &HostDeviceInfo;
     fn realdevice_ref(&self) -> &Self::RD;
     fn detach_token(&self) -> CancellationToken;
 }
@@ -81,14 +185,16 @@ pub trait CompleteRealDevice: Debug + Send + Sync + 'static {
 #[derive(Debug)]
 pub struct CompleteRealDeviceImpl<RD: RealDevice, ID: Identifier> {
     pub identifier: ID,
+    pub host_info: HostDeviceInfo,
     pub real_device: RD,
     pub cancel: CancellationToken,
 }
 
 impl<RD: RealDevice, ID: Identifier> CompleteRealDeviceImpl<RD, ID> {
-    pub fn new(identifier: ID, real_device: RD) -> Self {
+    pub fn new(identifier: ID, host_info: HostDeviceInfo, real_device: RD) -> Self {
         Self {
             identifier,
+            host_info,
             real_device,
             cancel: CancellationToken::new(),
         }
@@ -100,7 +206,11 @@ impl<RD: RealDevice, ID: Identifier> CompleteRealDevice for CompleteRealDeviceIm
     type ID = ID;
 
     fn identifier(&self) -> Self::ID {
-        self.identifier
+        self.identifier.clone()
+    }
+
+    fn host_info(&self) -> &HostDeviceInfo {
+        &self.host_info
     }
 
     fn realdevice_ref(&self) -> &Self::RD {
@@ -116,7 +226,41 @@ impl<RD: RealDevice, ID: Identifier> CompleteRealDevice for CompleteRealDeviceIm
 pub mod tests {
     use super::*;
 
+    #[test]
+    fn device_identity_survives_re_enumeration() {
+        let identity = |bus_device, usb_id: &str, port_path: Option<&str>| {
+            let host_info = HostDeviceInfo {
+                usb_id: usb_id.parse().unwrap(),
+                port_path: port_path.map(str::to_string),
+            };
+            DeviceIdentity::new(bus_device, &host_info)
+        };
+
+        let original = identity((1, 5), "1050:0407", Some("1-3.2"));
+        // replugged into the same port
+        assert_eq!(original, identity((1, 9), "1050:0407", Some("1-3.2")));
+        // an identical device in another port
+        assert_ne!(original, identity((1, 6), "1050:0407", Some("1-4")));
+        // another device in the same port
+        assert_ne!(original, identity((1, 9), "046d:c52b", Some("1-3.2")));
+        // without port path, bus and device number decide
+        assert_eq!(
+            identity((1, 5), "1050:0407", None),
+            identity((1, 5), "1050:0407", None)
+        );
+        assert_ne!(
+            identity((1, 5), "1050:0407", None),
+            identity((1, 9), "1050:0407", None)
+        );
+        assert_eq!(original.bus_device(), (1, 5));
+    }
+
     pub mod testutils {
+        use std::sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Condvar, Mutex,
+        };
+
         use super::*;
 
         use crate::device::xhci::{
@@ -127,7 +271,53 @@ pub mod tests {
         };
 
         #[derive(Default, Debug)]
-        pub struct MockRealDevice {}
+        pub struct MockRealDevice {
+            suspended: Mutex<bool>,
+            resumed: Condvar,
+            never_suspends: AtomicBool,
+            hides_power_state: AtomicBool,
+            resets: AtomicUsize,
+            fail_resets: AtomicBool,
+            fail_stream_allocation: AtomicBool,
+        }
+
+        impl MockRealDevice {
+            pub fn is_suspended(&self) -> bool {
+                *self.suspended.lock().unwrap()
+            }
+
+            /// Let the suspended device signal remote wakeup.
+            pub fn remote_wakeup(&self) {
+                self.resume().unwrap();
+            }
+
+            /// Keep the device active when the guest suspends it, as the
+            /// host does without autosuspend.
+            pub fn never_suspend(&self) {
+                self.never_suspends.store(true, Ordering::Relaxed);
+            }
+
+            /// Let `host_suspended` fail, as if the host does not expose
+            /// the power state of the device.
+            pub fn hide_power_state(&self) {
+                self.hides_power_state.store(true, Ordering::Relaxed);
+            }
+
+            pub fn resets(&self) -> usize {
+                self.resets.load(Ordering::Relaxed)
+            }
+
+            /// Let resets fail, as if the device vanished.
+            pub fn fail_resets(&self) {
+                self.fail_resets.store(true, Ordering::Relaxed);
+            }
+
+            /// Let stream allocations fail, as for a device that cannot
+            /// use streams.
+            pub fn fail_stream_allocation(&self) {
+                self.fail_stream_allocation.store(true, Ordering::Relaxed);
+            }
+        }
 
         impl RealDevice for MockRealDevice {
             type RCEH = MockRealControlEndpointReadStatic;
@@ -135,11 +325,54 @@ pub mod tests {
             type RBOEH = MockRealOutEndpoint;
             type RIIEH = MockRealInEndpoint;
             type RIOEH = MockRealOutEndpoint;
+            type RISIEH = MockRealInEndpoint;
+            type RISOEH = MockRealOutEndpoint;
+
+            const SUPPORTS_STREAMS: bool = true;
 
             fn speed(&self) -> Option<real_device::Speed> {
                 Some(Speed::Super)
             }
 
+            fn suspend(&self) -> anyhow::Result<()> {
+                *self.suspended.lock().unwrap() = true;
+                Ok(())
+            }
+
+            fn resume(&self) -> anyhow::Result<()> {
+                *self.suspended.lock().unwrap() = false;
+                self.resumed.notify_all();
+                Ok(())
+            }
+
+            fn host_suspended(&self) -> anyhow::Result<bool> {
+                if self.hides_power_state.load(Ordering::Relaxed) {
+                    return Err(anyhow::anyhow!("power state not available"));
+                }
+                Ok(self.is_suspended() && !self.never_suspends.load(Ordering::Relaxed))
+            }
+
+            fn wait_for_resume(&self) -> anyhow::Result<()> {
+                let suspended = self.suspended.lock().unwrap();
+                let _resumed = self
+                    .resumed
+                    .wait_while(suspended, |suspended| *suspended)
+                    .unwrap();
+                Ok(())
+            }
+
+            fn reset(&self) -> anyhow::Result<()> {
+                if self.fail_resets.load(Ordering::Relaxed) {
+                    return Err(anyhow::anyhow!("device vanished during reset"));
+                }
+                self.resets.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+
+            fn exposes_endpoint(&self, _endpoint_id: u8) -> bool {
+                true
+            }
+
             fn control_endpoint_handle(&self) -> Self::RCEH {
                 MockRealControlEndpointReadStatic::new()
             }
@@ -152,6 +385,21 @@ pub mod tests {
                 MockRealOutEndpoint::new()
             }
 
+            fn alloc_streams(&self, _endpoint_id: u8) -> anyhow::Result<()> {
+                if self.fail_stream_allocation.load(Ordering::Relaxed) {
+                    return Err(anyhow::anyhow!("no streams on this endpoint"));
+                }
+                Ok(())
+            }
+
+            fn bulk_in_stream_handle(&self, _endpoint_id: u8, _stream_id: u16) -> Self::RBIEH {
+                MockRealInEndpoint::new()
+            }
+
+            fn bulk_out_stream_handle(&self, _endpoint_id: u8, _stream_id: u16) -> Self::RBOEH {
+                MockRealOutEndpoint::new()
+            }
+
             fn interrupt_in_endpoint_handle(&self, _endpoint_id: u8) -> Self::RIIEH {
                 MockRealInEndpoint::new()
             }
@@ -159,6 +407,14 @@ pub mod tests {
             fn interrupt_out_endpoint_handle(&self, _endpoint_id: u8) -> Self::RIOEH {
                 MockRealOutEndpoint::new()
             }
+
+            fn isoch_in_endpoint_handle(&self, _endpoint_id: u8) -> Self::RISIEH {
+                MockRealInEndpoint::new()
+            }
+
+            fn isoch_out_endpoint_handle(&self, _endpoint_id: u8) -> Self::RISOEH {
+                MockRealOutEndpoint::new()
+            }
         }
     }
 }
@@ -1,17 +1,19 @@
 use std::sync::{
-    atomic::{AtomicU32, AtomicU64, Ordering},
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
     Arc,
 };
 
-use tokio::sync::Notify;
-use tracing::{info, trace, warn};
+use tokio::sync::{oneshot, Notify};
+use tracing::{debug, info, trace, warn};
 
 use crate::device::{
     pci::constants::xhci::{
-        operational::{portsc, usbcmd, usbsts},
+        operational::{portli, portpmsc, portsc, usbcmd, usbsts},
         MAX_SLOTS,
     },
-    xhci::{interrupter::EventSender, port::UsbVersion, trb::EventTrb},
+    xhci::{
+        controller_reset::ResetSender, interrupter::EventSender, port::UsbVersion, trb::EventTrb,
+    },
 };
 
 /// A somewhat simple PORTSC register implementation supporting RW1C bits and
@@ -19,27 +21,60 @@ use crate::device::{
 ///
 /// The PORTSC register requires us to initially set some bits and
 /// later react to 1-to-clear writes (RW1C) to get a device to show up.
-/// Additionally the PR bit is handled specific to this implementation to avoid
-/// an actual reset. Instead we pretend it was successful.
-/// We might need further specific logic or access to the bits or state
-/// handling later, for now this implementation is enough.
+/// Additionally the PR and WPR bits are handled specific to this
+/// implementation. Resetting a port with a connected device is handed to the
+/// port worker, which resets the real device. Without a device, we pretend the
+/// reset was successful.
+/// Link state writes that suspend or resume the port are handed to the port
+/// worker as a [`LinkStateRequest`], because they affect the real device.
+/// USB 2 L1 (LPM) is only emulated, as the host manages the link to the real
+/// device.
 #[derive(Debug)]
 pub struct PortscRegister {
     value: AtomicU64,
     event_sender: EventSender,
     usb_version: UsbVersion,
     port_id: u8,
+    portpmsc: PortpmscRegister,
+    portli: PortliRegister,
 }
 
-const BITMASK_RW1C: u64 = 0x00260000;
+const BITMASK_RW1C: u64 = portsc::CSC | portsc::PEC | portsc::WRC | portsc::PRC | portsc::PLC;
+
+/// A link state transition requested by the driver that involves the real
+/// device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkStateRequest {
+    /// The port entered U3; the device may be suspended.
+    Suspend,
+    /// The port left U3 (USB 2 resume signaling).
+    Resume,
+    /// The driver requested U0 from U3 or Resume. The transition completes
+    /// with [`PortscRegister::enter_u0`] once the device is resumed.
+    EnterU0,
+    /// The driver reset the port, with a warm reset on USB 3 ports if
+    /// `warm`. The reset completes with [`PortscRegister::complete_reset`]
+    /// once the device is reset.
+    Reset { warm: bool },
+}
 
 impl PortscRegister {
-    pub const fn new(event_sender: EventSender, usb_version: UsbVersion, port_id: u8) -> Self {
+    /// Create the PORTSC register of a port with the port's PORTPMSC and
+    /// PORTLI registers, which link state changes and resets update.
+    pub const fn new(
+        event_sender: EventSender,
+        usb_version: UsbVersion,
+        port_id: u8,
+        portpmsc: PortpmscRegister,
+        portli: PortliRegister,
+    ) -> Self {
         Self {
             value: AtomicU64::new(portsc::PP | portsc::value::PLS_RXDETECT),
             event_sender,
             usb_version,
             port_id,
+            portpmsc,
+            portli,
         }
     }
 
@@ -64,36 +99,230 @@ impl PortscRegister {
     ///
     /// This function should be called when an MMIO write happens.
     /// RW1C bits are updates according to RW1C semantics.
-    /// PR bit is handled by a custom logic path.
+    /// PR and WPR bits are handled by a custom logic path.
+    /// PLS is written only together with LWS on an enabled port.
     /// All other bits are treated as read-only.
-    pub fn write(&self, new_value: u64) -> anyhow::Result<()> {
+    ///
+    /// Returns the link state transition the port worker has to carry out,
+    /// if any.
+    pub fn write(&self, new_value: u64) -> anyhow::Result<Option<LinkStateRequest>> {
         let bits_to_clear = new_value & BITMASK_RW1C;
-        let port_reset_bit = new_value & portsc::PR != 0;
-
+        // WPR is reserved on USB 2 ports
+        let warm_reset_bit = new_value & portsc::WPR != 0 && self.usb_version == UsbVersion::USB3;
+        let port_reset_bit = new_value & portsc::PR != 0 || warm_reset_bit;
+        let link_state_write = new_value & portsc::LWS != 0;
+
+        let mut request = None;
+        let mut reset_completed = false;
+        let mut link_state_change = false;
         match self.value.fetch_update(
             std::sync::atomic::Ordering::Relaxed,
             std::sync::atomic::Ordering::Relaxed,
             |reg| {
                 let mut new_reg = reg & !bits_to_clear;
+                let link_state = reg & portsc::PLS;
+                request = None;
+                reset_completed = false;
 
                 if port_reset_bit {
-                    Self::port_reset(&mut new_reg, self.usb_version);
+                    if reg & portsc::PR != 0 {
+                        trace!("port {} is already being reset", self.port_id);
+                    } else if reg & portsc::CCS != 0 {
+                        // the port is disabled until the device is reset
+                        Self::update_with_mask(&mut new_reg, portsc::PR, portsc::PR | portsc::PED);
+                        request = Some(LinkStateRequest::Reset {
+                            warm: warm_reset_bit,
+                        });
+                    } else {
+                        Self::port_reset(&mut new_reg, self.usb_version);
+                        if warm_reset_bit {
+                            Self::update_with_mask(&mut new_reg, portsc::WRC, portsc::WRC);
+                        }
+                        reset_completed = true;
+                    }
+                } else if link_state_write && reg & portsc::PED != 0 {
+                    request =
+                        self.link_state_write(&mut new_reg, link_state, new_value & portsc::PLS);
                 }
 
+                // a status change event is only generated when a change bit
+                // is raised
+                link_state_change = (new_reg & !reg) & portsc::PLC != 0;
                 Some(new_reg)
             },
         ) {
             Ok(_) => {
-                if port_reset_bit {
+                if reset_completed {
+                    self.portli.reset();
+                }
+                if reset_completed || link_state_change {
                     let event = EventTrb::new_port_status_change_event_trb(self.port_id);
                     self.event_sender.send(event)?;
                 }
-                Ok(())
+                Ok(request)
             }
             Err(_) => unreachable!("update function never returns None"),
         }
     }
 
+    // See XHCI spec 4.15.2 for the transitions.
+    fn link_state_write(
+        &self,
+        register: &mut u64,
+        link_state: u64,
+        requested: u64,
+    ) -> Option<LinkStateRequest> {
+        use portsc::value::{PLS_RESUME, PLS_U0, PLS_U2, PLS_U3};
+
+        match (link_state, requested) {
+            // USB 2 software LPM (see XHCI spec 4.23.5.1.1.1)
+            (PLS_U0, PLS_U2) if self.usb_version == UsbVersion::USB2 => {
+                if self.portpmsc.l1_device_slot() == 0 {
+                    // there is no device to address the LPM transaction to
+                    self.portpmsc
+                        .set_l1_status(portpmsc::value::L1S_TIMEOUT_ERROR);
+                    Self::update_with_mask(register, portsc::PLC, portsc::PLC);
+                } else {
+                    self.portpmsc.set_l1_status(portpmsc::value::L1S_SUCCESS);
+                    Self::update_with_mask(register, PLS_U2, portsc::PLS);
+                }
+                None
+            }
+            (PLS_U2, PLS_U0) if self.usb_version == UsbVersion::USB2 => {
+                Self::update_with_mask(register, PLS_U0 | portsc::PLC, portsc::PLS | portsc::PLC);
+                None
+            }
+            (PLS_U0, PLS_U3) => {
+                Self::update_with_mask(register, PLS_U3, portsc::PLS);
+                Some(LinkStateRequest::Suspend)
+            }
+            (PLS_U3, PLS_RESUME) if self.usb_version == UsbVersion::USB2 => {
+                Self::update_with_mask(register, PLS_RESUME, portsc::PLS);
+                Some(LinkStateRequest::Resume)
+            }
+            (PLS_U3 | PLS_RESUME, PLS_U0) => Some(LinkStateRequest::EnterU0),
+            (current, requested) => {
+                trace!(
+                    "ignoring link state write {requested:#x} in state {current:#x} on port {}",
+                    self.port_id
+                );
+                None
+            }
+        }
+    }
+
+    /// Complete a port reset once the real device is reset.
+    ///
+    /// The port is enabled in U0 and reports the reset with PRC, and WRC
+    /// for a warm reset.
+    pub fn complete_reset(&self, warm: bool) -> anyhow::Result<()> {
+        let changes = if warm {
+            portsc::PRC | portsc::WRC
+        } else {
+            portsc::PRC
+        };
+        let resetting = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reg| {
+                (reg & portsc::PR != 0).then(|| {
+                    let mut new_reg = reg;
+                    Self::update_with_mask(
+                        &mut new_reg,
+                        portsc::PED | portsc::value::PLS_U0 | changes,
+                        portsc::PR | portsc::PED | portsc::PLS | changes,
+                    );
+                    new_reg
+                })
+            })
+            .is_ok();
+
+        if resetting {
+            self.portli.reset();
+            let event = EventTrb::new_port_status_change_event_trb(self.port_id);
+            self.event_sender.send(event)?;
+        }
+
+        Ok(())
+    }
+
+    /// Complete a transition from U3 or Resume to U0.
+    ///
+    /// Call once the real device is resumed.
+    pub fn enter_u0(&self) -> anyhow::Result<()> {
+        self.change_link_state(
+            &[portsc::value::PLS_U3, portsc::value::PLS_RESUME],
+            portsc::value::PLS_U0,
+        )
+    }
+
+    /// Report a remote wakeup of the device on the suspended port.
+    ///
+    /// The port moves to Resume, and the driver completes the transition
+    /// to U0.
+    pub fn signal_remote_wakeup(&self) -> anyhow::Result<()> {
+        self.change_link_state(&[portsc::value::PLS_U3], portsc::value::PLS_RESUME)
+    }
+
+    // Set PLS and PLC and send a port status change event if the port
+    // currently is in one of the `from` states.
+    fn change_link_state(&self, from: &[u64], to: u64) -> anyhow::Result<()> {
+        let changed = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reg| {
+                from.contains(&(reg & portsc::PLS)).then(|| {
+                    let mut new_reg = reg;
+                    Self::update_with_mask(
+                        &mut new_reg,
+                        to | portsc::PLC,
+                        portsc::PLS | portsc::PLC,
+                    );
+                    new_reg
+                })
+            })
+            .is_ok();
+
+        if changed {
+            let event = EventTrb::new_port_status_change_event_trb(self.port_id);
+            self.event_sender.send(event)?;
+        }
+
+        Ok(())
+    }
+
+    /// Capture the PORTSC, PORTPMSC and PORTLI values for Save State.
+    pub fn save(&self) -> PortRegisterState {
+        PortRegisterState {
+            portsc: self.read(),
+            portpmsc: self.portpmsc.read(),
+            portli: self.portli.read(),
+        }
+    }
+
+    /// Restore the values captured by [`Self::save`] for Restore State.
+    ///
+    /// A port that raised change bits since the save, e.g., because a device
+    /// was attached or woke up, keeps its current state. The port reports the
+    /// change again, so the driver learns about it after resuming.
+    pub fn restore(&self, saved: PortRegisterState) -> anyhow::Result<()> {
+        let restored = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reg| {
+                (reg & BITMASK_RW1C & !saved.portsc == 0).then_some(saved.portsc)
+            })
+            .is_ok();
+
+        if restored {
+            self.portpmsc.update(saved.portpmsc, u32::MAX);
+            self.portli.value.store(saved.portli, Ordering::Relaxed);
+        } else {
+            debug!("port {} changed while the state was saved", self.port_id);
+            let event = EventTrb::new_port_status_change_event_trb(self.port_id);
+            self.event_sender.send(event)?;
+        }
+
+        Ok(())
+    }
+
     fn port_reset(register: &mut u64, usb_version: UsbVersion) {
         match usb_version {
             UsbVersion::USB2 => {
@@ -124,22 +353,109 @@ impl PortscRegister {
     }
 }
 
+/// The register values of a port captured by Save State.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRegisterState {
+    portsc: u64,
+    portpmsc: u32,
+    portli: u32,
+}
+
 /// Port Power Management Status and Control (chapter 5.4.9)
 ///
+/// USB 3 ports hold the U1 and U2 timeouts and FLA. The port never enters U1
+/// or U2 by itself, because the host manages the link to the real device.
+/// USB 2 ports hold the L1 (LPM) parameters and the L1 Status of the last
+/// software initiated L1 entry (see [`PortscRegister`]).
+///
 /// Limitations:
 /// 1. no separation between RW and RWS
-/// 1. no checks for RsvdP at: 17 <= bits <= 31
-#[derive(Debug, Default, Clone)]
+/// 1. no port test modes
+#[derive(Debug, Clone)]
 pub struct PortpmscRegister {
     value: Arc<AtomicU32>,
+    usb_version: UsbVersion,
 }
+
 impl PortpmscRegister {
+    pub fn new(usb_version: UsbVersion) -> Self {
+        Self {
+            value: Arc::new(AtomicU32::new(0)),
+            usb_version,
+        }
+    }
+
     pub fn read(&self) -> u32 {
         self.value.load(Ordering::Relaxed)
     }
 
     pub fn write(&self, value: u32) {
-        self.value.store(value, Ordering::Relaxed);
+        let writable = match self.usb_version {
+            UsbVersion::USB3 => portpmsc::U1_TIMEOUT | portpmsc::U2_TIMEOUT | portpmsc::FLA,
+            UsbVersion::USB2 => {
+                if value & portpmsc::PORT_TEST_CONTROL != 0 {
+                    warn!("ignoring unsupported port test mode request {value:#x}");
+                }
+                portpmsc::RWE | portpmsc::HIRD | portpmsc::L1_DEVICE_SLOT | portpmsc::HLE
+            }
+        };
+
+        // the L1 Status is read-only
+        self.update(value, writable);
+    }
+
+    /// The slot of the device addressed by software initiated L1 entry on
+    /// USB 2 ports.
+    pub fn l1_device_slot(&self) -> u8 {
+        ((self.read() & portpmsc::L1_DEVICE_SLOT) >> 8) as u8
+    }
+
+    fn set_l1_status(&self, status: u32) {
+        self.update(status, portpmsc::L1S);
+    }
+
+    fn update(&self, value: u32, mask: u32) {
+        let _ = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reg| {
+                Some((reg & !mask) | (value & mask))
+            });
+    }
+}
+
+/// Port Link Info (chapter 5.4.10)
+///
+/// Only USB 3 ports count link errors, the register is reserved on USB 2
+/// ports. No link errors happen on the emulated links, so the count only
+/// changes when software writes it.
+#[derive(Debug, Clone)]
+pub struct PortliRegister {
+    value: Arc<AtomicU32>,
+    usb_version: UsbVersion,
+}
+
+impl PortliRegister {
+    pub fn new(usb_version: UsbVersion) -> Self {
+        Self {
+            value: Arc::new(AtomicU32::new(0)),
+            usb_version,
+        }
+    }
+
+    pub fn read(&self) -> u32 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub fn write(&self, value: u32) {
+        if self.usb_version == UsbVersion::USB3 {
+            self.value
+                .store(value & portli::LINK_ERROR_COUNT, Ordering::Relaxed);
+        }
+    }
+
+    /// Clear the link error count, which a port reset does.
+    pub fn reset(&self) {
+        self.value.store(0, Ordering::Relaxed);
     }
 }
 
@@ -192,24 +508,31 @@ impl DcbaapRegister {
 pub struct UsbcmdRegister {
     value: Arc<AtomicU32>,
     reset_notify: Arc<Notify>,
+    state_notify: Arc<Notify>,
 }
 
+/// The Controller Save State and Controller Restore State commands.
+const STATE_COMMANDS: u64 = usbcmd::CSS | usbcmd::CRS;
+
 impl UsbcmdRegister {
     pub fn new() -> Self {
         Self {
             value: Arc::new(AtomicU32::new(0)),
             reset_notify: Default::default(),
+            state_notify: Default::default(),
         }
     }
 
+    /// Read the register. CSS and CRS always read as 0.
     pub fn read(&self) -> u64 {
-        self.value.load(std::sync::atomic::Ordering::Relaxed).into()
+        u64::from(self.value.load(std::sync::atomic::Ordering::Relaxed)) & !STATE_COMMANDS
     }
 
     /// A simple write with a very limited list of allowed bits (RsvdP is also not written).
     pub fn write(&self, value: u64) {
         // Currently writable bits, ignoring any other bits and printing a warning.
-        const BITMASK_PRESERVED: u64 = usbcmd::RS | usbcmd::INTE | usbcmd::HCRST;
+        const BITMASK_PRESERVED: u64 =
+            usbcmd::RS | usbcmd::INTE | usbcmd::HCRST | usbcmd::CSS | usbcmd::CRS | usbcmd::EWE;
         if value & !BITMASK_PRESERVED != 0 {
             warn!(
                 "received at least one bit that is ignored for USBCMD: {}",
@@ -217,11 +540,27 @@ impl UsbcmdRegister {
             );
         }
 
-        // Preserve an ongoing reset until the reset coordinator clears HCRST.
+        let state_command = value & STATE_COMMANDS;
+        let mut state_command_accepted = false;
+        // Preserve an ongoing reset until the reset coordinator clears HCRST,
+        // and an ongoing save or restore until the state coordinator clears
+        // CSS or CRS.
         self.value
             .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
-                let hcrst = current as u64 & usbcmd::HCRST;
-                let value = (value & BITMASK_PRESERVED) | hcrst;
+                let current = u64::from(current);
+                let pending = current & (usbcmd::HCRST | STATE_COMMANDS);
+                // Saving and restoring state is only allowed while the
+                // controller is halted, one operation at a time.
+                state_command_accepted = state_command != 0
+                    && state_command != STATE_COMMANDS
+                    && pending & STATE_COMMANDS == 0
+                    && (current | value) & usbcmd::RS == 0;
+                let accepted = if state_command_accepted {
+                    state_command
+                } else {
+                    0
+                };
+                let value = (value & BITMASK_PRESERVED & !STATE_COMMANDS) | pending | accepted;
                 // SAFETY: USBCMD is defined as 32 bit and the masks used above enforce it.
                 Some(value.try_into().unwrap())
             })
@@ -230,6 +569,12 @@ impl UsbcmdRegister {
             info!("Host Controller Reset requested");
             self.reset_notify.notify_waiters();
         }
+        if state_command_accepted {
+            info!("Controller Save or Restore State requested: {state_command:#x}");
+            self.state_notify.notify_one();
+        } else if state_command != 0 {
+            warn!("ignoring Controller Save or Restore State request {state_command:#x}");
+        }
     }
 
     pub fn value_reference(&self) -> Arc<AtomicU32> {
@@ -244,22 +589,83 @@ impl UsbcmdRegister {
         self.value
             .fetch_and(!(usbcmd::HCRST as u32), Ordering::Relaxed);
     }
+
+    pub async fn state_command_notification(&self) {
+        self.state_notify.notified().await;
+    }
+
+    /// The pending Save (CSS) or Restore (CRS) State command, if any.
+    pub fn state_command(&self) -> u64 {
+        u64::from(self.value.load(Ordering::Relaxed)) & STATE_COMMANDS
+    }
+
+    /// Finish the pending Save or Restore State command.
+    pub fn clear_state_command(&self) {
+        self.value
+            .fetch_and(!(STATE_COMMANDS as u32), Ordering::Relaxed);
+    }
 }
 
-#[derive(Debug)]
+/// USB Status Register (chapter 5.4.2)
+///
+/// Most bits are derived from USBCMD. SSS and RSS are set while the Save or
+/// Restore State command in USBCMD is pending.
+#[derive(Debug, Clone)]
 pub struct UsbstsRegister {
     usbcmd: Arc<AtomicU32>,
+    save_restore_error: Arc<AtomicBool>,
 }
 
 impl UsbstsRegister {
-    pub const fn new(usbcmd: Arc<AtomicU32>) -> Self {
-        Self { usbcmd }
+    pub fn new(usbcmd: Arc<AtomicU32>) -> Self {
+        Self {
+            usbcmd,
+            save_restore_error: Default::default(),
+        }
     }
 
     pub fn read(&self) -> u64 {
-        let is_running = (self.usbcmd.load(Ordering::Relaxed) as u64 & usbcmd::RS) == usbcmd::RS;
+        let usbcmd = u64::from(self.usbcmd.load(Ordering::Relaxed));
+        let is_running = (usbcmd & usbcmd::RS) == usbcmd::RS;
         let hch = if is_running { 0 } else { usbsts::HCH };
-        hch | usbsts::EINT | usbsts::PCD
+        let sss = if usbcmd & usbcmd::CSS != 0 {
+            usbsts::SSS
+        } else {
+            0
+        };
+        let rss = if usbcmd & usbcmd::CRS != 0 {
+            usbsts::RSS
+        } else {
+            0
+        };
+        let sre = if self.save_restore_error.load(Ordering::Relaxed) {
+            usbsts::SRE
+        } else {
+            0
+        };
+        hch | sss | rss | sre | usbsts::EINT | usbsts::PCD
+    }
+
+    /// Update the register on an MMIO write. Only SRE is RW1C, writes to the
+    /// other bits are ignored.
+    pub fn write(&self, value: u64) {
+        if value & usbsts::SRE != 0 {
+            self.save_restore_error.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Report a failed Save or Restore State operation with SRE.
+    pub fn set_save_restore_error(&self) {
+        self.save_restore_error.store(true, Ordering::Relaxed);
+    }
+}
+
+impl ResetSender for UsbstsRegister {
+    fn send_reset(&self, completion_notifier: oneshot::Sender<()>) -> anyhow::Result<()> {
+        self.save_restore_error.store(false, Ordering::Relaxed);
+        completion_notifier
+            .send(())
+            .map_err(|_| anyhow::anyhow!("reset completion receiver dropped"))
     }
 }
 
@@ -315,7 +721,7 @@ impl ErstbaRegister {
 
 #[cfg(test)]
 mod tests {
-    use crate::device::xhci::interrupter::Interrupter;
+    use crate::device::xhci::interrupter::{tests::testutils::MockInterrupter, Interrupter};
     use crate::dynamic_bus::DynamicBus;
 
     use super::*;
@@ -325,7 +731,13 @@ mod tests {
         let async_runtime = tokio::runtime::Handle::current();
         let dma_bus = Arc::new(DynamicBus::new());
         let interrupter = Interrupter::new(dma_bus, &async_runtime);
-        let reg = PortscRegister::new(interrupter.create_event_sender(), UsbVersion::USB3, 1);
+        let reg = PortscRegister::new(
+            interrupter.create_event_sender(),
+            UsbVersion::USB3,
+            1,
+            PortpmscRegister::new(UsbVersion::USB3),
+            PortliRegister::new(UsbVersion::USB3),
+        );
 
         reg.set(0x00260203);
         assert_eq!(reg.read(), 0x00260203);
@@ -359,6 +771,189 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn portsc_link_state_writes() {
+        use portsc::value::{PLS_RESUME, PLS_U0, PLS_U3};
+
+        let async_runtime = tokio::runtime::Handle::current();
+        let dma_bus = Arc::new(DynamicBus::new());
+        let interrupter = Interrupter::new(dma_bus, &async_runtime);
+        let reg = PortscRegister::new(
+            interrupter.create_event_sender(),
+            UsbVersion::USB2,
+            1,
+            PortpmscRegister::new(UsbVersion::USB2),
+            PortliRegister::new(UsbVersion::USB2),
+        );
+        reg.set(portsc::CCS | portsc::PED | portsc::PP);
+
+        assert_eq!(
+            reg.write(portsc::PED | PLS_U3).unwrap(),
+            None,
+            "PLS should only be written together with LWS."
+        );
+        assert_eq!(reg.read() & portsc::PLS, PLS_U0);
+
+        assert_eq!(
+            reg.write(portsc::LWS | PLS_U3).unwrap(),
+            Some(LinkStateRequest::Suspend)
+        );
+        assert_eq!(reg.read() & portsc::PLS, PLS_U3);
+
+        assert_eq!(
+            reg.write(portsc::LWS | PLS_RESUME).unwrap(),
+            Some(LinkStateRequest::Resume)
+        );
+        assert_eq!(reg.read() & portsc::PLS, PLS_RESUME);
+
+        assert_eq!(
+            reg.write(portsc::LWS | PLS_U0).unwrap(),
+            Some(LinkStateRequest::EnterU0)
+        );
+        assert_eq!(
+            reg.read() & portsc::PLS,
+            PLS_RESUME,
+            "the port should only enter U0 once the device is resumed."
+        );
+
+        reg.enter_u0().unwrap();
+        assert_eq!(
+            reg.read() & (portsc::PLS | portsc::PLC),
+            PLS_U0 | portsc::PLC
+        );
+    }
+
+    fn usb2_port(event_sender: EventSender) -> (PortscRegister, PortpmscRegister) {
+        let portpmsc = PortpmscRegister::new(UsbVersion::USB2);
+        let reg = PortscRegister::new(
+            event_sender,
+            UsbVersion::USB2,
+            1,
+            portpmsc.clone(),
+            PortliRegister::new(UsbVersion::USB2),
+        );
+        reg.set(portsc::CCS | portsc::PED | portsc::PP);
+
+        (reg, portpmsc)
+    }
+
+    #[tokio::test]
+    async fn portsc_usb2_l1_entry_and_exit() {
+        use portsc::value::{PLS_U0, PLS_U2};
+
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let (reg, portpmsc) = usb2_port(event_sender);
+
+        // HIRD 4 and L1 Device Slot 1
+        portpmsc.write(0x0140);
+        assert_eq!(reg.write(portsc::LWS | PLS_U2).unwrap(), None);
+        assert_eq!(reg.read() & (portsc::PLS | portsc::PLC), PLS_U2);
+        assert_eq!(
+            portpmsc.read(),
+            0x0140 | portpmsc::value::L1S_SUCCESS,
+            "the L1 entry should succeed without a status change."
+        );
+        assert!(interrupter.is_empty());
+
+        assert_eq!(reg.write(portsc::LWS | PLS_U0).unwrap(), None);
+        assert_eq!(
+            reg.read() & (portsc::PLS | portsc::PLC),
+            PLS_U0 | portsc::PLC
+        );
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn portsc_usb2_l1_entry_without_device_slot_is_rejected() {
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let (reg, portpmsc) = usb2_port(event_sender);
+
+        assert_eq!(
+            reg.write(portsc::LWS | portsc::value::PLS_U2).unwrap(),
+            None
+        );
+        assert_eq!(
+            reg.read() & (portsc::PLS | portsc::PLC),
+            portsc::value::PLS_U0 | portsc::PLC
+        );
+        assert_eq!(
+            portpmsc.read() & portpmsc::L1S,
+            portpmsc::value::L1S_TIMEOUT_ERROR
+        );
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(1))
+        );
+    }
+
+    #[test]
+    fn portpmsc_writes_respect_port_version() {
+        let usb3 = PortpmscRegister::new(UsbVersion::USB3);
+        usb3.write(0xffff_ffff);
+        assert_eq!(
+            usb3.read(),
+            portpmsc::U1_TIMEOUT | portpmsc::U2_TIMEOUT | portpmsc::FLA
+        );
+
+        let usb2 = PortpmscRegister::new(UsbVersion::USB2);
+        usb2.write(0xffff_ffff);
+        assert_eq!(
+            usb2.read(),
+            portpmsc::RWE | portpmsc::HIRD | portpmsc::L1_DEVICE_SLOT | portpmsc::HLE,
+            "L1S is read-only and port test modes are unsupported."
+        );
+        assert_eq!(usb2.l1_device_slot(), 0xff);
+    }
+
+    #[tokio::test]
+    async fn portsc_warm_reset_completes_later_and_clears_link_errors() {
+        let (event_sender, mut interrupter) = MockInterrupter::new();
+        let portli = PortliRegister::new(UsbVersion::USB3);
+        let reg = PortscRegister::new(
+            event_sender,
+            UsbVersion::USB3,
+            1,
+            PortpmscRegister::new(UsbVersion::USB3),
+            portli.clone(),
+        );
+        reg.set(portsc::CCS | portsc::PED | portsc::PP);
+        portli.write(0xdead_beef);
+        assert_eq!(portli.read(), 0xbeef);
+
+        assert_eq!(
+            reg.write(portsc::WPR).unwrap(),
+            Some(LinkStateRequest::Reset { warm: true })
+        );
+        assert_eq!(
+            reg.read(),
+            portsc::CCS | portsc::PP | portsc::PR,
+            "WPR should read as 0 and the port should be disabled during the reset."
+        );
+        assert_eq!(
+            reg.write(portsc::PR).unwrap(),
+            None,
+            "a reset in progress should not be restarted."
+        );
+        assert!(interrupter.is_empty());
+
+        reg.complete_reset(true).unwrap();
+        assert_eq!(
+            reg.read(),
+            portsc::CCS | portsc::PED | portsc::PP | portsc::WRC | portsc::PRC
+        );
+        assert_eq!(portli.read(), 0);
+        assert_eq!(
+            interrupter.await_event().await,
+            Some(EventTrb::new_port_status_change_event_trb(1))
+        );
+
+        reg.write(portsc::WRC | portsc::PRC).unwrap();
+        assert_eq!(reg.read(), portsc::CCS | portsc::PED | portsc::PP);
+    }
+
     #[test]
     fn usbcmd_read_write() {
         let reg = UsbcmdRegister::new();
@@ -8,7 +8,7 @@ use tracing::{debug, info, trace, warn};
 use crate::{
     device::{
         bus::{BusDeviceRef, Request, RequestSize},
-        pci::constants::xhci::{device_slots::slot_state, MAX_SLOTS},
+        pci::constants::xhci::{device_slots::slot_state, MAX_INTRS, MAX_SLOTS},
         xhci::{
             controller_reset::ResetSender,
             endpoint::EndpointSender,
@@ -72,10 +72,10 @@ impl SlotManager {
         }
     }
 
-    pub fn doorbell(&self, slot_id: u8, endpoint_id: u8) -> anyhow::Result<()> {
-        debug!("Doorbell for slot {slot_id} endpoint {endpoint_id}");
+    pub fn doorbell(&self, slot_id: u8, endpoint_id: u8, stream_id: u16) -> anyhow::Result<()> {
+        debug!("Doorbell for slot {slot_id} endpoint {endpoint_id} stream {stream_id}");
         self.msg_send
-            .send(SlotMessage::Doorbell(slot_id, endpoint_id))?;
+            .send(SlotMessage::Doorbell(slot_id, endpoint_id, stream_id))?;
 
         Ok(())
     }
@@ -105,8 +105,8 @@ pub struct SlotWorker {
 
 #[derive(Debug)]
 pub enum SlotMessage {
-    // slot_id, endpoint_id
-    Doorbell(u8, u8),
+    // slot_id, endpoint_id, stream_id
+    Doorbell(u8, u8, u16),
     EnableSlot(oneshot::Sender<Result<u8, CompletionCode>>),
     DisableSlot(u8, oneshot::Sender<CompletionCode>),
     AddressDevice(AddressDeviceCommandTrbData, oneshot::Sender<CompletionCode>),
@@ -119,6 +119,9 @@ pub enum SlotMessage {
         oneshot::Sender<CompletionCode>,
     ),
     ResetDevice(u8, oneshot::Sender<CompletionCode>),
+    NegotiateBandwidth(u8, oneshot::Sender<CompletionCode>),
+    // root hub port
+    SlotOfPort(u8, oneshot::Sender<Option<u8>>),
     ResetAllSlots(oneshot::Sender<()>),
     // slot_id, endpoint_id
     StopEndpoint(u8, u8, oneshot::Sender<CompletionCode>),
@@ -158,7 +161,7 @@ impl SlotWorker {
     async fn run_loop(&mut self) -> anyhow::Result<()> {
         loop {
             match self.next_msg().await? {
-                SlotMessage::Doorbell(slot_id, endpoint_id) => {
+                SlotMessage::Doorbell(slot_id, endpoint_id, stream_id) => {
                     let slot = match self.slot_ref(slot_id) {
                         Some(slot) => slot,
                         None => {
@@ -179,7 +182,7 @@ impl SlotWorker {
                             continue;
                         }
                     };
-                    ep_sender.doorbell()?;
+                    ep_sender.doorbell(stream_id)?;
                 }
                 SlotMessage::EnableSlot(sender) => {
                     let result = self.allocate_slot();
@@ -249,6 +252,18 @@ impl SlotWorker {
                     let result = slot.handle_reset_device().await?;
                     sender.send_anyhow(result)?;
                 }
+                SlotMessage::NegotiateBandwidth(slot_id, sender) => {
+                    // The host schedules the bandwidth of the real devices, so
+                    // there is nothing to renegotiate for us.
+                    let result = match self.slot_ref(slot_id) {
+                        Some(_) => CompletionCode::Success,
+                        None => CompletionCode::SlotNotEnabledError,
+                    };
+                    sender.send_anyhow(result)?;
+                }
+                SlotMessage::SlotOfPort(port_id, sender) => {
+                    sender.send_anyhow(self.slot_of_port(port_id))?;
+                }
                 SlotMessage::ResetAllSlots(completion) => {
                     self.reset().await?;
                     completion.send_anyhow(())?;
@@ -309,6 +324,7 @@ impl SlotWorker {
                     };
 
                     ep_sender.set_tr_dequeue_pointer(
+                        trb_data.stream_id,
                         trb_data.dequeue_pointer,
                         trb_data.dequeue_cycle_state,
                         sender,
@@ -337,6 +353,19 @@ impl SlotWorker {
             .and_then(|opt| opt.as_mut())
     }
 
+    // The slot with the device on the root hub port, if the device is
+    // addressed already.
+    fn slot_of_port(&self, port_id: u8) -> Option<u8> {
+        self.slots
+            .iter()
+            .filter_map(Option::as_ref)
+            .find(|slot| {
+                slot.base_address()
+                    .is_some_and(|base_address| slot.root_hub_port(base_address) == port_id)
+            })
+            .map(|slot| slot.id)
+    }
+
     fn allocate_slot(&mut self) -> Result<u8, CompletionCode> {
         let available_slot_id = (1..=self.config_reg.num_slots_enabled())
             .find(|&slot_id| self.slots[slot_id as usize].is_none());
@@ -434,6 +463,15 @@ impl Slot {
         );
     }
 
+    const fn base_address(&self) -> Option<u64> {
+        match self.state {
+            SlotState::Enabled => None,
+            SlotState::Default(base_address)
+            | SlotState::Addressed(base_address)
+            | SlotState::Configured(base_address) => Some(base_address),
+        }
+    }
+
     fn endpoint_sender(&self, endpoint_id: u8) -> Option<&EndpointSender> {
         self.endpoint_senders
             .get(endpoint_id as usize)
@@ -498,6 +536,7 @@ impl Slot {
         self.write_slot_state();
 
         self.dma_copy_ep_context(1, input_context_pointer, base_address);
+        // The control endpoint is never refused.
         self.configure_endpoint(1, base_address).await?;
 
         Ok(CompletionCode::Success)
@@ -586,9 +625,18 @@ impl Slot {
                 self.deconfigure_endpoint(ep).await?;
             }
 
-            for ep in to_configure {
+            for (index, &ep) in to_configure.iter().enumerate() {
                 self.dma_copy_ep_context(ep, input_context_pointer, base_address);
-                self.configure_endpoint(ep, base_address).await?;
+                if !self.configure_endpoint(ep, base_address).await? {
+                    warn!(
+                        "Refusing to configure endpoint {ep} of slot {}: not exposed by the device",
+                        self.id
+                    );
+                    for &configured in &to_configure[..index] {
+                        self.deconfigure_endpoint(configured).await?;
+                    }
+                    return Ok(CompletionCode::ResourceError);
+                }
             }
 
             self.state = SlotState::Configured(base_address);
@@ -617,11 +665,12 @@ impl Slot {
 
     // helper method for address_advice and configure_endpoint.
     // do only call for already enabled endpoints.
+    // returns false if the device refuses the endpoint.
     async fn configure_endpoint(
         &mut self,
         endpoint_id: u8,
         base_address: u64,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<bool> {
         let context = EndpointContext::new(
             base_address.wrapping_add(endpoint_id as u64 * 32),
             self.dma_bus.clone(),
@@ -636,16 +685,93 @@ impl Slot {
                 context,
             )
             .await?;
-        self.endpoint_senders[endpoint_id as usize] = Some(ep_sender);
+        let configured = ep_sender.is_some();
+        self.endpoint_senders[endpoint_id as usize] = ep_sender;
 
-        Ok(())
+        Ok(configured)
     }
 
+    // 4.6.7 for reference
     async fn handle_evaluate_context(
         &self,
-        _input_context_pointer: u64,
+        input_context_pointer: u64,
     ) -> anyhow::Result<CompletionCode> {
-        warn!("handle_evaluate_context is not implemented yet. Just reporting success");
+        let base_address = match self.state {
+            SlotState::Enabled => return Ok(CompletionCode::ContextStateError),
+            SlotState::Default(base_address)
+            | SlotState::Addressed(base_address)
+            | SlotState::Configured(base_address) => base_address,
+        };
+
+        // Only A0 (slot context) and A1 (EP0 context) may be set.
+        let drop_flags = self
+            .dma_bus
+            .read(Request::new(input_context_pointer, RequestSize::Size4));
+        let add_flags = self.dma_bus.read(Request::new(
+            input_context_pointer.wrapping_add(4),
+            RequestSize::Size4,
+        ));
+        if drop_flags != 0 || add_flags & !0x3 != 0 {
+            return Ok(CompletionCode::TrbError);
+        }
+        let evaluate_slot = add_flags & 0x1 != 0;
+        let evaluate_ep0 = add_flags & 0x2 != 0;
+
+        let input_slot_context = input_context_pointer.wrapping_add(32);
+        let input_ep0_context =
+            EndpointContext::new(input_context_pointer.wrapping_add(64), self.dma_bus.clone());
+
+        // we should not touch anything if the input is bad
+        let interrupter_target = self.dma_bus.read(Request::new(
+            input_slot_context.wrapping_add(8),
+            RequestSize::Size4,
+        )) >> 22;
+        if evaluate_slot && interrupter_target >= MAX_INTRS {
+            return Ok(CompletionCode::ParameterError);
+        }
+        let max_packet_size = input_ep0_context.get_max_packet_size();
+        if evaluate_ep0 && max_packet_size == 0 {
+            return Ok(CompletionCode::ParameterError);
+        }
+
+        if evaluate_slot {
+            let max_exit_latency = self.dma_bus.read(Request::new(
+                input_slot_context.wrapping_add(4),
+                RequestSize::Size2,
+            ));
+            debug!(
+                "slot {}: setting max exit latency to {max_exit_latency} and interrupter target to {interrupter_target}",
+                self.id
+            );
+            self.dma_bus.write(
+                Request::new(base_address.wrapping_add(4), RequestSize::Size2),
+                max_exit_latency,
+            );
+            // Interrupter Target occupies the upper 10 bits of the dword.
+            let target_addr = base_address.wrapping_add(8);
+            let dword = self
+                .dma_bus
+                .read(Request::new(target_addr, RequestSize::Size4));
+            self.dma_bus.write(
+                Request::new(target_addr, RequestSize::Size4),
+                (dword & 0x3f_ffff) | (interrupter_target << 22),
+            );
+        }
+
+        if evaluate_ep0 {
+            debug!(
+                "slot {}: setting EP0 max packet size to {max_packet_size}",
+                self.id
+            );
+            // The running EP0 worker takes over the new max packet size
+            // without touching its transfer ring.
+            match self.endpoint_sender(1) {
+                Some(ep0_sender) => ep0_sender.set_max_packet_size(max_packet_size).await?,
+                None => EndpointContext::new(base_address.wrapping_add(32), self.dma_bus.clone())
+                    .set_max_packet_size(max_packet_size),
+            }
+        }
+
         Ok(CompletionCode::Success)
     }
 
@@ -687,7 +813,7 @@ impl Slot {
 /// An endpoint context has a size of 32 bytes, lies in guest memory, and
 /// contains information about an endpoint, most importantly for us the dequeue
 /// pointer and cycle state of the associated transfer ring.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EndpointContext {
     /// The address of the endpoint context in guest memory.
     address: u64,
@@ -738,12 +864,55 @@ impl EndpointContext {
             .write(Request::new(self.address, RequestSize::Size1), state as u64);
     }
 
+    /// DMA read the Max Packet Size field.
+    pub fn get_max_packet_size(&self) -> u16 {
+        self.dma_bus.read(Request::new(
+            self.address.wrapping_add(6),
+            RequestSize::Size2,
+        )) as u16
+    }
+
+    /// DMA write the Max Packet Size field.
+    pub fn set_max_packet_size(&self, max_packet_size: u16) {
+        self.dma_bus.write(
+            Request::new(self.address.wrapping_add(6), RequestSize::Size2),
+            max_packet_size as u64,
+        );
+    }
+
+    /// DMA read the MaxPStreams field.
+    ///
+    /// A value of 0 means the endpoint does not use streams. Otherwise, the
+    /// dequeue pointer field points to a Primary Stream Context Array with
+    /// 2^(MaxPStreams + 1) entries.
+    pub fn get_max_primary_streams(&self) -> u8 {
+        let guest_mem_byte = self.dma_bus.read(Request::new(
+            self.address.wrapping_add(1),
+            RequestSize::Size1,
+        ));
+        ((guest_mem_byte >> 2) & 0x1f) as u8
+    }
+
+    /// DMA read the Linear Stream Array (LSA) flag.
+    ///
+    /// If set, the Primary Stream Context Array contains transfer rings
+    /// only and no Secondary Stream Context Arrays.
+    pub fn has_linear_stream_array(&self) -> bool {
+        let guest_mem_byte = self.dma_bus.read(Request::new(
+            self.address.wrapping_add(1),
+            RequestSize::Size1,
+        ));
+        guest_mem_byte & 0x80 != 0
+    }
+
     pub fn get_endpoint_type(&self) -> EndpointType {
         let guest_mem_byte = self.dma_bus.read(Request::new(
             self.address.wrapping_add(4),
             RequestSize::Size1,
         ));
         match (guest_mem_byte >> 3) & 0x7 {
+            1 => EndpointType::IsochOut,
+            5 => EndpointType::IsochIn,
             2 => EndpointType::BulkOut,
             6 => EndpointType::BulkIn,
             4 => EndpointType::Control,
@@ -764,6 +933,8 @@ pub enum EndpointType {
     BulkOut,
     InterruptIn,
     InterruptOut,
+    IsochIn,
+    IsochOut,
     Unsupported,
 }
 
@@ -830,6 +1001,23 @@ impl SlotWorkerHandle {
         Ok(completion_code)
     }
 
+    pub async fn negotiate_bandwidth(&self, slot_id: u8) -> anyhow::Result<CompletionCode> {
+        let (send, recv) = oneshot::channel();
+        let msg = SlotMessage::NegotiateBandwidth(slot_id, send);
+        self.msg_send.send(msg)?;
+        let completion_code = recv.await?;
+        Ok(completion_code)
+    }
+
+    /// Look up the slot of the device on the root hub port.
+    pub async fn slot_of_port(&self, port_id: u8) -> anyhow::Result<Option<u8>> {
+        let (send, recv) = oneshot::channel();
+        let msg = SlotMessage::SlotOfPort(port_id, send);
+        self.msg_send.send(msg)?;
+        let slot_id = recv.await?;
+        Ok(slot_id)
+    }
+
     pub async fn stop_endpoint(
         &self,
         slot_id: u8,
@@ -868,7 +1056,172 @@ impl SlotWorkerHandle {
 
 #[cfg(test)]
 pub mod test {
+    use std::sync::Arc;
+
     use super::*;
+    use crate::device::{
+        bus::{testutils::TestBusDevice, BusDevice},
+        xhci::{
+            endpoint::EndpointMessage, endpoint_launcher::tests::testutils::mock_launch_requester,
+        },
+    };
+
+    const INPUT_CONTEXT: u64 = 0x0;
+    const OUTPUT_CONTEXT: u64 = 0x100;
+
+    fn addressed_slot(ram: &Arc<TestBusDevice>) -> Slot {
+        let (launch_requester, _) = mock_launch_requester();
+        let mut slot = Slot::new(1, 0x300, ram.clone(), launch_requester);
+        slot.state = SlotState::Addressed(OUTPUT_CONTEXT);
+        slot
+    }
+
+    fn write_input_context(
+        ram: &TestBusDevice,
+        add_flags: u64,
+        max_exit_latency: u64,
+        interrupter_target: u64,
+        max_packet_size: u64,
+    ) {
+        ram.write(
+            Request::new(INPUT_CONTEXT + 4, RequestSize::Size4),
+            add_flags,
+        );
+        ram.write(
+            Request::new(INPUT_CONTEXT + 32 + 4, RequestSize::Size2),
+            max_exit_latency,
+        );
+        ram.write(
+            Request::new(INPUT_CONTEXT + 32 + 8, RequestSize::Size4),
+            interrupter_target << 22,
+        );
+        ram.write(
+            Request::new(INPUT_CONTEXT + 64 + 6, RequestSize::Size2),
+            max_packet_size,
+        );
+    }
+
+    #[tokio::test]
+    async fn evaluate_context_updates_slot_and_ep0_contexts() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x400]));
+        // output EP0 context: running, MPS 8, some transfer ring
+        ram.write(
+            Request::new(OUTPUT_CONTEXT + 32, RequestSize::Size8),
+            0x0008_0000_0000_0001,
+        );
+        ram.write(
+            Request::new(OUTPUT_CONTEXT + 32 + 8, RequestSize::Size8),
+            0x1001,
+        );
+        write_input_context(&ram, 0x3, 0x1234, 0, 64);
+        let slot = addressed_slot(&ram);
+
+        assert_eq!(
+            slot.handle_evaluate_context(INPUT_CONTEXT).await.unwrap(),
+            CompletionCode::Success
+        );
+
+        assert_eq!(
+            ram.read(Request::new(OUTPUT_CONTEXT + 4, RequestSize::Size2)),
+            0x1234
+        );
+        let ep0 = EndpointContext::new(OUTPUT_CONTEXT + 32, ram.clone());
+        assert_eq!(ep0.get_max_packet_size(), 64);
+        assert_eq!(ep0.get_dequeue_pointer_and_cycle_state(), (0x1000, true));
+        assert_eq!(
+            ram.read(Request::new(OUTPUT_CONTEXT + 32, RequestSize::Size1)),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn evaluate_context_rejects_invalid_input() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x400]));
+        let slot = addressed_slot(&ram);
+        let ep0 = EndpointContext::new(OUTPUT_CONTEXT + 32, ram.clone());
+
+        // interrupter target beyond the number of interrupters
+        write_input_context(&ram, 0x3, 0x1234, MAX_INTRS, 64);
+        assert_eq!(
+            slot.handle_evaluate_context(INPUT_CONTEXT).await.unwrap(),
+            CompletionCode::ParameterError
+        );
+        assert_eq!(
+            ram.read(Request::new(OUTPUT_CONTEXT + 4, RequestSize::Size2)),
+            0
+        );
+        assert_eq!(ep0.get_max_packet_size(), 0);
+
+        // zero max packet size
+        write_input_context(&ram, 0x2, 0, 0, 0);
+        assert_eq!(
+            slot.handle_evaluate_context(INPUT_CONTEXT).await.unwrap(),
+            CompletionCode::ParameterError
+        );
+
+        // only the slot and EP0 contexts can be evaluated
+        write_input_context(&ram, 0x7, 0, 0, 64);
+        assert_eq!(
+            slot.handle_evaluate_context(INPUT_CONTEXT).await.unwrap(),
+            CompletionCode::TrbError
+        );
+        write_input_context(&ram, 0x2, 0, 0, 64);
+        ram.write(Request::new(INPUT_CONTEXT, RequestSize::Size4), 0x4);
+        assert_eq!(
+            slot.handle_evaluate_context(INPUT_CONTEXT).await.unwrap(),
+            CompletionCode::TrbError
+        );
+        ram.write(Request::new(INPUT_CONTEXT, RequestSize::Size4), 0);
+        assert_eq!(ep0.get_max_packet_size(), 0);
+
+        // invalid values in contexts that are not evaluated are ignored
+        write_input_context(&ram, 0x2, 0, MAX_INTRS, 512);
+        assert_eq!(
+            slot.handle_evaluate_context(INPUT_CONTEXT).await.unwrap(),
+            CompletionCode::Success
+        );
+        assert_eq!(ep0.get_max_packet_size(), 512);
+    }
+
+    #[tokio::test]
+    async fn evaluate_context_passes_the_max_packet_size_to_the_ep0_worker() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x400]));
+        write_input_context(&ram, 0x2, 0, 0, 64);
+        let mut slot = addressed_slot(&ram);
+        let (ep0_sender, mut ep0_recv) = mpsc::unbounded_channel();
+        slot.endpoint_senders[1] = Some(EndpointSender::new(ep0_sender));
+        let ep0_worker = tokio::spawn(async move {
+            match ep0_recv.recv().await {
+                Some(EndpointMessage::SetMaxPacketSize(max_packet_size, completion)) => {
+                    completion.send(()).unwrap();
+                    max_packet_size
+                }
+                msg => panic!("unexpected endpoint message {msg:?}"),
+            }
+        });
+
+        assert_eq!(
+            slot.handle_evaluate_context(INPUT_CONTEXT).await.unwrap(),
+            CompletionCode::Success
+        );
+        assert_eq!(ep0_worker.await.unwrap(), 64);
+        // the worker owns the EP0 context while it runs
+        let ep0 = EndpointContext::new(OUTPUT_CONTEXT + 32, ram.clone());
+        assert_eq!(ep0.get_max_packet_size(), 0);
+    }
+
+    #[tokio::test]
+    async fn evaluate_context_requires_device_context() {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x400]));
+        let (launch_requester, _) = mock_launch_requester();
+        let slot = Slot::new(1, 0x300, ram.clone(), launch_requester);
+        write_input_context(&ram, 0x3, 0x1234, 0, 64);
+
+        assert_eq!(
+            slot.handle_evaluate_context(INPUT_CONTEXT).await.unwrap(),
+            CompletionCode::ContextStateError
+        );
+    }
 
     pub mod testutils {
         use tokio::sync::mpsc::UnboundedReceiver;
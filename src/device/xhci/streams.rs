@@ -0,0 +1,401 @@
+//! USB3 bulk streams.
+//!
+//! A bulk endpoint with streams has a transfer ring per stream instead of a
+//! single one. The dequeue pointer field of the endpoint context then points
+//! to a Primary Stream Context Array, whose entries hold the dequeue pointers
+//! of the transfer rings, and doorbells select the ring by their Stream ID
+//! (XHCI spec 4.12).
+//!
+//! We only support Linear Stream Arrays, i.e., no Secondary Stream Context
+//! Arrays, as advertised by the NSS flag in HCCPARAMS1. Every stream gets its
+//! own endpoint worker, launched on the first doorbell for the stream, so
+//! transfers on different streams can be pending on the real device at the
+//! same time.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fmt,
+};
+
+use anyhow::anyhow;
+use tokio::{
+    runtime,
+    sync::{mpsc, oneshot},
+};
+use tracing::{trace, warn};
+
+use crate::{
+    device::{
+        bus::{BusDeviceRef, Request, RequestSize},
+        pci::constants::xhci::device_slots::{endpoint_state, stream_context_type},
+        xhci::{
+            endpoint::{EndpointMessage, EndpointSender},
+            slot_manager::EndpointContext,
+            trb::CompletionCode,
+        },
+    },
+    oneshot_anyhow::SendWithAnyhowError,
+};
+
+/// Size of a Stream Context in bytes.
+const STREAM_CONTEXT_SIZE: u64 = 16;
+
+/// A wrapper around DMA accesses to a stream context.
+///
+/// The structure is explained in the XHCI spec 6.2.4.1. Like the endpoint
+/// context of an endpoint without streams, it holds the dequeue pointer and
+/// cycle state of a transfer ring.
+#[derive(Debug, Clone)]
+pub struct StreamContext {
+    /// The address of the stream context in guest memory.
+    address: u64,
+    /// Reference to the guest memory.
+    dma_bus: BusDeviceRef,
+}
+
+impl StreamContext {
+    /// DMA read the dequeue pointer and consumer cycle state of the stream's
+    /// transfer ring.
+    pub fn get_dequeue_pointer_and_cycle_state(&self) -> (u64, bool) {
+        let bytes = self
+            .dma_bus
+            .read(Request::new(self.address, RequestSize::Size8));
+        let dequeue_pointer = bytes & !0xf;
+        let cycle_state = bytes & 0x1 != 0;
+        (dequeue_pointer, cycle_state)
+    }
+
+    /// DMA write the dequeue pointer and consumer cycle state of the stream's
+    /// transfer ring, keeping the stream context type.
+    pub fn set_dequeue_pointer_and_cycle_state(&self, dequeue_pointer: u64, cycle_state: bool) {
+        assert!(
+            dequeue_pointer & 0xf == 0,
+            "dequeue_pointer has to be aligned to 16 bytes"
+        );
+        let stream_context_type = u64::from(self.get_stream_context_type()) << 1;
+        self.dma_bus.write(
+            Request::new(self.address, RequestSize::Size8),
+            dequeue_pointer | stream_context_type | cycle_state as u64,
+        );
+    }
+
+    fn get_stream_context_type(&self) -> u8 {
+        let guest_mem_byte = self
+            .dma_bus
+            .read(Request::new(self.address, RequestSize::Size1));
+        ((guest_mem_byte >> 1) & 0x7) as u8
+    }
+}
+
+/// A wrapper around the Primary Stream Context Array of an endpoint.
+#[derive(Debug, Clone)]
+pub struct StreamContextArray {
+    /// The address of the array in guest memory.
+    address: u64,
+    /// The number of entries, including the reserved entry of Stream ID 0.
+    size: u32,
+    /// Reference to the guest memory.
+    dma_bus: BusDeviceRef,
+}
+
+impl StreamContextArray {
+    /// Locate the Primary Stream Context Array of an endpoint with streams.
+    pub fn new(context: &EndpointContext, dma_bus: BusDeviceRef) -> Self {
+        let (address, _) = context.get_dequeue_pointer_and_cycle_state();
+        let size = 1 << (context.get_max_primary_streams() + 1);
+
+        Self {
+            address,
+            size,
+            dma_bus,
+        }
+    }
+
+    /// The stream context of a stream.
+    ///
+    /// Returns `None` if the Stream ID is invalid: Stream ID 0 is reserved,
+    /// the ID might lie outside the array, or the stream context might not
+    /// describe a transfer ring.
+    pub fn stream_context(&self, stream_id: u16) -> Option<StreamContext> {
+        if stream_id == 0 || u32::from(stream_id) >= self.size {
+            return None;
+        }
+
+        let stream_context = StreamContext {
+            address: self
+                .address
+                .wrapping_add(u64::from(stream_id) * STREAM_CONTEXT_SIZE),
+            dma_bus: self.dma_bus.clone(),
+        };
+        (stream_context.get_stream_context_type() == stream_context_type::PRIMARY_TRANSFER_RING)
+            .then_some(stream_context)
+    }
+}
+
+/// Routes the messages for an endpoint with streams to per-stream workers.
+pub struct StreamEndpoint<F> {
+    context: EndpointContext,
+    stream_contexts: StreamContextArray,
+    streams: BTreeMap<u16, EndpointSender>,
+    /// Launches the endpoint worker for a stream.
+    launch_stream: F,
+    recv: mpsc::UnboundedReceiver<EndpointMessage>,
+}
+
+impl<F> fmt::Debug for StreamEndpoint<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamEndpoint")
+            .field("context", &self.context)
+            .field("stream_contexts", &self.stream_contexts)
+            .field("streams", &self.streams)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F> StreamEndpoint<F>
+where
+    F: FnMut(u16, StreamContext) -> EndpointSender + Send + 'static,
+{
+    pub fn launch(
+        async_runtime: &runtime::Handle,
+        context: EndpointContext,
+        stream_contexts: StreamContextArray,
+        launch_stream: F,
+    ) -> EndpointSender {
+        let (sender, recv) = mpsc::unbounded_channel();
+
+        context.set_state(endpoint_state::RUNNING);
+
+        let endpoint = Self {
+            context,
+            stream_contexts,
+            streams: BTreeMap::new(),
+            launch_stream,
+            recv,
+        };
+        async_runtime.spawn(endpoint.run());
+
+        EndpointSender::new(sender)
+    }
+
+    async fn run(self) {
+        match self.run_loop().await {
+            Ok(_) => {
+                // endpoint terminated properly
+            }
+            Err(err) => warn!("stream endpoint stopped unexpectedly {err}"),
+        }
+    }
+
+    async fn run_loop(mut self) -> anyhow::Result<()> {
+        loop {
+            let msg = self
+                .recv
+                .recv()
+                .await
+                .ok_or_else(|| anyhow!("endpoint channel closed"))?;
+            trace!("stream endpoint received: {msg:?}");
+
+            match msg {
+                EndpointMessage::Doorbell(stream_id) => match self.stream(stream_id) {
+                    Some(stream) => stream.doorbell(stream_id)?,
+                    None => warn!("Doorbell for invalid stream {stream_id}"),
+                },
+                EndpointMessage::Stop(completion) => {
+                    let completion_code = if self.streams.is_empty() {
+                        self.context.set_state(endpoint_state::STOPPED);
+                        CompletionCode::Success
+                    } else {
+                        command_all_streams(&self.streams, EndpointSender::stop).await?
+                    };
+                    completion.send_anyhow(completion_code)?;
+                }
+                EndpointMessage::Reset(completion) => {
+                    let completion_code = if self.streams.is_empty() {
+                        CompletionCode::ContextStateError
+                    } else {
+                        command_all_streams(&self.streams, EndpointSender::reset).await?
+                    };
+                    completion.send_anyhow(completion_code)?;
+                }
+                EndpointMessage::SetTrDequeuePointer(stream_id, ptr, cs, completion) => {
+                    if let Some(stream) = self.streams.get(&stream_id) {
+                        stream.set_tr_dequeue_pointer(stream_id, ptr, cs, completion)?;
+                    } else if let Some(stream_context) =
+                        self.stream_contexts.stream_context(stream_id)
+                    {
+                        // no worker owns the transfer ring yet
+                        stream_context.set_dequeue_pointer_and_cycle_state(ptr, cs);
+                        completion.send_anyhow(CompletionCode::Success)?;
+                    } else {
+                        completion.send_anyhow(CompletionCode::InvalidStreamIdError)?;
+                    }
+                }
+                EndpointMessage::SetMaxPacketSize(max_packet_size, completion) => {
+                    // only sent to control endpoints, which have no streams
+                    self.context.set_max_packet_size(max_packet_size);
+                    completion.send_anyhow(())?;
+                }
+                EndpointMessage::Terminate(sender) => {
+                    for stream in self.streams.values() {
+                        stream.terminate().await?;
+                    }
+                    self.context.set_state(endpoint_state::DISABLED);
+                    sender.send_anyhow(())?;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The worker of a stream, launched on first use.
+    ///
+    /// Returns `None` for invalid Stream IDs.
+    fn stream(&mut self, stream_id: u16) -> Option<&EndpointSender> {
+        match self.streams.entry(stream_id) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let stream_context = self.stream_contexts.stream_context(stream_id)?;
+                Some(entry.insert((self.launch_stream)(stream_id, stream_context)))
+            }
+        }
+    }
+}
+
+/// Send a command to the workers of all streams.
+///
+/// The streams of an endpoint share a single endpoint state, but only
+/// the stream that caused a halt actually needs a reset. The command
+/// thus succeeds if it succeeds for any stream.
+async fn command_all_streams(
+    streams: &BTreeMap<u16, EndpointSender>,
+    command: fn(&EndpointSender, oneshot::Sender<CompletionCode>) -> anyhow::Result<()>,
+) -> anyhow::Result<CompletionCode> {
+    let mut completion_codes = Vec::new();
+    for stream in streams.values() {
+        let (send, recv) = oneshot::channel();
+        command(stream, send)?;
+        completion_codes.push(recv.await?);
+    }
+
+    Ok(if completion_codes.contains(&CompletionCode::Success) {
+        CompletionCode::Success
+    } else {
+        completion_codes
+            .first()
+            .copied()
+            .unwrap_or(CompletionCode::Success)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::runtime::Handle;
+
+    use crate::device::bus::testutils::TestBusDevice;
+
+    use super::*;
+
+    const STREAM_ARRAY: u64 = 0x100;
+
+    /// Guest memory with an endpoint context at 0 whose Primary Stream
+    /// Context Array holds 4 entries: streams 1 and 3 are transfer rings,
+    /// stream 2 has an invalid stream context type.
+    fn init_guest_memory() -> Arc<TestBusDevice> {
+        let ram = Arc::new(TestBusDevice::new(&[0; 0x200]));
+        // MaxPStreams = 1, LSA = 1
+        ram.write_bulk(1, &[0x84]);
+        ram.write_bulk(8, &STREAM_ARRAY.to_le_bytes());
+        ram.write_bulk(STREAM_ARRAY + 16, &0x1003u64.to_le_bytes());
+        ram.write_bulk(STREAM_ARRAY + 32, &0x1100u64.to_le_bytes());
+        ram.write_bulk(STREAM_ARRAY + 48, &0x1202u64.to_le_bytes());
+        ram
+    }
+
+    #[test]
+    fn stream_context_array_rejects_invalid_streams() {
+        let ram = init_guest_memory();
+        let context = EndpointContext::new(0, ram.clone());
+        assert_eq!(context.get_max_primary_streams(), 1);
+        assert!(context.has_linear_stream_array());
+
+        let stream_contexts = StreamContextArray::new(&context, ram.clone());
+        assert!(stream_contexts.stream_context(0).is_none());
+        assert!(stream_contexts.stream_context(2).is_none());
+        assert!(stream_contexts.stream_context(4).is_none());
+
+        let stream_context = stream_contexts.stream_context(1).unwrap();
+        assert_eq!(
+            stream_context.get_dequeue_pointer_and_cycle_state(),
+            (0x1000, true)
+        );
+        stream_context.set_dequeue_pointer_and_cycle_state(0x1040, false);
+        let mut bytes = [0; 8];
+        ram.read_bulk(STREAM_ARRAY + 16, &mut bytes);
+        // the stream context type survives the update
+        assert_eq!(u64::from_le_bytes(bytes), 0x1042);
+
+        let stream_context = stream_contexts.stream_context(3).unwrap();
+        assert_eq!(
+            stream_context.get_dequeue_pointer_and_cycle_state(),
+            (0x1200, false)
+        );
+    }
+
+    #[tokio::test]
+    async fn doorbells_are_routed_by_stream_id() {
+        let ram = init_guest_memory();
+        let context = EndpointContext::new(0, ram.clone());
+        let stream_contexts = StreamContextArray::new(&context, ram.clone());
+
+        let (launched_send, mut launched_recv) = mpsc::unbounded_channel();
+        let endpoint = StreamEndpoint::launch(
+            &Handle::current(),
+            context,
+            stream_contexts,
+            move |stream_id, _| {
+                let (send, recv) = mpsc::unbounded_channel();
+                launched_send.send((stream_id, recv)).unwrap();
+                EndpointSender::new(send)
+            },
+        );
+
+        endpoint.doorbell(3).unwrap();
+        let (stream_id, mut stream_recv) = launched_recv.recv().await.unwrap();
+        assert_eq!(stream_id, 3);
+        assert!(matches!(
+            stream_recv.recv().await,
+            Some(EndpointMessage::Doorbell(3))
+        ));
+
+        // invalid streams launch no worker, the running one gets the doorbell
+        endpoint.doorbell(0).unwrap();
+        endpoint.doorbell(2).unwrap();
+        endpoint.doorbell(3).unwrap();
+        assert!(matches!(
+            stream_recv.recv().await,
+            Some(EndpointMessage::Doorbell(3))
+        ));
+        assert!(launched_recv.is_empty());
+
+        // a stream without worker gets its dequeue pointer directly
+        let (send, recv) = oneshot::channel();
+        endpoint
+            .set_tr_dequeue_pointer(1, 0x1080, false, send)
+            .unwrap();
+        assert_eq!(recv.await.unwrap(), CompletionCode::Success);
+        let mut bytes = [0; 8];
+        ram.read_bulk(STREAM_ARRAY + 16, &mut bytes);
+        assert_eq!(u64::from_le_bytes(bytes), 0x1082);
+
+        let (send, recv) = oneshot::channel();
+        endpoint
+            .set_tr_dequeue_pointer(4, 0x1080, false, send)
+            .unwrap();
+        assert_eq!(recv.await.unwrap(), CompletionCode::InvalidStreamIdError);
+    }
+}
@@ -33,8 +33,9 @@ pub enum EventTrb {
     //BandwidthRequest,
     //Doorbell,
     //HostController,
-    //DeviceNotification,
-    //MfIndexWrap,
+    DeviceNotification(DeviceNotificationEventTrbData),
+    /// The microframe index wrapped around (XHCI spec Section 6.4.2.8).
+    MfIndexWrap,
 }
 
 impl EventTrb {
@@ -53,6 +54,8 @@ impl EventTrb {
             Self::Transfer(data) => data.to_bytes(),
             Self::CommandCompletion(data) => data.to_bytes(),
             Self::PortStatusChange(data) => data.to_bytes(),
+            Self::DeviceNotification(data) => data.to_bytes(),
+            Self::MfIndexWrap => mfindex_wrap_event_bytes(),
         };
         // set cycle bit
         trb_data[12] = (trb_data[12] & !0x1) | cycle_bit as u8;
@@ -168,6 +171,65 @@ impl PortStatusChangeEventTrbData {
     }
 }
 
+/// Stores the relevant data for a Device Notification Event.
+///
+/// Do not use this struct directly, use EventTrb::new_device_notification_event_trb
+/// instead.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DeviceNotificationEventTrbData {
+    notification_type: u8,
+    notification_data: u64,
+    slot_id: u8,
+}
+
+impl EventTrb {
+    /// Create a new Device Notification Event TRB.
+    ///
+    /// The XHCI spec describes this structure in Section 6.4.2.7.
+    ///
+    /// # Parameters
+    ///
+    /// - `notification_type`: The type of the Device Notification that the
+    ///   device sent.
+    /// - `notification_data`: The 56 bit type-specific data of the Device
+    ///   Notification.
+    /// - `slot_id`: The slot of the device that sent the notification.
+    pub const fn new_device_notification_event_trb(
+        notification_type: u8,
+        notification_data: u64,
+        slot_id: u8,
+    ) -> Self {
+        Self::DeviceNotification(DeviceNotificationEventTrbData {
+            notification_type,
+            notification_data,
+            slot_id,
+        })
+    }
+}
+
+impl DeviceNotificationEventTrbData {
+    fn to_bytes(&self) -> RawTrbBuffer {
+        let mut trb = zeroed_trb_buffer();
+
+        trb[0] = (self.notification_type & 0xf) << 4;
+        trb[1..8].copy_from_slice(&self.notification_data.to_le_bytes()[0..7]);
+        trb[11] = CompletionCode::Success as u8;
+        trb[13] = DEVICE_NOTIFICATION_EVENT << 2;
+        trb[15] = self.slot_id;
+
+        trb
+    }
+}
+
+const fn mfindex_wrap_event_bytes() -> RawTrbBuffer {
+    let mut trb = zeroed_trb_buffer();
+
+    trb[11] = CompletionCode::Success as u8;
+    trb[13] = MFINDEX_WRAP_EVENT << 2;
+
+    trb
+}
+
 /// Stores the relevant data for a Transfer Event.
 #[derive(Debug, PartialEq, Eq)]
 pub struct TransferEventTrbData {
@@ -210,6 +272,37 @@ impl EventTrb {
             slot_id,
         })
     }
+
+    /// Create a new Transfer Event TRB for an Event Data TRB.
+    ///
+    /// See XHCI spec Section 4.11.5.2. Instead of a TRB pointer and residual
+    /// length, the event carries the Event Data of the TRB and the Event
+    /// Data Transfer Length Accumulator (EDTLA).
+    ///
+    /// # Parameters
+    ///
+    /// - `event_data`: The Event Data field of the Event Data TRB.
+    /// - `edtla`: Number of bytes transferred in the TD since its start or
+    ///   the previous Event Data TRB.
+    /// - `completion_code`: Encodes the completion status of the TD so far.
+    /// - `endpoint_id`: On which endpoint the transfer happened.
+    /// - `slot_id`: On which slot the transfer happened.
+    pub const fn new_event_data_transfer_event_trb(
+        event_data: u64,
+        edtla: u32,
+        completion_code: CompletionCode,
+        endpoint_id: u8,
+        slot_id: u8,
+    ) -> Self {
+        Self::new_transfer_event_trb(
+            event_data,
+            edtla,
+            completion_code,
+            true,
+            endpoint_id,
+            slot_id,
+        )
+    }
 }
 
 impl TransferEventTrbData {
@@ -367,7 +460,11 @@ pub enum CommandTrbVariant {
     StopEndpoint(StopEndpointCommandTrbData),
     SetTrDequeuePointer(SetTrDequeuePointerCommandTrbData),
     ResetDevice(ResetDeviceCommandTrbData),
-    ForceHeader,
+    NegotiateBandwidth(NegotiateBandwidthCommandTrbData),
+    SetLatencyToleranceValue(SetLatencyToleranceValueCommandTrbData),
+    GetPortBandwidth(GetPortBandwidthCommandTrbData),
+    ForceHeader(ForceHeaderCommandTrbData),
+    GetExtendedProperty(GetExtendedPropertyCommandTrbData),
     Unrecognized(RawTrbBuffer, TrbParseError),
 }
 
@@ -405,28 +502,13 @@ impl CommandTrbVariant {
                 bytes,
                 TrbParseError::UnsupportedOptionalCommand(18, "Force Event Command".to_string()),
             ),
-            trb_types::NEGOTIATE_BANDWIDTH_COMMAND => Self::Unrecognized(
-                bytes,
-                TrbParseError::UnsupportedOptionalCommand(
-                    19,
-                    "Negotiate Bandwidth Command".to_string(),
-                ),
-            ),
-            trb_types::SET_LATENCY_TOLERANCE_VALUE_COMMAND => Self::Unrecognized(
-                bytes,
-                TrbParseError::UnsupportedOptionalCommand(
-                    20,
-                    "Set Latency Tolerance Value Command".to_string(),
-                ),
-            ),
-            trb_types::GET_PORT_BANDWIDTH_COMMAND => Self::Unrecognized(
-                bytes,
-                TrbParseError::UnsupportedOptionalCommand(
-                    21,
-                    "Get Port Bandwidth Command".to_string(),
-                ),
-            ),
-            trb_types::FORCE_HEADER_COMMAND => Self::ForceHeader,
+            trb_types::NEGOTIATE_BANDWIDTH_COMMAND => parse(Self::NegotiateBandwidth, bytes),
+            trb_types::SET_LATENCY_TOLERANCE_VALUE_COMMAND => {
+                parse(Self::SetLatencyToleranceValue, bytes)
+            }
+            trb_types::GET_PORT_BANDWIDTH_COMMAND => parse(Self::GetPortBandwidth, bytes),
+            trb_types::FORCE_HEADER_COMMAND => parse(Self::ForceHeader, bytes),
+            trb_types::GET_EXTENDED_PROPERTY_COMMAND => parse(Self::GetExtendedProperty, bytes),
             trb_type => Self::Unrecognized(bytes, TrbParseError::UnknownTrbType(trb_type)),
         }
     }
@@ -747,7 +829,7 @@ pub struct SetTrDequeuePointerCommandTrbData {
 impl TrbData for SetTrDequeuePointerCommandTrbData {
     fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
         let dequeue_cycle_state = trb_bytes[0] & 1 != 0;
-        let stream_context_type = trb_bytes[0] & 0xe;
+        let stream_context_type = (trb_bytes[0] & 0xe) >> 1;
 
         // SAFETY: range matches array length
         let mut dequeue_pointer_bytes: [u8; 8] = trb_bytes[0..8].try_into().unwrap();
@@ -755,7 +837,7 @@ impl TrbData for SetTrDequeuePointerCommandTrbData {
         let dequeue_pointer = u64::from_le_bytes(dequeue_pointer_bytes);
 
         // SAFETY: range matches array length
-        let stream_id_bytes: [u8; 2] = trb_bytes[11..13].try_into().unwrap();
+        let stream_id_bytes: [u8; 2] = trb_bytes[10..12].try_into().unwrap();
         let stream_id = u16::from_le_bytes(stream_id_bytes);
 
         let endpoint_id = trb_bytes[14] & 0x1f;
@@ -804,6 +886,218 @@ impl TrbData for ResetDeviceCommandTrbData {
     }
 }
 
+/// Negotiate Bandwidth Command TRB data structure.
+///
+/// See XHCI specification Section 6.4.3.12 for detailed field descriptions.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct NegotiateBandwidthCommandTrbData {
+    /// The slot ID associated with this command.
+    pub slot_id: u8,
+}
+
+impl TrbData for NegotiateBandwidthCommandTrbData {
+    /// Parse data of a Negotiate Bandwidth Command TRB.
+    ///
+    /// Only `CommandTrb::try_from` should call this function.
+    ///
+    /// # Limitations
+    ///
+    /// The function currently does not check if the slice respects all RsvdZ
+    /// fields.
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes[13] >> 2;
+        assert_eq!(
+            trb_types::NEGOTIATE_BANDWIDTH_COMMAND,
+            trb_type,
+            "NegotiateBandwidthCommandTrbData::parse called on TRB data with incorrect TRB type ({trb_type:#x})"
+        );
+
+        let slot_id = trb_bytes[15];
+
+        Ok(Self { slot_id })
+    }
+}
+
+/// Set Latency Tolerance Value Command TRB data structure.
+///
+/// See XHCI specification Section 6.4.3.13 for detailed field descriptions.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SetLatencyToleranceValueCommandTrbData {
+    /// The Best Effort Latency Tolerance (BELT) value in the LTV format of
+    /// the USB 3 specification.
+    pub best_effort_latency_tolerance: u16,
+}
+
+impl TrbData for SetLatencyToleranceValueCommandTrbData {
+    /// Parse data of a Set Latency Tolerance Value Command TRB.
+    ///
+    /// Only `CommandTrb::try_from` should call this function.
+    ///
+    /// # Limitations
+    ///
+    /// The function currently does not check if the slice respects all RsvdZ
+    /// fields.
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes[13] >> 2;
+        assert_eq!(
+            trb_types::SET_LATENCY_TOLERANCE_VALUE_COMMAND,
+            trb_type,
+            "SetLatencyToleranceValueCommandTrbData::parse called on TRB data with incorrect TRB type ({trb_type:#x})"
+        );
+
+        let best_effort_latency_tolerance =
+            u16::from_le_bytes([trb_bytes[14], trb_bytes[15]]) & 0xfff;
+
+        Ok(Self {
+            best_effort_latency_tolerance,
+        })
+    }
+}
+
+/// Get Port Bandwidth Command TRB data structure.
+///
+/// See XHCI specification Section 6.4.3.14 for detailed field descriptions.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct GetPortBandwidthCommandTrbData {
+    /// Guest address where the Port Bandwidth Context should be written to.
+    pub port_bandwidth_context_pointer: u64,
+    /// The speed for which the available bandwidth is requested.
+    pub dev_speed: u8,
+    /// The slot ID of the hub whose ports are evaluated, or 0 for the root hub.
+    pub hub_slot_id: u8,
+}
+
+impl TrbData for GetPortBandwidthCommandTrbData {
+    /// Parse data of a Get Port Bandwidth Command TRB.
+    ///
+    /// Only `CommandTrb::try_from` should call this function.
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes[13] >> 2;
+        assert_eq!(
+            trb_types::GET_PORT_BANDWIDTH_COMMAND,
+            trb_type,
+            "GetPortBandwidthCommandTrbData::parse called on TRB data with incorrect TRB type ({trb_type:#x})"
+        );
+
+        // SAFETY: range matches array length
+        let pbcp_bytes: [u8; 8] = trb_bytes[0..8].try_into().unwrap();
+        let port_bandwidth_context_pointer = u64::from_le_bytes(pbcp_bytes);
+
+        // the context has to be 16-byte aligned
+        if port_bandwidth_context_pointer & 0xf != 0 {
+            return Err(TrbParseError::RsvdZViolation);
+        }
+
+        let dev_speed = trb_bytes[14] & 0xf;
+        let hub_slot_id = trb_bytes[15];
+
+        Ok(Self {
+            port_bandwidth_context_pointer,
+            dev_speed,
+            hub_slot_id,
+        })
+    }
+}
+
+/// Force Header Command TRB data structure.
+///
+/// See XHCI specification Section 6.4.3.15 for detailed field descriptions.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ForceHeaderCommandTrbData {
+    /// The type of the packet whose header should be sent.
+    pub packet_type: u8,
+    /// The header info fields (dwords 0-2 without the packet type bits).
+    pub header_info: [u32; 3],
+    /// The root hub port the header should be sent on.
+    pub root_hub_port: u8,
+}
+
+impl TrbData for ForceHeaderCommandTrbData {
+    /// Parse data of a Force Header Command TRB.
+    ///
+    /// Only `CommandTrb::try_from` should call this function.
+    ///
+    /// # Limitations
+    ///
+    /// The function currently does not check if the slice respects all RsvdZ
+    /// fields.
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes[13] >> 2;
+        assert_eq!(
+            trb_types::FORCE_HEADER_COMMAND,
+            trb_type,
+            "ForceHeaderCommandTrbData::parse called on TRB data with incorrect TRB type ({trb_type:#x})"
+        );
+
+        let packet_type = trb_bytes[0] & 0x1f;
+        let dword = |offset: usize| {
+            // SAFETY: all offsets below leave 4 bytes in the buffer
+            u32::from_le_bytes(trb_bytes[offset..offset + 4].try_into().unwrap())
+        };
+        let header_info = [dword(0) & !0x1f, dword(4), dword(8)];
+        let root_hub_port = trb_bytes[15];
+
+        Ok(Self {
+            packet_type,
+            header_info,
+            root_hub_port,
+        })
+    }
+}
+
+/// Get Extended Property Command TRB data structure.
+///
+/// See XHCI specification Section 6.4.3.17 for detailed field descriptions.
+#[derive(Debug, PartialEq, Eq)]
+pub struct GetExtendedPropertyCommandTrbData {
+    /// Guest address of the Extended Property Context.
+    pub extended_property_context_pointer: u64,
+    /// The Extended Capability Identifier (ECI) of the requested property.
+    pub extended_capability_identifier: u16,
+    /// The command subtype that is specific to the ECI.
+    pub command_subtype: u8,
+    /// The endpoint ID associated with this command, if any.
+    pub endpoint_id: u8,
+    /// The slot ID associated with this command, if any.
+    pub slot_id: u8,
+}
+
+impl TrbData for GetExtendedPropertyCommandTrbData {
+    /// Parse data of a Get Extended Property Command TRB.
+    ///
+    /// Only `CommandTrb::try_from` should call this function.
+    ///
+    /// # Limitations
+    ///
+    /// The function currently does not check if the slice respects all RsvdZ
+    /// fields.
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes[13] >> 2;
+        assert_eq!(
+            trb_types::GET_EXTENDED_PROPERTY_COMMAND,
+            trb_type,
+            "GetExtendedPropertyCommandTrbData::parse called on TRB data with incorrect TRB type ({trb_type:#x})"
+        );
+
+        // SAFETY: range matches array length
+        let epcp_bytes: [u8; 8] = trb_bytes[0..8].try_into().unwrap();
+        let extended_property_context_pointer = u64::from_le_bytes(epcp_bytes) & !0xf;
+
+        let extended_capability_identifier = u16::from_le_bytes([trb_bytes[8], trb_bytes[9]]);
+        let command_subtype = trb_bytes[14] & 0x7;
+        let endpoint_id = (trb_bytes[14] >> 3) & 0x1f;
+        let slot_id = trb_bytes[15];
+
+        Ok(Self {
+            extended_property_context_pointer,
+            extended_capability_identifier,
+            command_subtype,
+            endpoint_id,
+            slot_id,
+        })
+    }
+}
+
 /// Represents a TRB that the driver can place on a transfer ring.
 #[derive(Debug, PartialEq, Eq)]
 pub struct TransferTrb {
@@ -822,7 +1116,7 @@ pub enum TransferTrbVariant {
     SetupStage(SetupStageTrbData),
     DataStage(DataStageTrbData),
     StatusStage(StatusStageTrbData),
-    Isoch,
+    Isoch(IsochTrbData),
     EventData(EventDataTrbData),
     NoOp,
     #[allow(unused)]
@@ -849,7 +1143,7 @@ impl TransferTrbVariant {
             trb_types::SETUP_STAGE => parse(Self::SetupStage, bytes),
             trb_types::DATA_STAGE => parse(Self::DataStage, bytes),
             trb_types::STATUS_STAGE => parse(Self::StatusStage, bytes),
-            trb_types::ISOCH => Self::Isoch,
+            trb_types::ISOCH => parse(Self::Isoch, bytes),
             trb_types::EVENT_DATA => parse(Self::EventData, bytes),
             trb_types::NO_OP => Self::NoOp,
             trb_type => Self::Unrecognized(bytes, TrbParseError::UnknownTrbType(trb_type)),
@@ -1051,10 +1345,82 @@ impl TrbData for StatusStageTrbData {
     }
 }
 
+/// Isoch TRB data structure.
+///
+/// The Isoch TRB is the first TRB of an isochronous TD; Normal TRBs may
+/// follow it in the same TD.
+///
+/// See XHCI specification Section 6.4.1.3 for detailed field descriptions.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IsochTrbData {
+    pub data_pointer: u64,
+    pub transfer_length: u32,
+    pub chain: bool,
+    pub interrupt_on_completion: bool,
+    pub interrupt_on_short: bool,
+    pub immediate_data: bool,
+    /// Number of bursts (minus one) needed to transfer the TD.
+    pub transfer_burst_count: u8,
+    /// Number of packets (minus one) in the last burst of the TD.
+    pub transfer_last_burst_packet_count: u8,
+    /// The 1 ms frame in which the TD shall be executed.
+    pub frame_id: u16,
+    /// Start Isoch ASAP; the Frame ID is ignored if set.
+    pub start_isoch_asap: bool,
+}
+
+impl TrbData for IsochTrbData {
+    /// Parse data of an Isoch TRB.
+    ///
+    /// Only `TransferTrb::try_from` should call this function.
+    ///
+    /// # Limitations
+    ///
+    /// The function currently does not check if the slice respects RsvdZ
+    /// fields.
+    fn parse(trb_bytes: RawTrbBuffer) -> Result<Self, TrbParseError> {
+        let trb_type = trb_bytes[13] >> 2;
+        assert_eq!(
+            trb_types::ISOCH,
+            trb_type,
+            "IsochTrbData::parse called on TRB data with incorrect TRB type ({trb_type:#x})"
+        );
+
+        // SAFETY: range matches array length
+        let dp_bytes: [u8; 8] = trb_bytes[0..8].try_into().unwrap();
+        let data_pointer = u64::from_le_bytes(dp_bytes);
+
+        let tl_bytes: [u8; 4] = [trb_bytes[8], trb_bytes[9], trb_bytes[10] & 0x01, 0];
+        let transfer_length = u32::from_le_bytes(tl_bytes);
+
+        let chain = trb_bytes[12] & 0x10 != 0;
+        let interrupt_on_short = trb_bytes[12] & 0x04 != 0;
+        let interrupt_on_completion = trb_bytes[12] & 0x20 != 0;
+        let immediate_data = trb_bytes[12] & 0x40 != 0;
+        let transfer_burst_count = (trb_bytes[12] >> 7) | ((trb_bytes[13] & 0x1) << 1);
+        let transfer_last_burst_packet_count = trb_bytes[14] & 0xf;
+        let frame_id = (u16::from(trb_bytes[14]) >> 4) | ((u16::from(trb_bytes[15]) & 0x7f) << 4);
+        let start_isoch_asap = trb_bytes[15] & 0x80 != 0;
+
+        Ok(Self {
+            data_pointer,
+            transfer_length,
+            chain,
+            interrupt_on_completion,
+            interrupt_on_short,
+            immediate_data,
+            transfer_burst_count,
+            transfer_last_burst_packet_count,
+            frame_id,
+            start_isoch_asap,
+        })
+    }
+}
+
 /// Event Data TRB data structure.
 ///
 /// See XHCI specification Section 6.4.4.2 for detailed field descriptions.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct EventDataTrbData {
     pub event_data: u64,
     pub chain: bool,
@@ -1433,6 +1799,61 @@ mod tests {
         );
     }
 
+    #[test]
+    fn mfindex_wrap_event_trb() {
+        assert_eq!(
+            [
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x9c,
+                0x00, 0x00,
+            ],
+            EventTrb::MfIndexWrap.to_bytes(true),
+        );
+    }
+
+    #[test]
+    fn parse_get_port_bandwidth_command_trb() {
+        let trb_bytes = [
+            0x40, 0x33, 0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x54,
+            0x04, 0x05,
+        ];
+        let expected = CommandTrbVariant::GetPortBandwidth(GetPortBandwidthCommandTrbData {
+            port_bandwidth_context_pointer: 0x11223340,
+            dev_speed: 4,
+            hub_slot_id: 5,
+        });
+        assert_eq!(CommandTrbVariant::parse(trb_bytes), expected);
+    }
+
+    #[test]
+    fn parse_force_header_command_trb() {
+        let trb_bytes = [
+            0x24, 0x00, 0x00, 0x80, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x00, 0x58,
+            0x00, 0x02,
+        ];
+        let expected = CommandTrbVariant::ForceHeader(ForceHeaderCommandTrbData {
+            packet_type: 0x4,
+            header_info: [0x80000020, 0x44332211, 0x88776655],
+            root_hub_port: 2,
+        });
+        assert_eq!(CommandTrbVariant::parse(trb_bytes), expected);
+    }
+
+    #[test]
+    fn parse_get_extended_property_command_trb() {
+        let trb_bytes = [
+            0x50, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x60,
+            0x1a, 0x07,
+        ];
+        let expected = CommandTrbVariant::GetExtendedProperty(GetExtendedPropertyCommandTrbData {
+            extended_property_context_pointer: 0x10050,
+            extended_capability_identifier: 0xa,
+            command_subtype: 2,
+            endpoint_id: 3,
+            slot_id: 7,
+        });
+        assert_eq!(CommandTrbVariant::parse(trb_bytes), expected);
+    }
+
     #[test]
     fn test_parse_normal_trb() {
         let trb_bytes = [
@@ -1498,6 +1919,38 @@ mod tests {
         assert_eq!(TransferTrbVariant::parse(trb_bytes), expected);
     }
 
+    #[test]
+    fn test_parse_isoch_trb() {
+        let trb_bytes = [
+            0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, 0x0c, 0x00, 0x00, 0xa4, 0x15,
+            0x32, 0x5a,
+        ];
+        let expected = TransferTrbVariant::Isoch(IsochTrbData {
+            data_pointer: 0x1122334455667788,
+            transfer_length: 0x0c00,
+            chain: false,
+            interrupt_on_completion: true,
+            interrupt_on_short: true,
+            immediate_data: false,
+            transfer_burst_count: 3,
+            transfer_last_burst_packet_count: 2,
+            frame_id: 0x5a3,
+            start_isoch_asap: false,
+        });
+        assert_eq!(TransferTrbVariant::parse(trb_bytes), expected);
+
+        let mut trb_bytes = trb_bytes;
+        trb_bytes[15] |= 0x80;
+        assert!(matches!(
+            TransferTrbVariant::parse(trb_bytes),
+            TransferTrbVariant::Isoch(IsochTrbData {
+                start_isoch_asap: true,
+                frame_id: 0x5a3,
+                ..
+            })
+        ));
+    }
+
     #[test]
     fn test_parse_event_data_trb() {
         let trb_bytes = [
@@ -1511,4 +1964,27 @@ mod tests {
         });
         assert_eq!(TransferTrbVariant::parse(trb_bytes), expected);
     }
+
+    #[test]
+    fn test_parse_set_tr_dequeue_pointer_trb() {
+        let trb_bytes = [
+            0x83, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, 0x00, 0x34, 0x12, 0x00, 0x40,
+            0x03, 0x02,
+        ];
+        let CommandTrbVariant::SetTrDequeuePointer(data) = CommandTrbVariant::parse(trb_bytes)
+        else {
+            panic!("expected a Set TR Dequeue Pointer Command TRB");
+        };
+        assert_eq!(
+            data,
+            SetTrDequeuePointerCommandTrbData {
+                dequeue_cycle_state: true,
+                stream_context_type: 1,
+                dequeue_pointer: 0x1122334455667780,
+                stream_id: 0x1234,
+                endpoint_id: 3,
+                slot_id: 2,
+            }
+        );
+    }
 }
@@ -0,0 +1,651 @@
+//! usbfs requests that nusb does not offer. See `linux/usbdevice_fs.h`.
+//!
+//! nusb reaps every URB completed on the usbfs file it uses and takes it for
+//! one of its own transfers, so other URBs cannot be submitted on that file.
+//! Isochronous transfers and transfers on bulk streams use another usbfs
+//! file of the device instead, the [`UrbFile`], which claims the interfaces
+//! of such endpoints in place of nusb.
+
+use std::{
+    collections::HashMap,
+    ffi::{c_char, c_int, c_uint, c_void},
+    fmt::Debug,
+    fs::OpenOptions,
+    io, mem,
+    os::fd::{AsRawFd, OwnedFd, RawFd},
+    ptr,
+    sync::{Arc, Mutex},
+};
+
+use nusb::transfer::TransferError;
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    runtime,
+    sync::oneshot,
+    task::AbortHandle,
+};
+use tracing::{debug, warn};
+
+/// Request numbers of usbfs. Their size field comes from the structs below,
+/// which match the kernel's on every architecture.
+pub mod usbdevfs {
+    use std::ffi::{c_uint, c_void};
+
+    use super::{DisconnectClaim, SetInterface, Streams, Urb, UsbfsIoctl};
+
+    const TYPE: u32 = b'U' as u32;
+
+    pub const SETINTERFACE: libc::Ioctl = libc::_IOR::<SetInterface>(TYPE, 4);
+    pub const SUBMITURB: libc::Ioctl = libc::_IOR::<Urb>(TYPE, 10);
+    pub const DISCARDURB: libc::Ioctl = libc::_IO(TYPE, 11);
+    pub const REAPURB: libc::Ioctl = libc::_IOW::<*mut c_void>(TYPE, 12);
+    pub const REAPURBNDELAY: libc::Ioctl = libc::_IOW::<*mut c_void>(TYPE, 13);
+    pub const RELEASEINTERFACE: libc::Ioctl = libc::_IOR::<c_uint>(TYPE, 16);
+    pub const IOCTL: libc::Ioctl = libc::_IOWR::<UsbfsIoctl>(TYPE, 18);
+    pub const CLEAR_HALT: libc::Ioctl = libc::_IOR::<c_uint>(TYPE, 21);
+    pub const CONNECT: libc::Ioctl = libc::_IO(TYPE, 23);
+    pub const DISCONNECT_CLAIM: libc::Ioctl = libc::_IOR::<DisconnectClaim>(TYPE, 27);
+    pub const ALLOC_STREAMS: libc::Ioctl = libc::_IOR::<Streams>(TYPE, 28);
+    pub const FREE_STREAMS: libc::Ioctl = libc::_IOR::<Streams>(TYPE, 29);
+    pub const FORBID_SUSPEND: libc::Ioctl = libc::_IO(TYPE, 33);
+    pub const ALLOW_SUSPEND: libc::Ioctl = libc::_IO(TYPE, 34);
+    pub const WAIT_FOR_RESUME: libc::Ioctl = libc::_IO(TYPE, 35);
+}
+
+const URB_TYPE_ISO: u8 = 0;
+const URB_TYPE_INTERRUPT: u8 = 1;
+const URB_TYPE_BULK: u8 = 3;
+
+const URB_ISO_ASAP: c_uint = 0x02;
+
+/// Issue a usbfs request, again if a signal interrupted it.
+///
+/// # Safety
+///
+/// `arg` has to be what the kernel expects for `request`.
+unsafe fn ioctl(fd: RawFd, request: libc::Ioctl, arg: *mut c_void) -> io::Result<c_int> {
+    loop {
+        // SAFETY: the caller passes a valid argument for the request.
+        let ret = unsafe { libc::ioctl(fd, request, arg) };
+        if ret >= 0 {
+            return Ok(ret);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Issue a usbfs request without argument.
+pub fn usbfs_ioctl(usbfs: &OwnedFd, request: libc::Ioctl) -> io::Result<()> {
+    // SAFETY: the requests take no argument and the file descriptor is valid
+    // for the lifetime of `usbfs`.
+    unsafe { ioctl(usbfs.as_raw_fd(), request, ptr::null_mut()) }.map(|_| ())
+}
+
+/// Issue a usbfs request whose argument is an unsigned int, e.g., an
+/// interface number or an endpoint address.
+fn usbfs_ioctl_uint(fd: RawFd, request: libc::Ioctl, value: u8) -> io::Result<()> {
+    let mut value = c_uint::from(value);
+    // SAFETY: the requests read an unsigned int.
+    unsafe { ioctl(fd, request, (&raw mut value).cast()) }.map(|_| ())
+}
+
+/// `struct usbdevfs_iso_packet_desc`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct IsoPacketDesc {
+    length: c_uint,
+    actual_length: c_uint,
+    status: c_uint,
+}
+
+/// `struct usbdevfs_urb`, without its isochronous packet descriptors.
+#[repr(C)]
+#[derive(Debug)]
+struct Urb {
+    urb_type: u8,
+    endpoint: u8,
+    status: c_int,
+    flags: c_uint,
+    buffer: *mut c_void,
+    buffer_length: c_int,
+    actual_length: c_int,
+    start_frame: c_int,
+    number_of_packets_or_stream_id: c_int,
+    error_count: c_int,
+    signr: c_uint,
+    usercontext: *mut c_void,
+}
+
+/// The type of the transfers on an endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrbKind {
+    /// Every transfer carries the data of one service interval, which is a
+    /// single isochronous packet.
+    Isochronous,
+    Interrupt,
+    /// Stream 0 means that the endpoint does not use streams.
+    Bulk {
+        stream_id: u16,
+    },
+}
+
+// SAFETY: the pointers of a URB refer to its transfer and to the buffer of
+// that transfer, which move along with the URB.
+unsafe impl Send for Urb {}
+
+/// A transfer and its buffer, which the kernel owns while the transfer is
+/// submitted.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Transfer {
+    // first, so that the URB and the transfer have the same address
+    urb: Urb,
+    // the packet descriptors follow the URB, like in `struct usbdevfs_urb`
+    iso_frame_desc: [IsoPacketDesc; 1],
+    buffer: Vec<u8>,
+}
+
+impl Transfer {
+    /// Create a transfer on the endpoint. IN transfers receive up to the
+    /// length of `buffer`, OUT transfers send `buffer`.
+    pub fn new(kind: UrbKind, endpoint: u8, mut buffer: Vec<u8>) -> Self {
+        let length = c_int::try_from(buffer.len()).unwrap_or(c_int::MAX);
+        let mut urb = Urb {
+            urb_type: URB_TYPE_BULK,
+            endpoint,
+            status: 0,
+            flags: 0,
+            buffer: buffer.as_mut_ptr().cast(),
+            buffer_length: length,
+            actual_length: 0,
+            start_frame: 0,
+            number_of_packets_or_stream_id: 0,
+            error_count: 0,
+            signr: 0,
+            usercontext: ptr::null_mut(),
+        };
+        let mut iso_frame_desc = [IsoPacketDesc::default()];
+        match kind {
+            UrbKind::Isochronous => {
+                urb.urb_type = URB_TYPE_ISO;
+                urb.flags = URB_ISO_ASAP;
+                urb.number_of_packets_or_stream_id = 1;
+                iso_frame_desc[0].length = length as c_uint;
+            }
+            UrbKind::Interrupt => urb.urb_type = URB_TYPE_INTERRUPT,
+            UrbKind::Bulk { stream_id } => urb.number_of_packets_or_stream_id = stream_id.into(),
+        }
+
+        Self {
+            urb,
+            iso_frame_desc,
+            buffer,
+        }
+    }
+
+    /// The result of the completed transfer and the data received.
+    pub fn into_completion(self) -> (Result<(), TransferError>, Vec<u8>) {
+        let Self {
+            urb,
+            iso_frame_desc,
+            mut buffer,
+        } = self;
+        // The URB of an isochronous transfer succeeds even if the packet
+        // failed.
+        let (status, actual_length) = match urb.urb_type {
+            URB_TYPE_ISO => (
+                iso_frame_desc[0].status as c_int,
+                iso_frame_desc[0].actual_length as usize,
+            ),
+            _ => (urb.status, urb.actual_length as usize),
+        };
+        if urb.endpoint & 0x80 != 0 {
+            buffer.truncate(actual_length);
+        }
+        let status = match status {
+            0 => Ok(()),
+            status => Err(errno_to_transfer_error(-status)),
+        };
+
+        (status, buffer)
+    }
+}
+
+const fn errno_to_transfer_error(errno: c_int) -> TransferError {
+    match errno {
+        libc::ENODEV | libc::ESHUTDOWN => TransferError::Disconnected,
+        libc::EPIPE => TransferError::Stall,
+        libc::ENOENT | libc::ECONNRESET | libc::ETIMEDOUT => TransferError::Cancelled,
+        libc::EPROTO | libc::EILSEQ | libc::EOVERFLOW | libc::ECOMM | libc::ETIME | libc::EXDEV => {
+            TransferError::Fault
+        }
+        libc::EINVAL => TransferError::InvalidArgument,
+        errno => TransferError::Unknown(errno as u32),
+    }
+}
+
+/// Identifies a submitted transfer, e.g., to discard it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UrbId(usize);
+
+/// The usbfs file and the transfers submitted on it, shared with the reaper.
+#[derive(Debug)]
+struct Urbs {
+    file: AsyncFd<OwnedFd>,
+    // by the address of the leaked transfer
+    pending: Mutex<HashMap<UrbId, oneshot::Sender<Box<Transfer>>>>,
+    // runs the reaping of the discarded transfers on drop
+    async_runtime: runtime::Handle,
+}
+
+impl Urbs {
+    /// Hand a reaped URB back to its submitter.
+    fn complete(&self, urb: *mut Urb) {
+        let Some(completion) = self.pending.lock().unwrap().remove(&UrbId(urb as usize)) else {
+            warn!("Reaped an unknown URB at {urb:?}");
+            return;
+        };
+        // SAFETY: the URB is the first field of a transfer that was leaked
+        // when it was submitted, and the kernel is done with it once reaped.
+        let transfer = unsafe { Box::from_raw(urb.cast::<Transfer>()) };
+        // Nobody waiting for the transfer is fine.
+        let _ = completion.send(transfer);
+    }
+
+    async fn reap(self: Arc<Self>) {
+        let fd = self.file.as_raw_fd();
+        loop {
+            // usbfs files are writable while completed URBs wait for reaping.
+            let mut guard = match self.file.writable().await {
+                Ok(guard) => guard,
+                Err(err) => {
+                    warn!("Stopped reaping URBs: {err}");
+                    return;
+                }
+            };
+            loop {
+                let mut urb: *mut Urb = ptr::null_mut();
+                // SAFETY: the request stores the address of a completed URB.
+                match unsafe { ioctl(fd, usbdevfs::REAPURBNDELAY, (&raw mut urb).cast()) } {
+                    Ok(_) => self.complete(urb),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        guard.clear_ready();
+                        break;
+                    }
+                    Err(err) => {
+                        debug!("Stopped reaping URBs: {err}");
+                        // The waiting submitters learn that the device is
+                        // gone. Their transfers are leaked, because the
+                        // kernel may not be done with them yet.
+                        self.pending.lock().unwrap().clear();
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Urbs {
+    fn drop(&mut self) {
+        let pending = mem::take(self.pending.get_mut().unwrap());
+        if pending.is_empty() {
+            return;
+        }
+        let fd = self.file.as_raw_fd();
+        for urb in pending.keys() {
+            // SAFETY: the URB is only used to look up the submitted transfer.
+            let _ = unsafe { ioctl(fd, usbdevfs::DISCARDURB, urb.0 as *mut c_void) };
+        }
+        // Reaping the discarded transfers blocks until the device gave them
+        // back, which must not happen on a runtime worker. The duplicate
+        // keeps the file open until then.
+        match self.file.get_ref().try_clone() {
+            Ok(file) => {
+                self.async_runtime
+                    .spawn_blocking(move || reap_discarded(&file, pending));
+            }
+            Err(err) => warn!("Leaking {} discarded URBs: {err}", pending.len()),
+        }
+    }
+}
+
+/// Free the discarded transfers once they are reaped.
+fn reap_discarded(file: &OwnedFd, mut pending: HashMap<UrbId, oneshot::Sender<Box<Transfer>>>) {
+    while !pending.is_empty() {
+        let mut urb: *mut Urb = ptr::null_mut();
+        // SAFETY: the request stores the address of a completed URB.
+        if unsafe { ioctl(file.as_raw_fd(), usbdevfs::REAPURB, (&raw mut urb).cast()) }.is_err() {
+            // The remaining transfers are leaked, see `Urbs::reap`.
+            break;
+        }
+        if pending.remove(&UrbId(urb as usize)).is_some() {
+            // SAFETY: see `Urbs::complete`
+            drop(unsafe { Box::from_raw(urb.cast::<Transfer>()) });
+        }
+    }
+}
+
+/// `struct usbdevfs_setinterface`
+#[repr(C)]
+struct SetInterface {
+    interface: c_uint,
+    altsetting: c_uint,
+}
+
+/// `struct usbdevfs_disconnect_claim`
+#[repr(C)]
+struct DisconnectClaim {
+    interface: c_uint,
+    flags: c_uint,
+    driver: [c_char; 256],
+}
+
+/// `struct usbdevfs_streams`, without its endpoints.
+#[repr(C)]
+struct Streams {
+    num_streams: c_uint,
+    num_eps: c_uint,
+}
+
+/// `struct usbdevfs_streams` with a single endpoint.
+#[repr(C)]
+struct StreamsOfEndpoint {
+    streams: Streams,
+    ep: u8,
+}
+
+/// `struct usbdevfs_ioctl`
+#[repr(C)]
+struct UsbfsIoctl {
+    ifno: c_int,
+    ioctl_code: c_int,
+    data: *mut c_void,
+}
+
+/// Another usbfs file of a device for the transfers nusb cannot do.
+///
+/// The file claims the interfaces with such endpoints, so all transfers of
+/// these interfaces are submitted here.
+#[derive(Debug)]
+pub struct UrbFile {
+    urbs: Arc<Urbs>,
+    reaper: AbortHandle,
+    claimed: Mutex<Vec<u8>>,
+    // interface and address of the endpoints with streams
+    streams: Mutex<Vec<(u8, u8)>>,
+}
+
+impl Drop for UrbFile {
+    fn drop(&mut self) {
+        self.reaper.abort();
+    }
+}
+
+impl UrbFile {
+    /// Use `file` for URBs. It has to be a usbfs file of its own, not a
+    /// duplicate of the file nusb uses.
+    pub fn new(file: OwnedFd, async_runtime: &runtime::Handle) -> io::Result<Self> {
+        let _guard = async_runtime.enter();
+        let urbs = Arc::new(Urbs {
+            file: AsyncFd::with_interest(file, Interest::WRITABLE)?,
+            pending: Mutex::new(HashMap::new()),
+            async_runtime: async_runtime.clone(),
+        });
+        let reaper = async_runtime.spawn(urbs.clone().reap()).abort_handle();
+
+        Ok(Self {
+            urbs,
+            reaper,
+            claimed: Mutex::new(vec![]),
+            streams: Mutex::new(vec![]),
+        })
+    }
+
+    /// Open the device of `usbfs` once more, for clients that do not pass
+    /// a second file.
+    ///
+    /// This opens the device file again instead of duplicating `usbfs`, so
+    /// it needs read and write access to the device file itself.
+    pub fn open(usbfs: &OwnedFd, async_runtime: &runtime::Handle) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/self/fd/{}", usbfs.as_raw_fd()))?;
+
+        Self::new(file.into(), async_runtime)
+    }
+
+    fn fd(&self) -> RawFd {
+        self.urbs.file.as_raw_fd()
+    }
+
+    /// Claim the interface, after detaching its kernel driver.
+    pub fn claim(&self, interface: u8) -> io::Result<()> {
+        let mut claim = DisconnectClaim {
+            interface: interface.into(),
+            flags: 0,
+            driver: [0; 256],
+        };
+        // SAFETY: the request reads a `struct usbdevfs_disconnect_claim`.
+        unsafe {
+            ioctl(
+                self.fd(),
+                usbdevfs::DISCONNECT_CLAIM,
+                (&raw mut claim).cast(),
+            )
+        }?;
+        self.claimed.lock().unwrap().push(interface);
+
+        Ok(())
+    }
+
+    /// Whether the file claimed the interface.
+    pub fn claims(&self, interface: u8) -> bool {
+        self.claimed.lock().unwrap().contains(&interface)
+    }
+
+    /// Release all claimed interfaces, which cancels their transfers.
+    ///
+    /// `reattach_drivers` binds the kernel drivers to the interfaces again.
+    pub fn release_all(&self, reattach_drivers: bool) {
+        self.free_streams(|_| true);
+        let claimed = mem::take(&mut *self.claimed.lock().unwrap());
+        for interface in claimed {
+            if let Err(err) = usbfs_ioctl_uint(self.fd(), usbdevfs::RELEASEINTERFACE, interface) {
+                warn!("Failed to release interface {interface}: {err}");
+                continue;
+            }
+            if !reattach_drivers {
+                continue;
+            }
+            let mut connect = UsbfsIoctl {
+                ifno: interface.into(),
+                ioctl_code: usbdevfs::CONNECT as c_int,
+                data: ptr::null_mut(),
+            };
+            // SAFETY: the request reads a `struct usbdevfs_ioctl`, and CONNECT
+            // takes no data.
+            let result = unsafe { ioctl(self.fd(), usbdevfs::IOCTL, (&raw mut connect).cast()) };
+            if let Err(err) = result {
+                debug!("Failed to reattach the driver of interface {interface}: {err}");
+            }
+        }
+    }
+
+    /// Select an alternate setting of a claimed interface.
+    pub fn set_interface(&self, interface: u8, alt_setting: u8) -> io::Result<()> {
+        self.free_streams(|streams_interface| streams_interface == interface);
+        let mut set_interface = SetInterface {
+            interface: interface.into(),
+            altsetting: alt_setting.into(),
+        };
+        // SAFETY: the request reads a `struct usbdevfs_setinterface`.
+        unsafe {
+            ioctl(
+                self.fd(),
+                usbdevfs::SETINTERFACE,
+                (&raw mut set_interface).cast(),
+            )
+        }
+        .map(|_| ())
+    }
+
+    /// Allocate streams on a bulk endpoint of a claimed interface, unless
+    /// they are allocated already. The host controller may allocate fewer
+    /// than `num_streams`.
+    pub fn alloc_streams(&self, interface: u8, endpoint: u8, num_streams: u32) -> io::Result<()> {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.iter().any(|&(_, ep)| ep == endpoint) {
+            return Ok(());
+        }
+        let mut request = StreamsOfEndpoint {
+            streams: Streams {
+                num_streams,
+                num_eps: 1,
+            },
+            ep: endpoint,
+        };
+        // SAFETY: the request reads a `struct usbdevfs_streams` with as many
+        // endpoints as it says.
+        let allocated = unsafe {
+            ioctl(
+                self.fd(),
+                usbdevfs::ALLOC_STREAMS,
+                (&raw mut request).cast(),
+            )
+        }?;
+        debug!("Allocated {allocated} streams on endpoint {endpoint:#04x}");
+        streams.push((interface, endpoint));
+        drop(streams);
+
+        Ok(())
+    }
+
+    /// Free the streams of the endpoints of the interfaces that match.
+    fn free_streams(&self, interface_matches: impl Fn(u8) -> bool) {
+        self.streams
+            .lock()
+            .unwrap()
+            .retain(|&(interface, endpoint)| {
+                if !interface_matches(interface) {
+                    return true;
+                }
+                let mut request = StreamsOfEndpoint {
+                    streams: Streams {
+                        num_streams: 0,
+                        num_eps: 1,
+                    },
+                    ep: endpoint,
+                };
+                // SAFETY: the request reads a `struct usbdevfs_streams` with as
+                // many endpoints as it says.
+                let result =
+                    unsafe { ioctl(self.fd(), usbdevfs::FREE_STREAMS, (&raw mut request).cast()) };
+                if let Err(err) = result {
+                    debug!("Failed to free the streams of endpoint {endpoint:#04x}: {err}");
+                }
+                false
+            });
+    }
+
+    /// Submit the transfer. The receiver yields the transfer once it
+    /// completed, or fails if the device is gone.
+    pub fn submit(
+        &self,
+        transfer: Transfer,
+    ) -> io::Result<(UrbId, oneshot::Receiver<Box<Transfer>>)> {
+        let (completion, receiver) = oneshot::channel();
+        let transfer = Box::into_raw(Box::new(transfer));
+        let id = UrbId(transfer as usize);
+        // SAFETY: the transfer was just leaked and is not shared yet.
+        unsafe { (*transfer).urb.usercontext = transfer.cast() };
+
+        // The reaper must not see the URB before it is pending.
+        let mut pending = self.urbs.pending.lock().unwrap();
+        pending.insert(id, completion);
+        // SAFETY: the request reads the URB, which stays valid until it is
+        // reaped, and so does its buffer.
+        if let Err(err) = unsafe { ioctl(self.fd(), usbdevfs::SUBMITURB, transfer.cast()) } {
+            pending.remove(&id);
+            // SAFETY: the kernel did not take the URB.
+            drop(unsafe { Box::from_raw(transfer) });
+            return Err(err);
+        }
+        drop(pending);
+
+        Ok((id, receiver))
+    }
+
+    /// Cancel a submitted transfer. It completes as cancelled, unless it
+    /// completed before.
+    pub fn discard(&self, id: UrbId) {
+        // SAFETY: the URB is only used to look up the submitted transfer.
+        if let Err(err) = unsafe { ioctl(self.fd(), usbdevfs::DISCARDURB, id.0 as *mut c_void) } {
+            debug!("Failed to discard URB {:#x}: {err}", id.0);
+        }
+    }
+
+    /// Clear the halt condition of an endpoint.
+    pub fn clear_halt(&self, endpoint: u8) -> io::Result<()> {
+        usbfs_ioctl_uint(self.fd(), usbdevfs::CLEAR_HALT, endpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_numbers_match_the_kernel() {
+        // The numbers as found in linux/usbdevice_fs.h for these
+        // architectures. Flexible arrays do not count for their size.
+        if cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+            assert_eq!(usbdevfs::SETINTERFACE, 0x8008_5504);
+            assert_eq!(usbdevfs::SUBMITURB, 0x8038_550a);
+            assert_eq!(usbdevfs::REAPURB, 0x4008_550c);
+            assert_eq!(usbdevfs::RELEASEINTERFACE, 0x8004_5510);
+            assert_eq!(usbdevfs::IOCTL, 0xc010_5512);
+            assert_eq!(usbdevfs::DISCONNECT_CLAIM, 0x8108_551b);
+            assert_eq!(usbdevfs::ALLOC_STREAMS, 0x8008_551c);
+        }
+        assert_eq!(usbdevfs::WAIT_FOR_RESUME, 0x5523);
+        assert_eq!(
+            mem::offset_of!(Transfer, iso_frame_desc),
+            mem::size_of::<Urb>()
+        );
+        assert_eq!(
+            mem::offset_of!(StreamsOfEndpoint, ep),
+            mem::size_of::<Streams>()
+        );
+    }
+
+    #[test]
+    fn isochronous_transfers_report_the_packet() {
+        let mut transfer = Transfer::new(UrbKind::Isochronous, 0x81, vec![0; 192]);
+        assert_eq!(transfer.urb.number_of_packets_or_stream_id, 1);
+        assert_eq!(transfer.iso_frame_desc[0].length, 192);
+
+        transfer.urb.actual_length = 100;
+        transfer.iso_frame_desc[0].actual_length = 100;
+        transfer.iso_frame_desc[0].status = (-libc::EXDEV) as c_uint;
+        let (status, data) = transfer.into_completion();
+        assert_eq!(status, Err(TransferError::Fault));
+        assert_eq!(data.len(), 100);
+
+        let transfer = Transfer::new(UrbKind::Isochronous, 0x02, vec![1; 64]);
+        let (status, data) = transfer.into_completion();
+        assert_eq!(status, Ok(()));
+        assert_eq!(data, vec![1; 64]);
+    }
+
+    #[test]
+    fn bulk_transfers_carry_the_stream() {
+        let transfer = Transfer::new(UrbKind::Bulk { stream_id: 7 }, 0x81, vec![0; 512]);
+        assert_eq!(transfer.urb.urb_type, URB_TYPE_BULK);
+        assert_eq!(transfer.urb.number_of_packets_or_stream_id, 7);
+    }
+}
@@ -0,0 +1,114 @@
+use std::io::{self, Read, Write};
+
+use crate::hotplug_protocol::usb_id::UsbId;
+
+/// Description of a device on a guest root-hub port, as reported by the
+/// server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachedDevice {
+    /// The guest root-hub port (1-based).
+    pub port: u8,
+    /// Host bus number.
+    pub bus: u8,
+    /// Host device number.
+    pub device: u8,
+    pub usb_id: UsbId,
+    /// Host port path as found in /sys/bus/usb/devices, e.g. `1-3.2`.
+    pub host_port_path: Option<String>,
+}
+
+impl AttachedDevice {
+    /// Serialize the device description.
+    ///
+    /// Layout: port, bus, device (one byte each), vendor ID, product ID
+    /// (u16 little endian each), followed by serial number and host port
+    /// path, each as u16 little endian length and UTF-8 data. Missing
+    /// strings have length 0.
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<(), io::Error> {
+        writer.write_all(&[self.port, self.bus, self.device])?;
+        writer.write_all(&self.usb_id.vendor_id.to_le_bytes())?;
+        writer.write_all(&self.usb_id.product_id.to_le_bytes())?;
+        write_string(writer, self.usb_id.serial.as_deref())?;
+        write_string(writer, self.host_port_path.as_deref())?;
+
+        Ok(())
+    }
+
+    /// Deserialize a device description written by [`Self::write_to`].
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, io::Error> {
+        let mut buf = [0u8; 7];
+        reader.read_exact(&mut buf)?;
+        let serial = read_string(reader)?;
+        let host_port_path = read_string(reader)?;
+
+        Ok(Self {
+            port: buf[0],
+            bus: buf[1],
+            device: buf[2],
+            usb_id: UsbId {
+                vendor_id: u16::from_le_bytes([buf[3], buf[4]]),
+                product_id: u16::from_le_bytes([buf[5], buf[6]]),
+                serial,
+            },
+            host_port_path,
+        })
+    }
+}
+
+pub(crate) fn write_string(writer: &mut impl Write, string: Option<&str>) -> Result<(), io::Error> {
+    let bytes = string.unwrap_or_default().as_bytes();
+    let len = u16::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "string too long"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+pub(crate) fn read_string(reader: &mut impl Read) -> Result<Option<String>, io::Error> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u16::from_le_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+
+    String::from_utf8(bytes)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attached_device_roundtrip() {
+        let devices = [
+            AttachedDevice {
+                port: 5,
+                bus: 1,
+                device: 7,
+                usb_id: "046d:c52b:ABC123".parse().unwrap(),
+                host_port_path: Some("1-3.2".to_string()),
+            },
+            AttachedDevice {
+                port: 1,
+                bus: 2,
+                device: 3,
+                usb_id: "0781:5581".parse().unwrap(),
+                host_port_path: None,
+            },
+        ];
+
+        let mut buf = vec![];
+        for device in &devices {
+            device.write_to(&mut buf).unwrap();
+        }
+
+        let mut reader = buf.as_slice();
+        for device in &devices {
+            assert_eq!(&AttachedDevice::read_from(&mut reader).unwrap(), device);
+        }
+        assert!(reader.is_empty());
+    }
+}
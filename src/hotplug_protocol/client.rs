@@ -0,0 +1,375 @@
+//! Typed clients for the hotplug socket of usbvfiod.
+//!
+//! [`BlockingHotplugClient`] talks to the server from synchronous code,
+//! [`HotplugClient`] offers the same operations for tokio-based code. Both
+//! keep the connection open across requests and transparently reconnect
+//! if the server closed it in the meantime.
+
+use std::{
+    io::{self, Read},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use thiserror::Error;
+
+use crate::hotplug_protocol::{
+    attached_device::AttachedDevice,
+    command::{Command, CommandSendError, DetachSelector},
+    device_file::{DeviceFile, DeviceOpenError},
+    response::{Response, ResponseError},
+};
+
+/// Blocking client for the hotplug socket.
+#[derive(Debug)]
+pub struct BlockingHotplugClient {
+    socket_path: PathBuf,
+    socket: Option<UnixStream>,
+}
+
+impl BlockingHotplugClient {
+    /// Connect to the hotplug socket at `socket_path`.
+    pub fn connect<P: AsRef<Path>>(socket_path: P) -> Result<Self, ClientError> {
+        let mut client = Self {
+            socket_path: socket_path.as_ref().to_path_buf(),
+            socket: None,
+        };
+        client.connection()?;
+
+        Ok(client)
+    }
+
+    /// Attach an opened device, optionally to a specific guest root-hub
+    /// port and with only the listed interfaces.
+    pub fn attach(
+        &mut self,
+        device: DeviceFile,
+        port: Option<u8>,
+        interfaces: Option<Vec<u8>>,
+    ) -> Result<(), ClientError> {
+        let command = Command::Attach {
+            bus: device.bus,
+            device: device.device,
+            port,
+            interfaces,
+            fd: device.file,
+            urb_fd: Some(device.urb_file),
+        };
+
+        match self.request(command, |_, response| Ok(response))? {
+            Response::SuccessfulOperation => Ok(()),
+            response => Err(ClientError::Rejected(response)),
+        }
+    }
+
+    /// Open and reset the device at `device_path` and attach it. Returns
+    /// the bus and device number of the attached device.
+    pub fn attach_path<P: AsRef<Path>>(
+        &mut self,
+        device_path: P,
+        port: Option<u8>,
+        interfaces: Option<Vec<u8>>,
+    ) -> Result<(u8, u8), ClientError> {
+        let device = DeviceFile::open_and_reset(device_path)?;
+        let identifier = (device.bus, device.device);
+        self.attach(device, port, interfaces)?;
+
+        Ok(identifier)
+    }
+
+    /// Detach all devices matching the selector. Returns the detached
+    /// devices, which is empty if no device matched.
+    pub fn detach(&mut self, selector: DetachSelector) -> Result<Vec<AttachedDevice>, ClientError> {
+        self.request(
+            Command::Detach(selector),
+            |socket, response| match response {
+                Response::DetachedFollowing => Ok(response.receive_attached_devices(socket)?),
+                Response::NoSuchDevice => Ok(vec![]),
+                response => Err(ClientError::Rejected(response)),
+            },
+        )
+    }
+
+    /// List the bus and device numbers of all attached devices.
+    pub fn list(&mut self) -> Result<Vec<(u8, u8)>, ClientError> {
+        self.request(Command::List, |socket, response| match response {
+            Response::ListFollowing => Ok(response.receive_devices_list(socket)?),
+            response => Err(ClientError::Rejected(response)),
+        })
+    }
+
+    /// List all attached devices with their guest ports, USB IDs and host
+    /// ports.
+    pub fn list_attached(&mut self) -> Result<Vec<AttachedDevice>, ClientError> {
+        self.request(Command::ListDetailed, |socket, response| match response {
+            Response::AttachedFollowing => Ok(response.receive_attached_devices(socket)?),
+            response => Err(ClientError::Rejected(response)),
+        })
+    }
+
+    fn request<T>(
+        &mut self,
+        command: Command,
+        receive: impl FnOnce(&mut UnixStream, Response) -> Result<T, ClientError>,
+    ) -> Result<T, ClientError> {
+        let socket = self.connection()?;
+        let result = command
+            .send_over_socket(socket)
+            .map_err(ClientError::from)
+            .and_then(|()| {
+                let response =
+                    Response::receive_from_socket(socket).map_err(ResponseError::from)?;
+                receive(socket, response)
+            });
+
+        // The state of the connection is unknown after IO errors, so start
+        // over with the next request.
+        if matches!(result, Err(ClientError::Send(_) | ClientError::Receive(_))) {
+            self.socket = None;
+        }
+
+        result
+    }
+
+    // Reuse the existing connection unless the server closed it.
+    fn connection(&mut self) -> Result<&mut UnixStream, ClientError> {
+        if self.socket.as_ref().is_some_and(|socket| !is_open(socket)) {
+            self.socket = None;
+        }
+
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => {
+                UnixStream::connect(&self.socket_path).map_err(|source| ClientError::Connect {
+                    path: self.socket_path.clone(),
+                    source,
+                })?
+            }
+        };
+
+        Ok(self.socket.insert(socket))
+    }
+}
+
+// The server does not send anything unrequested, so pending data or EOF
+// mean that the connection is unusable.
+fn is_open(socket: &UnixStream) -> bool {
+    if socket.set_nonblocking(true).is_err() {
+        return false;
+    }
+    // Reading would consume data, but any data here is unexpected anyway.
+    let result = (&mut &*socket).read(&mut [0u8; 1]);
+    let reset = socket.set_nonblocking(false);
+
+    reset.is_ok() && matches!(result, Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+/// Async client for the hotplug socket.
+///
+/// Clones share the same connection. Requests are executed on the
+/// blocking thread pool of the tokio runtime.
+#[derive(Debug, Clone)]
+pub struct HotplugClient {
+    inner: Arc<Mutex<BlockingHotplugClient>>,
+}
+
+impl HotplugClient {
+    /// Connect to the hotplug socket at `socket_path`.
+    pub async fn connect<P: AsRef<Path>>(socket_path: P) -> Result<Self, ClientError> {
+        let socket_path = socket_path.as_ref().to_path_buf();
+        let client =
+            tokio::task::spawn_blocking(move || BlockingHotplugClient::connect(socket_path))
+                .await??;
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(client)),
+        })
+    }
+
+    /// See [`BlockingHotplugClient::attach`].
+    pub async fn attach(
+        &self,
+        device: DeviceFile,
+        port: Option<u8>,
+        interfaces: Option<Vec<u8>>,
+    ) -> Result<(), ClientError> {
+        self.run(move |client| client.attach(device, port, interfaces))
+            .await
+    }
+
+    /// See [`BlockingHotplugClient::attach_path`].
+    pub async fn attach_path<P: AsRef<Path>>(
+        &self,
+        device_path: P,
+        port: Option<u8>,
+        interfaces: Option<Vec<u8>>,
+    ) -> Result<(u8, u8), ClientError> {
+        let device_path = device_path.as_ref().to_path_buf();
+        // Resetting the device does not need the connection.
+        let device =
+            tokio::task::spawn_blocking(move || DeviceFile::open_and_reset(device_path)).await??;
+        let identifier = (device.bus, device.device);
+        self.attach(device, port, interfaces).await?;
+
+        Ok(identifier)
+    }
+
+    /// See [`BlockingHotplugClient::detach`].
+    pub async fn detach(
+        &self,
+        selector: DetachSelector,
+    ) -> Result<Vec<AttachedDevice>, ClientError> {
+        self.run(move |client| client.detach(selector)).await
+    }
+
+    /// See [`BlockingHotplugClient::list`].
+    pub async fn list(&self) -> Result<Vec<(u8, u8)>, ClientError> {
+        self.run(BlockingHotplugClient::list).await
+    }
+
+    /// See [`BlockingHotplugClient::list_attached`].
+    pub async fn list_attached(&self) -> Result<Vec<AttachedDevice>, ClientError> {
+        self.run(BlockingHotplugClient::list_attached).await
+    }
+
+    async fn run<T, F>(&self, request: F) -> Result<T, ClientError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut BlockingHotplugClient) -> Result<T, ClientError> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut client = match inner.lock() {
+                Ok(client) => client,
+                Err(poisoned) => {
+                    // A request panicked in the middle of an exchange, so
+                    // start over with a new connection next time.
+                    poisoned.into_inner().socket = None;
+                    inner.clear_poison();
+                    return Err(ClientError::Poisoned);
+                }
+            };
+            request(&mut client)
+        })
+        .await?
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Failed to connect to the hotplug socket {path}")]
+    Connect {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to send the command")]
+    Send(#[from] CommandSendError),
+    #[error("Failed to receive the response")]
+    Receive(#[from] ResponseError),
+    #[error("The server rejected the command: {0:?}")]
+    Rejected(Response),
+    #[error(transparent)]
+    Device(#[from] DeviceOpenError),
+    #[error("The client task failed")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("An earlier request panicked while using the connection")]
+    Poisoned,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener, thread};
+
+    use super::*;
+
+    // Serve a single connection and answer every command with `respond`.
+    fn serve_one_connection(
+        name: &str,
+        respond: impl Fn(Command, &mut UnixStream) + Send + 'static,
+    ) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "usbvfiod-client-test-{}-{name}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Ok(command) = Command::receive_from_socket(&stream) {
+                respond(command, &mut stream);
+            }
+        });
+
+        path
+    }
+
+    fn attached_device() -> AttachedDevice {
+        AttachedDevice {
+            port: 1,
+            bus: 1,
+            device: 2,
+            usb_id: "1234:5678".parse().unwrap(),
+            host_port_path: None,
+        }
+    }
+
+    fn respond(command: Command, stream: &mut UnixStream) {
+        match command {
+            Command::List => Response::ListFollowing
+                .send_device_list(vec![(1, 2)], stream)
+                .unwrap(),
+            Command::ListDetailed => Response::AttachedFollowing
+                .send_attached_devices(&[attached_device()], stream)
+                .unwrap(),
+            Command::Detach(DetachSelector::All) => Response::DetachedFollowing
+                .send_attached_devices(&[attached_device()], stream)
+                .unwrap(),
+            Command::Detach(_) | Command::LegacyDetach { .. } => {
+                Response::NoSuchDevice.send_over_socket(stream).unwrap();
+            }
+            Command::Attach { .. } => Response::NoFreePort.send_over_socket(stream).unwrap(),
+        }
+    }
+
+    #[test]
+    fn blocking_client_reuses_connection() {
+        let path = serve_one_connection("blocking", respond);
+        let mut client = BlockingHotplugClient::connect(&path).unwrap();
+
+        // the server only accepts a single connection, so all requests must
+        // use the same one
+        assert_eq!(client.list().unwrap(), vec![(1, 2)]);
+        assert_eq!(client.detach(DetachSelector::Port(3)).unwrap(), vec![]);
+        let detached = client.detach(DetachSelector::All).unwrap();
+        assert_eq!(detached.len(), 1);
+        assert_eq!(detached[0].port, 1);
+        assert_eq!(client.list().unwrap(), vec![(1, 2)]);
+        assert_eq!(client.list_attached().unwrap(), vec![attached_device()]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn async_client_reports_rejections() {
+        let path = serve_one_connection("async", respond);
+        let client = HotplugClient::connect(&path).await.unwrap();
+
+        let device = DeviceFile {
+            bus: 1,
+            device: 2,
+            path: PathBuf::from("/dev/null"),
+            file: std::fs::File::open("/dev/null").unwrap(),
+            urb_file: std::fs::File::open("/dev/null").unwrap(),
+        };
+        assert!(matches!(
+            client.attach(device, None, None).await,
+            Err(ClientError::Rejected(Response::NoFreePort))
+        ));
+        assert_eq!(client.clone().list().await.unwrap(), vec![(1, 2)]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,82 +1,324 @@
 use std::fs::File;
-use std::os::fd::AsRawFd;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 
 use vmm_sys_util::errno::Error;
 use vmm_sys_util::sock_ctrl_msg::ScmSocket;
 
+use crate::hotplug_protocol::usb_id::UsbId;
+
 const COMMAND_ATTACH: u8 = 0;
 const COMMAND_DETACH: u8 = 1;
 const COMMAND_LIST: u8 = 2;
+const COMMAND_DETACH_PORT: u8 = 3;
+const COMMAND_DETACH_USB_ID: u8 = 4;
+const COMMAND_DETACH_HOST_PORT: u8 = 5;
+const COMMAND_DETACH_ALL: u8 = 6;
+const COMMAND_LIST_DETAILED: u8 = 7;
+const COMMAND_ATTACH_INTERFACES: u8 = 8;
+const COMMAND_ATTACH_PORT: u8 = 9;
+const COMMAND_DETACH_DEVICE: u8 = 10;
+
+/// Attach, detach by bus and device number, and list commands of clients
+/// predating the other commands only send a three byte header.
+const LEGACY_HEADER_LEN: usize = 3;
+const HEADER_LEN: usize = 4;
+
+/// Port byte value that lets the server choose a free root-hub port.
+const ANY_PORT: u8 = 0;
 
 #[derive(Debug)]
 pub enum Command {
-    Attach { bus: u8, device: u8, fd: File },
-    Detach { bus: u8, device: u8 },
+    /// Attach the opened device. `port` optionally names the guest
+    /// root-hub port (1-based) the device should be attached to.
+    /// `interfaces` optionally restricts the interfaces passed through to
+    /// the guest, the host keeps the others.
+    ///
+    /// `urb_fd` is the device opened a second time. nusb reaps every
+    /// transfer submitted on `fd`, so usbvfiod submits isochronous transfers
+    /// and bulk streams on `urb_fd`. Older clients only send `fd`.
+    Attach {
+        bus: u8,
+        device: u8,
+        port: Option<u8>,
+        interfaces: Option<Vec<u8>>,
+        fd: File,
+        urb_fd: Option<File>,
+    },
+    /// Detach all devices matching the selector.
+    Detach(DetachSelector),
+    /// Detach the device with the host bus and device number. This is the
+    /// original detach command, which is answered with
+    /// `SuccessfulOperation` instead of the list of detached devices.
+    LegacyDetach {
+        bus: u8,
+        device: u8,
+    },
     List,
+    /// List the attached devices with their guest ports, USB IDs and host
+    /// ports.
+    ListDetailed,
+}
+
+/// Selects the devices a detach command applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DetachSelector {
+    /// The device with the host bus and device number.
+    Device { bus: u8, device: u8 },
+    /// The device on the guest root-hub port.
+    Port(u8),
+    /// All devices with the vendor and product ID (and serial number, if
+    /// given).
+    UsbId(UsbId),
+    /// The device on the host port, e.g. `1-3.2`.
+    HostPort(String),
+    /// All attached devices.
+    All,
 }
 
 impl Command {
+    /// Send the command.
+    ///
+    /// Every command starts with a header: the command ID and
+    /// command-specific data. The original attach, detach and list
+    /// commands (IDs 0 to 2) keep their three byte header with bus and
+    /// device number, so existing clients continue to work. All other
+    /// commands have a four byte header. Commands with variable-length
+    /// arguments store the payload length as u16 little endian in the
+    /// second and third byte, and the payload follows the header. Attach
+    /// commands with a port or interfaces use the header for bus, device
+    /// and port instead and prefix the payload with the number of
+    /// interfaces. Attach commands pass the device files along with the
+    /// header.
     pub fn send_over_socket(self, socket: &UnixStream) -> Result<(), CommandSendError> {
         let id = self.variant_to_id();
-        let (buf, fd) = match &self {
-            Self::Attach { bus, device, fd } => ([id, *bus, *device], Some(fd.as_raw_fd())),
-            Self::Detach { bus, device } => ([id, *bus, *device], None),
-            Self::List => ([id, 0, 0], None),
+        let payload = self.payload()?;
+        let payload_len = u16::try_from(payload.len())
+            .map_err(|_| CommandSendError::PayloadTooLarge(payload.len()))?
+            .to_le_bytes();
+        let (header, fds) = match &self {
+            Self::Attach {
+                bus,
+                device,
+                port,
+                fd,
+                urb_fd,
+                ..
+            } => (
+                [id, *bus, *device, port.unwrap_or(ANY_PORT)],
+                [Some(fd), urb_fd.as_ref()]
+                    .into_iter()
+                    .flatten()
+                    .map(AsRawFd::as_raw_fd)
+                    .collect(),
+            ),
+            Self::Detach(DetachSelector::Device { bus, device })
+            | Self::LegacyDetach { bus, device } => ([id, *bus, *device, 0], vec![]),
+            Self::Detach(DetachSelector::Port(port)) => ([id, *port, 0, 0], vec![]),
+            Self::Detach(DetachSelector::UsbId(_) | DetachSelector::HostPort(_)) => {
+                ([id, payload_len[0], payload_len[1], 0], vec![])
+            }
+            Self::Detach(DetachSelector::All) | Self::List | Self::ListDetailed => {
+                ([id, 0, 0, ANY_PORT], vec![])
+            }
         };
 
-        let transmitted = fd.map_or_else(
-            || socket.send_with_fds(&[&buf[..]], &[]),
-            |fd| socket.send_with_fd(&buf[..], fd),
-        )?;
+        let header = &header[..Self::header_len(id)];
+        let total_len = header.len() + payload.len();
+        let transmitted = socket.send_with_fds(&[header, &payload[..]], &fds)?;
 
         // TODO implement a transmission loop to be safe (we should not run
         // into problems with how little data we send, though).
-        if transmitted == buf.len() {
+        if transmitted == total_len {
             Ok(())
         } else {
-            Err(CommandSendError::NotSentEnough(buf.len(), transmitted))
+            Err(CommandSendError::NotSentEnough(total_len, transmitted))
         }
     }
 
     pub fn receive_from_socket(socket: &UnixStream) -> Result<Self, CommandReceiveError> {
-        let mut buf = [0u8; 3];
-        let (bytes_read, file) = socket.recv_with_fd(&mut buf[..])?;
-        if bytes_read != buf.len() {
-            return Err(CommandReceiveError::NotEnoughData(buf.len(), bytes_read));
+        let mut buf = [0u8; HEADER_LEN];
+        let mut iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: LEGACY_HEADER_LEN,
+        }];
+        let mut fds: [RawFd; 2] = [-1; 2];
+        // SAFETY: The iovec points to the first bytes of `buf`, which is
+        // not otherwise accessed until the call returns.
+        let (bytes_read, fd_count) = unsafe { socket.recv_with_fds(&mut iovecs, &mut fds) }
+            .map_err(|e| match e.errno() {
+                // the read timeout expired before the client sent a command
+                libc::EAGAIN => CommandReceiveError::TimedOut,
+                libc::ECONNRESET => CommandReceiveError::ConnectionClosed,
+                _ => CommandReceiveError::from(e),
+            })?;
+        // SAFETY: The first `fd_count` file descriptors were received from
+        // the socket and are owned by us.
+        let mut files = fds[..fd_count]
+            .iter()
+            .map(|&fd| unsafe { File::from_raw_fd(fd) });
+        let file = files.next();
+        let urb_file = files.next();
+        if bytes_read == 0 && file.is_none() {
+            return Err(CommandReceiveError::ConnectionClosed);
+        }
+        if bytes_read != LEGACY_HEADER_LEN {
+            return Err(CommandReceiveError::NotEnoughData(
+                LEGACY_HEADER_LEN,
+                bytes_read,
+            ));
         }
+        if Self::header_len(buf[0]) == HEADER_LEN {
+            let mut reader = socket;
+            reader.read_exact(&mut buf[LEGACY_HEADER_LEN..])?;
+        }
+        let read_payload = || {
+            let mut payload = vec![0u8; u16::from_le_bytes([buf[1], buf[2]]) as usize];
+            let mut reader = socket;
+            reader.read_exact(&mut payload)?;
+            Ok::<_, CommandReceiveError>(payload)
+        };
+        let read_interfaces = || {
+            let mut reader = socket;
+            let mut count = [0u8];
+            reader.read_exact(&mut count)?;
+            let mut interfaces = vec![0u8; count[0] as usize];
+            reader.read_exact(&mut interfaces)?;
+            Ok::<_, CommandReceiveError>(interfaces)
+        };
         match (buf[0], file) {
             (COMMAND_ATTACH, Some(file)) => Ok(Self::Attach {
                 bus: buf[1],
                 device: buf[2],
+                port: None,
+                interfaces: None,
+                fd: file,
+                urb_fd: urb_file,
+            }),
+            (COMMAND_ATTACH_PORT, Some(file)) => Ok(Self::Attach {
+                bus: buf[1],
+                device: buf[2],
+                port: (buf[3] != ANY_PORT).then_some(buf[3]),
+                interfaces: None,
                 fd: file,
+                urb_fd: urb_file,
             }),
-            (COMMAND_ATTACH, None) => Err(CommandReceiveError::MissingFd),
-            (COMMAND_DETACH, None) => Ok(Self::Detach {
+            (COMMAND_ATTACH_INTERFACES, Some(file)) => Ok(Self::Attach {
                 bus: buf[1],
                 device: buf[2],
+                port: (buf[3] != ANY_PORT).then_some(buf[3]),
+                interfaces: Some(read_interfaces()?),
+                fd: file,
+                urb_fd: urb_file,
             }),
+            (COMMAND_ATTACH | COMMAND_ATTACH_PORT | COMMAND_ATTACH_INTERFACES, None) => {
+                Err(CommandReceiveError::MissingFd)
+            }
+            (COMMAND_DETACH, None) => Ok(Self::LegacyDetach {
+                bus: buf[1],
+                device: buf[2],
+            }),
+            (COMMAND_DETACH_DEVICE, None) => Ok(Self::Detach(DetachSelector::Device {
+                bus: buf[1],
+                device: buf[2],
+            })),
+            (COMMAND_DETACH_PORT, None) => Ok(Self::Detach(DetachSelector::Port(buf[1]))),
+            (COMMAND_DETACH_USB_ID, None) => {
+                let payload = read_payload()?;
+                if payload.len() < 4 {
+                    return Err(CommandReceiveError::InvalidPayload);
+                }
+                let serial = String::from_utf8(payload[4..].to_vec())
+                    .map_err(|_| CommandReceiveError::InvalidPayload)?;
+                Ok(Self::Detach(DetachSelector::UsbId(UsbId {
+                    vendor_id: u16::from_le_bytes([payload[0], payload[1]]),
+                    product_id: u16::from_le_bytes([payload[2], payload[3]]),
+                    serial: (!serial.is_empty()).then_some(serial),
+                })))
+            }
+            (COMMAND_DETACH_HOST_PORT, None) => {
+                let host_port = String::from_utf8(read_payload()?)
+                    .map_err(|_| CommandReceiveError::InvalidPayload)?;
+                Ok(Self::Detach(DetachSelector::HostPort(host_port)))
+            }
+            (COMMAND_DETACH_ALL, None) => Ok(Self::Detach(DetachSelector::All)),
             (COMMAND_LIST, None) => Ok(Self::List {}),
+            (COMMAND_LIST_DETAILED, None) => Ok(Self::ListDetailed),
             (command, None) => Err(CommandReceiveError::UnknownCommand(command)),
             (_, Some(_)) => Err(CommandReceiveError::UnexpectedFd),
         }
     }
 
+    fn payload(&self) -> Result<Vec<u8>, CommandSendError> {
+        let payload = match self {
+            Self::Attach {
+                interfaces: Some(interfaces),
+                ..
+            } => {
+                // The receiver cannot represent more interfaces, and a
+                // device cannot have more.
+                let count = u8::try_from(interfaces.len())
+                    .map_err(|_| CommandSendError::TooManyInterfaces(interfaces.len()))?;
+                let mut payload = vec![count];
+                payload.extend_from_slice(interfaces);
+                payload
+            }
+            Self::Detach(DetachSelector::UsbId(usb_id)) => {
+                let mut payload = vec![];
+                payload.extend_from_slice(&usb_id.vendor_id.to_le_bytes());
+                payload.extend_from_slice(&usb_id.product_id.to_le_bytes());
+                payload.extend_from_slice(usb_id.serial.as_deref().unwrap_or_default().as_bytes());
+                payload
+            }
+            Self::Detach(DetachSelector::HostPort(host_port)) => host_port.as_bytes().to_vec(),
+            _ => vec![],
+        };
+
+        Ok(payload)
+    }
+
+    const fn header_len(id: u8) -> usize {
+        match id {
+            COMMAND_ATTACH | COMMAND_DETACH | COMMAND_LIST => LEGACY_HEADER_LEN,
+            _ => HEADER_LEN,
+        }
+    }
+
     const fn variant_to_id(&self) -> u8 {
         match self {
             Self::Attach {
-                bus: _,
-                device: _,
-                fd: _,
+                port: None,
+                interfaces: None,
+                ..
             } => COMMAND_ATTACH,
-            Self::Detach { bus: _, device: _ } => COMMAND_DETACH,
+            Self::Attach {
+                port: Some(_),
+                interfaces: None,
+                ..
+            } => COMMAND_ATTACH_PORT,
+            Self::Attach {
+                interfaces: Some(_),
+                ..
+            } => COMMAND_ATTACH_INTERFACES,
+            Self::Detach(DetachSelector::Device { bus: _, device: _ }) => COMMAND_DETACH_DEVICE,
+            Self::LegacyDetach { .. } => COMMAND_DETACH,
+            Self::Detach(DetachSelector::Port(_)) => COMMAND_DETACH_PORT,
+            Self::Detach(DetachSelector::UsbId(_)) => COMMAND_DETACH_USB_ID,
+            Self::Detach(DetachSelector::HostPort(_)) => COMMAND_DETACH_HOST_PORT,
+            Self::Detach(DetachSelector::All) => COMMAND_DETACH_ALL,
             Self::List => COMMAND_LIST,
+            Self::ListDetailed => COMMAND_LIST_DETAILED,
         }
     }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum CommandReceiveError {
+    #[error("the peer closed the connection")]
+    ConnectionClosed,
+    #[error("the peer did not send a command in time")]
+    TimedOut,
     #[error("did not receive enough data over the socket. Expected {0}, received {1}")]
     NotEnoughData(usize, usize),
     #[error("expected to receive a file descriptor, but there was none")]
@@ -85,14 +327,150 @@ pub enum CommandReceiveError {
     UnexpectedFd,
     #[error("Unknown command")]
     UnknownCommand(u8),
+    #[error("The command payload is malformed")]
+    InvalidPayload,
     #[error("Encountered errno during socket IO")]
     ErrnoError(#[from] Error),
+    #[error("Failed to read the command payload")]
+    IoError(#[from] io::Error),
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum CommandSendError {
     #[error("did not receive enough data over the socket. Expected to send {0}, sent {1}")]
     NotSentEnough(usize, usize),
+    #[error("The command payload of {0} bytes is too large")]
+    PayloadTooLarge(usize),
+    #[error("Cannot select {0} interfaces, at most 255 fit into the command")]
+    TooManyInterfaces(usize),
     #[error("Encountered errno during socket IO")]
     ErrnoError(#[from] Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(command: Command) -> Command {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        command.send_over_socket(&sender).unwrap();
+        Command::receive_from_socket(&receiver).unwrap()
+    }
+
+    #[test]
+    fn attach_roundtrip() {
+        for (port, interfaces) in [
+            (None, None),
+            (Some(5), Some(vec![0, 3])),
+            (None, Some(vec![])),
+        ] {
+            let command = Command::Attach {
+                bus: 3,
+                device: 9,
+                port,
+                interfaces: interfaces.clone(),
+                fd: File::open("/dev/null").unwrap(),
+                urb_fd: Some(File::open("/dev/null").unwrap()),
+            };
+            match roundtrip(command) {
+                Command::Attach {
+                    bus: 3,
+                    device: 9,
+                    port: received_port,
+                    interfaces: received_interfaces,
+                    fd: _,
+                    urb_fd,
+                } => {
+                    assert_eq!(received_port, port);
+                    assert_eq!(received_interfaces, interfaces);
+                    assert!(urb_fd.is_some());
+                }
+                command => panic!("unexpected command {command:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn too_many_interfaces_are_not_sent() {
+        let (sender, _receiver) = UnixStream::pair().unwrap();
+        let command = Command::Attach {
+            bus: 3,
+            device: 9,
+            port: None,
+            interfaces: Some((0..=255).collect()),
+            fd: File::open("/dev/null").unwrap(),
+            urb_fd: None,
+        };
+
+        assert!(matches!(
+            command.send_over_socket(&sender),
+            Err(CommandSendError::TooManyInterfaces(256))
+        ));
+    }
+
+    #[test]
+    fn detach_selectors_roundtrip() {
+        let selectors = [
+            DetachSelector::Device { bus: 3, device: 9 },
+            DetachSelector::Port(5),
+            DetachSelector::UsbId("046d:c52b".parse().unwrap()),
+            DetachSelector::UsbId("0781:5581:4C530001".parse().unwrap()),
+            DetachSelector::HostPort("1-3.2".to_string()),
+            DetachSelector::All,
+        ];
+
+        for selector in selectors {
+            match roundtrip(Command::Detach(selector.clone())) {
+                Command::Detach(received) => assert_eq!(received, selector),
+                command => panic!("unexpected command {command:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_commands_use_three_byte_headers() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        // a client predating the four byte header
+        sender
+            .send_with_fds(&[&[COMMAND_DETACH, 3, 9][..]], &[])
+            .unwrap();
+        sender
+            .send_with_fds(&[&[COMMAND_LIST, 0, 0][..]], &[])
+            .unwrap();
+        let file = File::open("/dev/null").unwrap();
+        sender
+            .send_with_fd(&[COMMAND_ATTACH, 3, 9][..], file.as_raw_fd())
+            .unwrap();
+
+        match Command::receive_from_socket(&receiver).unwrap() {
+            Command::LegacyDetach { bus: 3, device: 9 } => {}
+            command => panic!("unexpected command {command:?}"),
+        }
+        assert!(matches!(
+            Command::receive_from_socket(&receiver).unwrap(),
+            Command::List
+        ));
+        assert!(matches!(
+            Command::receive_from_socket(&receiver).unwrap(),
+            Command::Attach {
+                bus: 3,
+                device: 9,
+                urb_fd: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn idle_connections_time_out() {
+        let (_sender, receiver) = UnixStream::pair().unwrap();
+        receiver
+            .set_read_timeout(Some(std::time::Duration::from_millis(10)))
+            .unwrap();
+
+        assert!(matches!(
+            Command::receive_from_socket(&receiver),
+            Err(CommandReceiveError::TimedOut)
+        ));
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use nusb::MaybeFuture;
+use thiserror::Error;
+
+use crate::hotplug_protocol::device_paths::{resolve_path, ResolveError};
+
+/// An opened USB device file, ready to be passed to usbvfiod.
+#[derive(Debug)]
+pub struct DeviceFile {
+    pub bus: u8,
+    pub device: u8,
+    /// The canonical path of the device file.
+    pub path: PathBuf,
+    pub file: File,
+    /// The device file opened a second time. usbvfiod submits the transfers
+    /// that nusb does not support on it, which needs a file of their own.
+    pub urb_file: File,
+}
+
+impl DeviceFile {
+    /// Open the USB device file at `path` (or a symlink to it) for reading
+    /// and writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DeviceOpenError> {
+        let (bus, device, path) = resolve_path(path)?;
+        let file = open_rw(&path)?;
+        let urb_file = open_rw(&path)?;
+
+        Ok(Self {
+            bus,
+            device,
+            path,
+            file,
+            urb_file,
+        })
+    }
+
+    /// Open the USB device file at `path`, reset the device and open it
+    /// again.
+    ///
+    /// Resetting puts the device into a well-defined state before the
+    /// guest driver takes over. The reset invalidates the first file
+    /// descriptors, which is why the device is opened again.
+    pub fn open_and_reset<P: AsRef<Path>>(path: P) -> Result<Self, DeviceOpenError> {
+        let Self {
+            bus,
+            device,
+            path,
+            file,
+            ..
+        } = Self::open(path)?;
+
+        let nusb_device = nusb::Device::from_fd(file.into())
+            .wait()
+            .map_err(DeviceOpenError::Reset)?;
+        nusb_device.reset().wait().map_err(DeviceOpenError::Reset)?;
+
+        // After the reset, the device instance is no longer usable and we need
+        // to reopen.
+        let file = open_rw(&path)?;
+        let urb_file = open_rw(&path)?;
+
+        Ok(Self {
+            bus,
+            device,
+            path,
+            file,
+            urb_file,
+        })
+    }
+}
+
+fn open_rw(path: &Path) -> Result<File, DeviceOpenError> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|source| DeviceOpenError::Open {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+#[derive(Error, Debug)]
+pub enum DeviceOpenError {
+    #[error(transparent)]
+    Resolve(#[from] ResolveError),
+    #[error("Failed to open USB device file {path}")]
+    Open {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to reset the USB device")]
+    Reset(#[source] nusb::Error),
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::poll_fn,
+    path::PathBuf,
+    pin::Pin,
+};
+
+use futures_core::Stream;
+use nusb::{
+    hotplug::{HotplugEvent, HotplugWatch},
+    DeviceId, MaybeFuture,
+};
+use thiserror::Error;
+
+use crate::hotplug_protocol::usb_id::UsbId;
+
+/// A USB device present on the host, as found by enumeration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub usb_id: UsbId,
+    /// The device class from the device descriptor.
+    pub class: u8,
+    /// The classes of the interfaces of the active configuration.
+    pub interface_classes: Vec<u8>,
+    /// Host port path as found in /sys/bus/usb/devices, e.g. `1-3.2`.
+    pub port_path: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+impl HostDevice {
+    fn from_info(info: &nusb::DeviceInfo) -> Self {
+        Self {
+            bus: info.busnum(),
+            device: info.device_address(),
+            usb_id: UsbId {
+                vendor_id: info.vendor_id(),
+                product_id: info.product_id(),
+                serial: info.serial_number().map(str::to_string),
+            },
+            class: info.class(),
+            interface_classes: info
+                .interfaces()
+                .map(|interface| interface.class())
+                .collect(),
+            port_path: info
+                .sysfs_path()
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(str::to_string),
+            manufacturer: info.manufacturer_string().map(str::to_string),
+            product: info.product_string().map(str::to_string),
+        }
+    }
+
+    /// The device file of the device in /dev/bus/usb.
+    #[must_use]
+    pub fn device_path(&self) -> PathBuf {
+        PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", self.bus, self.device))
+    }
+}
+
+/// Selects a host device by its properties instead of its device file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostDeviceSelector {
+    /// The device with the vendor and product ID (and serial number, if
+    /// given).
+    UsbId(UsbId),
+    /// The device on the host port, e.g. `1-3.2`.
+    Port(String),
+}
+
+impl HostDeviceSelector {
+    #[must_use]
+    pub fn matches(&self, device: &HostDevice) -> bool {
+        match self {
+            Self::UsbId(usb_id) => usb_id.matches(
+                device.usb_id.vendor_id,
+                device.usb_id.product_id,
+                device.usb_id.serial.as_deref(),
+            ),
+            Self::Port(path) => device.port_path.as_ref() == Some(path),
+        }
+    }
+}
+
+impl fmt::Display for HostDeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UsbId(usb_id) => write!(f, "{usb_id}"),
+            Self::Port(path) => write!(f, "host port {path}"),
+        }
+    }
+}
+
+/// Enumerate the USB devices of the host. Root hubs are not included.
+pub fn list_host_devices() -> Result<Vec<HostDevice>, HostDeviceError> {
+    let devices = nusb::list_devices()
+        .wait()
+        .map_err(HostDeviceError::Enumerate)?
+        .map(|info| HostDevice::from_info(&info))
+        .collect();
+
+    Ok(devices)
+}
+
+/// Find the single host device matching the selector.
+///
+/// Fails if no device or more than one device matches, so that a vague
+/// selector never picks an arbitrary device.
+pub fn find_host_device(selector: &HostDeviceSelector) -> Result<HostDevice, HostDeviceError> {
+    select_unique(selector, list_host_devices()?)
+}
+
+fn select_unique(
+    selector: &HostDeviceSelector,
+    devices: Vec<HostDevice>,
+) -> Result<HostDevice, HostDeviceError> {
+    let mut matching = devices
+        .into_iter()
+        .filter(|device| selector.matches(device))
+        .collect::<Vec<_>>();
+
+    match matching.len() {
+        0 => Err(HostDeviceError::NotFound(selector.clone())),
+        1 => Ok(matching.remove(0)),
+        _ => Err(HostDeviceError::Ambiguous(selector.clone(), matching)),
+    }
+}
+
+/// A change of the USB devices of the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostDeviceEvent {
+    Arrived(HostDevice),
+    Removed(HostDevice),
+}
+
+/// Watches the USB devices of the host as they appear and disappear.
+pub struct HostDeviceWatcher {
+    watch: HotplugWatch,
+    known: HashMap<DeviceId, HostDevice>,
+    pending: VecDeque<HostDeviceEvent>,
+}
+
+impl fmt::Debug for HostDeviceWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostDeviceWatcher")
+            .field("known", &self.known)
+            .field("pending", &self.pending)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HostDeviceWatcher {
+    /// Start watching. Devices that are already present are reported as
+    /// arrived first.
+    pub fn new() -> Result<Self, HostDeviceError> {
+        // Start watching before enumerating, so no device slips through.
+        let watch = nusb::watch_devices().map_err(HostDeviceError::Watch)?;
+        let known = nusb::list_devices()
+            .wait()
+            .map_err(HostDeviceError::Enumerate)?
+            .map(|info| (info.id(), HostDevice::from_info(&info)))
+            .collect::<HashMap<_, _>>();
+        let pending = known
+            .values()
+            .cloned()
+            .map(HostDeviceEvent::Arrived)
+            .collect();
+
+        Ok(Self {
+            watch,
+            known,
+            pending,
+        })
+    }
+
+    /// Wait for the next change. Returns `None` if the watch ended.
+    pub async fn next(&mut self) -> Option<HostDeviceEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+
+            match poll_fn(|cx| Pin::new(&mut self.watch).poll_next(cx)).await? {
+                HotplugEvent::Connected(info) => {
+                    // Devices found during the initial enumeration may be
+                    // reported again.
+                    if self.known.contains_key(&info.id()) {
+                        continue;
+                    }
+                    let device = HostDevice::from_info(&info);
+                    self.known.insert(info.id(), device.clone());
+                    return Some(HostDeviceEvent::Arrived(device));
+                }
+                HotplugEvent::Disconnected(id) => {
+                    if let Some(device) = self.known.remove(&id) {
+                        return Some(HostDeviceEvent::Removed(device));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HostDeviceError {
+    #[error("Failed to enumerate the USB devices of the host")]
+    Enumerate(#[source] nusb::Error),
+    #[error("Failed to watch the USB devices of the host")]
+    Watch(#[source] nusb::Error),
+    #[error("No host device matches {0}")]
+    NotFound(HostDeviceSelector),
+    #[error("Several host devices match {0}")]
+    Ambiguous(HostDeviceSelector, Vec<HostDevice>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_device(device: u8, usb_id: &str, port_path: &str) -> HostDevice {
+        HostDevice {
+            bus: 1,
+            device,
+            usb_id: usb_id.parse().unwrap(),
+            class: 0,
+            interface_classes: vec![],
+            port_path: Some(port_path.to_string()),
+            manufacturer: None,
+            product: None,
+        }
+    }
+
+    #[test]
+    fn select_unique_refuses_ambiguous_matches() {
+        let devices = vec![
+            host_device(2, "1050:0407:111", "1-1"),
+            host_device(3, "1050:0407:222", "1-3.2"),
+            host_device(4, "046d:c52b", "1-4"),
+        ];
+        let select = |selector| select_unique(&selector, devices.clone());
+
+        assert_eq!(
+            select(HostDeviceSelector::UsbId("046d:c52b".parse().unwrap()))
+                .unwrap()
+                .device,
+            4
+        );
+        assert_eq!(
+            select(HostDeviceSelector::UsbId("1050:0407:222".parse().unwrap()))
+                .unwrap()
+                .device,
+            3
+        );
+        assert_eq!(
+            select(HostDeviceSelector::Port("1-3.2".to_string()))
+                .unwrap()
+                .device,
+            3
+        );
+        assert!(matches!(
+            select(HostDeviceSelector::UsbId("1050:0407".parse().unwrap())),
+            Err(HostDeviceError::Ambiguous(_, matching)) if matching.len() == 2
+        ));
+        assert!(matches!(
+            select(HostDeviceSelector::Port("1-3".to_string())),
+            Err(HostDeviceError::NotFound(_))
+        ));
+    }
+}
@@ -1,3 +1,9 @@
+pub mod attached_device;
+pub mod client;
 pub mod command;
+pub mod device_file;
 pub mod device_paths;
+pub mod host_devices;
 pub mod response;
+pub mod rules;
+pub mod usb_id;
@@ -4,6 +4,10 @@ use std::{
     os::unix::net::UnixStream,
 };
 
+use thiserror::Error;
+
+use crate::hotplug_protocol::attached_device::AttachedDevice;
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Response {
@@ -13,27 +17,39 @@ pub enum Response {
     CouldNotDetermineSpeed,
     FailedToOpenFd,
     NoSuchDevice,
+    /// The requested root-hub port already has a device attached.
+    PortOccupied,
+    /// The requested root-hub port does not match the USB version of the
+    /// device.
+    PortVersionMismatch,
+    /// The requested root-hub port does not exist.
+    NoSuchPort,
+    /// The detach command succeeded and the list of detached devices
+    /// follows.
+    DetachedFollowing,
+    /// The list of attached devices with their details follows.
+    AttachedFollowing,
     Invalid,
 }
 
 impl Response {
     pub fn send_over_socket(&self, socket: &mut UnixStream) -> Result<(), io::Error> {
-        socket.write(&[*self as u8]).map(|_| ())
+        socket.write_all(&[*self as u8])
     }
 
     pub fn send_device_list(
         &self,
         devices: Vec<(u8, u8)>,
         socket: &mut UnixStream,
-    ) -> Result<(), io::Error> {
-        assert_eq!(*self, Self::ListFollowing);
+    ) -> Result<(), ResponseError> {
+        self.expect(Self::ListFollowing)?;
+        let len = list_length(devices.len())?;
 
         // send Response
         self.send_over_socket(socket)?;
 
         // send list length
-        assert!(devices.len() <= u8::MAX as usize);
-        socket.write_all(&[devices.len() as u8])?;
+        socket.write_all(&[len])?;
 
         // send list data
         let data = devices
@@ -45,24 +61,61 @@ impl Response {
         Ok(())
     }
 
+    pub fn send_attached_devices(
+        &self,
+        devices: &[AttachedDevice],
+        socket: &mut UnixStream,
+    ) -> Result<(), ResponseError> {
+        self.expect_attached_devices()?;
+        let len = list_length(devices.len())?;
+
+        // send Response
+        self.send_over_socket(socket)?;
+
+        // send list length and data
+        let mut data = vec![len];
+        for device in devices {
+            device.write_to(&mut data)?;
+        }
+        socket.write_all(&data)?;
+
+        Ok(())
+    }
+
+    pub fn receive_attached_devices(
+        &self,
+        socket: &mut UnixStream,
+    ) -> Result<Vec<AttachedDevice>, ResponseError> {
+        self.expect_attached_devices()?;
+
+        let mut buf = [0u8; 1];
+        socket.read_exact(&mut buf)?;
+
+        let devices = (0..buf[0])
+            .map(|_| AttachedDevice::read_from(socket))
+            .collect::<Result<_, _>>()?;
+
+        Ok(devices)
+    }
+
     pub fn receive_from_socket(socket: &mut UnixStream) -> Result<Self, io::Error> {
         let mut buf = [0u8; 1];
         socket
             .read_exact(&mut buf)
-            .map(|_| Self::try_from(buf[0]).unwrap())
+            .map(|_| Self::try_from(buf[0]).unwrap_or(Self::Invalid))
     }
 
     pub fn receive_devices_list(
         &self,
         socket: &mut UnixStream,
-    ) -> Result<Vec<(u8, u8)>, io::Error> {
-        assert_eq!(*self, Self::ListFollowing);
+    ) -> Result<Vec<(u8, u8)>, ResponseError> {
+        self.expect(Self::ListFollowing)?;
 
         let mut buf = [0u8; 1];
         socket.read_exact(&mut buf)?;
         // bus and device number take one byte each.
-        let len = buf[0] * 2;
-        let mut buf = vec![0u8; len as usize];
+        let len = buf[0] as usize * 2;
+        let mut buf = vec![0u8; len];
 
         socket.read_exact(&mut buf)?;
 
@@ -77,6 +130,31 @@ impl Response {
 
         Ok(devices)
     }
+
+    const fn expect(self, expected: Self) -> Result<(), ResponseError> {
+        if self as u8 == expected as u8 {
+            Ok(())
+        } else {
+            Err(ResponseError::Unexpected {
+                expected,
+                actual: self,
+            })
+        }
+    }
+}
+
+impl Response {
+    // Detached and attached devices share the same encoding.
+    const fn expect_attached_devices(self) -> Result<(), ResponseError> {
+        match self {
+            Self::AttachedFollowing => Ok(()),
+            _ => self.expect(Self::DetachedFollowing),
+        }
+    }
+}
+
+fn list_length(len: usize) -> Result<u8, ResponseError> {
+    u8::try_from(len).map_err(|_| ResponseError::TooManyDevices(len))
 }
 
 impl TryFrom<u8> for Response {
@@ -90,7 +168,57 @@ impl TryFrom<u8> for Response {
             3 => Self::CouldNotDetermineSpeed,
             4 => Self::FailedToOpenFd,
             5 => Self::NoSuchDevice,
+            6 => Self::PortOccupied,
+            7 => Self::PortVersionMismatch,
+            8 => Self::NoSuchPort,
+            9 => Self::DetachedFollowing,
+            10 => Self::AttachedFollowing,
             _ => Self::Invalid,
         })
     }
 }
+
+#[derive(Error, Debug)]
+pub enum ResponseError {
+    #[error("Expected the response {expected:?}, but got {actual:?}")]
+    Unexpected {
+        expected: Response,
+        actual: Response,
+    },
+    #[error("Cannot transfer a list of {0} devices, the maximum is 255")]
+    TooManyDevices(usize),
+    #[error("Encountered error during socket IO")]
+    IoError(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_helpers_reject_mismatching_responses() {
+        let (mut sender, mut receiver) = UnixStream::pair().unwrap();
+
+        assert!(matches!(
+            Response::SuccessfulOperation.send_device_list(vec![(1, 2)], &mut sender),
+            Err(ResponseError::Unexpected { .. })
+        ));
+        assert!(matches!(
+            Response::NoSuchDevice.receive_attached_devices(&mut receiver),
+            Err(ResponseError::Unexpected { .. })
+        ));
+        assert!(matches!(
+            Response::ListFollowing.send_device_list(vec![(0, 0); 256], &mut sender),
+            Err(ResponseError::TooManyDevices(256))
+        ));
+
+        Response::ListFollowing
+            .send_device_list(vec![(1, 2), (3, 4)], &mut sender)
+            .unwrap();
+        let response = Response::receive_from_socket(&mut receiver).unwrap();
+        assert_eq!(
+            response.receive_devices_list(&mut receiver).unwrap(),
+            vec![(1, 2), (3, 4)]
+        );
+    }
+}
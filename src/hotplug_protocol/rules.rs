@@ -0,0 +1,266 @@
+//! Rules that select host devices for automatic attachment.
+//!
+//! The rules file is line-based. Empty lines and lines starting with `#`
+//! are ignored. Every other line is an `attach` rule with one or more
+//! `key=value` conditions, which all have to match:
+//!
+//! ```text
+//! # Attach all YubiKeys.
+//! attach vid=1050 pid=0407
+//! # Attach HID devices plugged into host port 1-3.2 to guest port 5.
+//! attach class=03 port=1-3.2 guest-port=5
+//! attach vid=0781 pid=5581 serial=4C530001
+//! # Pass only the audio interfaces of this headset through.
+//! attach vid=046d pid=0a44 interfaces=1,2
+//! ```
+//!
+//! `vid`, `pid` and `class` are hexadecimal. `class` matches the device
+//! class as well as the class of any interface. `port` is the host port
+//! path as named in /sys/bus/usb/devices. `guest-port` and `interfaces` are
+//! not conditions. `guest-port` selects the guest root-hub port the device
+//! is attached to, `interfaces` lists the interface numbers passed through
+//! to the guest, while the host keeps the others.
+use std::{fs, path::Path, str::FromStr};
+
+use thiserror::Error;
+
+use crate::hotplug_protocol::host_devices::HostDevice;
+
+/// An ordered list of attach rules.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+}
+
+/// Attach host devices matching all conditions.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Rule {
+    /// The line of the rule in the rules file (1-based).
+    pub line: usize,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub serial: Option<String>,
+    pub class: Option<u8>,
+    /// Host port path, e.g. `1-3.2`.
+    pub port_path: Option<String>,
+    /// The guest root-hub port to attach matching devices to.
+    pub guest_port: Option<u8>,
+    /// The interfaces of matching devices passed through to the guest.
+    pub interfaces: Option<Vec<u8>>,
+}
+
+impl Rules {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RulesError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|source| RulesError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        content.parse()
+    }
+
+    /// Find the first rule matching the device.
+    #[must_use]
+    pub fn find(&self, device: &HostDevice) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(device))
+    }
+}
+
+impl FromStr for Rules {
+    type Err = RulesError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut rules = Self::default();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            if words.next() != Some("attach") {
+                return Err(RulesError::Parse {
+                    line: index + 1,
+                    message: format!("unknown directive: {line}"),
+                });
+            }
+
+            let rule = Rule::parse(index + 1, words)?;
+            rules.rules.push(rule);
+        }
+
+        Ok(rules)
+    }
+}
+
+impl Rule {
+    /// Parse the `key=value` conditions of the rule on line `line`.
+    pub fn parse<'a>(
+        line: usize,
+        conditions: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, RulesError> {
+        let error = |message: String| RulesError::Parse { line, message };
+        let mut rule = Self {
+            line,
+            ..Self::default()
+        };
+
+        for condition in conditions {
+            rule.parse_condition(condition).map_err(error)?;
+        }
+        if !rule.has_conditions() {
+            return Err(error("attach rule without conditions".to_string()));
+        }
+
+        Ok(rule)
+    }
+
+    #[must_use]
+    pub fn matches(&self, device: &HostDevice) -> bool {
+        let usb_id = &device.usb_id;
+
+        self.vendor_id.is_none_or(|id| id == usb_id.vendor_id)
+            && self.product_id.is_none_or(|id| id == usb_id.product_id)
+            && self
+                .serial
+                .as_ref()
+                .is_none_or(|serial| usb_id.serial.as_ref() == Some(serial))
+            && self.class.is_none_or(|class| {
+                device.class == class || device.interface_classes.contains(&class)
+            })
+            && self
+                .port_path
+                .as_ref()
+                .is_none_or(|path| device.port_path.as_ref() == Some(path))
+    }
+
+    const fn has_conditions(&self) -> bool {
+        self.vendor_id.is_some()
+            || self.product_id.is_some()
+            || self.serial.is_some()
+            || self.class.is_some()
+            || self.port_path.is_some()
+    }
+
+    fn parse_condition(&mut self, condition: &str) -> Result<(), String> {
+        let (key, value) = condition
+            .split_once('=')
+            .filter(|(_, value)| !value.is_empty())
+            .ok_or_else(|| format!("expected key=value, got {condition}"))?;
+        let invalid = || format!("invalid value for {key}: {value}");
+
+        match key {
+            "vid" => self.vendor_id = Some(parse_hex(value).ok_or_else(invalid)?),
+            "pid" => self.product_id = Some(parse_hex(value).ok_or_else(invalid)?),
+            "class" => {
+                let class = parse_hex(value).ok_or_else(invalid)?;
+                self.class = Some(u8::try_from(class).map_err(|_| invalid())?);
+            }
+            "serial" => self.serial = Some(value.to_string()),
+            "port" => self.port_path = Some(value.to_string()),
+            "guest-port" => {
+                let port = value.parse::<u8>().ok().filter(|port| *port != 0);
+                self.guest_port = Some(port.ok_or_else(invalid)?);
+            }
+            "interfaces" => {
+                self.interfaces = Some(parse_interface_list(value).ok_or_else(invalid)?);
+            }
+            _ => return Err(format!("unknown condition: {key}")),
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a comma-separated list of interface numbers, e.g. `0,2`.
+fn parse_interface_list(value: &str) -> Option<Vec<u8>> {
+    value
+        .split(',')
+        .map(|interface| interface.parse::<u8>().ok())
+        .collect()
+}
+
+fn parse_hex(value: &str) -> Option<u16> {
+    (value.len() <= 4)
+        .then(|| u16::from_str_radix(value, 16).ok())
+        .flatten()
+}
+
+#[derive(Error, Debug)]
+pub enum RulesError {
+    #[error("Failed to read the rules file {path}")]
+    Read {
+        path: std::path::PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("line {line}: {message}")]
+    Parse { line: usize, message: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_device(usb_id: &str, class: u8, interface_classes: &[u8], port: &str) -> HostDevice {
+        HostDevice {
+            bus: 1,
+            device: 2,
+            usb_id: usb_id.parse().unwrap(),
+            class,
+            interface_classes: interface_classes.to_vec(),
+            port_path: Some(port.to_string()),
+            manufacturer: None,
+            product: None,
+        }
+    }
+
+    #[test]
+    fn parse_and_match_rules() {
+        let rules = "
+            # comment
+            attach vid=1050 pid=0407
+
+            attach class=03 port=1-3.2 guest-port=5
+            attach vid=0781 serial=AAAA interfaces=0,2
+            "
+        .parse::<Rules>()
+        .unwrap();
+        assert_eq!(rules.rules.len(), 3);
+        assert_eq!(rules.rules[1].line, 5);
+        assert_eq!(rules.rules[1].guest_port, Some(5));
+        assert_eq!(rules.rules[1].interfaces, None);
+        assert_eq!(rules.rules[2].interfaces, Some(vec![0, 2]));
+
+        let yubikey = host_device("1050:0407", 0, &[3, 0x0b], "1-1");
+        assert_eq!(rules.find(&yubikey).unwrap().line, 3);
+        let keyboard = host_device("046d:c52b", 0, &[3], "1-3.2");
+        assert_eq!(rules.find(&keyboard).unwrap().line, 5);
+        let keyboard_elsewhere = host_device("046d:c52b", 0, &[3], "1-4");
+        assert_eq!(rules.find(&keyboard_elsewhere), None);
+        let storage = host_device("0781:5581:AAAA", 0, &[8], "1-4");
+        assert_eq!(rules.find(&storage).unwrap().line, 6);
+        let other_storage = host_device("0781:5581:BBBB", 0, &[8], "1-4");
+        assert_eq!(rules.find(&other_storage), None);
+    }
+
+    #[test]
+    fn reject_invalid_rules() {
+        for rules in [
+            "attach",
+            "attach guest-port=5",
+            "attach vid=12345",
+            "attach class=100",
+            "attach vid=1050 interfaces=",
+            "attach vid=1050 interfaces=0,,1",
+            "attach vid=1050 interfaces=256",
+            "attach vid",
+            "attach color=red",
+            "detach vid=1050",
+        ] {
+            rules.parse::<Rules>().unwrap_err();
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{fmt, str::FromStr};
+
+use thiserror::Error;
+
+/// Identifies USB devices by vendor ID, product ID and optionally the
+/// serial number, written as `vid:pid[:serial]` with hexadecimal IDs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UsbId {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial: Option<String>,
+}
+
+impl UsbId {
+    /// Check whether a device with the given IDs matches. A missing serial
+    /// number in `self` matches any device serial.
+    #[must_use]
+    pub fn matches(&self, vendor_id: u16, product_id: u16, serial: Option<&str>) -> bool {
+        self.vendor_id == vendor_id
+            && self.product_id == product_id
+            && self
+                .serial
+                .as_deref()
+                .is_none_or(|expected| serial == Some(expected))
+    }
+}
+
+impl FromStr for UsbId {
+    type Err = UsbIdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let parse_hex = |part: Option<&str>| {
+            part.filter(|part| !part.is_empty() && part.len() <= 4)
+                .and_then(|part| u16::from_str_radix(part, 16).ok())
+                .ok_or_else(|| UsbIdParseError(s.to_string()))
+        };
+        let vendor_id = parse_hex(parts.next())?;
+        let product_id = parse_hex(parts.next())?;
+        let serial = match parts.next() {
+            Some("") => return Err(UsbIdParseError(s.to_string())),
+            serial => serial.map(str::to_string),
+        };
+
+        Ok(Self {
+            vendor_id,
+            product_id,
+            serial,
+        })
+    }
+}
+
+impl fmt::Display for UsbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor_id, self.product_id)?;
+        if let Some(serial) = &self.serial {
+            write!(f, ":{serial}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Expected a USB ID in the form vid:pid[:serial] with hexadecimal IDs, but received {0}")]
+pub struct UsbIdParseError(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display_usb_id() {
+        let id: UsbId = "046d:C52b".parse().unwrap();
+        assert_eq!(
+            id,
+            UsbId {
+                vendor_id: 0x046d,
+                product_id: 0xc52b,
+                serial: None
+            }
+        );
+        assert_eq!(id.to_string(), "046d:c52b");
+
+        let id: UsbId = "0781:5581:4C53:0001".parse().unwrap();
+        assert_eq!(id.serial.as_deref(), Some("4C53:0001"));
+        assert_eq!(id.to_string(), "0781:5581:4C53:0001");
+
+        for invalid in ["", "046d", "046d:", "xyz:c52b", "12345:0001", "046d:c52b:"] {
+            invalid.parse::<UsbId>().unwrap_err();
+        }
+    }
+
+    #[test]
+    fn usb_id_matches_serial_only_if_given() {
+        let any_serial: UsbId = "046d:c52b".parse().unwrap();
+        assert!(any_serial.matches(0x046d, 0xc52b, None));
+        assert!(any_serial.matches(0x046d, 0xc52b, Some("1234")));
+        assert!(!any_serial.matches(0x046d, 0xc52c, None));
+
+        let with_serial: UsbId = "046d:c52b:1234".parse().unwrap();
+        assert!(with_serial.matches(0x046d, 0xc52b, Some("1234")));
+        assert!(!with_serial.matches(0x046d, 0xc52b, Some("4321")));
+        assert!(!with_serial.matches(0x046d, 0xc52b, None));
+    }
+}
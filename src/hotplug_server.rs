@@ -1,85 +1,322 @@
 //! Receive runtime commands
 
 use std::{
-    fs::File,
-    os::unix::net::{UnixListener, UnixStream},
+    fs::{self, File},
+    io,
+    os::unix::{
+        fs::{chown, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::Arc,
+    time::Duration,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use nusb::MaybeFuture;
-use tokio::runtime;
+use tokio::{
+    net, runtime,
+    sync::Semaphore,
+    time::{sleep, timeout},
+};
 use tracing::{debug, warn};
-use usbvfiod::hotplug_protocol::{command::Command, response::Response};
+use usbvfiod::hotplug_protocol::{
+    attached_device::AttachedDevice,
+    command::{Command, CommandReceiveError, DetachSelector},
+    host_devices::{HostDeviceEvent, HostDeviceWatcher},
+    response::{Response, ResponseError},
+};
 
-use crate::device::xhci::{
-    nusb::NusbRealDevice, port::HotplugControl, real_device::CompleteRealDeviceImpl,
+use crate::{
+    config::Config,
+    device::xhci::{
+        nusb::{read_host_device_info, NusbCompleteRealDevice, NusbRealDevice},
+        port::{DeviceSelector, HotplugControl, PortDevice},
+        real_device::{CompleteRealDevice, CompleteRealDeviceImpl, DeviceIdentity, Identifier},
+    },
+    reattach::Assignments,
 };
 
-pub fn run_hotplug_server(
+/// Settings for the hotplug socket and the connections accepted on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HotplugServerConfig {
+    /// Permission bits applied to the socket file after binding.
+    pub mode: Option<u32>,
+    /// Owner applied to the socket file after binding.
+    pub uid: Option<u32>,
+    /// Group applied to the socket file after binding.
+    pub gid: Option<u32>,
+    /// Maximum number of connections that are handled concurrently.
+    pub max_connections: usize,
+    /// Timeout for reading a command from and writing a response to a
+    /// connection.
+    pub io_timeout: Duration,
+}
+
+impl Default for HotplugServerConfig {
+    fn default() -> Self {
+        Self {
+            mode: None,
+            uid: None,
+            gid: None,
+            max_connections: 16,
+            io_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Bind the hotplug socket at `path` and apply the configured file
+/// permissions.
+///
+/// A socket file left over from a previous run is removed. Binding fails
+/// if another process still listens on the socket or if the path is not
+/// a socket.
+pub fn bind_hotplug_socket(path: &Path, config: &HotplugServerConfig) -> Result<UnixListener> {
+    remove_stale_socket(path)?;
+
+    let socket = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind hotplug socket at {}", path.display()))?;
+
+    if let Some(mode) = config.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).with_context(|| {
+            format!(
+                "Failed to set mode {mode:o} on hotplug socket {}",
+                path.display()
+            )
+        })?;
+    }
+    if config.uid.is_some() || config.gid.is_some() {
+        chown(path, config.uid, config.gid).with_context(|| {
+            format!(
+                "Failed to change owner of hotplug socket {}",
+                path.display()
+            )
+        })?;
+    }
+
+    Ok(socket)
+}
+
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to inspect {}", path.display()));
+        }
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!(
+            "Refusing to replace {}: the path exists and is not a socket",
+            path.display()
+        ));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(anyhow!(
+            "Hotplug socket {} is in use by another process",
+            path.display()
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            debug!("Removing stale hotplug socket {}", path.display());
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to probe socket {}", path.display())),
+    }
+}
+
+/// Everything handling hotplug commands needs besides the connection.
+#[derive(Debug, Clone)]
+pub struct HotplugContext {
+    pub device_config: Arc<Config>,
+    pub assignments: Arc<Assignments>,
+    pub hotplug_control: HotplugControl<NusbCompleteRealDevice>,
+    pub async_runtime: runtime::Handle,
+}
+
+pub async fn run_hotplug_server(
     socket: UnixListener,
-    hotplug_control: HotplugControl<CompleteRealDeviceImpl<NusbRealDevice, (u8, u8)>>,
-    async_runtime: runtime::Handle,
+    config: HotplugServerConfig,
+    context: HotplugContext,
 ) {
+    if let Err(e) = run_accept_loop(socket, config, context).await {
+        warn!("Hotplug server stopped {e:?}");
+    }
+}
+
+/// How long to wait before accepting connections again after an error.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// function only returns on error, but cannot use ! in Result
+async fn run_accept_loop(
+    socket: UnixListener,
+    config: HotplugServerConfig,
+    context: HotplugContext,
+) -> Result<()> {
+    socket
+        .set_nonblocking(true)
+        .context("Failed to make the hotplug socket non-blocking")?;
+    let socket = net::UnixListener::from_std(socket)
+        .context("Failed to register the hotplug socket with the async runtime")?;
+    let connection_limit = Arc::new(Semaphore::new(config.max_connections));
+
     loop {
-        if let Ok((mut stream, _addr)) = socket.accept() {
-            match Command::receive_from_socket(&stream) {
-                Ok(command) => {
-                    debug!("Received command {:?} on hotplug socket", command);
-                    if let Err(e) =
-                        handle_command(command, &mut stream, &hotplug_control, &async_runtime)
-                    {
-                        // The error contains all the necessary context
-                        warn!("{:?}", e);
-                    }
-                }
-                Err(e) => warn!("Error occurred while reading a hotplug command {}", e),
+        let stream = match socket.accept().await {
+            Ok((stream, _addr)) => stream,
+            Err(e) => {
+                warn!("Failed to accept hotplug connection: {e}");
+                // Errors like EMFILE persist until connections are closed,
+                // so do not retry right away.
+                sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
             }
-        }
+        };
+
+        let Ok(permit) = connection_limit.clone().try_acquire_owned() else {
+            warn!(
+                "Rejecting hotplug connection: limit of {} concurrent connections reached",
+                config.max_connections
+            );
+            continue;
+        };
+
+        let context = context.clone();
+        // The hotplug protocol and opening devices via nusb use blocking
+        // calls, so every connection is handled on the blocking pool.
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = handle_connection(stream, config.io_timeout, &context) {
+                // The error contains all the necessary context
+                warn!("{e:?}");
+            }
+            drop(permit);
+        });
+    }
+}
+
+fn handle_connection(
+    stream: net::UnixStream,
+    io_timeout: Duration,
+    context: &HotplugContext,
+) -> Result<()> {
+    let mut stream = stream
+        .into_std()
+        .context("Failed to take over hotplug connection")?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(io_timeout))?;
+    stream.set_write_timeout(Some(io_timeout))?;
+
+    // Clients may send several commands over the same connection.
+    loop {
+        let command = match Command::receive_from_socket(&stream) {
+            Ok(command) => command,
+            // Idle clients are disconnected, so they do not hold on to a
+            // connection slot.
+            Err(e @ (CommandReceiveError::ConnectionClosed | CommandReceiveError::TimedOut)) => {
+                debug!("Closing hotplug connection: {e}");
+                return Ok(());
+            }
+            Err(e) => return Err(e).context("Error occurred while reading a hotplug command"),
+        };
+        debug!("Received command {:?} on hotplug socket", command);
+
+        handle_command(command, &mut stream, context)?;
     }
 }
 
 fn handle_command(
     command: Command,
     socket: &mut UnixStream,
-    hotplug_control: &HotplugControl<CompleteRealDeviceImpl<NusbRealDevice, (u8, u8)>>,
-    async_runtime: &runtime::Handle,
+    context: &HotplugContext,
 ) -> Result<()> {
+    let HotplugContext {
+        hotplug_control,
+        async_runtime,
+        ..
+    } = context;
+
     match command {
         Command::Attach {
             bus,
             device: dev,
+            port,
+            interfaces,
             fd,
-        } => handle_attach(bus, dev, fd, socket, hotplug_control, async_runtime)
+            urb_fd,
+        } => handle_attach((bus, dev), port, interfaces, fd, urb_fd, socket, context)
             .context("Failed to handle attach command")?,
-        Command::Detach { bus, device } => {
-            handle_detach(bus, device, socket, hotplug_control, async_runtime)
-                .context("Failed to handle detach command")?;
+        Command::Detach(selector) => {
+            handle_detach(selector, socket, context).context("Failed to handle detach command")?;
+        }
+        Command::LegacyDetach { bus, device } => {
+            let detached = detach_devices(DeviceSelector::BusDevice(bus, device), context);
+            if detached.is_empty() {
+                Response::NoSuchDevice
+            } else {
+                Response::SuccessfulOperation
+            }
+            .send_over_socket(socket)
+            .context("Successfully performed detach command, but failed to send the response")?;
         }
         Command::List => {
-            let devices = async_runtime.block_on(hotplug_control.list_devices());
+            let devices = async_runtime
+                .block_on(hotplug_control.list_devices())
+                .iter()
+                .map(Identifier::bus_device)
+                .collect();
             Response::ListFollowing
                 .send_device_list(devices, socket)
                 .context("Failed to handle list command")?;
         }
+        Command::ListDetailed => {
+            let devices = async_runtime
+                .block_on(hotplug_control.list_ports())
+                .iter()
+                .map(attached_device)
+                .collect::<Vec<_>>();
+            Response::AttachedFollowing
+                .send_attached_devices(&devices, socket)
+                .context("Failed to handle list command")?;
+        }
     }
 
     Ok(())
 }
 
 fn handle_attach(
-    bus: u8,
-    dev: u8,
+    (bus, dev): (u8, u8),
+    port: Option<u8>,
+    interfaces: Option<Vec<u8>>,
     fd: File,
+    urb_fd: Option<File>,
     socket: &mut UnixStream,
-    hotplug_control: &HotplugControl<CompleteRealDeviceImpl<NusbRealDevice, (u8, u8)>>,
-    async_runtime: &runtime::Handle,
+    context: &HotplugContext,
 ) -> Result<()> {
+    let usbfs = fd
+        .try_clone()
+        .context("Failed to duplicate the supplied file descriptor")?;
     let device = nusb::Device::from_fd(fd.into())
         .wait()
         .context("Failed to open nusb device from the supplied file descriptor")?;
-    let real_device = NusbRealDevice::try_new(device, async_runtime.clone())?;
-    let complete_device = CompleteRealDeviceImpl::new((bus, dev), real_device);
-    let response = async_runtime.block_on(hotplug_control.attach(complete_device));
+    let host_info = read_host_device_info(&device, bus, dev);
+    let port = port.or_else(|| context.device_config.pinned_port_of(&host_info));
+    let real_device = NusbRealDevice::try_new(
+        device,
+        usbfs.into(),
+        urb_fd.map(Into::into),
+        interfaces.clone(),
+        context.device_config.reattach_drivers(&host_info),
+        context.async_runtime.clone(),
+    )?;
+    let identity = DeviceIdentity::new((bus, dev), &host_info);
+    let complete_device = CompleteRealDeviceImpl::new(identity.clone(), host_info, real_device);
+    let response = context
+        .async_runtime
+        .block_on(context.hotplug_control.attach(complete_device, port));
+    if response == Response::SuccessfulOperation {
+        context.assignments.record(identity, port, interfaces);
+    }
     response
         .send_over_socket(socket)
         .context("Successfully performed hot-plug command, but failed to send the response")?;
@@ -88,16 +325,161 @@ fn handle_attach(
 }
 
 fn handle_detach(
-    bus: u8,
-    dev: u8,
+    selector: DetachSelector,
     socket: &mut UnixStream,
-    hotplug_control: &HotplugControl<CompleteRealDeviceImpl<NusbRealDevice, (u8, u8)>>,
-    async_runtime: &runtime::Handle,
+    context: &HotplugContext,
 ) -> Result<()> {
-    let response = async_runtime.block_on(hotplug_control.detach((bus, dev)));
-    response
-        .send_over_socket(socket)
-        .context("Successfully performed detach command, but failed to send the response")?;
+    let selector = match selector {
+        DetachSelector::Device { bus, device } => DeviceSelector::BusDevice(bus, device),
+        DetachSelector::Port(port) => DeviceSelector::Port(port),
+        DetachSelector::UsbId(usb_id) => DeviceSelector::UsbId(usb_id),
+        DetachSelector::HostPort(path) => DeviceSelector::HostPort(path),
+        DetachSelector::All => DeviceSelector::All,
+    };
+    let detached = detach_devices(selector, context);
+
+    if detached.is_empty() {
+        Response::NoSuchDevice
+            .send_over_socket(socket)
+            .map_err(ResponseError::from)
+    } else {
+        Response::DetachedFollowing.send_attached_devices(&detached, socket)
+    }
+    .context("Successfully performed detach command, but failed to send the response")?;
 
     Ok(())
 }
+
+fn detach_devices(selector: DeviceSelector, context: &HotplugContext) -> Vec<AttachedDevice> {
+    let detached = context
+        .async_runtime
+        .block_on(context.hotplug_control.detach(selector));
+    // Devices detached on request are not re-attached when replugged.
+    for port_device in &detached {
+        context.assignments.remove(&port_device.device.identifier());
+    }
+
+    detached.iter().map(attached_device).collect()
+}
+
+/// How long to wait for a detached device to release its interfaces.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Detach all devices and wait until their interfaces are released, so that
+/// the host kernel drivers can be bound again.
+pub async fn release_devices(hotplug_control: &HotplugControl<NusbCompleteRealDevice>) {
+    // The detached devices are dropped right away, only the endpoint
+    // handles may keep them alive until they notice the detach.
+    let released = hotplug_control
+        .detach(DeviceSelector::All)
+        .await
+        .into_iter()
+        .map(|port_device| port_device.device.realdevice_ref().released())
+        .collect::<Vec<_>>();
+
+    for token in &released {
+        if timeout(RELEASE_TIMEOUT, token.cancelled()).await.is_err() {
+            warn!("A detached device was not released in time");
+        }
+    }
+}
+
+/// Detach devices as soon as the host reports their removal.
+///
+/// Otherwise, removal is only noticed when a transfer fails, which never
+/// happens for a device the guest does not use.
+pub async fn watch_host_removals(hotplug_control: HotplugControl<NusbCompleteRealDevice>) {
+    let mut watcher = match HostDeviceWatcher::new() {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Cannot watch the host for removed devices: {e:?}");
+            return;
+        }
+    };
+
+    while let Some(event) = watcher.next().await {
+        let HostDeviceEvent::Removed(host_device) = event else {
+            continue;
+        };
+        // The bus and device number name exactly the removed device, while
+        // its successor may already be plugged in with the same identity.
+        let removed = (host_device.bus, host_device.device);
+        for port_device in hotplug_control.list_ports().await {
+            if port_device.device.identifier().bus_device() == removed {
+                debug!(
+                    "Device {} was removed from the host",
+                    port_device.device.identifier()
+                );
+                // The detach listener takes care of the rest.
+                port_device.device.detach_token().cancel();
+            }
+        }
+    }
+
+    warn!("Stopped watching the host for removed devices");
+}
+
+fn attached_device(port_device: &PortDevice<NusbCompleteRealDevice>) -> AttachedDevice {
+    let (bus, device) = port_device.device.identifier().bus_device();
+    let host_info = port_device.device.host_info();
+    AttachedDevice {
+        port: port_device.port_id,
+        bus,
+        device,
+        usb_id: host_info.usb_id.clone(),
+        host_port_path: host_info.port_path.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("usbvfiod-test-{}-{name}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn bind_replaces_stale_socket() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let config = HotplugServerConfig {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        let _socket = bind_hotplug_socket(&path, &config).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_refuses_socket_in_use() {
+        let path = socket_path("in-use");
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        bind_hotplug_socket(&path, &HotplugServerConfig::default()).unwrap_err();
+        assert!(path.exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_refuses_regular_file() {
+        let path = socket_path("regular-file");
+        File::create(&path).unwrap();
+
+        bind_hotplug_socket(&path, &HotplugServerConfig::default()).unwrap_err();
+        assert!(path.exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,23 +2,41 @@
 
 mod async_runtime;
 mod cli;
+mod config;
 mod device;
 mod dynamic_bus;
 mod hotplug_server;
 mod memory_segment;
 mod one_indexed_array;
 mod oneshot_anyhow;
+mod reattach;
 mod xhci_backend;
 
-use std::{os::unix::net::UnixListener, thread};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::{Context, Result};
 use async_runtime::init_runtime;
 use clap::Parser;
 use cli::Cli;
+use config::Config;
 use device::pcap::UsbPcapManager;
-use hotplug_server::run_hotplug_server;
-use tracing::{info, Level};
+use device::xhci::{nusb::NusbCompleteRealDevice, port::HotplugControl};
+use hotplug_server::{
+    bind_hotplug_socket, release_devices, run_hotplug_server, watch_host_removals, HotplugContext,
+};
+use reattach::{Assignments, Reattacher};
+use tokio::{
+    select,
+    signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    },
+};
+use tracing::{info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 use vfio_user::Server;
 
@@ -49,10 +67,22 @@ fn main() -> Result<()> {
 
     let mut backend = xhci_backend::XhciBackend::new(runtime.clone())
         .context("Failed to create virtual XHCI controller")?;
+    let config = Arc::new(match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    });
+    let assignments = Arc::new(Assignments::default());
+
     for device in &args.devices {
-        let path = device.as_path();
+        let path = device.path.as_path();
         // if initial device attachment fails, make it clear by panicking
-        if let Err(err) = runtime.block_on(backend.add_device_from_path(path, runtime.clone())) {
+        if let Err(err) = runtime.block_on(backend.add_device_from_path(
+            path,
+            device.port,
+            &config,
+            &assignments,
+            runtime.clone(),
+        )) {
             panic!("Device attachment failed for {path:?}: {err}");
         }
     }
@@ -64,27 +94,80 @@ fn main() -> Result<()> {
         unimplemented!("Using a file descriptor as vfio-user connection is not implemented")
     };
 
+    runtime.spawn(watch_host_removals(backend.hotplug_control()));
+
+    let context = HotplugContext {
+        device_config: config.clone(),
+        assignments,
+        hotplug_control: backend.hotplug_control(),
+        async_runtime: runtime.clone(),
+    };
+
     // listen on socket for hot-attach fds
     if let Some(hotplug_socket_path) = args.hotplug_socket_path.clone() {
-        let hotplug_control = backend.hotplug_control();
-        let socket = UnixListener::bind(hotplug_socket_path.as_path()).unwrap();
-        thread::Builder::new()
-            .name("hot-attach-socket listener".to_string())
-            .spawn(move || run_hotplug_server(socket, hotplug_control, runtime.clone()))
-            .unwrap();
+        let server_config = args.hotplug_server_config();
+        let socket = bind_hotplug_socket(&hotplug_socket_path, &server_config)
+            .context("Failed to set up hotplug socket")?;
+        runtime.spawn(run_hotplug_server(socket, server_config, context.clone()));
+    }
+
+    if args.reattach || !config.follow_rules.is_empty() {
+        runtime.spawn(Reattacher::new(context, args.reattach).run());
     }
 
+    let hotplug_control = backend.hotplug_control();
+    runtime.spawn(exit_on_signal(
+        hotplug_control.clone(),
+        args.hotplug_socket_path.clone(),
+    ));
+
     info!("We're up!");
 
     server
         .run(&mut backend)
         .context("Failed to start vfio-user server")?;
 
-    if let Some(hotplug_socket_path) = args.hotplug_socket_path {
+    runtime.block_on(shutdown(
+        &hotplug_control,
+        args.hotplug_socket_path.as_deref(),
+    ));
+
+    Ok(())
+}
+
+/// Give the devices back to the host and remove the hotplug socket.
+async fn shutdown(
+    hotplug_control: &HotplugControl<NusbCompleteRealDevice>,
+    hotplug_socket_path: Option<&Path>,
+) {
+    release_devices(hotplug_control).await;
+
+    if let Some(hotplug_socket_path) = hotplug_socket_path {
         if hotplug_socket_path.exists() {
-            let _ = std::fs::remove_file(&hotplug_socket_path);
+            let _ = fs::remove_file(hotplug_socket_path);
         }
     }
+}
 
-    Ok(())
+/// Shut down cleanly on SIGINT and SIGTERM.
+async fn exit_on_signal(
+    hotplug_control: HotplugControl<NusbCompleteRealDevice>,
+    hotplug_socket_path: Option<PathBuf>,
+) {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("Failed to handle SIGTERM: {e}");
+            return;
+        }
+    };
+
+    select! {
+        _ = ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+
+    info!("Shutting down");
+    shutdown(&hotplug_control, hotplug_socket_path.as_deref()).await;
+    std::process::exit(0);
 }
@@ -0,0 +1,601 @@
+//! Re-attach devices after they were unplugged and plugged in again.
+//!
+//! Every attached device leaves an assignment behind, which records the
+//! identity of the device and the guest root-hub port it was requested on.
+//! Unplugging the device from the host keeps the assignment, only an
+//! explicit detach removes it. When a device with a recorded identity
+//! appears on the host again, it is opened and attached as before. If the
+//! host port of the assignment is pinned, any device plugged into that port
+//! takes the place of the old one. The interfaces passed through are
+//! restricted as before, unless another device took the place.
+//!
+//! Devices with a follow rule are followed when they re-enumerate, e.g. to
+//! switch into firmware update mode: whatever device appears on the same
+//! host port within the timeout is attached to the guest port of the lost
+//! device. Devices attached this way are followed, too. The successor may
+//! appear on the host before the loss of its predecessor is noticed, so
+//! arrivals are remembered for a short grace period.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use nusb::MaybeFuture;
+use tokio::{runtime, select, sync::broadcast::error::RecvError};
+use tracing::{debug, info, warn};
+use usbvfiod::hotplug_protocol::{
+    device_file::DeviceFile,
+    host_devices::{HostDevice, HostDeviceEvent, HostDeviceWatcher},
+    response::Response,
+};
+
+use crate::{
+    config::Config,
+    device::xhci::{
+        nusb::{read_host_device_info, NusbCompleteRealDevice, NusbRealDevice},
+        port::LostDevice,
+        real_device::{
+            CompleteRealDevice, CompleteRealDeviceImpl, DeviceIdentity, HostDeviceInfo, Identifier,
+        },
+    },
+    hotplug_server::HotplugContext,
+};
+
+/// How long an arrived device may wait for the loss of the followed device
+/// it replaces.
+const ARRIVAL_GRACE: Duration = Duration::from_secs(2);
+
+/// A device that should be attached whenever it is present on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub identity: DeviceIdentity,
+    /// The guest root-hub port the device was requested on, if any.
+    pub port: Option<u8>,
+    /// The interfaces passed through, if not all.
+    pub interfaces: Option<Vec<u8>>,
+}
+
+impl Assignment {
+    fn matches(&self, host_info: &HostDeviceInfo, config: &Config) -> bool {
+        // Without a host port, the identity does not survive a replug.
+        let Some(path) = self.identity.port_path.as_deref() else {
+            return false;
+        };
+
+        host_info.port_path.as_deref() == Some(path)
+            && (self.identity.usb_id == host_info.usb_id || config.host_port_pin(path).is_some())
+    }
+}
+
+/// The assignments of all devices attached since startup.
+#[derive(Debug, Default)]
+pub struct Assignments {
+    assignments: Mutex<Vec<Assignment>>,
+}
+
+impl Assignments {
+    /// Record that the device was attached. The assignment replaces earlier
+    /// ones of the same device or host port.
+    pub fn record(&self, identity: DeviceIdentity, port: Option<u8>, interfaces: Option<Vec<u8>>) {
+        let mut assignments = self.assignments.lock().unwrap();
+        assignments.retain(|assignment| {
+            assignment.identity != identity
+                && (assignment.identity.port_path.is_none()
+                    || assignment.identity.port_path != identity.port_path)
+        });
+        assignments.push(Assignment {
+            identity,
+            port,
+            interfaces,
+        });
+    }
+
+    /// Forget the device, e.g. because it was detached on request.
+    pub fn remove(&self, identity: &DeviceIdentity) {
+        self.assignments
+            .lock()
+            .unwrap()
+            .retain(|assignment| assignment.identity != *identity);
+    }
+
+    /// Find the assignment a device appearing on the host belongs to.
+    pub fn find(&self, host_info: &HostDeviceInfo, config: &Config) -> Option<Assignment> {
+        self.assignments
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|assignment| assignment.matches(host_info, config))
+            .cloned()
+    }
+}
+
+/// Watches the host for devices to attach again.
+#[derive(Debug)]
+pub struct Reattacher {
+    context: HotplugContext,
+    /// Whether devices with an assignment are attached again. Otherwise,
+    /// only followed devices are.
+    reattach: bool,
+    follows: Follows,
+}
+
+impl Reattacher {
+    pub fn new(context: HotplugContext, reattach: bool) -> Self {
+        Self {
+            context,
+            reattach,
+            follows: Follows::default(),
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut lost_devices = self.context.hotplug_control.subscribe_lost_devices();
+        let mut watcher = match HostDeviceWatcher::new() {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                warn!("Cannot re-attach replugged devices: {e:?}");
+                return;
+            }
+        };
+
+        loop {
+            select! {
+                // Usually, a re-enumerating device is lost before its
+                // successor appears.
+                biased;
+                lost = lost_devices.recv() => match lost {
+                    Ok(lost) => {
+                        let successor = self.follows.lost(&lost, &self.context.device_config, Instant::now());
+                        if let Some((waiting, host_device)) = successor {
+                            if let Err(e) = self.follow(waiting, host_device).await {
+                                warn!("{e:?}");
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => warn!("Missed {count} lost devices"),
+                    Err(RecvError::Closed) => break,
+                },
+                event = watcher.next() => match event {
+                    Some(HostDeviceEvent::Arrived(host_device)) => {
+                        if let Err(e) = self.arrived(host_device).await {
+                            warn!("{e:?}");
+                        }
+                    }
+                    Some(HostDeviceEvent::Removed(host_device)) => self.follows.removed(&host_device),
+                    None => break,
+                },
+            }
+        }
+
+        warn!("Stopped watching the host for replugged devices");
+    }
+
+    async fn arrived(&mut self, host_device: HostDevice) -> Result<()> {
+        let HotplugContext {
+            device_config,
+            assignments,
+            hotplug_control,
+            ..
+        } = &self.context;
+        let host_info = HostDeviceInfo {
+            usb_id: host_device.usb_id.clone(),
+            port_path: host_device.port_path.clone(),
+        };
+
+        if let Some(waiting) = self.follows.arrived(&host_device, Instant::now()) {
+            return self.follow(waiting, host_device).await;
+        }
+
+        if !self.reattach {
+            return Ok(());
+        }
+        let Some(assignment) = assignments.find(&host_info, device_config) else {
+            return Ok(());
+        };
+        // Devices present at startup are reported as arrived, too.
+        let identity = DeviceIdentity::new((host_device.bus, host_device.device), &host_info);
+        if is_attached(&hotplug_control.list_devices().await, &host_device) {
+            debug!("Device {identity} is already attached");
+            return Ok(());
+        }
+
+        info!("Re-attaching device {identity}");
+        // The interface numbers are meaningless for another device taking
+        // the place on a pinned host port.
+        let interfaces = assignment
+            .interfaces
+            .filter(|_| assignment.identity.usb_id == host_info.usb_id);
+        let device = self.open(host_device, interfaces.clone()).await?;
+        let identity = device.identifier();
+        let port = assignment
+            .port
+            .or_else(|| device_config.pinned_port_of(device.host_info()));
+        match hotplug_control.attach(device, port).await {
+            Response::SuccessfulOperation => assignments.record(identity, port, interfaces),
+            response => warn!("Failed to re-attach device {identity}: {response:?}"),
+        }
+
+        Ok(())
+    }
+
+    /// Attach the successor of a followed device to the guest port of the
+    /// lost device.
+    async fn follow(&mut self, waiting: Waiting, host_device: HostDevice) -> Result<()> {
+        let HotplugContext {
+            assignments,
+            hotplug_control,
+            ..
+        } = &self.context;
+        let identity = DeviceIdentity::new(
+            (host_device.bus, host_device.device),
+            &HostDeviceInfo {
+                usb_id: host_device.usb_id.clone(),
+                port_path: host_device.port_path.clone(),
+            },
+        );
+        // An early successor may have been re-attached in the meantime.
+        if is_attached(&hotplug_control.list_devices().await, &host_device) {
+            debug!("Device {identity} is already attached");
+            return Ok(());
+        }
+
+        info!(
+            "Following the device on host port {} to port {}",
+            waiting.host_port, waiting.port
+        );
+        let device = self.open(host_device, None).await?;
+        let identity = device.identifier();
+        match hotplug_control.attach(device, Some(waiting.port)).await {
+            Response::SuccessfulOperation => {
+                assignments.record(identity.clone(), Some(waiting.port), None);
+                self.follows.followed(identity, waiting.timeout);
+            }
+            response => warn!("Failed to attach the device {identity}: {response:?}"),
+        }
+
+        Ok(())
+    }
+
+    async fn open(
+        &self,
+        host_device: HostDevice,
+        interfaces: Option<Vec<u8>>,
+    ) -> Result<NusbCompleteRealDevice> {
+        let async_runtime = self.context.async_runtime.clone();
+        let config = self.context.device_config.clone();
+        let path = host_device.device_path();
+        tokio::task::spawn_blocking(move || {
+            open_device(&host_device, interfaces, &config, async_runtime)
+        })
+        .await?
+        .with_context(|| format!("Failed to open {}", path.display()))
+    }
+}
+
+/// A lost device that waits for its successor on the host port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Waiting {
+    host_port: String,
+    /// The guest root-hub port of the lost device.
+    port: u8,
+    timeout: Duration,
+    deadline: Instant,
+}
+
+/// Keeps track of followed devices.
+#[derive(Debug, Default)]
+struct Follows {
+    /// Devices attached by following, with their timeout.
+    followed: Vec<(DeviceIdentity, Duration)>,
+    waiting: Vec<Waiting>,
+    /// Devices that arrived recently, in case they replace a followed device
+    /// whose loss is noticed later.
+    arrivals: Vec<(HostDevice, Instant)>,
+}
+
+impl Follows {
+    /// Wait for the successor of the lost device if it is followed. Returns
+    /// the successor if it already arrived.
+    fn lost(
+        &mut self,
+        lost: &LostDevice<DeviceIdentity>,
+        config: &Config,
+        now: Instant,
+    ) -> Option<(Waiting, HostDevice)> {
+        let followed = self
+            .followed
+            .iter()
+            .find(|(identity, _)| *identity == lost.identifier)
+            .map(|(_, timeout)| *timeout);
+        self.followed
+            .retain(|(identity, _)| *identity != lost.identifier);
+
+        let timeout = followed.or_else(|| config.follow_timeout(&lost.host_info));
+        let (Some(timeout), Some(host_port)) = (timeout, lost.host_info.port_path.clone()) else {
+            return None;
+        };
+        self.waiting
+            .retain(|waiting| waiting.host_port != host_port);
+        let waiting = Waiting {
+            host_port,
+            port: lost.port_id,
+            timeout,
+            deadline: now + timeout,
+        };
+
+        self.arrivals
+            .retain(|(_, arrived)| now.saturating_duration_since(*arrived) <= ARRIVAL_GRACE);
+        let successor = self.arrivals.iter().rposition(|(host_device, _)| {
+            host_device.port_path.as_deref() == Some(waiting.host_port.as_str())
+                && (host_device.bus, host_device.device)
+                    != (lost.identifier.bus, lost.identifier.device)
+        });
+        if let Some(index) = successor {
+            let (host_device, _) = self.arrivals.remove(index);
+            return Some((waiting, host_device));
+        }
+
+        debug!(
+            "Waiting {timeout:?} for a device on host port {}",
+            waiting.host_port
+        );
+        self.waiting.push(waiting);
+        None
+    }
+
+    /// Take the lost device waiting for the arrived device. Otherwise, the
+    /// arrival is remembered in case a followed device on the same host port
+    /// turns out to be lost.
+    fn arrived(&mut self, host_device: &HostDevice, now: Instant) -> Option<Waiting> {
+        self.waiting.retain(|waiting| waiting.deadline >= now);
+        let host_port = host_device.port_path.as_deref()?;
+        let Some(index) = self
+            .waiting
+            .iter()
+            .position(|waiting| waiting.host_port == host_port)
+        else {
+            self.arrivals
+                .retain(|(_, arrived)| now.saturating_duration_since(*arrived) <= ARRIVAL_GRACE);
+            self.arrivals.push((host_device.clone(), now));
+            return None;
+        };
+
+        Some(self.waiting.remove(index))
+    }
+
+    /// Forget the arrival of a device that is gone again.
+    fn removed(&mut self, host_device: &HostDevice) {
+        self.arrivals.retain(|(arrived, _)| arrived != host_device);
+    }
+
+    fn followed(&mut self, identity: DeviceIdentity, timeout: Duration) {
+        self.followed
+            .retain(|(followed, _)| followed.port_path != identity.port_path);
+        self.followed.push((identity, timeout));
+    }
+}
+
+/// Whether the host device itself is attached. An attached device with the
+/// same identity may be the unplugged predecessor whose removal is not
+/// handled yet, so only the bus and device number count. Attaching replaces
+/// the predecessor.
+fn is_attached(attached: &[DeviceIdentity], host_device: &HostDevice) -> bool {
+    attached
+        .iter()
+        .any(|identity| identity.bus_device() == (host_device.bus, host_device.device))
+}
+
+fn open_device(
+    host_device: &HostDevice,
+    interfaces: Option<Vec<u8>>,
+    config: &Config,
+    async_runtime: runtime::Handle,
+) -> Result<NusbCompleteRealDevice> {
+    let DeviceFile {
+        bus,
+        device: dev,
+        file,
+        urb_file,
+        ..
+    } = DeviceFile::open_and_reset(host_device.device_path())?;
+    let usbfs = file.try_clone()?;
+    let device = nusb::Device::from_fd(file.into()).wait()?;
+    let host_info = read_host_device_info(&device, bus, dev);
+    let real_device = NusbRealDevice::try_new(
+        device,
+        usbfs.into(),
+        Some(urb_file.into()),
+        interfaces,
+        config.reattach_drivers(&host_info),
+        async_runtime,
+    )?;
+
+    Ok(CompleteRealDeviceImpl::new(
+        DeviceIdentity::new((bus, dev), &host_info),
+        host_info,
+        real_device,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(bus_device: (u8, u8), usb_id: &str, port_path: Option<&str>) -> DeviceIdentity {
+        DeviceIdentity::new(bus_device, &host_info(usb_id, port_path))
+    }
+
+    fn host_info(usb_id: &str, port_path: Option<&str>) -> HostDeviceInfo {
+        HostDeviceInfo {
+            usb_id: usb_id.parse().unwrap(),
+            port_path: port_path.map(str::to_string),
+        }
+    }
+
+    fn host_device((bus, device): (u8, u8), usb_id: &str, port_path: &str) -> HostDevice {
+        HostDevice {
+            bus,
+            device,
+            usb_id: usb_id.parse().unwrap(),
+            class: 0,
+            interface_classes: vec![],
+            port_path: Some(port_path.to_string()),
+            manufacturer: None,
+            product: None,
+        }
+    }
+
+    fn follow_config() -> Config {
+        Config {
+            follow_rules: vec![crate::config::FollowRule {
+                device: crate::config::DevicePattern::UsbId("12d1:1506".parse().unwrap()),
+                timeout: Duration::from_secs(10),
+            }],
+            ..Config::default()
+        }
+    }
+
+    fn lost(identity: DeviceIdentity, port_id: u8) -> LostDevice<DeviceIdentity> {
+        LostDevice {
+            port_id,
+            host_info: HostDeviceInfo {
+                usb_id: identity.usb_id.clone(),
+                port_path: identity.port_path.clone(),
+            },
+            identifier: identity,
+        }
+    }
+
+    #[test]
+    fn assignments_match_replugged_devices() {
+        let config = Config {
+            host_port_pins: vec![crate::config::HostPortPin {
+                host_port: "1-4".to_string(),
+                port: 3,
+            }],
+            ..Config::default()
+        };
+        let assignments = Assignments::default();
+        assignments.record(
+            identity((1, 5), "1050:0407", Some("1-2")),
+            Some(5),
+            Some(vec![1]),
+        );
+        assignments.record(identity((1, 6), "046d:c52b", Some("1-4")), Some(3), None);
+        assignments.record(identity((1, 7), "0781:5581", None), None, None);
+
+        // the same device on the same host port
+        let found = assignments
+            .find(&host_info("1050:0407", Some("1-2")), &config)
+            .unwrap();
+        assert_eq!(found.port, Some(5));
+        assert_eq!(found.interfaces, Some(vec![1]));
+        // another device on an unpinned host port
+        assert_eq!(
+            assignments.find(&host_info("046d:c52b", Some("1-2")), &config),
+            None
+        );
+        // any device on a pinned host port
+        let found = assignments.find(&host_info("1050:0407", Some("1-4")), &config);
+        assert_eq!(found.unwrap().port, Some(3));
+        // without host port, there is no way to recognize the device
+        assert_eq!(
+            assignments.find(&host_info("0781:5581", None), &config),
+            None
+        );
+
+        // a detached device is not attached again
+        assignments.remove(&identity((1, 9), "1050:0407", Some("1-2")));
+        assert_eq!(
+            assignments.find(&host_info("1050:0407", Some("1-2")), &config),
+            None
+        );
+
+        // a new device on the host port replaces the old assignment
+        assignments.record(identity((1, 8), "1234:5678", Some("1-4")), Some(3), None);
+        assert_eq!(assignments.assignments.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn replugged_devices_are_not_mistaken_for_their_predecessor() {
+        // the replugged device arrives before the removal of the old one
+        let attached = [identity((1, 5), "1050:0407", Some("1-2"))];
+        let replugged = host_device((1, 9), "1050:0407", "1-2");
+        // the identity cannot tell them apart
+        assert_eq!(attached[0], identity((1, 9), "1050:0407", Some("1-2")));
+        assert!(!is_attached(&attached, &replugged));
+
+        // devices present at startup are attached already
+        assert!(is_attached(
+            &attached,
+            &host_device((1, 5), "1050:0407", "1-2")
+        ));
+    }
+
+    #[test]
+    fn follows_wait_for_the_successor_on_the_host_port() {
+        let config = follow_config();
+        let start = Instant::now();
+        let mut follows = Follows::default();
+
+        // devices without follow rule are not followed
+        follows.lost(
+            &lost(identity((1, 2), "1050:0407", Some("1-2")), 1),
+            &config,
+            start,
+        );
+        let key = host_device((1, 5), "1050:0407", "1-2");
+        assert_eq!(follows.arrived(&key, start), None);
+
+        // a followed device is replaced by whatever appears on its host port
+        let modem = identity((1, 3), "12d1:1506", Some("1-3"));
+        assert_eq!(follows.lost(&lost(modem, 5), &config, start), None);
+        let mouse = host_device((1, 6), "046d:c52b", "1-4");
+        assert_eq!(follows.arrived(&mouse, start), None);
+        let successor = host_device((1, 4), "12d1:1c05", "1-3");
+        let waiting = follows.arrived(&successor, start).unwrap();
+        assert_eq!(waiting.port, 5);
+        assert_eq!(follows.arrived(&successor, start), None);
+
+        // the successor is followed as well, but only within the timeout
+        let updater = identity((1, 4), "12d1:1c05", Some("1-3"));
+        follows.followed(updater.clone(), waiting.timeout);
+        follows.lost(&lost(updater, 5), &config, start);
+        assert_eq!(
+            follows.arrived(
+                &host_device((1, 7), "12d1:1506", "1-3"),
+                start + Duration::from_secs(11)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn follows_take_successors_that_arrived_early() {
+        let config = follow_config();
+        let start = Instant::now();
+        let mut follows = Follows::default();
+        let modem = identity((1, 3), "12d1:1506", Some("1-3"));
+        let successor = host_device((1, 4), "12d1:1c05", "1-3");
+
+        // the successor appears before the loss of the modem is noticed
+        assert_eq!(follows.arrived(&successor, start), None);
+        let (waiting, followed) = follows
+            .lost(&lost(modem.clone(), 5), &config, start + ARRIVAL_GRACE)
+            .unwrap();
+        assert_eq!(waiting.port, 5);
+        assert_eq!(followed, successor);
+        assert!(follows.waiting.is_empty());
+
+        // arrivals are forgotten after the grace period or when removed
+        assert_eq!(follows.arrived(&successor, start), None);
+        let later = start + ARRIVAL_GRACE + Duration::from_millis(1);
+        assert_eq!(follows.lost(&lost(modem.clone(), 5), &config, later), None);
+
+        let mut follows = Follows::default();
+        assert_eq!(follows.arrived(&successor, start), None);
+        follows.removed(&successor);
+        assert_eq!(follows.lost(&lost(modem, 5), &config, start), None);
+    }
+}
@@ -5,12 +5,12 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 use nusb::MaybeFuture;
 use tokio::runtime;
 use tracing::{debug, info, trace};
 
-use usbvfiod::hotplug_protocol::{device_paths::resolve_path, response::Response};
+use usbvfiod::hotplug_protocol::{device_file::DeviceFile, response::Response};
 use vfio_bindings::bindings::vfio::{
     vfio_region_info, VFIO_PCI_BAR0_REGION_INDEX, VFIO_PCI_BAR1_REGION_INDEX,
     VFIO_PCI_BAR2_REGION_INDEX, VFIO_PCI_BAR3_REGION_INDEX, VFIO_PCI_BAR4_REGION_INDEX,
@@ -25,13 +25,15 @@ use crate::device::{
     interrupt_line::{DummyInterruptLine, InterruptLine},
     pci::{traits::PciDevice, xhci::XhciController},
     xhci::{
-        nusb::NusbRealDevice,
+        nusb::{read_host_device_info, NusbCompleteRealDevice, NusbRealDevice},
         port::HotplugControl,
-        real_device::{CompleteRealDevice, CompleteRealDeviceImpl},
+        real_device::{CompleteRealDevice, CompleteRealDeviceImpl, DeviceIdentity},
     },
 };
 
-use crate::{dynamic_bus::DynamicBus, memory_segment::MemorySegment};
+use crate::{
+    config::Config, dynamic_bus::DynamicBus, memory_segment::MemorySegment, reattach::Assignments,
+};
 
 #[derive(Debug)]
 pub struct XhciBackend<CRD: CompleteRealDevice> {
@@ -84,37 +86,43 @@ impl<CRD: CompleteRealDevice> XhciBackend<CRD> {
     }
 }
 
-impl XhciBackend<CompleteRealDeviceImpl<NusbRealDevice, (u8, u8)>> {
+impl XhciBackend<NusbCompleteRealDevice> {
     pub async fn add_device_from_path(
         &self,
         path: impl AsRef<Path>,
+        port: Option<u8>,
+        config: &Config,
+        assignments: &Assignments,
         async_runtime: runtime::Handle,
     ) -> Result<()> {
-        let (bus, dev, path) = resolve_path(path)?;
-        let open_file = |err_msg| {
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&path)
-                .with_context(|| format!("{}: {}", err_msg, path.display()))
-        };
-
-        let file = open_file("Failed to open USB device file")?;
-        let device = nusb::Device::from_fd(file.into()).wait()?;
-        device.reset().wait()?;
-
-        // After the reset, the device instance is no longer usable and we need
-        // to reopen.
-        let file = open_file("Failed to open USB device file after device reset")?;
+        let DeviceFile {
+            bus,
+            device: dev,
+            file,
+            urb_file,
+            ..
+        } = DeviceFile::open_and_reset(path)?;
+        let usbfs = file.try_clone()?;
         let device = nusb::Device::from_fd(file.into()).wait()?;
-        let real_device = NusbRealDevice::try_new(device, async_runtime.clone())?;
-        let complete_device = CompleteRealDeviceImpl::new((bus, dev), real_device);
-        let response = self.hotplug_control().attach(complete_device).await;
+        let host_info = read_host_device_info(&device, bus, dev);
+        let port = port.or_else(|| config.pinned_port_of(&host_info));
+        let real_device = NusbRealDevice::try_new(
+            device,
+            usbfs.into(),
+            Some(urb_file.into()),
+            None,
+            config.reattach_drivers(&host_info),
+            async_runtime.clone(),
+        )?;
+        let identity = DeviceIdentity::new((bus, dev), &host_info);
+        let complete_device = CompleteRealDeviceImpl::new(identity.clone(), host_info, real_device);
+        let response = self.hotplug_control().attach(complete_device, port).await;
         if response != Response::SuccessfulOperation {
             return Err(anyhow!(
                 "initial attach of device {bus:03}:{dev:03} failed: {response:?}"
             ));
         }
+        assignments.record(identity, port, None);
 
         Ok(())
     }